@@ -2,13 +2,13 @@ use {
     criterion::{criterion_group, criterion_main, Criterion},
     futures::executor::block_on,
     gluesql_core::prelude::Glue,
-    gluesql_encryption::EncryptedStore,
+    gluesql_encryption::{
+        test_util::{self, RandNonce},
+        EncryptedStore,
+    },
     gluesql_sled_storage::SledStorage,
     std::{path::Path, sync::LazyLock},
-    test_utils::RandNonce,
 };
-#[path = "../src/test_utils.rs"]
-mod test_utils;
 
 const ITEM_SIZE: u32 = 5000;
 
@@ -30,7 +30,7 @@ pub fn bench_insert(c: &mut Criterion) {
     let storage = SledStorage::try_from(config).unwrap();
     let mut glue = Glue::new(EncryptedStore::new_unchecked(
         storage,
-        test_utils::new_key(),
+        test_util::new_key(),
         RandNonce::new(),
     ));
     // Create a dummy table
@@ -77,7 +77,7 @@ pub fn bench_select(c: &mut Criterion) {
     let storage = SledStorage::try_from(config).unwrap();
     let mut glue = Glue::new(EncryptedStore::new_unchecked(
         storage,
-        test_utils::new_key(),
+        test_util::new_key(),
         RandNonce::new(),
     ));
     // Create a dummy table
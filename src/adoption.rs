@@ -0,0 +1,230 @@
+use futures::TryStreamExt;
+use gluesql_core::{
+    data::Key,
+    store::{DataRow, Store, StoreMut, Transaction},
+};
+use ring::aead::NonceSequence;
+
+use crate::{encdec, in_transaction, EncryptedStore, Error, MaybeSendSync, TableColumns};
+
+/// Plaintext rows left in the encrypted tables of a store, as found by
+/// [`EncryptedStore::plaintext_report`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PlaintextReport {
+    /// The encrypted tables holding plaintext rows. Tables without any are left out.
+    pub tables: Vec<PlaintextTable>,
+}
+
+impl PlaintextReport {
+    /// Returns whether every row of every encrypted table is encrypted.
+    #[must_use]
+    pub const fn is_clean(&self) -> bool {
+        self.tables.is_empty()
+    }
+}
+
+/// Rows of an encrypted table holding values, or keys, the policy encrypts in plain text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlaintextTable {
+    pub table_name: String,
+    pub plaintext_rows: u64,
+    pub encrypted_rows: u64,
+}
+
+impl PlaintextTable {
+    /// Returns whether the table holds encrypted rows along with the plaintext ones, e.g. because
+    /// it was written to before the store was wrapped.
+    #[must_use]
+    pub const fn is_mixed(&self) -> bool {
+        self.plaintext_rows > 0 && self.encrypted_rows > 0
+    }
+}
+
+impl<S: Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>
+    EncryptedStore<S, NonceSeq>
+{
+    /// Returns the encrypted tables stored under the name the policy gives them.
+    ///
+    /// Tables the policy pseudonymizes but that still go by their real name are left to
+    /// [`EncryptedStore::encrypt_existing_store`].
+    async fn adopted_tables(&self) -> Result<Vec<String>, Error> {
+        let mut tables = Vec::new();
+
+        for schema in self.store.fetch_all_schemas().await? {
+            if self.tables.contains(&schema.table_name) {
+                continue;
+            }
+
+            let table_name = self.reveal_name(schema.table_name.clone()).await?;
+
+            if self.encrypts_table(&table_name)
+                && self.inner_table_name(&table_name) == schema.table_name
+            {
+                tables.push(table_name);
+            }
+        }
+
+        Ok(tables)
+    }
+
+    /// Returns whether a row of an encrypted table, as read from the inner store, must be
+    /// written again to be encrypted as the policy says.
+    fn needs_encrypting(
+        &self,
+        table_name: &str,
+        columns: &TableColumns,
+        key: &Key,
+        row: &mut DataRow,
+    ) -> bool {
+        (self.encrypts_row_keys(table_name) && !encdec::is_encrypted_key(key))
+            || self.holds_plaintext(table_name, columns, row)
+    }
+
+    /// Finds the rows of encrypted tables that are still in plain text, e.g. because they were
+    /// written to the inner store before it was wrapped, or by another client. Call it right
+    /// after opening a store adopted that way, since such rows are read as they are, or fail
+    /// with [`Error::PlaintextValue`] under [`EncryptedStore::with_strict_reads`].
+    ///
+    /// Every row is scanned, but none is decrypted. Plaintext `BYTEA` values look like
+    /// ciphertexts, and aren't found.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store fails to scan the data.
+    pub async fn plaintext_report(&self) -> Result<PlaintextReport, Error> {
+        let mut report = PlaintextReport::default();
+
+        for table_name in self.adopted_tables().await? {
+            let columns = self.table_columns(&table_name).await?;
+            let mut rows = self
+                .store
+                .scan_data(&self.inner_table_name(&table_name))
+                .await?;
+            let (mut plaintext_rows, mut encrypted_rows) = (0, 0);
+
+            while let Some((key, mut row)) = rows.try_next().await? {
+                if self.needs_encrypting(&table_name, &columns, &key, &mut row) {
+                    plaintext_rows += 1;
+                } else {
+                    encrypted_rows += 1;
+                }
+            }
+
+            if plaintext_rows > 0 {
+                report.tables.push(PlaintextTable {
+                    table_name,
+                    plaintext_rows,
+                    encrypted_rows,
+                });
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+impl<
+        S: Store + StoreMut + Transaction + MaybeSendSync,
+        NonceSeq: NonceSequence + MaybeSendSync,
+    > EncryptedStore<S, NonceSeq>
+{
+    /// Encrypts the rows [`EncryptedStore::plaintext_report`] finds, leaving the others alone,
+    /// and returns how many there were.
+    ///
+    /// Rows are encrypted a batch at a time, each batch in a transaction if the inner store
+    /// supports them. Encrypted rows aren't touched again, so calling this again after an
+    /// interruption picks up where it stopped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store fails to fetch, encrypt, or write the data.
+    pub async fn encrypt_remaining(&mut self) -> Result<u64, Error> {
+        in_transaction(self, async |store: &mut Self, autocommit| {
+            store.encrypt_remaining_tables(autocommit).await
+        })
+        .await
+    }
+
+    /// Encrypts the rows of [`EncryptedStore::encrypt_remaining`] in the transaction it began.
+    async fn encrypt_remaining_tables(&mut self, autocommit: bool) -> Result<u64, Error> {
+        let mut encrypted = 0;
+
+        for table_name in self.adopted_tables().await? {
+            let inner_table_name = self.inner_table_name(&table_name).into_owned();
+            let columns = self.table_columns(&table_name).await?;
+
+            loop {
+                // encrypted rows stop matching, so every scan starts over
+                let mut batch = Vec::new();
+                let mut rows = self.store.scan_data(&inner_table_name).await?;
+
+                while let Some((key, mut row)) = rows.try_next().await? {
+                    if self.needs_encrypting(&table_name, &columns, &key, &mut row) {
+                        batch.push((key, row));
+
+                        if batch.len() == self.batch_size {
+                            break;
+                        }
+                    }
+                }
+
+                drop(rows);
+
+                let done = batch.len() < self.batch_size;
+                encrypted += batch.len() as u64;
+
+                if !batch.is_empty() {
+                    self.encrypt_remaining_batch(&table_name, &inner_table_name, &columns, batch)
+                        .await?;
+                    self.commit_batch(autocommit).await?;
+                }
+
+                if done {
+                    break;
+                }
+            }
+        }
+
+        Ok(encrypted)
+    }
+
+    /// Writes a batch of rows holding plaintext through the encrypting write path, and deletes
+    /// what's left of them under their plaintext key, in the transaction the batch was read in.
+    async fn encrypt_remaining_batch(
+        &mut self,
+        table_name: &str,
+        inner_table_name: &str,
+        columns: &TableColumns,
+        batch: Vec<(Key, DataRow)>,
+    ) -> Result<(), Error> {
+        let mut rows = Vec::with_capacity(batch.len());
+        let mut plaintext_keys = Vec::new();
+
+        for (key, mut row) in batch {
+            // the values already encrypted are written again along with the others
+            encdec::decrypt_row_in_place(self.row_keys(), &mut row, self.compression)?;
+
+            if self.policy.has_tokenized_columns(table_name) {
+                self.detokenize_row(table_name, columns, &mut row).await?;
+            }
+
+            let row_key = encdec::decrypt_row_key(self.row_keys(), key.clone())?;
+
+            if self.encrypts_row_keys(table_name) && !encdec::is_encrypted_key(&key) {
+                plaintext_keys.push(key);
+            }
+
+            rows.push((row_key, row));
+        }
+
+        StoreMut::insert_data(self, table_name, rows).await?;
+
+        if !plaintext_keys.is_empty() {
+            self.store
+                .delete_data(inner_table_name, plaintext_keys)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,272 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+};
+
+use chrono::{NaiveDateTime, Utc};
+use futures::TryStreamExt;
+use gluesql_core::{
+    data::{Schema, Value},
+    store::{DataRow, Store, StoreMut},
+};
+use ring::aead::NonceSequence;
+
+use crate::{encdec, Compression, EncryptedStore, Error, MaybeSendSync};
+
+/// Rows of a table the store decrypted over a period, as recorded by the access audit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessRecord {
+    pub table: String,
+    pub rows: u64,
+    pub since: NaiveDateTime,
+    pub until: NaiveDateTime,
+}
+
+impl AccessRecord {
+    fn to_value(&self) -> Value {
+        Value::Map(
+            [
+                ("table".to_owned(), Value::Str(self.table.clone())),
+                ("rows".to_owned(), Value::U64(self.rows)),
+                ("since".to_owned(), Value::Timestamp(self.since)),
+                ("until".to_owned(), Value::Timestamp(self.until)),
+            ]
+            .into_iter()
+            .collect(),
+        )
+    }
+
+    fn from_value(value: Value) -> Result<Self, Error> {
+        let Value::Map(mut values) = value else {
+            return Err(Error::InvalidValue);
+        };
+        let mut remove = |name| values.remove(name).ok_or(Error::InvalidValue);
+
+        match (
+            remove("table")?,
+            remove("rows")?,
+            remove("since")?,
+            remove("until")?,
+        ) {
+            (
+                Value::Str(table),
+                Value::U64(rows),
+                Value::Timestamp(since),
+                Value::Timestamp(until),
+            ) => Ok(Self {
+                table,
+                rows,
+                since,
+                until,
+            }),
+            _ => Err(Error::InvalidValue),
+        }
+    }
+}
+
+/// Rows of a table the store opened since it was opened, as returned by
+/// [`EncryptedStore::access_stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TableAccessStats {
+    pub rows_decrypted: u64,
+    /// Rows that failed to open, which the corruption report details.
+    pub failures: u64,
+}
+
+impl TableAccessStats {
+    const fn count(&mut self, opened: bool) {
+        if opened {
+            self.rows_decrypted += 1;
+        } else {
+            self.failures += 1;
+        }
+    }
+}
+
+/// Rows decrypted by table since the accesses were last taken.
+#[derive(Debug)]
+pub struct PendingAccess {
+    since: NaiveDateTime,
+    rows: HashMap<String, u64>,
+}
+
+impl PendingAccess {
+    pub(crate) fn new() -> Self {
+        Self {
+            since: Utc::now().naive_utc(),
+            rows: HashMap::new(),
+        }
+    }
+
+    fn records(&self) -> Vec<AccessRecord> {
+        let until = Utc::now().naive_utc();
+        let mut records = self
+            .rows
+            .iter()
+            .map(|(table, &rows)| AccessRecord {
+                table: table.clone(),
+                rows,
+                since: self.since,
+                until,
+            })
+            .collect::<Vec<_>>();
+
+        records.sort_by(|a, b| a.table.cmp(&b.table));
+        records
+    }
+}
+
+impl<S, NonceSeq: NonceSequence> EncryptedStore<S, NonceSeq> {
+    /// Counts a row of a table opened, or that failed to, in the access stats and, if it's on,
+    /// the access audit.
+    pub(crate) fn record_access(&self, table_name: &str, opened: bool) {
+        {
+            let mut stats = self
+                .access_stats
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+
+            // tables are few and read over and over, so their name is only copied once
+            if let Some(table) = stats.get_mut(table_name) {
+                table.count(opened);
+            } else {
+                let mut table = TableAccessStats::default();
+
+                table.count(opened);
+                stats.insert(table_name.to_owned(), table);
+            }
+        }
+
+        if let (true, Some(pending)) = (opened, &self.access_audit) {
+            let mut pending = pending.lock().unwrap_or_else(PoisonError::into_inner);
+
+            *pending.rows.entry(table_name.to_owned()).or_default() += 1;
+        }
+    }
+
+    /// Returns how many rows of each table the store decrypted, or failed to, since it was
+    /// opened, e.g. to notice bulk reads of sensitive tables as they happen. Counts are kept for
+    /// every table read, regardless of the access audit.
+    #[must_use]
+    pub fn access_stats(&self) -> HashMap<String, TableAccessStats> {
+        self.access_stats
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Returns the rows decrypted by table since the access audit was turned on or last taken,
+    /// and starts counting anew, e.g. to hand them to a sink of the application's own instead of
+    /// [`EncryptedStore::flush_access_audit`].
+    ///
+    /// Returns nothing if the access audit is off.
+    pub fn take_access_records(&self) -> Vec<AccessRecord> {
+        self.access_audit.as_ref().map_or_else(Vec::new, |pending| {
+            let mut pending = pending.lock().unwrap_or_else(PoisonError::into_inner);
+            let records = pending.records();
+
+            *pending = PendingAccess::new();
+            records
+        })
+    }
+}
+
+impl<S: Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>
+    EncryptedStore<S, NonceSeq>
+{
+    /// Returns the accesses [`EncryptedStore::flush_access_audit`] recorded, oldest first.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the audit table can't be read or decrypted.
+    pub async fn access_audit_log(&self) -> Result<Vec<AccessRecord>, Error> {
+        if self.store.fetch_schema(&self.tables.audit).await?.is_none() {
+            return Ok(Vec::new());
+        }
+
+        let mut rows = self.store.scan_data(&self.tables.audit).await?;
+        let mut log = Vec::new();
+
+        while let Some((_, row)) = rows.try_next().await? {
+            let DataRow::Map(mut values) = row else {
+                return Err(Error::InvalidValue);
+            };
+            let mut value = values.remove("access").ok_or(Error::InvalidValue)?;
+
+            self.decrypt_value(&mut value, Compression::None)?;
+            log.push(AccessRecord::from_value(value)?);
+        }
+
+        log.sort_by(|a, b| a.until.cmp(&b.until).then_with(|| a.table.cmp(&b.table)));
+
+        Ok(log)
+    }
+}
+
+impl<S: Store + StoreMut + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>
+    EncryptedStore<S, NonceSeq>
+{
+    /// Writes the rows decrypted by table since the access audit was turned on or last flushed
+    /// to an encrypted table of the store, e.g. as evidence of who read what for compliance, and
+    /// returns how many records were written.
+    ///
+    /// The counts are kept in memory until then, since reads can't write to the store. They're
+    /// only reset once written, so a failed flush can be retried.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the records can't be encrypted or written.
+    pub async fn flush_access_audit(&mut self) -> Result<usize, Error> {
+        let Some(pending) = &mut self.access_audit else {
+            return Ok(0);
+        };
+        let records = pending
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .records();
+
+        if records.is_empty() {
+            return Ok(0);
+        }
+
+        if self.store.fetch_schema(&self.tables.audit).await?.is_none() {
+            self.store
+                .insert_schema(&Schema {
+                    table_name: self.tables.audit.clone(),
+                    column_defs: None,
+                    indexes: vec![],
+                    engine: None,
+                    foreign_keys: vec![],
+                    comment: Some("Table to store the accesses to encrypted tables".to_string()),
+                })
+                .await?;
+            self.clear_schema_cache();
+        }
+
+        self.prepare_nonces().await?;
+
+        let rows = records
+            .iter()
+            .map(|record| {
+                let mut value = record.to_value();
+
+                encdec::encrypt_value_in_place(
+                    &self.key,
+                    &mut *self.nonces(),
+                    &mut value,
+                    Compression::None,
+                )?;
+
+                Ok(DataRow::Map(HashMap::from([("access".to_owned(), value)])))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        self.store.append_data(&self.tables.audit, rows).await?;
+
+        if let Some(pending) = &mut self.access_audit {
+            *pending = Mutex::new(PendingAccess::new());
+        }
+
+        Ok(records.len())
+    }
+}
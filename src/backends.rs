@@ -0,0 +1,56 @@
+//! Shortcuts wrapping the storages of the gluesql project, each behind a feature named after it.
+
+#[cfg(feature = "csv-storage")]
+pub use gluesql_csv_storage::CsvStorage;
+#[cfg(feature = "json-storage")]
+pub use gluesql_json_storage::JsonStorage;
+#[cfg(feature = "sled-storage")]
+pub use gluesql_sled_storage::SledStorage;
+use ring::aead::UnboundKey;
+
+use crate::{open_in_transaction, EncryptedStore, Error};
+
+/// Opens the sled database at `path`, creating it if needed, and wraps it like
+/// [`EncryptedStore::new`], making the key check in a transaction.
+///
+/// # Errors
+///
+/// Returns an error if the database can't be opened, or like [`EncryptedStore::new`].
+#[cfg(feature = "sled-storage")]
+pub async fn encrypted_sled(
+    path: &str,
+    key: UnboundKey,
+) -> Result<EncryptedStore<SledStorage>, Error> {
+    open_in_transaction(SledStorage::new(path)?, async |store| {
+        EncryptedStore::new(store, key).await
+    })
+    .await
+}
+
+/// Opens the directory of JSON files at `path`, creating it if needed, and wraps it like
+/// [`EncryptedStore::new`].
+///
+/// # Errors
+///
+/// Returns an error if the directory can't be opened, or like [`EncryptedStore::new`].
+#[cfg(feature = "json-storage")]
+pub async fn encrypted_json(
+    path: &str,
+    key: UnboundKey,
+) -> Result<EncryptedStore<JsonStorage>, Error> {
+    EncryptedStore::new(JsonStorage::new(path)?, key).await
+}
+
+/// Opens the directory of CSV files at `path`, creating it if needed, and wraps it like
+/// [`EncryptedStore::new`].
+///
+/// # Errors
+///
+/// Returns an error if the directory can't be opened, or like [`EncryptedStore::new`].
+#[cfg(feature = "csv-storage")]
+pub async fn encrypted_csv(
+    path: &str,
+    key: UnboundKey,
+) -> Result<EncryptedStore<CsvStorage>, Error> {
+    EncryptedStore::new(CsvStorage::new(path)?, key).await
+}
@@ -0,0 +1,362 @@
+use std::io;
+
+use futures::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    TryStreamExt,
+};
+use gluesql_core::{
+    data::{Key, Schema},
+    store::{Store, StoreMut},
+};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, NonceSequence, UnboundKey, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+
+use crate::{encdec, EncryptedStore, Error, MaybeSendSync, RandomNonce};
+
+/// Starts every backup, ahead of its version and id.
+const MAGIC: &[u8; 4] = b"GEBK";
+const VERSION: u8 = 1;
+/// Length of the header: the magic, the version, and 16 random bytes identifying the backup.
+const HEADER_LEN: usize = MAGIC.len() + 1 + 16;
+/// Most bytes a frame may take, so a corrupt length isn't allocated.
+const MAX_FRAME_LEN: usize = 1 << 30;
+
+/// What a frame holds, as told by its first byte.
+const SCHEMA_FRAME: u8 = 0;
+const ROWS_FRAME: u8 = 1;
+const END_FRAME: u8 = 2;
+
+fn malformed() -> Error {
+    Error::Backup("malformed backup".to_owned())
+}
+
+fn io_error(error: &io::Error) -> Error {
+    if error.kind() == io::ErrorKind::UnexpectedEof {
+        Error::Backup("backup is cut short".to_owned())
+    } else {
+        Error::Backup(error.to_string())
+    }
+}
+
+/// Authenticates a frame along with the header and its position, so frames can't be dropped,
+/// reordered, or taken from another backup.
+fn frame_aad(header: &[u8; HEADER_LEN], index: u64) -> Aad<Vec<u8>> {
+    let mut aad = header.to_vec();
+
+    aad.extend_from_slice(&index.to_be_bytes());
+
+    Aad::from(aad)
+}
+
+/// Appends what `encode` writes at the end of `frame`, prefixed with its length.
+fn push_prefixed(
+    mut frame: Vec<u8>,
+    encode: impl FnOnce(Vec<u8>) -> Result<Vec<u8>, Error>,
+) -> Result<Vec<u8>, Error> {
+    let start = frame.len();
+
+    frame.extend_from_slice(&[0; 4]);
+
+    let mut frame = encode(frame)?;
+    let len = u32::try_from(frame.len() - start - 4)
+        .map_err(|_| Error::Backup("row too large for a backup".to_owned()))?;
+
+    frame[start..start + 4].copy_from_slice(&len.to_be_bytes());
+
+    Ok(frame)
+}
+
+/// Takes the next item [`push_prefixed`] wrote off the front of `bytes`.
+fn take_prefixed<'a>(bytes: &mut &'a [u8]) -> Result<&'a [u8], Error> {
+    let (len, rest) = bytes.split_first_chunk::<4>().ok_or_else(malformed)?;
+    let len = u32::from_be_bytes(*len) as usize;
+
+    if rest.len() < len {
+        return Err(malformed());
+    }
+
+    let (item, rest) = rest.split_at(len);
+    *bytes = rest;
+
+    Ok(item)
+}
+
+struct BackupWriter<'w, W> {
+    key: LessSafeKey,
+    nonces: RandomNonce,
+    header: [u8; HEADER_LEN],
+    frames: u64,
+    writer: &'w mut W,
+}
+
+impl<'w, W: AsyncWrite + Unpin> BackupWriter<'w, W> {
+    /// Writes the header of a new backup.
+    async fn new(key: UnboundKey, writer: &'w mut W) -> Result<Self, Error> {
+        let mut header = [0; HEADER_LEN];
+
+        header[..MAGIC.len()].copy_from_slice(MAGIC);
+        header[MAGIC.len()] = VERSION;
+        SystemRandom::new().fill(&mut header[MAGIC.len() + 1..])?;
+
+        writer.write_all(&header).await.map_err(|e| io_error(&e))?;
+
+        Ok(Self {
+            key: LessSafeKey::new(key),
+            nonces: RandomNonce::new(),
+            header,
+            frames: 0,
+            writer,
+        })
+    }
+
+    /// Seals a frame and writes it as `len || nonce || ciphertext || tag`.
+    async fn write_frame(&mut self, mut frame: Vec<u8>) -> Result<(), Error> {
+        let nonce = self.nonces.advance()?;
+        let nonce_bytes = *nonce.as_ref();
+
+        self.key.seal_in_place_append_tag(
+            nonce,
+            frame_aad(&self.header, self.frames),
+            &mut frame,
+        )?;
+
+        let len = NONCE_LEN + frame.len();
+        let len = u32::try_from(len)
+            .ok()
+            .filter(|_| len <= MAX_FRAME_LEN)
+            .ok_or_else(|| Error::Backup("frame too large for a backup".to_owned()))?;
+
+        for bytes in [&len.to_be_bytes()[..], &nonce_bytes[..], &frame[..]] {
+            self.writer
+                .write_all(bytes)
+                .await
+                .map_err(|e| io_error(&e))?;
+        }
+
+        self.frames += 1;
+
+        Ok(())
+    }
+}
+
+struct BackupReader<'r, R> {
+    key: LessSafeKey,
+    header: [u8; HEADER_LEN],
+    frames: u64,
+    reader: &'r mut R,
+}
+
+impl<'r, R: AsyncRead + Unpin> BackupReader<'r, R> {
+    /// Reads the header of a backup.
+    async fn new(key: UnboundKey, reader: &'r mut R) -> Result<Self, Error> {
+        let mut header = [0; HEADER_LEN];
+
+        reader
+            .read_exact(&mut header)
+            .await
+            .map_err(|e| io_error(&e))?;
+
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(Error::Backup("not a backup".to_owned()));
+        }
+
+        if header[MAGIC.len()] != VERSION {
+            return Err(Error::Backup(format!(
+                "unsupported backup version {}",
+                header[MAGIC.len()]
+            )));
+        }
+
+        Ok(Self {
+            key: LessSafeKey::new(key),
+            header,
+            frames: 0,
+            reader,
+        })
+    }
+
+    /// Reads and opens the next frame.
+    async fn read_frame(&mut self) -> Result<Vec<u8>, Error> {
+        let mut len = [0; 4];
+
+        self.reader
+            .read_exact(&mut len)
+            .await
+            .map_err(|e| io_error(&e))?;
+
+        let len = u32::from_be_bytes(len) as usize;
+
+        if len < NONCE_LEN + self.key.algorithm().tag_len() || len > MAX_FRAME_LEN {
+            return Err(malformed());
+        }
+
+        let mut frame = vec![0; len];
+
+        self.reader
+            .read_exact(&mut frame)
+            .await
+            .map_err(|e| io_error(&e))?;
+
+        let (nonce, sealed) = frame.split_at_mut(NONCE_LEN);
+        let plaintext_len = self
+            .key
+            .open_in_place(
+                Nonce::try_assume_unique_for_key(nonce)?,
+                frame_aad(&self.header, self.frames),
+                sealed,
+            )
+            .map_err(|_| {
+                Error::Backup("backup doesn't open with the key, or was altered".to_owned())
+            })?
+            .len();
+
+        self.frames += 1;
+        frame.truncate(NONCE_LEN + plaintext_len);
+        frame.drain(..NONCE_LEN);
+
+        Ok(frame)
+    }
+}
+
+impl<S: Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>
+    EncryptedStore<S, NonceSeq>
+{
+    /// Writes every table of the store to `writer` as a backup sealed with `key`, e.g. to archive
+    /// the data without it ever being written down in plain text, and returns how many rows it
+    /// holds. [`EncryptedStore::import_backup`] reads it back into any store.
+    ///
+    /// The backup is a header, holding its version and a random id, followed by frames of
+    /// schemas and rows, [`EncryptedStore::with_batch_size`] rows at a time. Frames are sealed
+    /// with `key` along with the header and their position, and the last one marks the end, so
+    /// importing a backup that's altered, reordered or cut short fails rather than restoring part
+    /// of it unnoticed. Custom functions aren't backed up.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the data can't be read from the store, or [`Error::Backup`] if it
+    /// can't be written to `writer`.
+    pub async fn export_backup<W: AsyncWrite + Unpin>(
+        &self,
+        key: UnboundKey,
+        writer: &mut W,
+    ) -> Result<u64, Error> {
+        let mut backup = BackupWriter::new(key, writer).await?;
+        let mut exported = 0_u64;
+
+        for schema in Store::fetch_all_schemas(self).await? {
+            if self.tables.contains(&schema.table_name) {
+                continue;
+            }
+
+            let mut frame = vec![SCHEMA_FRAME];
+            serde_json::to_writer(&mut frame, &schema).map_err(|_| Error::InvalidValue)?;
+            backup.write_frame(frame).await?;
+
+            let mut rows = Store::scan_data(self, &schema.table_name).await?;
+            let mut frame = vec![ROWS_FRAME];
+            let mut batch = 0;
+
+            while let Some((key, row)) = rows.try_next().await? {
+                frame = push_prefixed(frame, |buffer| Ok(postcard::to_extend(&key, buffer)?))?;
+                frame = push_prefixed(frame, |buffer| encdec::encode_row_into(&row, buffer))?;
+                batch += 1;
+                exported += 1;
+
+                if batch == self.batch_size {
+                    backup
+                        .write_frame(std::mem::replace(&mut frame, vec![ROWS_FRAME]))
+                        .await?;
+                    batch = 0;
+                }
+            }
+
+            if batch > 0 {
+                backup.write_frame(frame).await?;
+            }
+        }
+
+        let mut end = vec![END_FRAME];
+        end.extend_from_slice(&exported.to_be_bytes());
+        backup.write_frame(end).await?;
+        backup.writer.flush().await.map_err(|e| io_error(&e))?;
+
+        Ok(exported)
+    }
+}
+
+impl<S: Store + StoreMut + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>
+    EncryptedStore<S, NonceSeq>
+{
+    /// Reads a backup written by [`EncryptedStore::export_backup`] into the store, encrypting
+    /// it with the key and policy of the store, and returns how many rows it held.
+    ///
+    /// Like [`EncryptedStore::copy_to`], the import isn't atomic: if it fails, the tables
+    /// imported so far are left in the store.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Backup`] if the backup can't be read, doesn't open with `key`, or was
+    /// altered or cut short, [`Error::TableExists`] if the store already has one of its tables,
+    /// or an error if a row can't be written to the store.
+    pub async fn import_backup<R: AsyncRead + Unpin>(
+        &mut self,
+        key: UnboundKey,
+        reader: &mut R,
+    ) -> Result<u64, Error> {
+        let mut backup = BackupReader::new(key, reader).await?;
+        let mut schema = None;
+        let mut imported = 0_u64;
+
+        loop {
+            let frame = backup.read_frame().await?;
+            let Some((&kind, mut body)) = frame.split_first() else {
+                return Err(malformed());
+            };
+
+            match kind {
+                SCHEMA_FRAME => {
+                    let table: Schema = serde_json::from_slice(body).map_err(|_| malformed())?;
+
+                    if Store::fetch_schema(self, &table.table_name)
+                        .await?
+                        .is_some()
+                    {
+                        return Err(Error::TableExists(table.table_name));
+                    }
+
+                    StoreMut::insert_schema(self, &table).await?;
+                    schema = Some(table);
+                }
+                ROWS_FRAME => {
+                    let schema = schema.as_ref().ok_or_else(malformed)?;
+                    let mut rows = Vec::new();
+
+                    while !body.is_empty() {
+                        let key: Key = postcard::from_bytes(take_prefixed(&mut body)?)?;
+                        let row = encdec::decode_row(take_prefixed(&mut body)?)?;
+
+                        rows.push((key, row));
+                    }
+
+                    imported += rows.len() as u64;
+                    self.write_copied_rows(schema, rows).await?;
+                }
+                END_FRAME => {
+                    let exported = body
+                        .try_into()
+                        .map(u64::from_be_bytes)
+                        .map_err(|_| malformed())?;
+
+                    return if exported == imported {
+                        Ok(imported)
+                    } else {
+                        Err(malformed())
+                    };
+                }
+                _ => return Err(malformed()),
+            }
+        }
+    }
+}
@@ -0,0 +1,361 @@
+//! Operator tasks on encrypted stores kept in files, for those who'd rather not write Rust.
+//!
+//! Keys are read in hex from environment variables rather than arguments, which other users of
+//! the machine can see. Stores are opened as described by an [`EncryptionConfig`] read from a
+//! TOML file, so the tool reads them the way the app writes them. The sled backend is always
+//! built in, and JSON and CSV with the `json-storage` and `csv-storage` features.
+
+use std::{
+    env,
+    error::Error as StdError,
+    fs,
+    io::{BufReader, BufWriter},
+    process::ExitCode,
+};
+
+use futures::{executor::block_on, io::AllowStdIo};
+use gluesql_core::store::{GStore, GStoreMut, Transaction};
+#[cfg(feature = "csv-storage")]
+use gluesql_encryption::CsvStorage;
+#[cfg(feature = "json-storage")]
+use gluesql_encryption::JsonStorage;
+#[cfg(feature = "sled-storage")]
+use gluesql_encryption::SledStorage;
+use gluesql_encryption::{
+    from_hex, in_transaction, open_in_transaction, EncryptedStore, EncryptionConfig, Error,
+    MaybeSendSync, RandomNonce, UnboundKey,
+};
+
+const USAGE: &str = "\
+usage: gluesql-enc [--config <path>] <command> <store> [<backup>]
+
+commands:
+    verify-key <store>        check GLUESQL_ENC_KEY against the key check of the store
+    verify <store>            decrypt every row, and report the ones that don't open
+    stats <store>             print what encryption costs in storage, by table
+    rekey <store>             change the key of the store to GLUESQL_ENC_NEW_KEY
+    export <store> <backup>   write a backup of the store sealed with GLUESQL_ENC_BACKUP_KEY
+    import <backup> <store>   read a backup sealed with GLUESQL_ENC_BACKUP_KEY into the store
+
+Stores are given as <backend>:<path>, the backend being sled, json or csv, if it was built
+in, and backups as the path of a new file. Stores are opened as described by the
+EncryptionConfig in the TOML file given with --config or GLUESQL_ENC_CONFIG, or with the
+defaults without one. Keys are the key material of the config in hex, e.g. AES-256-GCM keys
+of 32 bytes by default. GLUESQL_ENC_BACKUP_KEY defaults to GLUESQL_ENC_KEY.";
+
+type Result<T> = std::result::Result<T, Box<dyn StdError>>;
+
+#[derive(Clone, Copy)]
+enum Backend {
+    #[cfg(feature = "sled-storage")]
+    Sled,
+    #[cfg(feature = "json-storage")]
+    Json,
+    #[cfg(feature = "csv-storage")]
+    Csv,
+}
+
+/// A store given on the command line.
+struct Location {
+    backend: Backend,
+    path: String,
+}
+
+impl Location {
+    fn parse(location: &str) -> Result<Self> {
+        let (backend, path) = location
+            .split_once(':')
+            .ok_or_else(|| format!("store {location} isn't of the form <backend>:<path>"))?;
+        let backend = match backend {
+            #[cfg(feature = "sled-storage")]
+            "sled" => Backend::Sled,
+            #[cfg(feature = "json-storage")]
+            "json" => Backend::Json,
+            #[cfg(feature = "csv-storage")]
+            "csv" => Backend::Csv,
+            #[allow(unreachable_patterns)]
+            backend @ ("sled" | "json" | "csv") => {
+                return Err(format!(
+                    "the {backend} backend isn't built in, see the {backend}-storage feature"
+                )
+                .into())
+            }
+            backend => return Err(format!("unknown backend {backend}").into()),
+        };
+
+        Ok(Self {
+            backend,
+            path: path.to_owned(),
+        })
+    }
+}
+
+/// Opens the inner store at a location as `$store`, and evaluates `$body` with it, once per
+/// backend since each is of its own type.
+macro_rules! with_store {
+    ($location: expr, $store: ident => $body: expr) => {{
+        let location: Location = $location;
+
+        match location.backend {
+            #[cfg(feature = "sled-storage")]
+            Backend::Sled => {
+                let $store = SledStorage::new(&location.path).map_err(Error::from)?;
+                $body
+            }
+            #[cfg(feature = "json-storage")]
+            Backend::Json => {
+                let $store = JsonStorage::new(&location.path).map_err(Error::from)?;
+                $body
+            }
+            #[cfg(feature = "csv-storage")]
+            Backend::Csv => {
+                let $store = CsvStorage::new(&location.path).map_err(Error::from)?;
+                $body
+            }
+        }
+    }};
+}
+
+/// Reads a key in hex from an environment variable.
+fn key_bytes(var: &str) -> Result<Vec<u8>> {
+    let hex = env::var(var).map_err(|_| format!("{var} isn't set"))?;
+
+    from_hex(hex.trim()).map_err(|_| format!("{var} isn't hex").into())
+}
+
+/// Reads the config from the file given with `--config`, which is taken out of the arguments, or
+/// else from the one named by `GLUESQL_ENC_CONFIG`. Without either, the defaults are used.
+fn config(args: &mut Vec<&str>) -> Result<EncryptionConfig> {
+    let path = match args.iter().position(|&arg| arg == "--config") {
+        Some(i) => {
+            let path = (*args.get(i + 1).ok_or("--config needs the path of a file")?).to_owned();
+            args.drain(i..=i + 1);
+
+            path
+        }
+        None => match env::var("GLUESQL_ENC_CONFIG") {
+            Ok(path) => path,
+            Err(_) => return Ok(EncryptionConfig::default()),
+        },
+    };
+
+    let config = fs::read_to_string(&path)
+        .map_err(|error| format!("can't read the config {path}: {error}"))?;
+
+    Ok(
+        toml::from_str(&config)
+            .map_err(|error| format!("the config {path} is invalid: {error}"))?,
+    )
+}
+
+/// Opens a store without writing to it.
+async fn open_read_only<S: GStore + Transaction + MaybeSendSync>(
+    store: S,
+    config: &EncryptionConfig,
+    key_material: &[u8],
+) -> std::result::Result<EncryptedStore<S>, Error> {
+    open_in_transaction(store, async |store| {
+        EncryptedStore::open_read_only_from_config(store, config.clone(), key_material).await
+    })
+    .await
+}
+
+/// Opens a store, setting it up if it's new.
+async fn open<S: GStore + GStoreMut + MaybeSendSync>(
+    store: S,
+    config: &EncryptionConfig,
+    key_material: &[u8],
+) -> std::result::Result<EncryptedStore<S>, Error> {
+    open_in_transaction(store, async |store| {
+        EncryptedStore::from_config(store, config.clone(), key_material, RandomNonce::new()).await
+    })
+    .await
+}
+
+async fn verify_key<S: GStore + Transaction + MaybeSendSync>(
+    store: S,
+    config: &EncryptionConfig,
+) -> Result<ExitCode> {
+    match open_read_only(store, config, &key_bytes("GLUESQL_ENC_KEY")?).await {
+        Ok(_) => {
+            println!("the key matches");
+            Ok(ExitCode::SUCCESS)
+        }
+        Err(Error::InvalidKey) => {
+            println!("the key doesn't match");
+            Ok(ExitCode::FAILURE)
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+async fn verify<S: GStore + Transaction + MaybeSendSync>(
+    store: S,
+    config: &EncryptionConfig,
+) -> Result<ExitCode> {
+    let mut store = open_read_only(store, config, &key_bytes("GLUESQL_ENC_KEY")?).await?;
+    let report = in_transaction(&mut store, async |store, _| store.verify_all().await).await?;
+
+    for table in &report.tables {
+        println!(
+            "{}: {} ok, {} tampered, {} corrupt",
+            table.table_name, table.ok, table.tampered, table.corrupt
+        );
+    }
+
+    Ok(if report.is_intact() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+async fn stats<S: GStore + Transaction + MaybeSendSync>(
+    store: S,
+    config: &EncryptionConfig,
+) -> Result<ExitCode> {
+    let mut store = open_read_only(store, config, &key_bytes("GLUESQL_ENC_KEY")?).await?;
+
+    println!(
+        "{} bytes of overhead per sealed value, with {:?}",
+        store.per_value_overhead(),
+        store.algorithm()
+    );
+
+    for table in in_transaction(&mut store, async |store, _| store.stats().await)
+        .await?
+        .tables
+    {
+        println!(
+            "{}: {} rows in {:?} mode, {} values sealed, {} bytes of plaintext, {} bytes stored",
+            table.table_name,
+            table.rows,
+            table.mode,
+            table.sealed_values,
+            table.plaintext_bytes,
+            table.ciphertext_bytes
+        );
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn rekey<S: GStore + GStoreMut + MaybeSendSync>(
+    store: S,
+    config: &EncryptionConfig,
+) -> Result<ExitCode> {
+    let new_key = config.key(&key_bytes("GLUESQL_ENC_NEW_KEY")?)?;
+    let key_bytes = key_bytes("GLUESQL_ENC_KEY")?;
+
+    // a store without a key check is refused rather than set up
+    open_in_transaction(store, async |store| {
+        EncryptedStore::open_existing_from_config(
+            store,
+            config.clone(),
+            &key_bytes,
+            RandomNonce::new(),
+        )
+        .await
+    })
+    .await?
+    .change_key(new_key)
+    .await?;
+    println!("the key was changed");
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Reads the key of backups, which defaults to the key of the store.
+fn backup_key(config: &EncryptionConfig, key: &[u8]) -> Result<UnboundKey> {
+    let backup_key = match env::var_os("GLUESQL_ENC_BACKUP_KEY") {
+        Some(_) => key_bytes("GLUESQL_ENC_BACKUP_KEY")?,
+        None => key.to_vec(),
+    };
+
+    Ok(config.key(&backup_key)?)
+}
+
+async fn export<S: GStore + Transaction + MaybeSendSync>(
+    store: S,
+    config: &EncryptionConfig,
+    path: &str,
+) -> Result<ExitCode> {
+    let key = key_bytes("GLUESQL_ENC_KEY")?;
+    let backup_key = backup_key(config, &key)?;
+    let mut store = open_read_only(store, config, &key).await?;
+
+    let file = fs::File::create_new(path)
+        .map_err(|error| format!("can't create the backup {path}: {error}"))?;
+    let rows = in_transaction(&mut store, async |store, _| {
+        store
+            .export_backup(backup_key, &mut AllowStdIo::new(BufWriter::new(file)))
+            .await
+    })
+    .await?;
+    println!("{rows} rows were exported");
+
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn import<S: GStore + GStoreMut + MaybeSendSync>(
+    path: &str,
+    store: S,
+    config: &EncryptionConfig,
+) -> Result<ExitCode> {
+    let key = key_bytes("GLUESQL_ENC_KEY")?;
+    let backup_key = backup_key(config, &key)?;
+    let file =
+        fs::File::open(path).map_err(|error| format!("can't open the backup {path}: {error}"))?;
+    let mut store = open(store, config, &key).await?;
+
+    // the backup is read in a single transaction, so on stores that have them a failed import
+    // leaves none of its tables behind
+    let rows = in_transaction(&mut store, async |store, _| {
+        store
+            .import_backup(backup_key, &mut AllowStdIo::new(BufReader::new(file)))
+            .await
+    })
+    .await?;
+    println!("{rows} rows were imported");
+
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn run(args: &[String]) -> Result<ExitCode> {
+    let mut args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    let config = &config(&mut args)?;
+
+    match args[..] {
+        ["verify-key", location] => {
+            with_store!(Location::parse(location)?, store => verify_key(store, config).await)
+        }
+        ["verify", location] => {
+            with_store!(Location::parse(location)?, store => verify(store, config).await)
+        }
+        ["stats", location] => {
+            with_store!(Location::parse(location)?, store => stats(store, config).await)
+        }
+        ["rekey", location] => {
+            with_store!(Location::parse(location)?, store => rekey(store, config).await)
+        }
+        ["export", location, backup] => {
+            with_store!(Location::parse(location)?, store => export(store, config, backup).await)
+        }
+        ["import", backup, location] => {
+            with_store!(Location::parse(location)?, store => import(backup, store, config).await)
+        }
+        _ => {
+            eprintln!("{USAGE}");
+            Ok(ExitCode::from(2))
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+
+    block_on(run(&args)).unwrap_or_else(|error| {
+        eprintln!("gluesql-enc: {error}");
+        ExitCode::FAILURE
+    })
+}
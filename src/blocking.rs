@@ -0,0 +1,290 @@
+use std::sync::Arc;
+
+use futures::{channel::oneshot, stream, StreamExt};
+use gluesql_core::{
+    data::{Key, Value},
+    error::Result,
+    store::{DataRow, RowIter},
+};
+use ring::aead::NonceSequence;
+
+use crate::{encdec, trace::CryptoSpan, EncryptedStore, Error, RowSealer, TableColumns};
+
+/// Number of rows scans with [`EncryptedStore::with_scan_concurrency`] open in a task.
+const SCAN_CHUNK_SIZE: usize = 256;
+
+/// Work handed to the executor set with [`EncryptedStore::with_blocking_executor`].
+pub type BlockingTask = Box<dyn FnOnce() + Send>;
+
+/// Runs the sealing and opening of large rows away from the thread driving the store.
+#[derive(Clone)]
+pub struct BlockingExecutor {
+    /// Size from which rows are handed to `spawn`, in bytes of strings and byte strings.
+    pub threshold: usize,
+    pub spawn: Arc<dyn Fn(BlockingTask) + Send + Sync>,
+}
+
+/// Returns the size of the strings and byte strings of a row, which make up most of the work of
+/// sealing or opening it.
+fn payload_len(row: &DataRow) -> usize {
+    let values: Box<dyn Iterator<Item = &Value>> = match row {
+        DataRow::Vec(values) => Box::new(values.iter()),
+        DataRow::Map(values) => Box::new(values.values()),
+    };
+
+    values
+        .map(|value| match value {
+            Value::Str(string) => string.len(),
+            Value::Bytea(bytes) => bytes.len(),
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Seals a batch of rows, each along with its identity if its nonces are derived from it.
+fn seal_batch(
+    sealer: &RowSealer<'_>,
+    nonces: encdec::BatchNonces,
+    mut rows: Vec<(Option<encdec::RowIdentity>, DataRow)>,
+) -> Result<Vec<DataRow>, Error> {
+    let span = CryptoSpan::encrypt_row(sealer.table_name, rows.len());
+
+    sealer.seal_rows(
+        nonces,
+        rows.iter_mut()
+            .map(|(identity, row)| (identity.as_ref(), row)),
+    )?;
+    span.record_rows(rows.iter().map(|(_, row)| row));
+
+    Ok(rows.into_iter().map(|(_, row)| row).collect())
+}
+
+impl<S, NonceSeq: NonceSequence> EncryptedStore<S, NonceSeq> {
+    /// Returns the executor to run the sealing or opening of `rows` on, if one of them is large
+    /// enough to need it.
+    fn offloads<'a>(
+        &self,
+        rows: impl IntoIterator<Item = &'a DataRow>,
+    ) -> Option<&BlockingExecutor> {
+        self.blocking.as_ref().filter(|executor| {
+            rows.into_iter()
+                .any(|row| payload_len(row) >= executor.threshold)
+        })
+    }
+
+    /// Runs `task` on the executor, or on a thread of its own without one, and waits for it to
+    /// finish.
+    async fn run_blocking<T: Send + 'static>(
+        executor: Option<&BlockingExecutor>,
+        task: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<T, Error> {
+        let (sender, receiver) = oneshot::channel();
+        let task = move || {
+            // the store stopped waiting, so there's no one left to tell
+            sender.send(task()).ok();
+        };
+
+        match executor {
+            Some(executor) => (executor.spawn)(Box::new(task)),
+            None => drop(std::thread::spawn(task)),
+        }
+
+        receiver.await.map_err(|_| Error::BlockingTaskDropped)
+    }
+
+    /// Returns the number of rows scans open at a time, within the limit set with
+    /// [`EncryptedStore::with_max_in_flight_rows`].
+    pub(crate) fn scan_chunk_size(&self) -> usize {
+        self.max_in_flight_rows
+            .map_or(SCAN_CHUNK_SIZE, |rows| rows.min(SCAN_CHUNK_SIZE))
+    }
+
+    /// Returns a copy of the keys of the store, e.g. for a task to own.
+    pub(crate) fn key_set(&self) -> encdec::KeySet {
+        encdec::KeySet {
+            key: self.key.clone(),
+            name_key: self.name_key.clone(),
+            value_key: self.value_key.clone(),
+            identity_key: self.identity_key.clone(),
+            ciphers: self.ciphers.clone(),
+        }
+    }
+
+    /// Encrypts a batch of rows like [`EncryptedStore::encrypt_rows`], on the blocking executor
+    /// if one of them is large.
+    pub(crate) async fn encrypt_batch(
+        &self,
+        table_name: &str,
+        columns: &TableColumns,
+        rows: Vec<(Option<encdec::RowIdentity>, DataRow)>,
+    ) -> Result<Vec<DataRow>, Error> {
+        let nonces = self.batch_nonces()?;
+        let count = rows.len();
+
+        let sealed = if let Some(executor) = self.offloads(rows.iter().map(|(_, row)| row)) {
+            let (table_name, columns, policy, keys, compression) = (
+                table_name.to_owned(),
+                columns.clone(),
+                self.policy.clone(),
+                self.key_set(),
+                self.compression,
+            );
+
+            Self::run_blocking(Some(executor), move || {
+                let sealer = RowSealer {
+                    table_name: &table_name,
+                    columns: &columns,
+                    policy: &policy,
+                    keys: keys.row_keys(),
+                    compression,
+                };
+
+                seal_batch(&sealer, nonces, rows)
+            })
+            .await??
+        } else {
+            seal_batch(&self.row_sealer(table_name, columns), nonces, rows)?
+        };
+
+        if let Some(observer) = &self.observer {
+            observer.rows_encrypted(table_name, count);
+        }
+
+        Ok(sealed)
+    }
+
+    /// Decrypts a row like [`EncryptedStore::open_row`], on the blocking executor if it's large.
+    pub(crate) async fn open_large_row(
+        &self,
+        table_name: &str,
+        columns: &TableColumns,
+        key: &Key,
+        mut row: DataRow,
+    ) -> Result<DataRow, Error> {
+        let Some(executor) = self.offloads([&row]) else {
+            self.open_row(table_name, columns, key, &mut row)?;
+
+            return Ok(row);
+        };
+
+        self.check_row(table_name, columns, key, &mut row)?;
+
+        let (keys, previous, legacy, compression) = (
+            self.key_set(),
+            self.previous_keys.clone(),
+            self.legacy_ciphertexts,
+            self.compression,
+        );
+        let traced_table = table_name.to_owned();
+        let (mut row, opened) = Self::run_blocking(Some(executor), move || {
+            let span = CryptoSpan::decrypt_row(&traced_table, 1);
+            span.record_rows([&row]);
+
+            let keys = encdec::RowKeys {
+                previous: previous.as_ref(),
+                legacy,
+                ..keys.row_keys()
+            };
+            let opened = encdec::decrypt_row_in_place(keys, &mut row, compression);
+
+            (row, opened)
+        })
+        .await?;
+
+        self.row_opened(table_name, columns, key, &mut row, opened)?;
+
+        Ok(row)
+    }
+
+    /// Opens the rows of a scan a chunk at a time on other threads, up to
+    /// [`EncryptedStore::with_scan_concurrency`] chunks at once, and yields them in order.
+    ///
+    /// Row keys are decrypted, and strict reads checked, on the thread polling the scan.
+    pub(crate) fn scan_concurrently<'a>(
+        &'a self,
+        table_name: String,
+        columns: TableColumns,
+        encrypts_row_keys: bool,
+        rows: RowIter<'a>,
+    ) -> RowIter<'a> {
+        let keys = Arc::new((self.key_set(), self.previous_keys.clone()));
+        let (legacy, compression) = (self.legacy_ciphertexts, self.compression);
+        let scanned = Arc::new((table_name, columns));
+        let opening = Arc::clone(&scanned);
+
+        let chunk_size = self.scan_chunk_size();
+        // chunks opened ahead of the reader, along with the one being read
+        let concurrency = self
+            .max_in_flight_rows
+            .map_or(self.scan_concurrency, |rows| {
+                self.scan_concurrency.min(rows / chunk_size).max(1)
+            });
+
+        let chunks = rows.chunks(chunk_size).map(move |chunk| {
+            let (table_name, columns) = &*scanned;
+            let chunk = chunk
+                .into_iter()
+                .map(|row| {
+                    let (mut key, mut row) = row?;
+
+                    if encrypts_row_keys {
+                        key = encdec::decrypt_row_key(self.row_keys(), key)?;
+                    }
+
+                    self.check_row(table_name, columns, &key, &mut row)?;
+
+                    Ok((key, row))
+                })
+                .collect::<Vec<Result<_>>>();
+            let keys = Arc::clone(&keys);
+            let traced_table = table_name.clone();
+
+            Self::run_blocking(self.blocking.as_ref(), move || {
+                let span = CryptoSpan::decrypt_row(&traced_table, chunk.len());
+                span.record_rows(
+                    chunk
+                        .iter()
+                        .filter_map(|row| row.as_ref().ok())
+                        .map(|(_, row)| row),
+                );
+
+                let (current, previous) = &*keys;
+                let keys = encdec::RowKeys {
+                    previous: previous.as_ref(),
+                    legacy,
+                    ..current.row_keys()
+                };
+
+                chunk
+                    .into_iter()
+                    .map(|row| {
+                        row.map(|(key, mut row)| {
+                            let opened = encdec::decrypt_row_in_place(keys, &mut row, compression);
+
+                            (key, row, opened)
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+        });
+
+        Box::pin(chunks.buffered(concurrency).flat_map(move |chunk| {
+            let (table_name, columns) = &*opening;
+            let rows: Vec<Result<_>> = match chunk {
+                Ok(rows) => rows
+                    .into_iter()
+                    .map(|row| {
+                        let (key, mut row, opened) = row?;
+
+                        self.row_opened(table_name, columns, &key, &mut row, opened)?;
+
+                        Ok((key, row))
+                    })
+                    .collect(),
+                Err(error) => vec![Err(error.into())],
+            };
+
+            stream::iter(rows)
+        }))
+    }
+}
@@ -0,0 +1,321 @@
+use std::{borrow::Cow, num::NonZeroU32};
+
+use gluesql_core::store::Store;
+use ring::aead::{LessSafeKey, UnboundKey};
+use serde::{Deserialize, Serialize};
+
+use crate::{EncryptionPolicy, Error, InternalTables, DEFAULT_NAMESPACE};
+
+/// AEAD algorithm used to encrypt values.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    Aes128Gcm,
+    #[default]
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl Algorithm {
+    pub(crate) const ALL: [Self; 3] = [Self::Aes128Gcm, Self::Aes256Gcm, Self::ChaCha20Poly1305];
+
+    /// Returns the algorithm of a key, which `ring` only makes for the algorithms above.
+    pub(crate) fn of(key: &LessSafeKey) -> Self {
+        Self::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.ring() == key.algorithm())
+            .expect("ring has no other AEAD algorithms")
+    }
+
+    /// Returns the id of the algorithm in ciphertext headers.
+    pub(crate) const fn id(self) -> u8 {
+        match self {
+            Self::Aes128Gcm => 1,
+            Self::Aes256Gcm => 2,
+            Self::ChaCha20Poly1305 => 3,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|algorithm| algorithm.id() == id)
+    }
+
+    /// Returns AES-256-GCM if the CPU has instructions for AES and carry-less multiplication, and
+    /// ChaCha20-Poly1305, which is faster in software, if it doesn't.
+    #[must_use]
+    pub fn detect() -> Self {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        let accelerated = std::arch::is_x86_feature_detected!("aes")
+            && std::arch::is_x86_feature_detected!("pclmulqdq");
+        #[cfg(target_arch = "aarch64")]
+        let accelerated = std::arch::is_aarch64_feature_detected!("aes")
+            && std::arch::is_aarch64_feature_detected!("pmull");
+        #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+        let accelerated = false;
+
+        if accelerated {
+            Self::Aes256Gcm
+        } else {
+            Self::ChaCha20Poly1305
+        }
+    }
+
+    /// Returns the matching `ring` algorithm.
+    #[must_use]
+    pub fn ring(self) -> &'static ring::aead::Algorithm {
+        match self {
+            Self::Aes128Gcm => &ring::aead::AES_128_GCM,
+            Self::Aes256Gcm => &ring::aead::AES_256_GCM,
+            Self::ChaCha20Poly1305 => &ring::aead::CHACHA20_POLY1305,
+        }
+    }
+
+    /// Returns the length of the keys used by the algorithm.
+    #[must_use]
+    pub fn key_len(self) -> usize {
+        self.ring().key_len()
+    }
+
+    /// Creates a key for the algorithm from raw key bytes, e.g. to open a store without picking
+    /// the matching `ring` algorithm.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidKeyMaterial`] if the bytes aren't [`Algorithm::key_len`] long.
+    pub fn key(self, key_bytes: &[u8]) -> Result<UnboundKey, Error> {
+        UnboundKey::new(self.ring(), key_bytes).map_err(|_| Error::InvalidKeyMaterial)
+    }
+}
+
+/// Raw key bytes, as taken by [`EncryptedStore::from_key_bytes`].
+///
+/// [`EncryptedStore::from_key_bytes`]: crate::EncryptedStore::from_key_bytes
+pub trait KeyBytes {
+    fn key_bytes(&self) -> &[u8];
+}
+
+impl KeyBytes for [u8] {
+    fn key_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl<const N: usize> KeyBytes for [u8; N] {
+    fn key_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl KeyBytes for Vec<u8> {
+    fn key_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl<T: KeyBytes + zeroize::Zeroize> KeyBytes for zeroize::Zeroizing<T> {
+    fn key_bytes(&self) -> &[u8] {
+        (**self).key_bytes()
+    }
+}
+
+#[cfg(feature = "secrecy")]
+impl KeyBytes for secrecy::SecretVec<u8> {
+    fn key_bytes(&self) -> &[u8] {
+        secrecy::ExposeSecret::expose_secret(self)
+    }
+}
+
+/// Compression applied to values before they're encrypted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum Compression {
+    #[default]
+    None,
+    /// DEFLATE compression at the given level (0-10).
+    Deflate { level: u8 },
+}
+
+impl Compression {
+    pub(crate) fn compress(self, data: &[u8]) -> Cow<'_, [u8]> {
+        match self {
+            Self::None => Cow::Borrowed(data),
+            Self::Deflate { level } => {
+                Cow::Owned(miniz_oxide::deflate::compress_to_vec(data, level))
+            }
+        }
+    }
+
+    pub(crate) fn decompress(self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Self::None => Ok(data.to_owned()),
+            Self::Deflate { .. } => {
+                miniz_oxide::inflate::decompress_to_vec(data).map_err(|_| Error::InvalidValue)
+            }
+        }
+    }
+}
+
+/// Encoding of values and rows before they're encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    /// postcard encoding of gluesql's own types, written before the encoding was versioned. It
+    /// breaks whenever gluesql reorders its enums, so it's only read.
+    LegacyPostcard,
+    /// Version 1 of the crate's own encoding, independent of gluesql's types.
+    V1,
+}
+
+impl Codec {
+    /// The codec values are written with.
+    pub const CURRENT: Self = Self::V1;
+}
+
+/// How the key is obtained from the key material given to the store.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum Kdf {
+    /// The key material is the raw key.
+    #[default]
+    None,
+    /// The key material is a passphrase, stretched with PBKDF2-HMAC-SHA256.
+    Pbkdf2 {
+        iterations: NonZeroU32,
+        salt: String,
+    },
+}
+
+/// How often the key of a store should be changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RotationSchedule {
+    pub every_days: NonZeroU32,
+}
+
+impl RotationSchedule {
+    /// Returns the longest a key should be used for.
+    pub(crate) fn period(self) -> chrono::Duration {
+        chrono::Duration::days(i64::from(self.every_days.get()))
+    }
+}
+
+/// Everything needed to configure an `EncryptedStore`, in a form that can be stored in a file.
+///
+/// ```toml
+/// algorithm = "cha_cha20_poly1305"
+/// namespace = "myapp_crypt_"
+///
+/// [compression]
+/// kind = "deflate"
+/// level = 6
+///
+/// [kdf]
+/// kind = "pbkdf2"
+/// iterations = 600000
+/// salt = "my-app"
+///
+/// [policy]
+/// # deploy the wrapper without encrypting anything yet
+/// passthrough = true
+///
+/// [rotation]
+/// every_days = 90
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    pub algorithm: Algorithm,
+    pub policy: EncryptionPolicy,
+    pub compression: Compression,
+    pub kdf: Kdf,
+    pub rotation: Option<RotationSchedule>,
+    /// Prefix of the tables the store keeps its own data in, `encrypted_` if unset.
+    pub namespace: Option<String>,
+}
+
+impl EncryptionConfig {
+    /// Creates the key described by the config from the given key material.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key material doesn't fit the algorithm.
+    pub fn key(&self, key_material: &[u8]) -> Result<UnboundKey, Error> {
+        match &self.kdf {
+            Kdf::None => self.algorithm.key(key_material),
+            Kdf::Pbkdf2 { iterations, salt } => {
+                let mut key = vec![0; self.algorithm.key_len()];
+
+                ring::pbkdf2::derive(
+                    ring::pbkdf2::PBKDF2_HMAC_SHA256,
+                    *iterations,
+                    salt.as_bytes(),
+                    key_material,
+                    &mut key,
+                );
+
+                self.algorithm.key(&key)
+            }
+        }
+    }
+
+    /// Checks that the settings of the config don't contradict each other, e.g. that tables the
+    /// policy gives settings of their own are encrypted. [`EncryptedStore::from_config`] calls it
+    /// before opening the store.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidConfig`] describing the first contradiction found.
+    ///
+    /// [`EncryptedStore::from_config`]: crate::EncryptedStore::from_config
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Compression::Deflate { level } = self.compression {
+            if level > 10 {
+                return Err(Error::InvalidConfig(format!(
+                    "compression level {level} is out of range, pick a level from 0 to 10"
+                )));
+            }
+        }
+
+        if let Kdf::Pbkdf2 { salt, .. } = &self.kdf {
+            if salt.is_empty() {
+                return Err(Error::InvalidConfig(
+                    "the PBKDF2 salt is empty, set it to a string unique to the application"
+                        .to_owned(),
+                ));
+            }
+        }
+
+        if self.namespace.as_deref() == Some("") {
+            return Err(Error::InvalidConfig(
+                "the namespace is empty, so the store's own tables could clash with the \
+                 application's; set a prefix or leave it unset"
+                    .to_owned(),
+            ));
+        }
+
+        self.policy.validate()
+    }
+
+    /// Checks key material against the key check of a store, e.g. to validate a passphrase
+    /// before opening the store with [`EncryptedStore::from_config`], without reading anything
+    /// else from it.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::NonEncryptedDatabase`] if the store has no key check, or an error if the
+    /// key material doesn't fit the algorithm or the key check can't be read.
+    ///
+    /// [`EncryptedStore::from_config`]: crate::EncryptedStore::from_config
+    pub async fn verify_key<S: Store>(
+        &self,
+        store: &S,
+        key_material: &[u8],
+    ) -> Result<bool, Error> {
+        let key = LessSafeKey::new(self.key(key_material)?);
+        let tables = InternalTables::new(self.namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE));
+
+        crate::check_key(store, &tables, &key)
+            .await?
+            .ok_or(Error::NonEncryptedDatabase)
+    }
+}
@@ -0,0 +1,116 @@
+use futures::TryStreamExt;
+use gluesql_core::{
+    ast::ColumnUniqueOption,
+    data::{Key, Schema},
+    store::{DataRow, Store, StoreMut, Transaction},
+};
+use ring::aead::NonceSequence;
+
+use crate::{in_transaction, EncryptedStore, Error, MaybeSendSync};
+
+impl<S: Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>
+    EncryptedStore<S, NonceSeq>
+{
+    /// Copies every table of this store into `other`, which may use another key, algorithm,
+    /// policy or inner store, e.g. to move the data from one backend to another.
+    ///
+    /// Rows are decrypted and re-encrypted for `other` a batch of
+    /// [`EncryptedStore::with_batch_size`] rows at a time, so the store is never held in memory
+    /// as a whole. Schemas are copied along with their indexes, but custom functions aren't.
+    ///
+    /// The copy isn't atomic: if it fails, the tables copied so far are left in `other`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::TableExists`] before copying anything if `other` already has one of the
+    /// tables, or an error if a row can't be read from this store or written to `other`.
+    pub async fn copy_to<
+        T: Store + StoreMut + Transaction + MaybeSendSync,
+        OtherNonceSeq: NonceSequence + MaybeSendSync,
+    >(
+        &self,
+        other: &mut EncryptedStore<T, OtherNonceSeq>,
+    ) -> Result<(), Error> {
+        in_transaction(other, async |other, autocommit| {
+            self.copy_tables(other, autocommit).await
+        })
+        .await
+    }
+
+    /// Copies the tables of [`EncryptedStore::copy_to`] in the transaction it began in `other`.
+    async fn copy_tables<
+        T: Store + StoreMut + Transaction + MaybeSendSync,
+        OtherNonceSeq: NonceSequence + MaybeSendSync,
+    >(
+        &self,
+        other: &mut EncryptedStore<T, OtherNonceSeq>,
+        autocommit: bool,
+    ) -> Result<(), Error> {
+        let schemas = self
+            .fetch_all_schemas()
+            .await?
+            .into_iter()
+            .filter(|schema| !self.tables.contains(&schema.table_name))
+            .collect::<Vec<_>>();
+
+        for schema in &schemas {
+            if other.fetch_schema(&schema.table_name).await?.is_some() {
+                return Err(Error::TableExists(schema.table_name.clone()));
+            }
+        }
+
+        for schema in &schemas {
+            other.insert_schema(schema).await?;
+
+            let mut rows = self.scan_data(&schema.table_name).await?;
+            let mut batch = Vec::with_capacity(self.batch_size);
+
+            while let Some(row) = rows.try_next().await? {
+                batch.push(row);
+
+                if batch.len() == self.batch_size {
+                    other
+                        .write_copied_rows(schema, std::mem::take(&mut batch))
+                        .await?;
+                    other.commit_batch(autocommit).await?;
+                }
+            }
+
+            if !batch.is_empty() {
+                other.write_copied_rows(schema, batch).await?;
+            }
+
+            other.commit_batch(autocommit).await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<S: Store + StoreMut + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>
+    EncryptedStore<S, NonceSeq>
+{
+    /// Writes rows copied from another store into a table. Rows of tables with a primary key
+    /// keep their keys, but the others are appended: the inner store only moves past the keys
+    /// it generated itself, and would hand out the keys of the copied rows again.
+    pub(crate) async fn write_copied_rows(
+        &mut self,
+        schema: &Schema,
+        rows: Vec<(Key, DataRow)>,
+    ) -> Result<(), Error> {
+        let has_primary_key =
+            schema.column_defs.iter().flatten().any(|column_def| {
+                column_def.unique == Some(ColumnUniqueOption { is_primary: true })
+            });
+
+        if has_primary_key {
+            StoreMut::insert_data(self, &schema.table_name, rows).await?;
+        } else {
+            let rows = rows.into_iter().map(|(_, row)| row).collect();
+
+            StoreMut::append_data(self, &schema.table_name, rows).await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use std::{
+    fmt::{self, Debug, Formatter},
+    sync::{Arc, MutexGuard, PoisonError},
+};
+
+use gluesql_core::{data::Key, store::DataRow};
+use ring::aead::NonceSequence;
+
+use crate::{encdec, redact::RedactedKey, EncryptedStore, Error, TableColumns};
+
+/// Number of corruptions a store keeps in its report before it only counts them.
+const CORRUPTION_REPORT_LIMIT: usize = 1000;
+
+/// Callback set with [`EncryptedStore::with_decrypt_failure_hook`].
+pub type DecryptFailureHook = Arc<dyn Fn(&Corruption) + Send + Sync>;
+
+/// Why a row didn't open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionKind {
+    /// A ciphertext failed authentication: it was altered, or sealed with another key.
+    Tampered,
+    /// A ciphertext authenticated but its plaintext didn't decode.
+    Undecodable,
+    /// A value was encoded by a newer version of the crate.
+    UnsupportedVersion(u8),
+    /// The row isn't shaped like anything the store writes.
+    Malformed,
+}
+
+impl CorruptionKind {
+    pub(crate) const fn of(error: &Error) -> Self {
+        match error {
+            Error::DecryptFailed { kind, .. } => *kind,
+            Error::EncryptionError => Self::Tampered,
+            Error::SerializationError(_) => Self::Undecodable,
+            Error::UnsupportedValueVersion { version, .. } => Self::UnsupportedVersion(*version),
+            _ => Self::Malformed,
+        }
+    }
+}
+
+/// A row that failed to open when it was read.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Corruption {
+    pub table: String,
+    pub key: Key,
+    /// The column of the value that failed to open. Unknown for rows encrypted as a whole, and
+    /// for `DataRow::Vec` rows of tables whose column names weren't needed to read them.
+    pub column: Option<String>,
+    pub kind: CorruptionKind,
+}
+
+/// The key of the row is redacted, since it's data of the table.
+impl Debug for Corruption {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Corruption")
+            .field("table", &self.table)
+            .field("key", &RedactedKey(&self.key))
+            .field("column", &self.column)
+            .field("kind", &self.kind)
+            .finish()
+    }
+}
+
+/// The rows that failed to open since the report was last taken, as returned by
+/// [`EncryptedStore::corruption_report`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CorruptionReport {
+    /// The first corruptions, oldest first.
+    pub corruptions: Vec<Corruption>,
+    /// Corruptions past the first thousand, which are counted but not kept.
+    pub omitted: u64,
+}
+
+impl CorruptionReport {
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.corruptions.is_empty() && self.omitted == 0
+    }
+}
+
+impl<S, NonceSeq: NonceSequence> EncryptedStore<S, NonceSeq> {
+    fn corruptions(&self) -> MutexGuard<'_, CorruptionReport> {
+        self.corruptions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the rows that failed to open since the store was opened or the report was last
+    /// taken, with the table, key and column of each, e.g. to find the rows that broke reads
+    /// whose errors got lost on the way to the application.
+    #[must_use]
+    pub fn corruption_report(&self) -> CorruptionReport {
+        self.corruptions().clone()
+    }
+
+    /// Returns the report like [`EncryptedStore::corruption_report`] and starts a new one.
+    pub fn take_corruption_report(&self) -> CorruptionReport {
+        std::mem::take(&mut *self.corruptions())
+    }
+
+    /// Returns the column of the value of a row that failed to open, if it's known.
+    ///
+    /// `row` is the row as far as it was decrypted, so its first value still sealed that doesn't
+    /// open is the one that failed.
+    pub(crate) fn failed_column(
+        &self,
+        columns: &TableColumns,
+        row: &mut DataRow,
+    ) -> Option<String> {
+        if encdec::is_whole_row(row) {
+            return None;
+        }
+
+        encdec::named_values_mut(row, &columns.names)
+            .find(|(_, value)| {
+                encdec::decrypt_row_value_in_place(
+                    self.row_keys(),
+                    &mut (*value).clone(),
+                    self.compression,
+                )
+                .is_err()
+            })
+            .and_then(|(column_name, _)| column_name.map(str::to_owned))
+    }
+
+    /// Adds a row that failed to open to the corruption report.
+    pub(crate) fn record_corruption(
+        &self,
+        table_name: &str,
+        key: &Key,
+        column: Option<String>,
+        error: &Error,
+    ) {
+        let corruption = Corruption {
+            table: table_name.to_owned(),
+            key: key.clone(),
+            column,
+            kind: CorruptionKind::of(error),
+        };
+
+        // called before the report is locked, so the hook may read it
+        if let Some(hook) = &self.on_decrypt_failure {
+            hook(&corruption);
+        }
+
+        let mut report = self.corruptions();
+
+        if report.corruptions.len() < CORRUPTION_REPORT_LIMIT {
+            report.corruptions.push(corruption);
+        } else {
+            report.omitted += 1;
+        }
+    }
+}
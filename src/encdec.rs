@@ -1,96 +1,1399 @@
-use gluesql_core::{data::Value, store::DataRow};
-use ring::aead::{Aad, LessSafeKey, Nonce, NonceSequence};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+};
 
-pub fn encrypt_value_in_place<N: NonceSequence>(
+use crate::{Algorithm, Compression, NonceBatch, BATCH_PREFIX_LEN};
+use gluesql_core::{
+    ast::{AstLiteral, Expr},
+    data::{Key, Value},
+    store::DataRow,
+};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, NonceSequence, UnboundKey, NONCE_LEN},
+    digest, hkdf, hmac,
+};
+
+mod wire;
+
+pub use wire::{decode_row, encode_row_into, encode_value};
+
+/// Prefix of a ciphertext holding a whole row.
+const ROW_HEADER: &[u8] = b"GERW";
+
+/// Prefix of a ciphertext holding a value, followed by the version of its layout, so it can be
+/// told apart from `BYTEA` values left as-is.
+const VALUE_ENVELOPE: &[u8] = b"GEV\x01";
+
+/// Prefix of a value sealed with a table cipher, followed by the id of its algorithm.
+const CIPHER_HEADER: &[u8] = b"GEC";
+
+/// Prefix of a deterministically encrypted row key.
+const KEY_HEADER: &[u8] = b"GEKY";
+
+/// Prefix of an encrypted name, e.g. a map key of a schemaless row.
+const NAME_PREFIX: &str = "gen:";
+
+/// Prefix of an encrypted expression, stored as a string literal.
+const EXPR_PREFIX: &str = "gee:";
+
+/// Label the secret keys are derived from is sealed with, see [`derive_material`].
+const DERIVATION_LABEL: &[u8] = b"gluesql-encryption key derivation";
+
+/// Label the identity of a row starts with, ahead of its table and row key, see
+/// [`RowIdentity`].
+const ROW_IDENTITY_LABEL: &[u8] = b"gluesql-encryption row identity";
+
+/// Label the nonce of a deterministically encrypted value starts with, ahead of its table and
+/// column, see [`value_mac`].
+const DETERMINISTIC_VALUE_LABEL: &[u8] = b"gluesql-encryption deterministic value";
+
+/// Largest scratch buffer kept around between encryptions, so a single large value doesn't pin
+/// its size for the life of the thread.
+const SCRATCH_LIMIT: usize = 1 << 20;
+
+/// Size of the stack buffer small values are serialized into instead of the scratch buffer,
+/// enough for any number, date or other value of fixed size.
+const SMALL_PLAINTEXT_LEN: usize = 48;
+
+thread_local! {
+    /// Buffer plaintexts are serialized into before they're sealed.
+    static SCRATCH: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A key to seal values with, along with the header identifying it in front of its ciphertexts.
+#[derive(Clone, Copy)]
+pub struct Cipher<'a> {
+    key: &'a LessSafeKey,
+    header: &'a [u8],
+}
+
+impl<'a> Cipher<'a> {
+    /// The main key, whose ciphertexts have no header.
+    pub const fn main(key: &'a LessSafeKey) -> Self {
+        Self { key, header: &[] }
+    }
+}
+
+/// Keys of the ciphers tables can use instead of the algorithm of the main key, derived from it.
+#[derive(Clone)]
+pub struct TableCiphers {
+    keys: HashMap<Algorithm, (LessSafeKey, [u8; CIPHER_HEADER.len() + 1])>,
+}
+
+impl TableCiphers {
+    pub fn new(key: &LessSafeKey) -> Self {
+        let keys = Algorithm::ALL
+            .into_iter()
+            .map(|algorithm| {
+                let material = derive_material(
+                    key,
+                    &format!("gluesql-encryption cipher {}", algorithm.id()),
+                );
+                let cipher_key =
+                    UnboundKey::new(algorithm.ring(), &material[..algorithm.key_len()])
+                        .expect("derived material fits every algorithm");
+
+                let mut header = [0; CIPHER_HEADER.len() + 1];
+                header[..CIPHER_HEADER.len()].copy_from_slice(CIPHER_HEADER);
+                header[CIPHER_HEADER.len()] = algorithm.id();
+
+                (algorithm, (LessSafeKey::new(cipher_key), header))
+            })
+            .collect();
+
+        Self { keys }
+    }
+
+    /// Returns the cipher of the given algorithm.
+    pub fn cipher(&self, algorithm: Algorithm) -> Cipher<'_> {
+        let (key, header) = &self.keys[&algorithm];
+
+        Cipher { key, header }
+    }
+
+    /// Returns the cipher of the given algorithm, or the main key if there's none or it already
+    /// uses that algorithm.
+    pub fn select<'a>(&'a self, key: &'a LessSafeKey, algorithm: Option<Algorithm>) -> Cipher<'a> {
+        match algorithm {
+            Some(algorithm) if algorithm.ring() != key.algorithm() => self.cipher(algorithm),
+            _ => Cipher::main(key),
+        }
+    }
+
+    /// Reads the cipher header of a ciphertext, returning the cipher it names and the length of
+    /// the header.
+    fn split(&self, encrypted: &[u8]) -> Option<(Algorithm, &LessSafeKey, usize)> {
+        let &id = encrypted.strip_prefix(CIPHER_HEADER)?.first()?;
+        let algorithm = Algorithm::from_id(id)?;
+
+        Some((algorithm, &self.keys[&algorithm].0, CIPHER_HEADER.len() + 1))
+    }
+}
+
+/// A key, along with the keys derived from it.
+#[derive(Clone)]
+pub struct KeySet {
+    pub key: LessSafeKey,
+    pub name_key: hmac::Key,
+    pub value_key: hmac::Key,
+    pub identity_key: hmac::Key,
+    pub ciphers: TableCiphers,
+}
+
+impl KeySet {
+    pub fn new(key: LessSafeKey) -> Self {
+        Self {
+            name_key: derive_subkey(&key, crate::NAME_KEY_LABEL),
+            value_key: derive_subkey(&key, crate::VALUE_KEY_LABEL),
+            identity_key: derive_subkey(&key, crate::IDENTITY_KEY_LABEL),
+            ciphers: TableCiphers::new(&key),
+            key,
+        }
+    }
+
+    pub const fn row_keys(&self) -> RowKeys<'_> {
+        RowKeys {
+            key: &self.key,
+            ciphers: &self.ciphers,
+            name_key: &self.name_key,
+            value_key: &self.value_key,
+            previous: None,
+            legacy: false,
+        }
+    }
+}
+
+/// The keys needed to open the rows of tables.
+#[derive(Clone, Copy)]
+pub struct RowKeys<'a> {
+    pub key: &'a LessSafeKey,
+    pub ciphers: &'a TableCiphers,
+    pub name_key: &'a hmac::Key,
+    pub value_key: &'a hmac::Key,
+    /// The keys of a key change in progress, which opens what these can't.
+    pub previous: Option<&'a KeySet>,
+    /// Whether `BYTEA` values without an envelope are tried as ciphertexts written before it.
+    pub legacy: bool,
+}
+
+impl RowKeys<'_> {
+    /// Returns the cipher of the given algorithm, or the main key if there's none.
+    pub fn cipher(&self, algorithm: Option<Algorithm>) -> Cipher<'_> {
+        self.ciphers.select(self.key, algorithm)
+    }
+}
+
+/// Nonces for sealing a batch of rows away from the store's nonce sequence, e.g. on another
+/// thread, counting through the batch drawn from the sequence.
+pub struct BatchNonces {
+    batch: NonceBatch,
+    counter: u32,
+    /// The first counter that's out of range.
+    end: u32,
+}
+
+impl BatchNonces {
+    pub const fn new(batch: NonceBatch) -> Self {
+        Self {
+            batch,
+            counter: 0,
+            end: u32::MAX,
+        }
+    }
+
+    /// Splits the counters left into `parts` ranges of equal size, so parts of a batch can be
+    /// sealed on different threads without ever sharing a nonce.
+    #[cfg(feature = "rayon")]
+    pub fn split(self, parts: usize) -> impl Iterator<Item = Self> {
+        let parts = u32::try_from(parts).unwrap_or(u32::MAX).max(1);
+        let size = (self.end - self.counter) / parts;
+
+        (0..parts).map(move |part| Self {
+            batch: self.batch,
+            counter: self.counter + part * size,
+            end: self.counter + (part + 1) * size,
+        })
+    }
+}
+
+impl NonceSequence for BatchNonces {
+    fn advance(&mut self) -> Result<Nonce, ring::error::Unspecified> {
+        if self.counter == self.end {
+            return Err(ring::error::Unspecified);
+        }
+
+        let counter = self.counter.to_be_bytes();
+        let mut nonce = [0; NONCE_LEN];
+
+        match &self.batch {
+            NonceBatch::Derived(base) => {
+                let digest = digest::digest(&digest::SHA256, &[base.as_slice(), &counter].concat());
+                nonce.copy_from_slice(&digest.as_ref()[..NONCE_LEN]);
+            }
+            NonceBatch::Counter(prefix) => {
+                nonce[..BATCH_PREFIX_LEN].copy_from_slice(prefix);
+                nonce[BATCH_PREFIX_LEN..].copy_from_slice(&counter);
+            }
+        }
+
+        self.counter += 1;
+
+        Ok(Nonce::assume_unique_for_key(nonce))
+    }
+}
+
+/// Derives secret key material from the encryption key, distinct for every label.
+///
+/// `ring` keeps the bytes of the key to itself, so HKDF is keyed with a block of zeros sealed
+/// with it instead, tag included, and expands it with the label as its info.
+pub fn derive_material(key: &LessSafeKey, label: &str) -> [u8; 32] {
+    let digest = digest::digest(&digest::SHA256, DERIVATION_LABEL);
+    let nonce = Nonce::try_assume_unique_for_key(&digest.as_ref()[..NONCE_LEN])
+        .expect("digest is longer than a nonce");
+
+    let mut secret = [0; 32];
+    let tag = key
+        .seal_in_place_separate_tag(nonce, Aad::from(DERIVATION_LABEL), &mut secret)
+        .expect("sealing a single block can't fail");
+
+    let mut material = [0; 32];
+
+    hkdf::Salt::new(hkdf::HKDF_SHA256, &[])
+        .extract(&[secret.as_slice(), tag.as_ref()].concat())
+        .expand(&[label.as_bytes()], hkdf::HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut material))
+        .expect("the material is as long as a SHA-256 digest");
+
+    material
+}
+
+/// Derives a secret HMAC key from the encryption key, distinct for every label.
+pub fn derive_subkey(key: &LessSafeKey, label: &str) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, &derive_material(key, label))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    use std::fmt::Write;
+
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+/// Decodes a string of hex digits, e.g. a key kept in an environment variable, into bytes.
+///
+/// # Errors
+///
+/// Returns [`Error::InvalidValue`](crate::Error::InvalidValue) if the string has an odd length
+/// or anything but hex digits in it.
+pub fn from_hex(hex: &str) -> Result<Vec<u8>, crate::Error> {
+    // `from_str_radix` takes a leading sign, so the digits are checked first
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(crate::Error::InvalidValue);
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| crate::Error::InvalidValue))
+        .collect()
+}
+
+/// Deterministically seals a plaintext into `nonce || ciphertext || tag`.
+///
+/// The nonce is the HMAC of the plaintext under `mac_key`, making the scheme misuse-resistant.
+fn seal_deterministic(
+    key: &LessSafeKey,
+    mac_key: &hmac::Key,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, crate::Error> {
+    seal_with_mac(key, hmac::Context::with_key(mac_key), plaintext)
+}
+
+/// Like [`seal_deterministic`], but the nonce is the HMAC of the plaintext following whatever
+/// `mac` was already given.
+fn seal_with_mac(
+    key: &LessSafeKey,
+    mut mac: hmac::Context,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, crate::Error> {
+    mac.update(plaintext);
+
+    let mac = mac.sign();
+    let nonce_bytes = &mac.as_ref()[..NONCE_LEN];
+
+    let mut encrypted = Vec::with_capacity(NONCE_LEN + plaintext.len() + key.algorithm().tag_len());
+    encrypted.extend_from_slice(nonce_bytes);
+    encrypted.extend_from_slice(plaintext);
+
+    let nonce = Nonce::try_assume_unique_for_key(nonce_bytes)?;
+    let tag =
+        key.seal_in_place_separate_tag(nonce, Aad::from(nonce_bytes), &mut encrypted[NONCE_LEN..])?;
+
+    encrypted.extend_from_slice(tag.as_ref());
+
+    Ok(encrypted)
+}
+
+/// Opens a ciphertext sealed with [`seal_deterministic`].
+fn open_deterministic(key: &LessSafeKey, mut encrypted: Vec<u8>) -> Result<Vec<u8>, crate::Error> {
+    if encrypted.len() < NONCE_LEN {
+        return Err(crate::Error::InvalidValue);
+    }
+
+    let (nonce, ciphertext) = encrypted.split_at_mut(NONCE_LEN);
+
+    let nonce = Nonce::try_assume_unique_for_key(nonce)?;
+    let aad = Aad::from(*nonce.as_ref());
+
+    let plaintext_len = key.open_in_place(nonce, aad, ciphertext)?.len();
+
+    encrypted.truncate(NONCE_LEN + plaintext_len);
+    encrypted.drain(..NONCE_LEN);
+
+    Ok(encrypted)
+}
+
+/// Deterministically encrypts a name, so the same name always gives the same ciphertext.
+pub fn encrypt_name(
+    key: &LessSafeKey,
+    name_key: &hmac::Key,
+    name: &str,
+) -> Result<String, crate::Error> {
+    let encrypted = seal_deterministic(key, name_key, name.as_bytes())?;
+
+    Ok(format!("{NAME_PREFIX}{}", to_hex(&encrypted)))
+}
+
+/// Decrypts a name encrypted with [`encrypt_name`], or returns `None` if it wasn't encrypted.
+pub fn decrypt_name(key: &LessSafeKey, name: &str) -> Result<Option<String>, crate::Error> {
+    let Some(hex) = name.strip_prefix(NAME_PREFIX) else {
+        return Ok(None);
+    };
+
+    let plaintext = open_deterministic(key, from_hex(hex)?)?;
+
+    String::from_utf8(plaintext)
+        .map(Some)
+        .map_err(|_| crate::Error::InvalidValue)
+}
+
+/// Deterministically encrypts the key of a row, so equal keys still find the same row.
+pub fn encrypt_key(
+    key: &LessSafeKey,
+    name_key: &hmac::Key,
+    row_key: &Key,
+) -> Result<Key, crate::Error> {
+    let plaintext = postcard::to_extend(row_key, Vec::new())?;
+
+    let mut encrypted = KEY_HEADER.to_vec();
+    encrypted.extend(seal_deterministic(key, name_key, &plaintext)?);
+
+    Ok(Key::Bytea(encrypted))
+}
+
+/// Decrypts the key of a row encrypted with [`encrypt_key`].
+///
+/// Keys that weren't encrypted, e.g. ones generated by the inner store, are returned as-is.
+pub fn decrypt_key(key: &LessSafeKey, row_key: Key) -> Result<Key, crate::Error> {
+    match row_key {
+        Key::Bytea(encrypted) if encrypted.starts_with(KEY_HEADER) => {
+            let plaintext = open_deterministic(key, encrypted[KEY_HEADER.len()..].to_vec())?;
+
+            Ok(postcard::from_bytes(&plaintext)?)
+        }
+        row_key => Ok(row_key),
+    }
+}
+
+/// Decrypts the key of a row like [`decrypt_key`], with the previous keys if it wasn't rewritten
+/// since the key was changed.
+pub fn decrypt_row_key(keys: RowKeys<'_>, row_key: Key) -> Result<Key, crate::Error> {
+    match (decrypt_key(keys.key, row_key.clone()), keys.previous) {
+        (Err(crate::Error::EncryptionError), Some(previous)) => decrypt_key(&previous.key, row_key),
+        (result, _) => result,
+    }
+}
+
+/// Returns whether a row key was encrypted with [`encrypt_key`], under any key.
+pub fn is_encrypted_key(row_key: &Key) -> bool {
+    matches!(row_key, Key::Bytea(encrypted) if encrypted.starts_with(KEY_HEADER))
+}
+
+/// Returns whether a row key was encrypted with [`encrypt_key`] under the given key.
+pub fn is_key_of(key: &LessSafeKey, row_key: &Key) -> bool {
+    matches!(
+        row_key,
+        Key::Bytea(encrypted)
+            if encrypted.starts_with(KEY_HEADER)
+                && open_deterministic(key, encrypted[KEY_HEADER.len()..].to_vec()).is_ok()
+    )
+}
+
+/// Encrypts an expression into a string literal, e.g. to hide a column's default in the schema.
+pub fn encrypt_expr<N: NonceSequence>(
     key: &LessSafeKey,
     nonce_sequence: &mut N,
-    value: &mut Value,
+    expr: &Expr,
+) -> Result<Expr, crate::Error> {
+    // expressions hold types that need a self-describing format
+    let json = serde_json::to_string(expr).map_err(|_| crate::Error::InvalidValue)?;
+    let encrypted = seal(key, nonce_sequence, &[], &json, Compression::None)?;
+
+    Ok(Expr::Literal(AstLiteral::QuotedString(format!(
+        "{EXPR_PREFIX}{}",
+        to_hex(&encrypted)
+    ))))
+}
+
+/// Returns whether an expression was encrypted with [`encrypt_expr`].
+pub fn is_encrypted_expr(expr: &Expr) -> bool {
+    matches!(expr, Expr::Literal(AstLiteral::QuotedString(literal)) if literal.starts_with(EXPR_PREFIX))
+}
+
+/// Decrypts an expression encrypted with [`encrypt_expr`]. Other expressions are left as-is.
+pub fn decrypt_expr_in_place(key: &LessSafeKey, expr: &mut Expr) -> Result<(), crate::Error> {
+    let Expr::Literal(AstLiteral::QuotedString(literal)) = expr else {
+        return Ok(());
+    };
+
+    let Some(hex) = literal.strip_prefix(EXPR_PREFIX) else {
+        return Ok(());
+    };
+
+    let json: String = open_in_place(key, &mut from_hex(hex)?, Compression::None)?;
+
+    *expr = serde_json::from_str(&json).map_err(|_| crate::Error::InvalidValue)?;
+
+    Ok(())
+}
+
+/// Encrypts the keys of a schemaless row with [`encrypt_name`].
+pub fn encrypt_map_keys_in_place(
+    key: &LessSafeKey,
+    name_key: &hmac::Key,
+    row: &mut DataRow,
 ) -> Result<(), crate::Error> {
-    let nonce = nonce_sequence.advance()?;
+    if let DataRow::Map(values) = row {
+        *values = std::mem::take(values)
+            .into_iter()
+            .map(|(name, value)| Ok((encrypt_name(key, name_key, &name)?, value)))
+            .collect::<Result<_, crate::Error>>()?;
+    }
 
-    tracing::info!(nonce = ?nonce.as_ref(), "encrypting val with nonce");
+    Ok(())
+}
 
-    let mut encrypted = Vec::with_capacity(
-        key.algorithm().nonce_len() + std::mem::size_of::<Value>() + key.algorithm().tag_len(),
-    );
+/// Decrypts the keys of a schemaless row encrypted with [`encrypt_map_keys_in_place`].
+///
+/// Keys that weren't encrypted are left as-is.
+pub fn decrypt_map_keys_in_place(keys: RowKeys<'_>, row: &mut DataRow) -> Result<(), crate::Error> {
+    if let DataRow::Map(values) = row {
+        if values.keys().any(|name| name.starts_with(NAME_PREFIX)) {
+            *values = std::mem::take(values)
+                .into_iter()
+                .map(|(name, value)| Ok((decrypt_row_name(keys, &name)?.unwrap_or(name), value)))
+                .collect::<Result<_, crate::Error>>()?;
+        }
+    }
 
-    encrypted.extend_from_slice(nonce.as_ref());
+    Ok(())
+}
+
+/// Decrypts a map key of a row, with the previous keys if it wasn't rewritten since the key was
+/// changed.
+fn decrypt_row_name(keys: RowKeys<'_>, name: &str) -> Result<Option<String>, crate::Error> {
+    match (decrypt_name(keys.key, name), keys.previous) {
+        (Err(crate::Error::EncryptionError), Some(previous)) => decrypt_name(&previous.key, name),
+        (result, _) => result,
+    }
+}
 
-    let mut encrypted = postcard::to_extend(value, encrypted)?;
+/// Data that can be sealed, along with how it's serialized.
+trait Plaintext: Sized {
+    /// Serializes the data at the end of `buffer`.
+    fn encode_into(&self, buffer: Vec<u8>) -> Result<Vec<u8>, crate::Error>;
+    fn decode(bytes: &[u8]) -> Result<Self, crate::Error>;
+
+    /// Serializes the data into `buffer` if it's small enough to fit, returning the bytes
+    /// written, the same as [`Plaintext::encode_into`] would.
+    fn encode_small<'b>(&self, _buffer: &'b mut [u8; SMALL_PLAINTEXT_LEN]) -> Option<&'b [u8]> {
+        None
+    }
+}
+
+impl Plaintext for Value {
+    fn encode_into(&self, buffer: Vec<u8>) -> Result<Vec<u8>, crate::Error> {
+        wire::encode_value_into(self, buffer)
+    }
+
+    fn encode_small<'b>(&self, buffer: &'b mut [u8; SMALL_PLAINTEXT_LEN]) -> Option<&'b [u8]> {
+        wire::encode_small_value(self, buffer)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, crate::Error> {
+        wire::decode_value(bytes)
+    }
+}
+
+impl Plaintext for DataRow {
+    fn encode_into(&self, buffer: Vec<u8>) -> Result<Vec<u8>, crate::Error> {
+        wire::encode_row_into(self, buffer)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, crate::Error> {
+        wire::decode_row(bytes)
+    }
+}
+
+impl Plaintext for String {
+    fn encode_into(&self, buffer: Vec<u8>) -> Result<Vec<u8>, crate::Error> {
+        Ok(postcard::to_extend(self, buffer)?)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, crate::Error> {
+        Ok(postcard::from_bytes(bytes)?)
+    }
+}
+
+/// Serializes and compresses `data` into the scratch buffer of the thread, and hands the result
+/// to `f`.
+///
+/// The buffer is taken out for the call, so `f` may serialize other data in turn; it just
+/// allocates a buffer of its own then. Small values, which most columns hold, are serialized on
+/// the stack instead.
+fn with_plaintext<T: Plaintext, R>(
+    data: &T,
+    compression: Compression,
+    f: impl FnOnce(&[u8]) -> Result<R, crate::Error>,
+) -> Result<R, crate::Error> {
+    let mut small = [0; SMALL_PLAINTEXT_LEN];
+
+    if let Some(plaintext) = data.encode_small(&mut small) {
+        return f(&compression.compress(plaintext));
+    }
+
+    let mut buffer = SCRATCH.with(RefCell::take);
+    buffer.clear();
+
+    let buffer = data.encode_into(buffer)?;
+    let result = f(&compression.compress(&buffer));
+
+    if buffer.capacity() <= SCRATCH_LIMIT {
+        SCRATCH.with(|scratch| *scratch.borrow_mut() = buffer);
+    }
+
+    result
+}
+
+/// Serializes and seals `data`, returning `header || nonce || ciphertext || tag`.
+fn seal<T: Plaintext, N: NonceSequence>(
+    key: &LessSafeKey,
+    nonce_sequence: &mut N,
+    header: &[u8],
+    data: &T,
+    compression: Compression,
+) -> Result<Vec<u8>, crate::Error> {
+    let nonce = nonce_sequence.advance()?;
+
+    let mut encrypted = with_plaintext(data, compression, |plaintext| {
+        let mut encrypted = Vec::with_capacity(
+            header.len()
+                + key.algorithm().nonce_len()
+                + plaintext.len()
+                + key.algorithm().tag_len(),
+        );
+
+        encrypted.extend_from_slice(header);
+        encrypted.extend_from_slice(nonce.as_ref());
+        encrypted.extend_from_slice(plaintext);
+
+        Ok(encrypted)
+    })?;
 
     let aad = Aad::from(*nonce.as_ref());
 
-    let tag =
-        key.seal_in_place_separate_tag(nonce, aad, &mut encrypted[key.algorithm().nonce_len()..])?;
+    let tag = key.seal_in_place_separate_tag(
+        nonce,
+        aad,
+        &mut encrypted[header.len() + key.algorithm().nonce_len()..],
+    )?;
 
     encrypted.extend_from_slice(tag.as_ref());
 
+    Ok(encrypted)
+}
+
+/// Opens `nonce || ciphertext || tag` and deserializes the plaintext, leaving the ciphertext
+/// as-is so another key can be tried if this one doesn't open it.
+fn open<T: Plaintext>(
+    key: &LessSafeKey,
+    encrypted: &[u8],
+    compression: Compression,
+) -> Result<T, crate::Error> {
+    open_in_place(key, &mut encrypted.to_vec(), compression)
+}
+
+/// Like [`open`], but decrypts within `encrypted`, which is garbled afterwards even if it
+/// doesn't open.
+fn open_in_place<T: Plaintext>(
+    key: &LessSafeKey,
+    encrypted: &mut [u8],
+    compression: Compression,
+) -> Result<T, crate::Error> {
+    if encrypted.len() < key.algorithm().nonce_len() {
+        return Err(crate::Error::InvalidValue);
+    }
+
+    let (nonce, ciphertext) = encrypted.split_at_mut(key.algorithm().nonce_len());
+    let nonce = Nonce::try_assume_unique_for_key(nonce)?;
+    let aad = Aad::from(*nonce.as_ref());
+
+    let plaintext = key.open_in_place(nonce, aad, ciphertext)?;
+
+    match compression {
+        Compression::None => T::decode(plaintext),
+        compression @ Compression::Deflate { .. } => T::decode(&compression.decompress(plaintext)?),
+    }
+}
+
+/// How a value of a row is encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sealing {
+    /// Left as-is.
+    Plain,
+    /// Sealed with a random nonce.
+    Random,
+    /// Sealed with a nonce derived from the value and its column, so equal values of a column
+    /// give equal ciphertexts.
+    Deterministic,
+}
+
+/// Returns the bytes sealing with the main key adds to a value on top of its compressed
+/// plaintext.
+pub fn value_overhead(algorithm: Algorithm) -> usize {
+    VALUE_ENVELOPE.len() + algorithm.ring().nonce_len() + algorithm.ring().tag_len()
+}
+
+pub fn encrypt_value_in_place<N: NonceSequence>(
+    key: &LessSafeKey,
+    nonce_sequence: &mut N,
+    value: &mut Value,
+    compression: Compression,
+) -> Result<(), crate::Error> {
+    encrypt_row_value_in_place(Cipher::main(key), nonce_sequence, value, compression)
+}
+
+fn encrypt_row_value_in_place<N: NonceSequence>(
+    cipher: Cipher<'_>,
+    nonce_sequence: &mut N,
+    value: &mut Value,
+    compression: Compression,
+) -> Result<(), crate::Error> {
+    *value = Value::Bytea(seal(
+        cipher.key,
+        nonce_sequence,
+        &[VALUE_ENVELOPE, cipher.header].concat(),
+        &*value,
+        compression,
+    )?);
+
+    Ok(())
+}
+
+/// Returns the HMAC the nonce of a value of the given column is derived from when it's
+/// encrypted deterministically, so equal values only get equal ciphertexts within a column.
+fn value_mac(value_key: &hmac::Key, table_name: &str, column_name: Option<&str>) -> hmac::Context {
+    let mut mac = hmac::Context::with_key(value_key);
+
+    mac.update(DETERMINISTIC_VALUE_LABEL);
+    mac.update(&(table_name.len() as u64).to_be_bytes());
+    mac.update(table_name.as_bytes());
+    update_column(&mut mac, column_name);
+
+    mac
+}
+
+/// Adds the name of a column to an HMAC, or that there's none.
+fn update_column(mac: &mut hmac::Context, column_name: Option<&str>) {
+    match column_name {
+        Some(column_name) => {
+            mac.update(&[1]);
+            mac.update(&(column_name.len() as u64).to_be_bytes());
+            mac.update(column_name.as_bytes());
+        }
+        None => mac.update(&[0]),
+    }
+}
+
+/// Like [`encrypt_row_value_in_place`], but the nonce is derived from the table, the column and
+/// the value with `value_key`.
+///
+/// The ciphertext has the same layout, so it's opened like any other.
+pub fn encrypt_row_value_deterministically(
+    cipher: Cipher<'_>,
+    value_key: &hmac::Key,
+    table_name: &str,
+    column_name: Option<&str>,
+    value: &mut Value,
+    compression: Compression,
+) -> Result<(), crate::Error> {
+    let mut encrypted = [VALUE_ENVELOPE, cipher.header].concat();
+    encrypted.extend(with_plaintext(&*value, compression, |plaintext| {
+        seal_with_mac(
+            cipher.key,
+            value_mac(value_key, table_name, column_name),
+            plaintext,
+        )
+    })?);
+
+    *value = Value::Bytea(encrypted);
+
+    Ok(())
+}
+
+/// The table and key of a row, from which the nonces of its values are derived when they're
+/// sealed by [`encrypt_row_in_place`] or [`encrypt_whole_row_in_place`] without a nonce
+/// sequence.
+///
+/// Each nonce is the HMAC of the identity, the column and the plaintext, so writing the same row
+/// again gives the same ciphertexts, and any other value gets another nonce. The HMAC is keyed
+/// with a subkey of its own, so it never gives the nonce of a name or row key either.
+#[derive(Clone)]
+pub struct RowIdentity(hmac::Context);
+
+impl RowIdentity {
+    pub fn new(
+        identity_key: &hmac::Key,
+        table_name: &str,
+        row_key: &Key,
+    ) -> Result<Self, crate::Error> {
+        let mut mac = hmac::Context::with_key(identity_key);
+        let row_key = postcard::to_extend(row_key, Vec::new())?;
+
+        mac.update(ROW_IDENTITY_LABEL);
+
+        for part in [table_name.as_bytes(), &row_key] {
+            mac.update(&(part.len() as u64).to_be_bytes());
+            mac.update(part);
+        }
+
+        Ok(Self(mac))
+    }
+
+    /// Returns the HMAC to derive the nonce of a value of the given column from, or of the
+    /// whole row without one.
+    fn mac(&self, column_name: Option<&str>) -> hmac::Context {
+        let mut mac = self.0.clone();
+
+        update_column(&mut mac, column_name);
+
+        mac
+    }
+}
+
+/// Like [`encrypt_row_value_in_place`], but the nonce is derived from the identity of the row
+/// and the value.
+fn encrypt_row_value_synthetically(
+    cipher: Cipher<'_>,
+    mac: hmac::Context,
+    value: &mut Value,
+    compression: Compression,
+) -> Result<(), crate::Error> {
+    let mut encrypted = [VALUE_ENVELOPE, cipher.header].concat();
+    encrypted.extend(with_plaintext(&*value, compression, |plaintext| {
+        seal_with_mac(cipher.key, mac, plaintext)
+    })?);
+
     *value = Value::Bytea(encrypted);
 
     Ok(())
 }
 
+/// Returns whether a value was encrypted with [`encrypt_row_value_deterministically`], by
+/// deriving its nonce again.
+fn was_encrypted_deterministically(
+    mut mac: hmac::Context,
+    nonce: &[u8],
+    value: &Value,
+    compression: Compression,
+) -> Result<bool, crate::Error> {
+    with_plaintext(value, compression, |plaintext| {
+        mac.update(plaintext);
+
+        Ok(mac.sign().as_ref()[..NONCE_LEN] == *nonce)
+    })
+}
+
+/// Returns the number of bytes of ciphertext in a row.
+pub fn ciphertext_len(row: &DataRow) -> usize {
+    let values: Box<dyn Iterator<Item = &Value>> = match row {
+        DataRow::Vec(values) => Box::new(values.iter()),
+        DataRow::Map(values) => Box::new(values.values()),
+    };
+
+    values
+        .map(|value| match value {
+            Value::Bytea(bytes) => bytes.len(),
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Iterates over the values of a row, regardless of its layout.
+pub fn row_values_mut(row: &mut DataRow) -> Box<dyn Iterator<Item = &mut Value> + '_> {
+    match row {
+        DataRow::Vec(values) => Box::new(values.iter_mut()),
+        DataRow::Map(values) => Box::new(values.values_mut()),
+    }
+}
+
+/// Iterates over the values of a row along with the name of their column, if it's known.
+/// Columns of `DataRow::Vec` rows are named after `columns`.
+pub fn named_values_mut<'a>(
+    row: &'a mut DataRow,
+    columns: &'a [String],
+) -> Box<dyn Iterator<Item = (Option<&'a str>, &'a mut Value)> + 'a> {
+    match row {
+        DataRow::Vec(values) => Box::new(
+            values
+                .iter_mut()
+                .enumerate()
+                .map(|(i, value)| (columns.get(i).map(String::as_str), value)),
+        ),
+        DataRow::Map(values) => Box::new(
+            values
+                .iter_mut()
+                .map(|(name, value)| (Some(name.as_str()), value)),
+        ),
+    }
+}
+
+/// Encrypts the values of a row as told by `sealing`, which is given the name of their column if
+/// it's known. Columns of `DataRow::Vec` rows are named after `columns`.
+///
+/// Values sealed with a random nonce get one derived from `identity` instead, if it's given.
+#[allow(clippy::too_many_arguments)]
 pub fn encrypt_row_in_place<N: NonceSequence>(
-    key: &LessSafeKey,
+    cipher: Cipher<'_>,
+    value_key: &hmac::Key,
     nonce_sequence: &mut N,
+    identity: Option<&RowIdentity>,
+    table_name: &str,
     row: &mut DataRow,
+    columns: &[String],
+    sealing: impl Fn(Option<&str>, &Value) -> Sealing,
+    compression: Compression,
 ) -> Result<(), crate::Error> {
-    match row {
-        DataRow::Vec(ref mut values) => {
-            for value in values {
-                encrypt_value_in_place(key, nonce_sequence, value)?;
-            }
-        }
-        DataRow::Map(ref mut values) => {
-            for value in values.values_mut() {
-                encrypt_value_in_place(key, nonce_sequence, value)?;
-            }
+    for (column_name, value) in named_values_mut(row, columns) {
+        let sealing = sealing(column_name, value);
+
+        if let (Sealing::Random, Some(identity)) = (sealing, identity) {
+            encrypt_row_value_synthetically(cipher, identity.mac(column_name), value, compression)?;
+
+            continue;
         }
+
+        seal_row_value_in_place(
+            cipher,
+            value_key,
+            nonce_sequence,
+            table_name,
+            column_name,
+            value,
+            sealing,
+            compression,
+        )?;
     }
 
     Ok(())
 }
 
-pub fn decrypt_value_in_place(key: &LessSafeKey, value: &mut Value) -> Result<bool, crate::Error> {
-    tracing::info!("decrypting");
-    match value {
-        Value::Bytea(encrypted) => {
-            let mut decrypted = encrypted.clone();
+/// Encrypts a value of a row as told by `sealing`.
+#[allow(clippy::too_many_arguments)]
+pub fn seal_row_value_in_place<N: NonceSequence>(
+    cipher: Cipher<'_>,
+    value_key: &hmac::Key,
+    nonce_sequence: &mut N,
+    table_name: &str,
+    column_name: Option<&str>,
+    value: &mut Value,
+    sealing: Sealing,
+    compression: Compression,
+) -> Result<(), crate::Error> {
+    match sealing {
+        Sealing::Plain => Ok(()),
+        Sealing::Random => encrypt_row_value_in_place(cipher, nonce_sequence, value, compression),
+        Sealing::Deterministic => encrypt_row_value_deterministically(
+            cipher,
+            value_key,
+            table_name,
+            column_name,
+            value,
+            compression,
+        ),
+    }
+}
+
+/// Encrypts the whole row as a single value, stored as the only value of a `DataRow::Vec`.
+///
+/// The nonce is derived from `identity` if it's given, rather than drawn from `nonce_sequence`.
+pub fn encrypt_whole_row_in_place<N: NonceSequence>(
+    cipher: Cipher<'_>,
+    nonce_sequence: &mut N,
+    identity: Option<&RowIdentity>,
+    row: &mut DataRow,
+    compression: Compression,
+) -> Result<(), crate::Error> {
+    let header = [ROW_HEADER, cipher.header].concat();
+    let encrypted = match identity {
+        Some(identity) => {
+            let mut encrypted = header;
+            encrypted.extend(with_plaintext(&*row, compression, |plaintext| {
+                seal_with_mac(cipher.key, identity.mac(None), plaintext)
+            })?);
 
-            let (nonce, ciphertext) = decrypted.split_at_mut(key.algorithm().nonce_len());
+            encrypted
+        }
+        None => seal(cipher.key, nonce_sequence, &header, &*row, compression)?,
+    };
 
-            tracing::info!(nonce = ?nonce, "decrypting val with nonce");
+    *row = DataRow::Vec(vec![Value::Bytea(encrypted)]);
 
-            let nonce = Nonce::try_assume_unique_for_key(nonce)?;
-            let aad = Aad::from(*nonce.as_ref());
+    Ok(())
+}
 
-            key.open_in_place(nonce, aad, ciphertext)?;
+/// Returns whether a value left as-is would be mistaken for a ciphertext, and so must be
+/// encrypted regardless of the policy.
+pub fn looks_encrypted(value: &Value) -> bool {
+    matches!(
+        value,
+        Value::Bytea(bytes) if bytes.starts_with(VALUE_ENVELOPE) || bytes.starts_with(ROW_HEADER)
+    )
+}
 
-            *value = postcard::from_bytes(ciphertext)?;
+/// Returns whether the row was encrypted with [`encrypt_whole_row_in_place`].
+pub fn is_whole_row(row: &DataRow) -> bool {
+    matches!(
+        row,
+        DataRow::Vec(values)
+            if matches!(values.first(), Some(Value::Bytea(encrypted)) if encrypted.starts_with(ROW_HEADER))
+    )
+}
+
+/// Decrypts a value, returning whether it was a ciphertext. Only values in an envelope are
+/// ciphertexts, anything else is left as-is.
+///
+/// The ciphertext is opened within the buffer of the value, so the value is garbled if it
+/// doesn't open.
+pub fn decrypt_value_in_place(
+    key: &LessSafeKey,
+    value: &mut Value,
+    compression: Compression,
+) -> Result<bool, crate::Error> {
+    match value {
+        Value::Bytea(bytes) if bytes.starts_with(VALUE_ENVELOPE) => {
+            *value = open_in_place(key, &mut bytes[VALUE_ENVELOPE.len()..], compression)?;
 
             Ok(true)
         }
-        _ => {
-            // value is most likely a default column value
+        // e.g. metadata the inner store records on its own
+        _ => Ok(false),
+    }
+}
 
-            Ok(false)
+/// Decrypts a value like [`decrypt_value_in_place`], also opening `BYTEA` values without an
+/// envelope that were sealed before values were wrapped in one. Those are told apart from plain
+/// bytes by their tag, so plain bytes may be mistaken for one.
+pub fn decrypt_legacy_value_in_place(
+    key: &LessSafeKey,
+    value: &mut Value,
+    compression: Compression,
+) -> Result<bool, crate::Error> {
+    let Value::Bytea(bytes) = value else {
+        return Ok(false);
+    };
+
+    if bytes.starts_with(VALUE_ENVELOPE) {
+        return decrypt_value_in_place(key, value, compression);
+    }
+
+    match open(key, bytes, compression) {
+        Ok(decrypted) => *value = decrypted,
+        Err(crate::Error::EncryptionError | crate::Error::InvalidValue) => return Ok(false),
+        Err(error) => return Err(error),
+    }
+
+    Ok(true)
+}
+
+/// Opens a value of a row, which may have been sealed with a table cipher.
+///
+/// Returns the algorithm of the table cipher, or `None` if it was sealed with the main key.
+///
+/// Only the last key to try opens the ciphertext within `encrypted`, which is then garbled if
+/// it doesn't open; the ones before it open a copy.
+fn open_row_value<T: Plaintext>(
+    keys: RowKeys<'_>,
+    encrypted: &mut [u8],
+    compression: Compression,
+) -> Result<(T, Option<Algorithm>), crate::Error> {
+    if let Some((algorithm, key, header_len)) = keys.ciphers.split(encrypted) {
+        // a nonce of the main key may start like a cipher header, so fall back to the main key
+        if let Ok(decrypted) = open(key, &encrypted[header_len..], compression) {
+            return Ok((decrypted, Some(algorithm)));
         }
     }
+
+    let Some(previous) = keys.previous else {
+        return Ok((open_in_place(keys.key, encrypted, compression)?, None));
+    };
+
+    match open(keys.key, encrypted, compression) {
+        // values not rewritten since the key was changed
+        Err(crate::Error::EncryptionError) => open_row_value(
+            RowKeys {
+                legacy: keys.legacy,
+                ..previous.row_keys()
+            },
+            encrypted,
+            compression,
+        ),
+        decrypted => Ok((decrypted?, None)),
+    }
 }
 
-pub fn decrypt_row_in_place(key: &LessSafeKey, row: &mut DataRow) -> Result<(), crate::Error> {
-    match row {
-        DataRow::Vec(ref mut values) => {
-            for value in values {
-                decrypt_value_in_place(key, value)?;
-            }
+/// Opens a `BYTEA` value of a row if it's a ciphertext, returning the value, the algorithm of
+/// its table cipher and the length of its envelope. `BYTEA` values left as-is give `None`.
+///
+/// Only values in an envelope are ciphertexts, unless the keys are `legacy`. Ciphertexts in an
+/// envelope are opened within `bytes`, so they're garbled if they don't open.
+fn open_sealed_row_value(
+    keys: RowKeys<'_>,
+    bytes: &mut [u8],
+    compression: Compression,
+) -> Result<Option<(Value, Option<Algorithm>, usize)>, crate::Error> {
+    if bytes.starts_with(VALUE_ENVELOPE) {
+        let (value, algorithm) =
+            open_row_value(keys, &mut bytes[VALUE_ENVELOPE.len()..], compression)?;
+
+        return Ok(Some((value, algorithm, VALUE_ENVELOPE.len())));
+    }
+
+    if !keys.legacy {
+        return Ok(None);
+    }
+
+    // ciphertexts written before the envelope are told apart from plain bytes by their tag, in
+    // a copy so plain bytes are left as they are
+    match open_row_value(keys, &mut bytes.to_vec(), compression) {
+        Ok((value, algorithm)) => Ok(Some((value, algorithm, 0))),
+        Err(crate::Error::EncryptionError | crate::Error::InvalidValue) => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// Decrypts a value of a row, which may have been sealed with a table cipher. Like
+/// [`decrypt_value_in_place`], the value is garbled if it doesn't open.
+pub fn decrypt_row_value_in_place(
+    keys: RowKeys<'_>,
+    value: &mut Value,
+    compression: Compression,
+) -> Result<(), crate::Error> {
+    if let Value::Bytea(bytes) = value {
+        if let Some((decrypted, ..)) = open_sealed_row_value(keys, bytes, compression)? {
+            *value = decrypted;
         }
-        DataRow::Map(ref mut values) => {
-            for value in values.values_mut() {
-                decrypt_value_in_place(key, value)?;
-            }
+    }
+
+    // other values were left as-is by the policy
+    Ok(())
+}
+
+/// Decrypts a row, whether it was encrypted value by value or as a whole.
+pub fn decrypt_row_in_place(
+    keys: RowKeys<'_>,
+    row: &mut DataRow,
+    compression: Compression,
+) -> Result<(), crate::Error> {
+    decrypt_row_in_place_inner(keys, row, compression).map(|_| ())
+}
+
+/// Decrypts a row, returning the table cipher of the whole row if it was encrypted as a whole.
+fn decrypt_row_in_place_inner(
+    keys: RowKeys<'_>,
+    row: &mut DataRow,
+    compression: Compression,
+) -> Result<Option<Algorithm>, crate::Error> {
+    if !is_whole_row(row) {
+        for value in row_values_mut(row) {
+            decrypt_row_value_in_place(keys, value, compression)?;
+        }
+
+        decrypt_map_keys_in_place(keys, row)?;
+
+        return Ok(None);
+    }
+
+    let DataRow::Vec(values) = row else {
+        unreachable!("whole rows are stored as a DataRow::Vec");
+    };
+    let mut values = std::mem::take(values).into_iter();
+
+    let Some(Value::Bytea(mut encrypted)) = values.next() else {
+        unreachable!("whole rows start with their ciphertext");
+    };
+
+    let (decrypted, algorithm) =
+        open_row_value(keys, &mut encrypted[ROW_HEADER.len()..], compression)?;
+    *row = decrypted;
+
+    // values after the ciphertext were added by the inner store, e.g. by `ALTER TABLE ADD COLUMN`
+    if let DataRow::Vec(decrypted) = row {
+        decrypted.extend(values);
+    }
+
+    Ok(algorithm)
+}
+
+/// Sizes of a sealed value, or whole row.
+#[derive(Debug, Clone, Copy)]
+pub struct SealedLen {
+    /// Size of the encoded plaintext.
+    pub plaintext: usize,
+    /// Size of the plaintext once compressed, i.e. of what was sealed.
+    pub compressed: usize,
+    /// Size of the ciphertext as stored, envelope and headers included.
+    pub ciphertext: usize,
+}
+
+fn sealed_len<T: Plaintext>(
+    data: &T,
+    ciphertext: usize,
+    compression: Compression,
+) -> Result<SealedLen, crate::Error> {
+    let plaintext = data.encode_into(Vec::new())?;
+
+    Ok(SealedLen {
+        plaintext: plaintext.len(),
+        compressed: compression.compress(&plaintext).len(),
+        ciphertext,
+    })
+}
+
+/// Opens the sealed values of a row, or the row if it was sealed as a whole, to measure them.
+/// Values left as-is are skipped.
+pub fn measure_row(
+    keys: RowKeys<'_>,
+    mut row: DataRow,
+    compression: Compression,
+) -> Result<Vec<SealedLen>, crate::Error> {
+    if is_whole_row(&row) {
+        let ciphertext = row_values_mut(&mut row)
+            .next()
+            .map_or(0, |value| match value {
+                Value::Bytea(encrypted) => encrypted.len(),
+                _ => 0,
+            });
+
+        decrypt_row_in_place(keys, &mut row, compression)?;
+
+        return Ok(vec![sealed_len(&row, ciphertext, compression)?]);
+    }
+
+    let mut lens = Vec::new();
+
+    for value in row_values_mut(&mut row) {
+        let Value::Bytea(bytes) = value else {
+            continue;
+        };
+        let ciphertext = bytes.len();
+
+        if let Some((decrypted, ..)) = open_sealed_row_value(keys, bytes, compression)? {
+            lens.push(sealed_len(&decrypted, ciphertext, compression)?);
+        }
+    }
+
+    Ok(lens)
+}
+
+/// Decrypts a row of the given table with `keys` and encrypts it again with `new_keys`, keeping
+/// its layout, the table ciphers of its values and which of them were encrypted
+/// deterministically. Columns of `DataRow::Vec` rows are named after `columns`.
+///
+/// Values and map keys that weren't encrypted are left as-is.
+pub fn reencrypt_row_in_place<N: NonceSequence>(
+    keys: RowKeys<'_>,
+    new_keys: RowKeys<'_>,
+    nonce_sequence: &mut N,
+    table_name: &str,
+    columns: &[String],
+    row: &mut DataRow,
+    compression: Compression,
+) -> Result<(), crate::Error> {
+    // the nonces of deterministic values are derived from the names of their columns, so the
+    // names are revealed until the values are sealed again
+    let mut encrypted_names = HashSet::new();
+
+    if let DataRow::Map(values) = row {
+        if values.keys().any(|name| name.starts_with(NAME_PREFIX)) {
+            *values = std::mem::take(values)
+                .into_iter()
+                .map(|(name, value)| match decrypt_row_name(keys, &name)? {
+                    Some(name) => {
+                        encrypted_names.insert(name.clone());
+
+                        Ok((name, value))
+                    }
+                    None => Ok((name, value)),
+                })
+                .collect::<Result<_, crate::Error>>()?;
+        }
+    }
+
+    if is_whole_row(row) {
+        let algorithm = decrypt_row_in_place_inner(keys, row, compression)?;
+
+        return encrypt_whole_row_in_place(
+            new_keys.cipher(algorithm),
+            nonce_sequence,
+            None,
+            row,
+            compression,
+        );
+    }
+
+    for (column_name, value) in named_values_mut(row, columns) {
+        // values left as-is by the policy aren't re-encrypted
+        let Value::Bytea(encrypted) = value else {
+            continue;
+        };
+        let Some((decrypted, algorithm, envelope_len)) =
+            open_sealed_row_value(keys, encrypted, compression)?
+        else {
+            continue;
+        };
+
+        let header_len = envelope_len + algorithm.map_or(0, |_| CIPHER_HEADER.len() + 1);
+        let nonce = &encrypted[header_len..header_len + NONCE_LEN];
+        let deterministic = was_encrypted_deterministically(
+            value_mac(keys.value_key, table_name, column_name),
+            nonce,
+            &decrypted,
+            compression,
+        )? || match keys.previous {
+            Some(previous) => was_encrypted_deterministically(
+                value_mac(&previous.value_key, table_name, column_name),
+                nonce,
+                &decrypted,
+                compression,
+            )?,
+            None => false,
+        };
+
+        *value = decrypted;
+
+        if deterministic {
+            encrypt_row_value_deterministically(
+                new_keys.cipher(algorithm),
+                new_keys.value_key,
+                table_name,
+                column_name,
+                value,
+                compression,
+            )?;
+        } else {
+            encrypt_row_value_in_place(
+                new_keys.cipher(algorithm),
+                nonce_sequence,
+                value,
+                compression,
+            )?;
+        }
+    }
+
+    if let DataRow::Map(values) = row {
+        if !encrypted_names.is_empty() {
+            *values = std::mem::take(values)
+                .into_iter()
+                .map(|(name, value)| {
+                    if encrypted_names.contains(&name) {
+                        Ok((encrypt_name(new_keys.key, new_keys.name_key, &name)?, value))
+                    } else {
+                        Ok((name, value))
+                    }
+                })
+                .collect::<Result<_, crate::Error>>()?;
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use ring::aead::AES_256_GCM;
+
+    use super::*;
+
+    /// Returns the nonce of a value sealed synthetically with `identity`, and of a name made of
+    /// the bytes it was derived from.
+    fn nonces(keys: &KeySet, identity: &RowIdentity) -> (Vec<u8>, Vec<u8>) {
+        let value = Value::I64(1);
+        let mut sealed = value.clone();
+
+        encrypt_row_value_synthetically(
+            keys.row_keys().cipher(None),
+            identity.mac(Some("c")),
+            &mut sealed,
+            Compression::None,
+        )
+        .unwrap();
+
+        let mut bytes = ROW_IDENTITY_LABEL.to_vec();
+        for part in [
+            b"Logs".as_slice(),
+            &postcard::to_extend(&Key::I64(1), Vec::new()).unwrap(),
+        ] {
+            bytes.extend((part.len() as u64).to_be_bytes());
+            bytes.extend(part);
+        }
+        bytes.push(1);
+        bytes.extend(1_u64.to_be_bytes());
+        bytes.extend(b"c");
+        with_plaintext(&value, Compression::None, |plaintext| {
+            bytes.extend(plaintext);
+            Ok(())
+        })
+        .unwrap();
+
+        let Value::Bytea(sealed) = sealed else {
+            panic!("the value isn't sealed");
+        };
+        let name = seal_deterministic(&keys.key, &keys.name_key, &bytes).unwrap();
+
+        (
+            sealed[VALUE_ENVELOPE.len()..][..NONCE_LEN].to_vec(),
+            name[..NONCE_LEN].to_vec(),
+        )
+    }
+
+    #[test]
+    fn row_identities_dont_share_nonces_with_names() {
+        let keys = KeySet::new(LessSafeKey::new(
+            UnboundKey::new(&AES_256_GCM, &[7; 32]).unwrap(),
+        ));
+
+        // keyed like names, a row identity gives a name of the same bytes the same nonce
+        let shared = RowIdentity::new(&keys.name_key, "Logs", &Key::I64(1)).unwrap();
+        let (value, name) = nonces(&keys, &shared);
+        assert_eq!(value, name);
+
+        let identity = RowIdentity::new(&keys.identity_key, "Logs", &Key::I64(1)).unwrap();
+        let (value, name) = nonces(&keys, &identity);
+        assert_ne!(value, name);
+    }
+}
@@ -0,0 +1,243 @@
+use std::{borrow::Cow, collections::BTreeMap, net::IpAddr};
+
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use gluesql_core::{
+    data::{Interval, Point, Value},
+    store::DataRow,
+};
+use rust_decimal::Decimal;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// First byte of an encoded value or row, naming the version of its encoding.
+///
+/// Ciphertexts written before the encoding was versioned hold bare postcard `Value`s and
+/// `DataRow`s, which start with a variant index below `0x80`, so they can't be mistaken for it.
+const VERSION: u8 = 0x81;
+
+/// Version 1 of the encoding of a [`Value`], independent of the layout of gluesql's enum.
+///
+/// Variants must never be reordered or removed; new ones go at the end.
+///
+/// Strings and blobs borrow from the value being encoded, or the plaintext being decoded, so
+/// they're only copied once, into the [`Value`] the plaintext decodes to.
+#[derive(Serialize, Deserialize)]
+enum WireValue<'a> {
+    Bool(bool),
+    I8(i8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    I128(i128),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    U128(u128),
+    F32(f32),
+    F64(f64),
+    Decimal(Decimal),
+    Str(#[serde(borrow)] Cow<'a, str>),
+    /// Encoded like the `Vec<u8>` it used to be, as postcard writes both as a length and the
+    /// raw bytes.
+    Bytea(#[serde(borrow)] Cow<'a, [u8]>),
+    Inet(IpAddr),
+    Date(NaiveDate),
+    Timestamp(NaiveDateTime),
+    Time(NaiveTime),
+    IntervalMonth(i32),
+    IntervalMicrosecond(i64),
+    Uuid(u128),
+    /// Sorted, so equal maps encode to equal bytes, as deterministic encryption needs.
+    Map(#[serde(borrow)] BTreeMap<Cow<'a, str>, Self>),
+    List(#[serde(borrow)] Vec<Self>),
+    Point(f64, f64),
+    Null,
+}
+
+/// Version 1 of the encoding of a [`DataRow`].
+#[derive(Serialize, Deserialize)]
+enum WireRow<'a> {
+    Vec(#[serde(borrow)] Vec<WireValue<'a>>),
+    Map(#[serde(borrow)] BTreeMap<Cow<'a, str>, WireValue<'a>>),
+}
+
+impl<'a> From<&'a Value> for WireValue<'a> {
+    fn from(value: &'a Value) -> Self {
+        match value {
+            Value::Bool(v) => Self::Bool(*v),
+            Value::I8(v) => Self::I8(*v),
+            Value::I16(v) => Self::I16(*v),
+            Value::I32(v) => Self::I32(*v),
+            Value::I64(v) => Self::I64(*v),
+            Value::I128(v) => Self::I128(*v),
+            Value::U8(v) => Self::U8(*v),
+            Value::U16(v) => Self::U16(*v),
+            Value::U32(v) => Self::U32(*v),
+            Value::U64(v) => Self::U64(*v),
+            Value::U128(v) => Self::U128(*v),
+            Value::F32(v) => Self::F32(*v),
+            Value::F64(v) => Self::F64(*v),
+            Value::Decimal(v) => Self::Decimal(*v),
+            Value::Str(v) => Self::Str(Cow::Borrowed(v)),
+            Value::Bytea(v) => Self::Bytea(Cow::Borrowed(v)),
+            Value::Inet(v) => Self::Inet(*v),
+            Value::Date(v) => Self::Date(*v),
+            Value::Timestamp(v) => Self::Timestamp(*v),
+            Value::Time(v) => Self::Time(*v),
+            Value::Interval(Interval::Month(v)) => Self::IntervalMonth(*v),
+            Value::Interval(Interval::Microsecond(v)) => Self::IntervalMicrosecond(*v),
+            Value::Uuid(v) => Self::Uuid(*v),
+            Value::Map(values) => Self::Map(
+                values
+                    .iter()
+                    .map(|(name, value)| (Cow::Borrowed(name.as_str()), value.into()))
+                    .collect(),
+            ),
+            Value::List(values) => Self::List(values.iter().map(Into::into).collect()),
+            Value::Point(point) => Self::Point(point.x, point.y),
+            Value::Null => Self::Null,
+        }
+    }
+}
+
+impl From<WireValue<'_>> for Value {
+    fn from(value: WireValue<'_>) -> Self {
+        match value {
+            WireValue::Bool(v) => Self::Bool(v),
+            WireValue::I8(v) => Self::I8(v),
+            WireValue::I16(v) => Self::I16(v),
+            WireValue::I32(v) => Self::I32(v),
+            WireValue::I64(v) => Self::I64(v),
+            WireValue::I128(v) => Self::I128(v),
+            WireValue::U8(v) => Self::U8(v),
+            WireValue::U16(v) => Self::U16(v),
+            WireValue::U32(v) => Self::U32(v),
+            WireValue::U64(v) => Self::U64(v),
+            WireValue::U128(v) => Self::U128(v),
+            WireValue::F32(v) => Self::F32(v),
+            WireValue::F64(v) => Self::F64(v),
+            WireValue::Decimal(v) => Self::Decimal(v),
+            WireValue::Str(v) => Self::Str(v.into_owned()),
+            WireValue::Bytea(v) => Self::Bytea(v.into_owned()),
+            WireValue::Inet(v) => Self::Inet(v),
+            WireValue::Date(v) => Self::Date(v),
+            WireValue::Timestamp(v) => Self::Timestamp(v),
+            WireValue::Time(v) => Self::Time(v),
+            WireValue::IntervalMonth(v) => Self::Interval(Interval::Month(v)),
+            WireValue::IntervalMicrosecond(v) => Self::Interval(Interval::Microsecond(v)),
+            WireValue::Uuid(v) => Self::Uuid(v),
+            WireValue::Map(values) => Self::Map(
+                values
+                    .into_iter()
+                    .map(|(name, value)| (name.into_owned(), value.into()))
+                    .collect(),
+            ),
+            WireValue::List(values) => Self::List(values.into_iter().map(Into::into).collect()),
+            WireValue::Point(x, y) => Self::Point(Point::new(x, y)),
+            WireValue::Null => Self::Null,
+        }
+    }
+}
+
+impl<'a> From<&'a DataRow> for WireRow<'a> {
+    fn from(row: &'a DataRow) -> Self {
+        match row {
+            DataRow::Vec(values) => Self::Vec(values.iter().map(Into::into).collect()),
+            DataRow::Map(values) => Self::Map(
+                values
+                    .iter()
+                    .map(|(name, value)| (Cow::Borrowed(name.as_str()), value.into()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl From<WireRow<'_>> for DataRow {
+    fn from(row: WireRow<'_>) -> Self {
+        match row {
+            WireRow::Vec(values) => Self::Vec(values.into_iter().map(Into::into).collect()),
+            WireRow::Map(values) => Self::Map(
+                values
+                    .into_iter()
+                    .map(|(name, value)| (name.into_owned(), value.into()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Decodes a value or row encoded as `W`, or as `T` if it was written before the encoding was
+/// versioned.
+///
+/// Plaintexts of another version, or holding variants this version doesn't know of, were written
+/// by a newer version of the crate.
+fn decode<'a, W: Deserialize<'a> + Into<T>, T: DeserializeOwned>(
+    bytes: &'a [u8],
+) -> Result<T, crate::Error> {
+    let unsupported = |version| crate::Error::UnsupportedValueVersion {
+        version,
+        table: None,
+        key: None,
+        raw: Some(bytes.to_vec()),
+    };
+
+    match bytes.split_first() {
+        Some((&VERSION, encoded)) => match postcard::from_bytes::<W>(encoded) {
+            Ok(decoded) => Ok(decoded.into()),
+            Err(postcard::Error::DeserializeBadEnum) => Err(unsupported(VERSION)),
+            Err(error) => Err(error.into()),
+        },
+        Some((&version, _)) if version >= 0x80 => Err(unsupported(version)),
+        _ => Ok(postcard::from_bytes(bytes)?),
+    }
+}
+
+/// Encodes a value with the current version of the encoding.
+pub fn encode_value(value: &Value) -> Result<Vec<u8>, crate::Error> {
+    encode_value_into(value, Vec::new())
+}
+
+/// Like [`encode_value`], but appends to `buffer`.
+pub fn encode_value_into(value: &Value, mut buffer: Vec<u8>) -> Result<Vec<u8>, crate::Error> {
+    buffer.push(VERSION);
+
+    Ok(postcard::to_extend(&WireValue::from(value), buffer)?)
+}
+
+/// Like [`encode_value`], but writes into `buffer`, returning the bytes written, or `None` if
+/// the value doesn't fit. Strings, blobs, maps and lists are never tried, since they seldom fit.
+pub fn encode_small_value<'b>(value: &Value, buffer: &'b mut [u8]) -> Option<&'b [u8]> {
+    if matches!(
+        value,
+        Value::Str(_) | Value::Bytea(_) | Value::Map(_) | Value::List(_)
+    ) {
+        return None;
+    }
+
+    let (version, encoded) = buffer.split_first_mut()?;
+    *version = VERSION;
+
+    let len = postcard::to_slice(&WireValue::from(value), encoded)
+        .ok()?
+        .len();
+
+    Some(&buffer[..=len])
+}
+
+/// Decodes a value encoded with [`encode_value`] or written before the encoding was versioned.
+pub fn decode_value(bytes: &[u8]) -> Result<Value, crate::Error> {
+    decode::<WireValue, _>(bytes)
+}
+
+/// Encodes a row with the current version of the encoding, at the end of `buffer`.
+pub fn encode_row_into(row: &DataRow, mut buffer: Vec<u8>) -> Result<Vec<u8>, crate::Error> {
+    buffer.push(VERSION);
+
+    Ok(postcard::to_extend(&WireRow::from(row), buffer)?)
+}
+
+/// Decodes a row encoded with [`encode_row_into`] or written before the encoding was versioned.
+pub fn decode_row(bytes: &[u8]) -> Result<DataRow, crate::Error> {
+    decode::<WireRow, _>(bytes)
+}
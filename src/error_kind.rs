@@ -0,0 +1,176 @@
+use gluesql_core::error::Error as GluesqlError;
+
+use crate::Error;
+
+/// Marks the message of a [`GluesqlError`] made from an [`Error`], ahead of the name of its kind.
+const KIND_TAG: &str = " [GluesqlEncryption::";
+
+/// The variant of an [`Error`], without its fields.
+///
+/// Errors returned through gluesql, e.g. by `Glue::execute`, are turned into a message, which
+/// keeps their kind so [`ErrorKind::of`] can recover it, e.g. to tell a wrong key from a corrupt
+/// row.
+///
+/// Each kind has a [code](ErrorKind::code) and a [name](ErrorKind::name) that never change, and
+/// are never given to another kind, so they can be stored or matched on by log pipelines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    NonEncryptedDatabase,
+    InvalidKey,
+    InvalidKeyMaterial,
+    SerializationError,
+    StoreError,
+    EncryptionError,
+    InvalidValue,
+    DecryptFailed,
+    Unsupported,
+    UnsupportedValueVersion,
+    PlaintextValue,
+    KeyChangeInProgress,
+    StagedCopyMismatch,
+    TableExists,
+    BlockingTaskDropped,
+    CiphertextTooLarge,
+    NoncesExhausted,
+    InvalidConfig,
+    Backup,
+}
+
+impl ErrorKind {
+    const ALL: [Self; 19] = [
+        Self::NonEncryptedDatabase,
+        Self::InvalidKey,
+        Self::InvalidKeyMaterial,
+        Self::SerializationError,
+        Self::StoreError,
+        Self::EncryptionError,
+        Self::InvalidValue,
+        Self::DecryptFailed,
+        Self::Unsupported,
+        Self::UnsupportedValueVersion,
+        Self::PlaintextValue,
+        Self::KeyChangeInProgress,
+        Self::StagedCopyMismatch,
+        Self::TableExists,
+        Self::BlockingTaskDropped,
+        Self::CiphertextTooLarge,
+        Self::NoncesExhausted,
+        Self::InvalidConfig,
+        Self::Backup,
+    ];
+
+    /// Returns the numeric code of the kind.
+    #[must_use]
+    pub const fn code(self) -> u16 {
+        match self {
+            Self::NonEncryptedDatabase => 1,
+            Self::InvalidKey => 2,
+            Self::InvalidKeyMaterial => 3,
+            Self::SerializationError => 4,
+            Self::StoreError => 5,
+            Self::EncryptionError => 6,
+            Self::InvalidValue => 7,
+            Self::DecryptFailed => 8,
+            Self::Unsupported => 9,
+            Self::UnsupportedValueVersion => 10,
+            Self::PlaintextValue => 11,
+            Self::KeyChangeInProgress => 12,
+            Self::StagedCopyMismatch => 13,
+            Self::TableExists => 14,
+            Self::BlockingTaskDropped => 15,
+            Self::CiphertextTooLarge => 16,
+            Self::NoncesExhausted => 17,
+            Self::InvalidConfig => 18,
+            Self::Backup => 19,
+        }
+    }
+
+    /// Returns the kind with the given numeric code, if there's one.
+    #[must_use]
+    pub fn from_code(code: u16) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.code() == code)
+    }
+
+    /// Returns the name of the kind, which is the name of the variant of [`Error`].
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::NonEncryptedDatabase => "NonEncryptedDatabase",
+            Self::InvalidKey => "InvalidKey",
+            Self::InvalidKeyMaterial => "InvalidKeyMaterial",
+            Self::SerializationError => "SerializationError",
+            Self::StoreError => "StoreError",
+            Self::EncryptionError => "EncryptionError",
+            Self::InvalidValue => "InvalidValue",
+            Self::DecryptFailed => "DecryptFailed",
+            Self::Unsupported => "Unsupported",
+            Self::UnsupportedValueVersion => "UnsupportedValueVersion",
+            Self::PlaintextValue => "PlaintextValue",
+            Self::KeyChangeInProgress => "KeyChangeInProgress",
+            Self::StagedCopyMismatch => "StagedCopyMismatch",
+            Self::TableExists => "TableExists",
+            Self::BlockingTaskDropped => "BlockingTaskDropped",
+            Self::CiphertextTooLarge => "CiphertextTooLarge",
+            Self::NoncesExhausted => "NoncesExhausted",
+            Self::InvalidConfig => "InvalidConfig",
+            Self::Backup => "Backup",
+        }
+    }
+
+    /// Recovers the kind of an [`Error`] returned through gluesql.
+    ///
+    /// Returns `None` for errors that didn't come from an `EncryptedStore`. Errors of the inner
+    /// store are passed through as they are, so they're never of kind
+    /// [`ErrorKind::StoreError`] once returned through gluesql.
+    #[must_use]
+    pub fn of(error: &GluesqlError) -> Option<Self> {
+        let GluesqlError::StorageMsg(message) = error else {
+            return None;
+        };
+        let (_, name) = message.strip_suffix(']')?.rsplit_once(KIND_TAG)?;
+
+        Self::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+}
+
+impl Error {
+    #[must_use]
+    pub const fn code(&self) -> u16 {
+        self.kind().code()
+    }
+
+    #[must_use]
+    pub const fn kind(&self) -> ErrorKind {
+        match self {
+            Self::NonEncryptedDatabase => ErrorKind::NonEncryptedDatabase,
+            Self::InvalidKey => ErrorKind::InvalidKey,
+            Self::InvalidKeyMaterial => ErrorKind::InvalidKeyMaterial,
+            Self::SerializationError(_) => ErrorKind::SerializationError,
+            Self::StoreError(_) => ErrorKind::StoreError,
+            Self::EncryptionError => ErrorKind::EncryptionError,
+            Self::InvalidValue => ErrorKind::InvalidValue,
+            Self::DecryptFailed { .. } => ErrorKind::DecryptFailed,
+            Self::Unsupported(_) => ErrorKind::Unsupported,
+            Self::UnsupportedValueVersion { .. } => ErrorKind::UnsupportedValueVersion,
+            Self::PlaintextValue { .. } => ErrorKind::PlaintextValue,
+            Self::KeyChangeInProgress { .. } => ErrorKind::KeyChangeInProgress,
+            Self::StagedCopyMismatch { .. } => ErrorKind::StagedCopyMismatch,
+            Self::TableExists(_) => ErrorKind::TableExists,
+            Self::BlockingTaskDropped => ErrorKind::BlockingTaskDropped,
+            Self::CiphertextTooLarge { .. } => ErrorKind::CiphertextTooLarge,
+            Self::NoncesExhausted => ErrorKind::NoncesExhausted,
+            Self::InvalidConfig(_) => ErrorKind::InvalidConfig,
+            Self::Backup(_) => ErrorKind::Backup,
+        }
+    }
+}
+
+impl From<Error> for GluesqlError {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::StoreError(error) => error,
+            error => Self::StorageMsg(format!("{error}{KIND_TAG}{}]", error.kind().name())),
+        }
+    }
+}
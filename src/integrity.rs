@@ -0,0 +1,110 @@
+use std::fmt::{self, Debug, Formatter};
+
+use futures::TryStreamExt;
+use gluesql_core::{data::Key, store::Store};
+use ring::aead::NonceSequence;
+
+use crate::{encdec, redact::RedactedKeys, EncryptedStore, Error, MaybeSendSync};
+
+/// Outcome of [`EncryptedStore::verify_all`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// The encrypted tables, internal ones included.
+    pub tables: Vec<TableIntegrity>,
+}
+
+impl IntegrityReport {
+    /// Returns whether every row of every encrypted table opened.
+    #[must_use]
+    pub fn is_intact(&self) -> bool {
+        self.tables
+            .iter()
+            .all(|table| table.tampered == 0 && table.corrupt == 0)
+    }
+}
+
+/// How the rows of a table fared in [`EncryptedStore::verify_all`].
+#[derive(Clone, PartialEq, Eq)]
+pub struct TableIntegrity {
+    pub table_name: String,
+    /// Rows that opened.
+    pub ok: u64,
+    /// Rows whose ciphertexts failed authentication: they were altered, or sealed with another
+    /// key.
+    pub tampered: u64,
+    /// Rows whose ciphertexts authenticated but didn't decode, or that are malformed otherwise.
+    pub corrupt: u64,
+    /// Keys of the tampered and corrupt rows, as stored in the inner store, so encrypted row
+    /// keys are left encrypted.
+    pub offending_keys: Vec<Key>,
+}
+
+/// Offending keys are redacted, since plaintext row keys are data of the table.
+impl Debug for TableIntegrity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TableIntegrity")
+            .field("table_name", &self.table_name)
+            .field("ok", &self.ok)
+            .field("tampered", &self.tampered)
+            .field("corrupt", &self.corrupt)
+            .field("offending_keys", &RedactedKeys(&self.offending_keys))
+            .finish()
+    }
+}
+
+impl<S: Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>
+    EncryptedStore<S, NonceSeq>
+{
+    /// Decrypts every row of every encrypted table, internal ones included, and reports which
+    /// ones don't open, e.g. for scheduled audits of the data at rest.
+    ///
+    /// Nothing is returned or kept of the decrypted data. Values the policy leaves in plain text
+    /// aren't checked.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store fails to scan the data. Rows that don't open are reported
+    /// rather than returned as errors.
+    pub async fn verify_all(&self) -> Result<IntegrityReport, Error> {
+        let mut report = IntegrityReport::default();
+
+        for (table_name, encrypts_row_keys) in self.rotated_tables(true).await? {
+            let mut rows = self.store.scan_data(&table_name).await?;
+            let mut table = TableIntegrity {
+                table_name: self.reveal_name(table_name.clone()).await?,
+                ok: 0,
+                tampered: 0,
+                corrupt: 0,
+                offending_keys: Vec::new(),
+            };
+
+            while let Some((key, mut row)) = rows.try_next().await? {
+                let opened = if encrypts_row_keys {
+                    encdec::decrypt_row_key(self.row_keys(), key.clone()).map(|_| ())
+                } else {
+                    Ok(())
+                }
+                .and_then(|()| {
+                    encdec::decrypt_row_in_place(self.row_keys(), &mut row, self.compression)
+                });
+
+                match opened {
+                    Ok(()) => table.ok += 1,
+                    Err(error) => {
+                        if matches!(error, Error::EncryptionError) {
+                            table.tampered += 1;
+                        } else {
+                            table.corrupt += 1;
+                        }
+
+                        table.offending_keys.push(key);
+                    }
+                }
+            }
+
+            report.tables.push(table);
+        }
+
+        Ok(report)
+    }
+}
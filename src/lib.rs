@@ -1,11 +1,22 @@
 #![warn(clippy::nursery, clippy::pedantic)]
+// gluesql's storage traits aren't `Send`, and the errors of the store wrap gluesql's, which are
+// as large
+#![allow(clippy::future_not_send, clippy::result_large_err)]
 
-use std::fmt::Debug;
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+};
 
 use async_trait::async_trait;
-use futures::StreamExt;
+use elsa::sync::FrozenMap;
+use futures::{future, stream, Stream, StreamExt, TryStreamExt};
 use gluesql_core::{
-    ast::{ColumnDef, DataType, IndexOperator, OrderByExpr},
+    ast::{ColumnDef, DataType, Expr, IndexOperator, OrderByExpr},
     data::{CustomFunction as StructCustomFunction, Key, Schema, Value},
     error::{Error as GluesqlError, Result},
     executor::Referencing,
@@ -14,24 +25,510 @@ use gluesql_core::{
         Metadata, RowIter, Store, StoreMut, Transaction,
     },
 };
-use ring::aead::{LessSafeKey, NonceSequence, UnboundKey};
+use ring::{aead::LessSafeKey, hmac};
 
+mod adoption;
+mod audit;
+#[cfg(any(
+    feature = "csv-storage",
+    feature = "json-storage",
+    feature = "sled-storage"
+))]
+mod backends;
+mod backup;
+mod blocking;
+mod config;
+mod copy;
+mod corruption;
 mod encdec;
+mod error_kind;
+mod integrity;
+mod nonces;
+mod observer;
+mod parallel;
+mod policy;
+mod pseudonym;
+mod redact;
+mod rekey;
+mod repair;
+mod rotation;
+mod routed;
+mod schema_cache;
+mod send;
+mod shared;
+mod sql_functions;
+mod stats;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+mod trace;
+mod transaction;
+mod value_cipher;
+mod vault;
+
+use audit::PendingAccess;
+use blocking::BlockingExecutor;
+use corruption::DecryptFailureHook;
+use nonces::{CheckedNonces, NonceStateHooks, RefillNonces};
+use redact::RedactedKey;
+use schema_cache::SchemaCache;
+use trace::CryptoSpan;
+
+pub use adoption::{PlaintextReport, PlaintextTable};
+pub use audit::{AccessRecord, TableAccessStats};
+#[cfg(feature = "csv-storage")]
+pub use backends::{encrypted_csv, CsvStorage};
+#[cfg(feature = "json-storage")]
+pub use backends::{encrypted_json, JsonStorage};
+#[cfg(feature = "sled-storage")]
+pub use backends::{encrypted_sled, SledStorage};
+pub use blocking::BlockingTask;
+pub use config::{
+    Algorithm, Codec, Compression, EncryptionConfig, Kdf, KeyBytes, RotationSchedule,
+};
+pub use corruption::{Corruption, CorruptionKind, CorruptionReport};
+pub use encdec::from_hex;
+pub use error_kind::ErrorKind;
+pub use integrity::{IntegrityReport, TableIntegrity};
+pub use nonces::{
+    CounterNonce, NonceBatch, NonceSource, PersistentNonceSequence, RandomNonce, SourcedNonces,
+    SyncNonceSource, BATCH_PREFIX_LEN,
+};
+pub use observer::EncryptionObserver;
+pub use policy::{EncryptionMode, EncryptionPolicy, Nulls, TableFilter, TypeFilter};
+pub use rekey::{RekeyHandle, RekeyState};
+pub use repair::RepairReport;
+pub use rotation::{
+    CancellationToken, KeyChange, KeyChangeProgress, KeyRotationRecord, RekeyEstimate,
+    RotationStatus, TableRotationStatus,
+};
+pub use routed::RoutedStore;
+pub use send::MaybeSendSync;
+pub use shared::SharedStore;
+pub use sql_functions::CipherFunctions;
+pub use stats::{StorageStats, TableStats};
+pub use transaction::{in_transaction, open_in_transaction};
+pub use value_cipher::ValueCipher;
+
+/// The `ring` types the API takes, so keys and nonce sequences can be made without depending
+/// on the same version of `ring`. Keys are best made with [`Algorithm::key`].
+pub use ring::{
+    aead::{Nonce, NonceSequence, UnboundKey, NONCE_LEN},
+    error::Unspecified,
+};
+
+/// Prefix of the names of the tables the `EncryptedStore` keeps its own data in, unless
+/// configured otherwise.
+const DEFAULT_NAMESPACE: &str = "encrypted_";
+
+/// Label of the key used to deterministically encrypt names and row keys.
+const NAME_KEY_LABEL: &str = "gluesql-encryption names";
+/// Label of the key the nonces of deterministically encrypted values are derived with.
+const VALUE_KEY_LABEL: &str = "gluesql-encryption deterministic values";
+/// Label of the key the nonces of rows sealed without a nonce sequence are derived with.
+const IDENTITY_KEY_LABEL: &str = "gluesql-encryption row identities";
+/// Label of the key material used to protect schemas.
+const SCHEMA_KEY_LABEL: &str = "gluesql-encryption schema";
+/// Key of the metadata row holding the schema key material, which must survive key changes.
+const SCHEMA_KEY_ROW: Key = Key::U8(1);
+/// Key of the metadata row holding when the store was created, the age of a key that was never
+/// changed.
+const CREATED_AT_ROW: Key = Key::U8(2);
+
+/// Number of rows bulk rewrites like `change_key` hold in memory at a time, per table, unless
+/// configured otherwise.
+const BATCH_SIZE: usize = 1000;
+/// Number of tables `change_key` rewrites at once, unless configured otherwise.
+const CHANGE_KEY_CONCURRENCY: usize = 4;
+
+/// Keys protecting the schemas in the inner store.
+///
+/// Schemas can't be rewritten in place by `change_key`, so these are derived from key material
+/// stored in the metadata table rather than from the encryption key.
+#[derive(Clone)]
+#[allow(clippy::struct_field_names)]
+struct SchemaKeys {
+    /// Used to derive the pseudonyms of tables and columns.
+    pseudonym_key: hmac::Key,
+    /// Used to encrypt column defaults and custom functions.
+    definitions_key: LessSafeKey,
+    /// Used to derive tokens, which live in user tables and so can't change with the key.
+    token_key: hmac::Key,
+}
+
+impl SchemaKeys {
+    fn new(algorithm: &'static ring::aead::Algorithm, material: &[u8]) -> Result<Self, Error> {
+        let pseudonym_key = hmac::Key::new(hmac::HMAC_SHA256, material);
+        let definitions_material = hmac::sign(&pseudonym_key, b"defaults");
+        let definitions_key = UnboundKey::new(
+            algorithm,
+            &definitions_material.as_ref()[..algorithm.key_len()],
+        )
+        .map_err(|_| Error::InvalidKeyMaterial)?;
+        let token_material = hmac::sign(&pseudonym_key, b"tokens");
+
+        Ok(Self {
+            token_key: hmac::Key::new(hmac::HMAC_SHA256, token_material.as_ref()),
+            pseudonym_key,
+            definitions_key: LessSafeKey::new(definitions_key),
+        })
+    }
+}
+
+/// Names of the tables the `EncryptedStore` keeps its own data in, all starting with its
+/// namespace.
+#[derive(Debug, Clone)]
+struct InternalTables {
+    /// Holds the key check and the rest of the metadata.
+    meta: String,
+    /// Maps pseudonyms to the real names of tables and columns.
+    names: String,
+    /// Holds the values behind the tokens of tokenized columns.
+    vault: String,
+    /// Holds the checkpoints of an interrupted `change_key`.
+    rotation: String,
+    /// Holds the records of the access audit.
+    audit: String,
+    /// Prefix of the tables `change_key_staged` copies tables into before swapping them in.
+    staging_prefix: String,
+}
+
+impl InternalTables {
+    fn new(namespace: &str) -> Self {
+        Self {
+            meta: format!("{namespace}meta"),
+            names: format!("{namespace}names"),
+            vault: format!("{namespace}vault"),
+            rotation: format!("{namespace}rotation"),
+            audit: format!("{namespace}access_audit"),
+            staging_prefix: format!("{namespace}staging_"),
+        }
+    }
+
+    fn contains(&self, table_name: &str) -> bool {
+        [
+            &self.meta,
+            &self.names,
+            &self.vault,
+            &self.rotation,
+            &self.audit,
+        ]
+        .into_iter()
+        .any(|name| name == table_name)
+            || table_name.starts_with(&self.staging_prefix)
+    }
+}
+
+impl Default for InternalTables {
+    fn default() -> Self {
+        Self::new(DEFAULT_NAMESPACE)
+    }
+}
+
+/// Checks a key against the key check of a store, without reading anything else from it.
+///
+/// Returns `None` if the store has no key check, i.e. wasn't opened as an `EncryptedStore` yet.
+async fn check_key<S: Store>(
+    store: &S,
+    tables: &InternalTables,
+    key: &LessSafeKey,
+) -> Result<Option<bool>, Error> {
+    match store.fetch_data(&tables.meta, &Key::U8(0)).await? {
+        Some(DataRow::Map(mut map)) => {
+            let encrypted_key = map.get_mut("key").ok_or(Error::InvalidValue)?;
+
+            // the key check is always sealed, so one sealed before the envelope opens too
+            Ok(Some(matches!(
+                encdec::decrypt_legacy_value_in_place(key, encrypted_key, Compression::None),
+                Ok(true)
+            )))
+        }
+        Some(DataRow::Vec(_)) => Err(Error::InvalidValue),
+        None => Ok(None),
+    }
+}
+
+/// Creates the metadata table, unless it exists already, e.g. because the state of a persistent
+/// nonce sequence was saved in it before the key check was sealed.
+async fn create_meta_table<S: Store + StoreMut>(
+    store: &mut S,
+    tables: &InternalTables,
+) -> Result<(), Error> {
+    if store.fetch_schema(&tables.meta).await?.is_some() {
+        return Ok(());
+    }
+
+    store
+        .insert_schema(&Schema {
+            table_name: tables.meta.clone(),
+            column_defs: Some(vec![ColumnDef {
+                name: "key".to_string(),
+                data_type: DataType::Bytea,
+                nullable: false,
+                default: None,
+                unique: None,
+                comment: None,
+            }]),
+            indexes: vec![],
+            engine: None,
+            foreign_keys: vec![],
+            comment: Some("Table to store the EncryptedStore metadata".to_string()),
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// Reads the material of the keys protecting schemas from the metadata, where it's kept so it
+/// survives key changes. Returns `None` for stores that don't keep it yet.
+async fn read_schema_material<S: Store>(
+    store: &S,
+    tables: &InternalTables,
+    key: &LessSafeKey,
+) -> Result<Option<Vec<u8>>, Error> {
+    match store.fetch_data(&tables.meta, &SCHEMA_KEY_ROW).await? {
+        Some(DataRow::Map(mut map)) => {
+            let mut value = map.remove("schema_key").ok_or(Error::InvalidValue)?;
+
+            encdec::decrypt_value_in_place(key, &mut value, Compression::None)?;
+
+            match value {
+                Value::Bytea(material) => Ok(Some(material)),
+                _ => Err(Error::InvalidValue),
+            }
+        }
+        Some(DataRow::Vec(_)) => Err(Error::InvalidValue),
+        None => Ok(None),
+    }
+}
+
+/// Returns the string a row is keyed by, as scanned from the inner store. Stores like sled scan
+/// keys as the bytes they're ordered by, which are the string behind a prefix.
+pub(crate) fn scanned_str(key: Key) -> Option<String> {
+    match key {
+        Key::Str(value) => Some(value),
+        Key::Bytea(bytes) => {
+            let prefix = Key::Str(String::new()).to_cmp_be_bytes().ok()?;
+
+            String::from_utf8(bytes.strip_prefix(prefix.as_slice())?.to_vec()).ok()
+        }
+        _ => None,
+    }
+}
+
+/// What the policy needs to know about the columns of a table to encrypt its rows.
+#[derive(Clone, Default)]
+struct TableColumns {
+    /// Names of the values of `DataRow::Vec` rows.
+    names: Vec<String>,
+    /// Columns whose values are encrypted deterministically.
+    deterministic: HashSet<String>,
+}
 
-#[derive(Debug, thiserror::Error, PartialEq)]
+impl TableColumns {
+    /// Returns how a value of the given column is encrypted in column mode.
+    fn sealing(
+        &self,
+        policy: &EncryptionPolicy,
+        table_name: &str,
+        column_name: Option<&str>,
+        value: &Value,
+    ) -> encdec::Sealing {
+        // tokens are random, so there's nothing left to hide. Values looking like ciphertexts
+        // are encrypted regardless, so they're read back as they were written
+        if (!policy.encrypts_value(table_name, column_name, value)
+            || column_name.is_some_and(|name| policy.tokenizes(table_name, name)))
+            && !encdec::looks_encrypted(value)
+        {
+            encdec::Sealing::Plain
+        } else if column_name.is_some_and(|name| self.deterministic.contains(name)) {
+            encdec::Sealing::Deterministic
+        } else {
+            encdec::Sealing::Random
+        }
+    }
+}
+
+/// Encrypts the rows of a table according to the policy. It only borrows what that takes from
+/// the store, so it can be shared between threads.
+struct RowSealer<'a> {
+    table_name: &'a str,
+    columns: &'a TableColumns,
+    policy: &'a EncryptionPolicy,
+    keys: encdec::RowKeys<'a>,
+    compression: Compression,
+}
+
+impl RowSealer<'_> {
+    /// Encrypts a batch of rows, with nonces drawn from `nonces` or derived from the identity of
+    /// the row, if it's given.
+    fn seal_rows<'r>(
+        &self,
+        nonces: encdec::BatchNonces,
+        rows: impl IntoIterator<Item = (Option<&'r encdec::RowIdentity>, &'r mut DataRow)>,
+    ) -> Result<(), Error> {
+        parallel::for_each_row(
+            &mut rows.into_iter().collect::<Vec<_>>(),
+            nonces,
+            |nonces, (identity, row)| self.seal(nonces, *identity, row),
+        )
+    }
+
+    fn seal(
+        &self,
+        nonces: &mut encdec::BatchNonces,
+        identity: Option<&encdec::RowIdentity>,
+        row: &mut DataRow,
+    ) -> Result<(), Error> {
+        let cipher = self
+            .keys
+            .cipher(self.policy.table_algorithm(self.table_name));
+
+        match self.policy.table_mode(self.table_name) {
+            EncryptionMode::Column => {
+                encdec::encrypt_row_in_place(
+                    cipher,
+                    self.keys.value_key,
+                    nonces,
+                    identity,
+                    self.table_name,
+                    row,
+                    &self.columns.names,
+                    |column_name, value| {
+                        self.columns
+                            .sealing(self.policy, self.table_name, column_name, value)
+                    },
+                    self.compression,
+                )?;
+
+                if self.policy.encrypts_map_keys() {
+                    encdec::encrypt_map_keys_in_place(self.keys.key, self.keys.name_key, row)?;
+                }
+
+                Ok(())
+            }
+            EncryptionMode::Row => {
+                encdec::encrypt_whole_row_in_place(cipher, nonces, identity, row, self.compression)
+            }
+        }
+    }
+}
+
+/// The keys of the rows of a table, as returned by [`EncryptedStore::scan_keys`].
+pub type KeyIter<'a> = Pin<Box<dyn Stream<Item = Result<Key>> + 'a>>;
+
+/// Errors of an `EncryptedStore`. New variants may be added, so match on [`Error::kind`] or
+/// [`Error::code`] where a stable identifier is needed, e.g. in logs.
+#[derive(thiserror::Error, PartialEq)]
+#[non_exhaustive]
 pub enum Error {
     #[error("[GlueqlEncryption] attempted to use EncryptedStore with a non-encrypted database")]
     NonEncryptedDatabase,
     #[error("[GluesqlEncryption] invalid key")]
     InvalidKey,
+    #[error("[GluesqlEncryption] key material doesn't fit the algorithm")]
+    InvalidKeyMaterial,
     #[error("[GluesqlEncryption] serialization error: {0}")]
     SerializationError(#[from] postcard::Error),
     #[error("[GluesqlEncryption] inner store error: {0}")]
     StoreError(#[from] GluesqlError),
+    /// Sealing or opening failed. Rows that fail to open when read are reported as
+    /// [`Error::DecryptFailed`] instead.
     #[error("[GluesqlEncryption] encryption error")]
     EncryptionError,
+    /// A value, e.g. in the store's own tables, isn't shaped like anything the store writes.
     #[error("[GluesqlEncryption] invalid value")]
     InvalidValue,
+    /// A row read from the inner store didn't open. The store's key passed the key check when
+    /// it was opened, so a ciphertext failing authentication was altered, or sealed with another
+    /// key, e.g. by a client using a key the store was since changed from.
+    #[error(
+        "[GluesqlEncryption] failed to decrypt row in table {table} (key: {:?}, column: {column:?}): {kind:?}",
+        RedactedKey(.key)
+    )]
+    DecryptFailed {
+        table: String,
+        key: Key,
+        /// The column of the value that failed, unknown for rows encrypted as a whole.
+        column: Option<String>,
+        kind: CorruptionKind,
+    },
+    #[error("[GluesqlEncryption] unsupported operation: {0}")]
+    Unsupported(&'static str),
+    /// A value was encoded by a newer version of the crate, e.g. with a type this one doesn't
+    /// know of.
+    #[error(
+        "[GluesqlEncryption] value encoded with unsupported version {version:#04x} (table: {table:?}, key: {:?})",
+        .key.as_ref().map(RedactedKey)
+    )]
+    UnsupportedValueVersion {
+        version: u8,
+        /// The table holding the value, if known.
+        table: Option<String>,
+        /// The key of the row holding the value, if known.
+        key: Option<Key>,
+        /// The decrypted, still encoded value. Only kept if the store was built with
+        /// [`EncryptedStore::with_raw_values_in_errors`].
+        raw: Option<Vec<u8>>,
+    },
+    /// A value the policy encrypts was read in plain text, e.g. because it was written to the
+    /// inner store directly. Only raised by stores built with [`EncryptedStore::with_strict_reads`].
+    ///
+    /// [`EncryptedStore::plaintext_report`] finds such values, and
+    /// [`EncryptedStore::encrypt_remaining`] encrypts them.
+    #[error(
+        "[GluesqlEncryption] plaintext value in encrypted table {table} (key: {:?})",
+        RedactedKey(.key)
+    )]
+    PlaintextValue { table: String, key: Key },
+    /// A key change to another key, or from another one, was interrupted and must be resumed
+    /// before the key can be changed again. Stores being encrypted by
+    /// [`EncryptedStore::encrypt_existing_store`] change from the key `plaintext`, and stores
+    /// being rewritten by [`EncryptedStore::migrate_codec`] from their key to itself.
+    #[error(
+        "[GluesqlEncryption] key change from key {old_key_id} to key {new_key_id} is in progress"
+    )]
+    KeyChangeInProgress {
+        old_key_id: String,
+        new_key_id: String,
+    },
+    /// The copy of a table `change_key_staged` made with the new key doesn't hold as many rows
+    /// as the table, so it wasn't swapped in.
+    #[error(
+        "[GluesqlEncryption] staged copy of table {table} holds {found} rows instead of {expected}"
+    )]
+    StagedCopyMismatch {
+        table: String,
+        expected: u64,
+        found: u64,
+    },
+    /// [`EncryptedStore::copy_to`] found a table it copies already in the destination store.
+    #[error("[GluesqlEncryption] table {0} already exists in the destination store")]
+    TableExists(String),
+    /// The executor set with [`EncryptedStore::with_blocking_executor`] dropped a task without
+    /// running it, e.g. because it was shutting down.
+    #[error("[GluesqlEncryption] blocking executor dropped a task without running it")]
+    BlockingTaskDropped,
+    /// A row held a ciphertext over the limit set with
+    /// [`EncryptedStore::with_max_ciphertext_len`].
+    #[error(
+        "[GluesqlEncryption] ciphertext of {len} bytes in table {table} is over the limit (key: {:?})",
+        RedactedKey(.key)
+    )]
+    CiphertextTooLarge { table: String, key: Key, len: usize },
+    /// The nonce sequence is about to run out of nonces, so writes are refused before any nonce
+    /// could be used twice. Counters don't start over when the key is changed, so the store must
+    /// be given another writer id, or its data copied to a new store with
+    /// [`EncryptedStore::copy_to`].
+    #[error("[GluesqlEncryption] nonce sequence is running out of nonces")]
+    NoncesExhausted,
+    /// An [`EncryptionConfig`] holds settings that contradict each other, as found by
+    /// [`EncryptionConfig::validate`]. The message says which, and how to fix them.
+    #[error("[GluesqlEncryption] invalid config: {0}")]
+    InvalidConfig(String),
+    /// A backup couldn't be written or read by [`EncryptedStore::export_backup`] or
+    /// [`EncryptedStore::import_backup`], or was cut short, altered, or sealed with another key.
+    #[error("[GluesqlEncryption] backup error: {0}")]
+    Backup(String),
 }
 
 impl From<ring::error::Unspecified> for Error {
@@ -40,16 +537,90 @@ impl From<ring::error::Unspecified> for Error {
     }
 }
 
-impl From<Error> for GluesqlError {
-    fn from(error: Error) -> Self {
-        Self::StorageMsg(error.to_string())
-    }
+/// What an `EncryptedStore` is made of, as returned by [`EncryptedStore::into_parts`] and put
+/// back together by [`EncryptedStore::from_store_parts`].
+#[derive(Debug)]
+pub struct StoreParts<S, NonceSeq> {
+    pub store: S,
+    /// The key of the store. `ring` keeps its bytes to itself, so it can seal and open values
+    /// but not be written down: keep the key material the store was opened with to open it
+    /// again.
+    pub key: LessSafeKey,
+    /// The key an online key change was moving away from, if one was in progress.
+    pub previous_key: Option<LessSafeKey>,
+    /// The nonce sequence, as far as it advanced. A [`PersistentNonceSequence`] still holds the
+    /// state it didn't save yet.
+    pub nonce_sequence: NonceSeq,
 }
 
-pub struct EncryptedStore<S, NonceSeq: NonceSequence> {
+#[allow(clippy::struct_excessive_bools)]
+pub struct EncryptedStore<S, NonceSeq: NonceSequence = RandomNonce> {
     key: LessSafeKey,
-    /// Should be a random nonce sequence.
-    nonce_sequence: NonceSeq,
+    /// Derived from `key`, used to deterministically encrypt names and row keys.
+    name_key: hmac::Key,
+    /// Derived from `key`, used to deterministically encrypt values.
+    value_key: hmac::Key,
+    /// Derived from `key`, used to derive the nonces of rows from their identity.
+    identity_key: hmac::Key,
+    /// Derived from `key`, used by tables encrypted with another algorithm.
+    ciphers: encdec::TableCiphers,
+    schema_keys: SchemaKeys,
+    /// Should be a random nonce sequence. Behind a lock, so sealing only takes a shared
+    /// reference to the store.
+    nonce_sequence: Mutex<CheckedNonces<NonceSeq>>,
+    /// How to save the state of `nonce_sequence`, if it's persistent.
+    nonce_state: Option<NonceStateHooks<NonceSeq>>,
+    /// How to fetch more nonces ahead of sealing, if they come from a [`NonceSource`].
+    nonce_refill: Option<RefillNonces<NonceSeq>>,
+    policy: EncryptionPolicy,
+    compression: Compression,
+    /// Whether `UnsupportedValueVersion` errors keep the decrypted bytes of the value.
+    raw_values_in_errors: bool,
+    /// Whether reading a plaintext value the policy encrypts is an error.
+    strict_reads: bool,
+    /// Whether `BYTEA` values without an envelope are opened as ciphertexts written before it.
+    legacy_ciphertexts: bool,
+    /// Whether the nonces of rows written with their key are derived from their identity.
+    synthetic_nonces: bool,
+    /// Number of rows bulk rewrites hold in memory at a time, per table.
+    batch_size: usize,
+    /// Number of tables `change_key` rewrites at once.
+    change_key_concurrency: usize,
+    /// Number of chunks of rows scans open at once, on other threads if more than one.
+    scan_concurrency: usize,
+    /// Most rows a scan holds decrypted at once, if limited.
+    max_in_flight_rows: Option<usize>,
+    /// Size over which ciphertexts are refused rather than opened, if limited.
+    max_ciphertext_len: Option<usize>,
+    /// How often the key should be changed, if at all.
+    rotation_schedule: Option<RotationSchedule>,
+    /// The key an online key change is moving away from, which still opens the data that wasn't
+    /// rewritten yet.
+    previous_keys: Option<encdec::KeySet>,
+    /// Names of the tables holding the store's own data.
+    tables: InternalTables,
+    /// Rows that failed to open since the report was last taken.
+    corruptions: Mutex<CorruptionReport>,
+    /// Called with every row that fails to open, if set.
+    on_decrypt_failure: Option<DecryptFailureHook>,
+    observer: Option<Arc<dyn EncryptionObserver>>,
+    /// Rows decrypted by table since they were last written to the audit table, if audited.
+    access_audit: Option<Mutex<PendingAccess>>,
+    /// Rows opened by table since the store was opened.
+    access_stats: Mutex<HashMap<String, TableAccessStats>>,
+    /// Whether the key check was written when the store was opened, which the observer is told
+    /// about once it's set.
+    wrote_sentinel: bool,
+    /// Schemas read so far, forgotten whenever one changes.
+    schemas: Mutex<SchemaCache>,
+    /// Real names behind the pseudonyms seen so far. Schemas are fetched outside transactions
+    /// while planning, where stores like sled can't read the names table.
+    names: Mutex<HashMap<String, String>>,
+    /// Where large rows are sealed and opened, if not inline.
+    blocking: Option<BlockingExecutor>,
+    /// Decrypted custom functions, kept since `fetch_function` hands out references. Behind a
+    /// lock, so shared stores can fill it in from several threads.
+    functions: FrozenMap<String, Box<StructCustomFunction>>,
     store: S,
 }
 
@@ -57,65 +628,1054 @@ impl<S: Debug, NonceSeq: NonceSequence> Debug for EncryptedStore<S, NonceSeq> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("EncryptedStore")
             .field("store", &self.store)
+            .field("policy", &self.policy)
+            .field("compression", &self.compression)
             .finish_non_exhaustive()
     }
 }
 
+/// Copies get a clone of the inner store, and draw their own random nonces. Stores with other
+/// nonce sequences can't be cloned, since their copies would repeat each other's nonces.
+///
+/// Each copy caches schemas on its own, so if copies share the inner store, e.g. a sled
+/// database, the others must call [`EncryptedStore::clear_schema_cache`] after one of them
+/// changes a schema.
+impl<S: Clone> Clone for EncryptedStore<S> {
+    fn clone(&self) -> Self {
+        Self {
+            key: self.key.clone(),
+            name_key: self.name_key.clone(),
+            value_key: self.value_key.clone(),
+            identity_key: self.identity_key.clone(),
+            ciphers: self.ciphers.clone(),
+            schema_keys: self.schema_keys.clone(),
+            nonce_sequence: Mutex::new(CheckedNonces::new(RandomNonce::new())),
+            nonce_state: None,
+            nonce_refill: None,
+            policy: self.policy.clone(),
+            compression: self.compression,
+            raw_values_in_errors: self.raw_values_in_errors,
+            strict_reads: self.strict_reads,
+            legacy_ciphertexts: self.legacy_ciphertexts,
+            synthetic_nonces: self.synthetic_nonces,
+            batch_size: self.batch_size,
+            change_key_concurrency: self.change_key_concurrency,
+            scan_concurrency: self.scan_concurrency,
+            max_in_flight_rows: self.max_in_flight_rows,
+            max_ciphertext_len: self.max_ciphertext_len,
+            rotation_schedule: self.rotation_schedule,
+            previous_keys: self.previous_keys.clone(),
+            tables: self.tables.clone(),
+            corruptions: Mutex::default(),
+            on_decrypt_failure: self.on_decrypt_failure.clone(),
+            observer: self.observer.clone(),
+            access_audit: self
+                .access_audit
+                .as_ref()
+                .map(|_| Mutex::new(PendingAccess::new())),
+            access_stats: Mutex::default(),
+            wrote_sentinel: false,
+            schemas: Mutex::default(),
+            names: Mutex::new(self.known_names()),
+            blocking: self.blocking.clone(),
+            functions: FrozenMap::new(),
+            store: self.store.clone(),
+        }
+    }
+}
+
 impl<S, NonceSeq: NonceSequence> EncryptedStore<S, NonceSeq> {
+    /// Creates the `EncryptedStore` with the default policy, deriving the keys it needs.
+    fn from_parts(store: S, key: LessSafeKey, nonce_sequence: CheckedNonces<NonceSeq>) -> Self {
+        Self {
+            name_key: encdec::derive_subkey(&key, NAME_KEY_LABEL),
+            value_key: encdec::derive_subkey(&key, VALUE_KEY_LABEL),
+            identity_key: encdec::derive_subkey(&key, IDENTITY_KEY_LABEL),
+            ciphers: encdec::TableCiphers::new(&key),
+            schema_keys: SchemaKeys::new(
+                key.algorithm(),
+                &encdec::derive_material(&key, SCHEMA_KEY_LABEL),
+            )
+            .expect("derived material fits every algorithm"),
+            key,
+            nonce_sequence: Mutex::new(nonce_sequence),
+            nonce_state: None,
+            nonce_refill: None,
+            policy: EncryptionPolicy::default(),
+            compression: Compression::default(),
+            raw_values_in_errors: false,
+            strict_reads: false,
+            legacy_ciphertexts: false,
+            synthetic_nonces: false,
+            batch_size: BATCH_SIZE,
+            change_key_concurrency: CHANGE_KEY_CONCURRENCY,
+            scan_concurrency: 1,
+            max_in_flight_rows: None,
+            max_ciphertext_len: None,
+            rotation_schedule: None,
+            previous_keys: None,
+            tables: InternalTables::default(),
+            corruptions: Mutex::default(),
+            on_decrypt_failure: None,
+            observer: None,
+            access_audit: None,
+            access_stats: Mutex::default(),
+            wrote_sentinel: false,
+            schemas: Mutex::default(),
+            names: Mutex::default(),
+            blocking: None,
+            functions: FrozenMap::new(),
+            store,
+        }
+    }
+
     /// Returns the inner store.
     pub fn into_inner(self) -> S {
         self.store
     }
+
+    /// Returns the inner store along with the key and nonce sequence of the store, e.g. so the
+    /// state of the nonce sequence can be saved elsewhere when shutting down, where
+    /// [`EncryptedStore::into_inner`] drops it.
+    ///
+    /// Nothing is written to the store: unlike [`EncryptedStore::close`], the state of a
+    /// persistent nonce sequence is left for the caller to save, e.g. with
+    /// [`PersistentNonceSequence::save_state`].
+    #[must_use]
+    pub fn into_parts(self) -> StoreParts<S, NonceSeq> {
+        StoreParts {
+            store: self.store,
+            key: self.key,
+            previous_key: self.previous_keys.map(|keys| keys.key),
+            nonce_sequence: self
+                .nonce_sequence
+                .into_inner()
+                .unwrap_or_else(PoisonError::into_inner)
+                .into_inner(),
+        }
+    }
+
+    /// Borrows the inner store, e.g. to reach APIs of its backend. What's read from it directly
+    /// is as encrypted as the store wrote it.
+    #[must_use]
+    pub const fn inner(&self) -> &S {
+        &self.store
+    }
+
+    /// Borrows the inner store mutably, e.g. to flush it. Call
+    /// [`EncryptedStore::clear_schema_cache`] after changing its schemas directly.
+    pub const fn inner_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    /// Returns the algorithm of the key of the store, which seals the values of every table
+    /// without an algorithm of its own.
+    #[must_use]
+    pub fn algorithm(&self) -> Algorithm {
+        Algorithm::of(&self.key)
+    }
+
+    /// Returns the bytes sealing adds to each value of an encrypted table on top of its
+    /// compressed plaintext: the envelope, nonce and tag. Tables in row mode add them once per
+    /// row, and tables given another algorithm by the policy a few bytes more naming it.
+    #[must_use]
+    pub fn per_value_overhead(&self) -> usize {
+        encdec::value_overhead(self.algorithm())
+    }
+
+    /// Returns the policy deciding what the store encrypts.
+    #[must_use]
+    pub const fn policy(&self) -> &EncryptionPolicy {
+        &self.policy
+    }
+
+    /// Replaces the inner store with what `f` makes of it, e.g. after reopening it.
+    #[must_use]
+    pub fn map_inner(self, f: impl FnOnce(S) -> S) -> Self {
+        let mut store = Self {
+            store: f(self.store),
+            ..self
+        };
+        store.forget_inner();
+
+        store
+    }
+
+    /// Replaces the inner store, returning the one it replaced. The new one must hold data
+    /// written with the same key, or none at all.
+    pub fn swap_inner(&mut self, store: S) -> S {
+        self.forget_inner();
+
+        std::mem::replace(&mut self.store, store)
+    }
+
+    /// Forgets what was cached from the inner store, before it's replaced.
+    fn forget_inner(&mut self) {
+        self.clear_schema_cache();
+        self.functions.as_mut().clear();
+    }
+
+    /// Sets the policy deciding which data is encrypted.
+    ///
+    /// The policy must stay the same for the lifetime of the data; rows written under one policy
+    /// are not readable under a policy that encrypts a different set of tables.
+    #[must_use]
+    pub fn with_policy(mut self, policy: EncryptionPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Sets the compression applied to values before they're encrypted.
+    ///
+    /// Like the policy, the compression must stay the same for the lifetime of the data.
+    #[must_use]
+    pub const fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Keeps the decrypted bytes of values this version of the crate can't decode in the
+    /// [`Error::UnsupportedValueVersion`] errors they cause, e.g. to salvage them by hand.
+    ///
+    /// Off by default, since errors tend to end up in logs.
+    #[must_use]
+    pub const fn with_raw_values_in_errors(mut self, raw_values_in_errors: bool) -> Self {
+        self.raw_values_in_errors = raw_values_in_errors;
+        self
+    }
+
+    /// Calls `hook` with the table, key and column of every row that fails to open as it's read,
+    /// e.g. to alert on tampering right away rather than when a query fails. Rows are reported
+    /// to the hook even once the [corruption report](EncryptedStore::corruption_report) is full.
+    ///
+    /// The hook runs on the thread reading the row, before the read returns its error.
+    #[must_use]
+    pub fn with_decrypt_failure_hook(
+        mut self,
+        hook: impl Fn(&Corruption) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_decrypt_failure = Some(Arc::new(hook));
+        self
+    }
+
+    /// Sets the observer told about the rows the store encrypts and decrypts, and its key
+    /// changes.
+    ///
+    /// If the store wrote its key check when it was opened, the observer is told right away.
+    #[must_use]
+    pub fn with_observer(mut self, observer: impl EncryptionObserver + 'static) -> Self {
+        if std::mem::take(&mut self.wrote_sentinel) {
+            observer.sentinel_written();
+        }
+
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// Counts the rows decrypted by table, to be written to an encrypted audit table with
+    /// [`EncryptedStore::flush_access_audit`] or taken with
+    /// [`EncryptedStore::take_access_records`].
+    #[must_use]
+    pub fn with_access_audit(mut self, enabled: bool) -> Self {
+        self.access_audit = enabled.then(|| Mutex::new(PendingAccess::new()));
+        self
+    }
+
+    /// Makes reading a value the policy encrypts an error if it isn't encrypted, rather than
+    /// passing it through, so data written to the inner store behind the `EncryptedStore`'s back
+    /// is noticed.
+    ///
+    /// Values the policy leaves as-is are still read as-is.
+    #[must_use]
+    pub const fn with_strict_reads(mut self, strict_reads: bool) -> Self {
+        self.strict_reads = strict_reads;
+        self
+    }
+
+    /// Also opens `BYTEA` values without an envelope, as ciphertexts written by versions of the
+    /// crate that didn't wrap values in one, when they're sealed with the keys of the store.
+    ///
+    /// Plain `BYTEA` values that happen to open are mistaken for ciphertexts, so this is only
+    /// meant for rewriting such a store with [`EncryptedStore::migrate_codec`], after which the
+    /// flag should be left off.
+    #[must_use]
+    pub const fn with_legacy_ciphertexts(mut self, enabled: bool) -> Self {
+        self.legacy_ciphertexts = enabled;
+        self
+    }
+
+    /// Derives the nonces of the rows written with `insert_data` from the table, the key of the
+    /// row and its values, rather than drawing them from the nonce sequence, so writing a row
+    /// again, e.g. when a write is replayed after a crash, gives the same ciphertexts.
+    ///
+    /// Rows appended to tables without a primary key only get their key once they're written,
+    /// so they're still sealed with nonces from the sequence, like schemas, tokens and the rows
+    /// key changes rewrite. Writing the same value to the same row twice gives the same
+    /// ciphertext, which a snapshot of the inner store may tell apart from a changed value.
+    #[must_use]
+    pub const fn with_synthetic_nonces(mut self, synthetic_nonces: bool) -> Self {
+        self.synthetic_nonces = synthetic_nonces;
+        self
+    }
+
+    /// Sets the number of rows every bulk rewrite of the store, from
+    /// [`EncryptedStore::change_key`] to [`EncryptedStore::decrypt_into_plaintext`], holds in
+    /// memory at a time per table. Defaults to 1000.
+    ///
+    /// Smaller batches cap the memory a rewrite needs; larger ones make fewer, bigger writes and
+    /// transactions.
+    #[must_use]
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Sets the number of tables [`EncryptedStore::change_key`] rewrites at once, re-encrypting
+    /// their rows on as many threads. Defaults to 4.
+    ///
+    /// Up to this many batches are held in memory at once. Other bulk rewrites hold one at a
+    /// time.
+    #[must_use]
+    pub fn with_change_key_concurrency(mut self, concurrency: usize) -> Self {
+        self.change_key_concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Seals and opens rows whose strings and byte strings add up to `threshold` bytes or more
+    /// on a blocking thread, so a large blob doesn't stall the executor driving the store.
+    ///
+    /// `spawn` must run the task it's given off the current thread, e.g. with
+    /// `tokio::task::spawn_blocking`. Rows written in a batch are sealed together if one of them
+    /// is large, and rows read by `fetch_data` are opened alone; scans open their rows inline.
+    #[must_use]
+    pub fn with_blocking_executor(
+        mut self,
+        threshold: usize,
+        spawn: impl Fn(BlockingTask) + Send + Sync + 'static,
+    ) -> Self {
+        self.blocking = Some(BlockingExecutor {
+            threshold,
+            spawn: Arc::new(spawn),
+        });
+        self
+    }
+
+    /// Sets the number of chunks of rows `scan_data` opens at once. Defaults to 1, which opens
+    /// rows one by one on the thread polling the scan.
+    ///
+    /// With more, rows are opened a chunk at a time on the executor set with
+    /// [`EncryptedStore::with_blocking_executor`], or on a thread per chunk without one, so full
+    /// scans use several cores. Rows are still yielded in the order of the inner store. Scans of
+    /// tables with tokenized columns open their rows one by one regardless.
+    #[must_use]
+    pub fn with_scan_concurrency(mut self, concurrency: usize) -> Self {
+        self.scan_concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Limits the rows a scan opens ahead of the reader, including those opened concurrently
+    /// with [`EncryptedStore::with_scan_concurrency`], so a large table doesn't pile up in
+    /// memory when it's read slowly. Scans of tables with tokenized columns also open their rows
+    /// that many at a time, rather than all at once.
+    #[must_use]
+    pub fn with_max_in_flight_rows(mut self, rows: usize) -> Self {
+        self.max_in_flight_rows = Some(rows.max(1));
+        self
+    }
+
+    /// Refuses to open values, or whole rows, whose ciphertext is over `len` bytes, failing the
+    /// read with [`Error::CiphertextTooLarge`] instead, e.g. to stop a corrupt or hostile inner
+    /// store from making reads allocate without bound.
+    #[must_use]
+    pub const fn with_max_ciphertext_len(mut self, len: usize) -> Self {
+        self.max_ciphertext_len = Some(len);
+        self
+    }
+
+    /// Sets how often the key should be changed, as reported by
+    /// [`EncryptedStore::rotation_due`].
+    #[must_use]
+    pub const fn with_rotation_schedule(mut self, schedule: RotationSchedule) -> Self {
+        self.rotation_schedule = Some(schedule);
+        self
+    }
+
+    /// Opens the data an online key change hasn't rewritten yet with the key it's moving away
+    /// from, when the store is reopened with the new key before the change finished.
+    ///
+    /// See [`EncryptedStore::start_key_change`].
+    #[must_use]
+    pub fn with_previous_key(mut self, key: UnboundKey) -> Self {
+        self.previous_keys = Some(encdec::KeySet::new(LessSafeKey::new(key)));
+        self
+    }
+
+    /// Adds the table, key and column of the row being decrypted to an error.
+    fn row_error(
+        &self,
+        error: Error,
+        table_name: &str,
+        row_key: &Key,
+        column: Option<String>,
+    ) -> Error {
+        match error {
+            Error::EncryptionError | Error::SerializationError(_) | Error::InvalidValue => {
+                Error::DecryptFailed {
+                    table: table_name.to_owned(),
+                    key: row_key.clone(),
+                    column,
+                    kind: CorruptionKind::of(&error),
+                }
+            }
+            Error::UnsupportedValueVersion { version, raw, .. } => Error::UnsupportedValueVersion {
+                version,
+                table: Some(table_name.to_owned()),
+                key: Some(row_key.clone()),
+                raw: raw.filter(|_| self.raw_values_in_errors),
+            },
+            error => error,
+        }
+    }
+
+    /// Decrypts a row of an encrypted table read from the inner store.
+    fn open_row(
+        &self,
+        table_name: &str,
+        columns: &TableColumns,
+        key: &Key,
+        row: &mut DataRow,
+    ) -> Result<(), Error> {
+        self.check_row(table_name, columns, key, row)?;
+
+        let opened = {
+            let span = CryptoSpan::decrypt_row(table_name, 1);
+            span.record_rows([&*row]);
+
+            encdec::decrypt_row_in_place(self.row_keys(), row, self.compression)
+        };
+
+        self.row_opened(table_name, columns, key, row, opened)
+    }
+
+    /// Fails reads of rows holding plaintext the policy encrypts, if reads are strict, or
+    /// ciphertexts over the size limit, before they're opened.
+    fn check_row(
+        &self,
+        table_name: &str,
+        columns: &TableColumns,
+        key: &Key,
+        row: &mut DataRow,
+    ) -> Result<(), Error> {
+        if self.strict_reads && self.holds_plaintext(table_name, columns, row) {
+            return Err(Error::PlaintextValue {
+                table: table_name.to_owned(),
+                key: key.clone(),
+            });
+        }
+
+        if let Some(max_len) = self.max_ciphertext_len {
+            let len = encdec::row_values_mut(row)
+                .map(|value| match value {
+                    Value::Bytea(encrypted) => encrypted.len(),
+                    _ => 0,
+                })
+                .max()
+                .unwrap_or(0);
+
+            if len > max_len {
+                return Err(Error::CiphertextTooLarge {
+                    table: table_name.to_owned(),
+                    key: key.clone(),
+                    len,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reports a row that failed to open, and turns the failure into the error the read returns.
+    fn row_opened(
+        &self,
+        table_name: &str,
+        columns: &TableColumns,
+        key: &Key,
+        row: &mut DataRow,
+        opened: Result<(), Error>,
+    ) -> Result<(), Error> {
+        self.record_access(table_name, opened.is_ok());
+
+        if let (Ok(()), Some(observer)) = (&opened, &self.observer) {
+            observer.row_decrypted(table_name);
+        }
+
+        opened.map_err(|error| {
+            let column = self.failed_column(columns, row);
+
+            self.record_corruption(table_name, key, column.clone(), &error);
+            self.row_error(error, table_name, key, column)
+        })
+    }
+
+    /// Returns whether a row of an encrypted table, as read from the inner store, holds values
+    /// the policy encrypts in plain text.
+    ///
+    /// Plaintext `BYTEA` values can't be told apart from ciphertexts without opening them, so
+    /// they're taken for ciphertexts.
+    fn holds_plaintext(&self, table_name: &str, columns: &TableColumns, row: &mut DataRow) -> bool {
+        !encdec::is_whole_row(row)
+            && encdec::named_values_mut(row, &columns.names).any(|(column_name, value)| {
+                !matches!(value, Value::Bytea(_))
+                    && columns.sealing(&self.policy, table_name, column_name, value)
+                        != encdec::Sealing::Plain
+            })
+    }
+
+    /// Returns whether rows of the given table are encrypted.
+    ///
+    /// Internal tables are always encrypted, regardless of the policy.
+    fn encrypts_table(&self, table_name: &str) -> bool {
+        self.tables.contains(table_name) || self.policy.encrypts_table(table_name)
+    }
+
+    /// Returns the keys needed to open rows.
+    const fn row_keys(&self) -> encdec::RowKeys<'_> {
+        encdec::RowKeys {
+            key: &self.key,
+            ciphers: &self.ciphers,
+            name_key: &self.name_key,
+            value_key: &self.value_key,
+            previous: self.previous_keys.as_ref(),
+            legacy: self.legacy_ciphertexts,
+        }
+    }
+
+    /// Decrypts a value of an internal table, with the previous key if it wasn't rewritten since
+    /// the key was changed.
+    fn decrypt_value(&self, value: &mut Value, compression: Compression) -> Result<bool, Error> {
+        let decrypt = if self.legacy_ciphertexts {
+            encdec::decrypt_legacy_value_in_place
+        } else {
+            encdec::decrypt_value_in_place
+        };
+
+        let Some(previous) = &self.previous_keys else {
+            return decrypt(&self.key, value, compression);
+        };
+
+        // values that don't open are garbled, so the previous key gets a copy
+        let mut decrypted = value.clone();
+
+        match decrypt(&self.key, &mut decrypted, compression) {
+            Err(Error::EncryptionError) => decrypt(&previous.key, value, compression),
+            result => {
+                *value = decrypted;
+                result
+            }
+        }
+    }
+
+    /// Returns whether the keys of rows in the given table are encrypted.
+    fn encrypts_row_keys(&self, table_name: &str) -> bool {
+        self.policy.encrypts_row_keys()
+            && self.policy.encrypts_table(table_name)
+            && !self.tables.contains(table_name)
+    }
+
+    /// Returns the key a row of the given table is stored under in the inner store.
+    fn inner_key<'a>(&self, table_name: &str, key: &'a Key) -> Result<Cow<'a, Key>, Error> {
+        if self.encrypts_row_keys(table_name) {
+            encdec::encrypt_key(&self.key, &self.name_key, key).map(Cow::Owned)
+        } else {
+            Ok(Cow::Borrowed(key))
+        }
+    }
+
+    /// Returns the key a row of the given table was stored under before an online key change, if
+    /// it differs from its current one.
+    fn previous_inner_key(&self, table_name: &str, key: &Key) -> Result<Option<Key>, Error> {
+        match &self.previous_keys {
+            Some(previous) if self.encrypts_row_keys(table_name) => Ok(Some(encdec::encrypt_key(
+                &previous.key,
+                &previous.name_key,
+                key,
+            )?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Encrypts the column defaults of a schema, so they don't leak to the inner store. Defaults
+    /// already encrypted are left as-is.
+    ///
+    /// The nonce sequence is advanced once for all of them, however wide the table.
+    fn encrypt_defaults(&self, schema: &mut Schema) -> Result<(), Error> {
+        let mut nonces = self.batch_nonces()?;
+
+        for column_def in schema.column_defs.iter_mut().flatten() {
+            match &mut column_def.default {
+                Some(default) if !encdec::is_encrypted_expr(default) => {
+                    *default = encdec::encrypt_expr(
+                        &self.schema_keys.definitions_key,
+                        &mut nonces,
+                        default,
+                    )?;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decrypts the column defaults of a schema encrypted with `encrypt_defaults`.
+    fn decrypt_defaults(&self, schema: &mut Schema) -> Result<(), Error> {
+        for column_def in schema.column_defs.iter_mut().flatten() {
+            if let Some(default) = &mut column_def.default {
+                encdec::decrypt_expr_in_place(&self.schema_keys.definitions_key, default)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encrypts the body and argument defaults of a custom function.
+    fn encrypt_function(
+        &self,
+        mut func: StructCustomFunction,
+    ) -> Result<StructCustomFunction, Error> {
+        if self.policy.is_passthrough() {
+            return Ok(func);
+        }
+
+        let key = &self.schema_keys.definitions_key;
+        let mut nonces = self.batch_nonces()?;
+
+        func.body = encdec::encrypt_expr(key, &mut nonces, &func.body)?;
+
+        for arg in &mut func.args {
+            if let Some(default) = &mut arg.default {
+                *default = encdec::encrypt_expr(key, &mut nonces, default)?;
+            }
+        }
+
+        Ok(func)
+    }
+
+    /// Decrypts a custom function encrypted with `encrypt_function`.
+    fn decrypt_function(
+        &self,
+        mut func: StructCustomFunction,
+    ) -> Result<StructCustomFunction, Error> {
+        let key = &self.schema_keys.definitions_key;
+
+        encdec::decrypt_expr_in_place(key, &mut func.body)?;
+
+        for arg in &mut func.args {
+            if let Some(default) = &mut arg.default {
+                encdec::decrypt_expr_in_place(key, default)?;
+            }
+        }
+
+        Ok(func)
+    }
+
+    /// Encrypts a batch of rows of the given table according to the policy.
+    ///
+    /// The nonce sequence is advanced once for the whole batch, and every value gets a nonce
+    /// derived from that one, like the rows of a key change.
+    fn encrypt_rows<'a>(
+        &self,
+        table_name: &str,
+        columns: &TableColumns,
+        rows: impl IntoIterator<Item = (Option<&'a encdec::RowIdentity>, &'a mut DataRow)>,
+    ) -> Result<(), Error> {
+        let nonces = self.batch_nonces()?;
+
+        self.row_sealer(table_name, columns).seal_rows(nonces, rows)
+    }
+
+    /// Returns the identity the nonces of a row are derived from, if the store derives them
+    /// rather than drawing them from the nonce sequence.
+    fn row_identity(
+        &self,
+        table_name: &str,
+        key: &Key,
+    ) -> Result<Option<encdec::RowIdentity>, Error> {
+        self.synthetic_nonces
+            .then(|| encdec::RowIdentity::new(&self.identity_key, table_name, key))
+            .transpose()
+    }
+
+    const fn row_sealer<'a>(
+        &'a self,
+        table_name: &'a str,
+        columns: &'a TableColumns,
+    ) -> RowSealer<'a> {
+        RowSealer {
+            table_name,
+            columns,
+            policy: &self.policy,
+            keys: self.row_keys(),
+            compression: self.compression,
+        }
+    }
 }
 
-impl<S: Store + StoreMut, NonceSeq: NonceSequence> EncryptedStore<S, NonceSeq> {
-    /// Creates the `EncryptedStore` with the given store, key, and nonce sequence.
+impl<S: Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>
+    EncryptedStore<S, NonceSeq>
+{
+    /// Scans the keys of the rows of a table, in the order [`Store::scan_data`] returns them,
+    /// without decrypting the rows, e.g. to count them or to collect the keys to delete.
+    ///
+    /// Encrypted row keys are still decrypted, which is cheap next to opening the values.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the inner store fails to scan the data. Row keys that fail to
+    /// decrypt are returned as errors by the stream.
+    pub async fn scan_keys(&self, table_name: &str) -> Result<KeyIter<'_>, Error> {
+        let inner_table_name = self.inner_table_name(table_name);
+        let encrypts_row_keys = self.encrypts_row_keys(table_name);
+        let rows = self.store.scan_data(&inner_table_name).await?;
+
+        Ok(Box::pin(rows.map(move |row| {
+            let (key, _) = row?;
+
+            if encrypts_row_keys {
+                Ok(encdec::decrypt_row_key(self.row_keys(), key)?)
+            } else {
+                Ok(key)
+            }
+        })))
+    }
+
+    /// Decrypts a row of a table with tokenized columns read from the inner store, and swaps
+    /// its tokens for their values.
+    async fn open_tokenized_row(
+        &self,
+        table_name: &str,
+        columns: &TableColumns,
+        encrypts_row_keys: bool,
+        row: Result<(Key, DataRow)>,
+    ) -> Result<(Key, DataRow)> {
+        let (mut key, mut row) = row?;
+
+        if encrypts_row_keys {
+            key = encdec::decrypt_row_key(self.row_keys(), key)?;
+        }
+
+        self.open_row(table_name, columns, &key, &mut row)?;
+        self.detokenize_row(table_name, columns, &mut row).await?;
+
+        Ok((key, row))
+    }
+
+    /// Returns the columns of a table, if they're needed to read its rows. Strict reads name the
+    /// column of a value that fails to open, so they load them regardless.
+    async fn read_columns(&self, table_name: &str) -> Result<TableColumns, Error> {
+        if self.strict_reads || self.policy.has_tokenized_columns(table_name) {
+            self.load_columns(table_name).await
+        } else {
+            Ok(TableColumns::default())
+        }
+    }
+
+    /// Returns the columns of a table, if the policy needs them to encrypt its rows.
+    async fn table_columns(&self, table_name: &str) -> Result<TableColumns, Error> {
+        if !self.policy.has_column_nulls(table_name)
+            && !self.policy.has_tokenized_columns(table_name)
+            && !self.policy.encrypts_unique_deterministically()
+            && !self.policy.encrypts_indexed_deterministically()
+        {
+            return Ok(TableColumns::default());
+        }
+
+        self.load_columns(table_name).await
+    }
+
+    /// Loads the columns of a table from its schema.
+    async fn load_columns(&self, table_name: &str) -> Result<TableColumns, Error> {
+        let Some(schema) = Store::fetch_schema(self, table_name).await? else {
+            return Ok(TableColumns::default());
+        };
+        let column_defs = schema.column_defs.unwrap_or_default();
+
+        let mut deterministic = HashSet::new();
+
+        if self.policy.encrypts_unique_deterministically() {
+            deterministic.extend(
+                column_defs
+                    .iter()
+                    .filter(|column_def| column_def.unique.is_some())
+                    .map(|column_def| column_def.name.clone()),
+            );
+        }
+
+        if self.policy.encrypts_indexed_deterministically() {
+            deterministic.extend(
+                schema
+                    .indexes
+                    .into_iter()
+                    .filter_map(|index| match index.expr {
+                        Expr::Identifier(name) => Some(name),
+                        _ => None,
+                    }),
+            );
+        }
+
+        Ok(TableColumns {
+            names: column_defs
+                .into_iter()
+                .map(|column_def| column_def.name)
+                .collect(),
+            deterministic,
+        })
+    }
+
+    /// Scans a table like an index over the given column would, by decrypting every row.
+    ///
+    /// `position` is the position of the column in `DataRow::Vec` rows.
+    async fn scan_and_filter(
+        &self,
+        table_name: &str,
+        column_name: &str,
+        position: Option<usize>,
+        asc: Option<bool>,
+        cmp_value: Option<(&IndexOperator, Value)>,
+    ) -> Result<RowIter<'_>> {
+        let rows = Store::scan_data(self, table_name)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        let mut rows = rows
+            .into_iter()
+            .map(|(key, row)| {
+                let value = match &row {
+                    DataRow::Vec(values) => position.and_then(|i| values.get(i)),
+                    DataRow::Map(values) => values.get(column_name),
+                };
+
+                (value.cloned().unwrap_or(Value::Null), key, row)
+            })
+            .filter(|(value, ..)| {
+                cmp_value.as_ref().is_none_or(|(operator, cmp_value)| {
+                    value
+                        .evaluate_cmp(cmp_value)
+                        .is_some_and(|ordering| match operator {
+                            IndexOperator::Gt => ordering.is_gt(),
+                            IndexOperator::Lt => ordering.is_lt(),
+                            IndexOperator::GtEq => ordering.is_ge(),
+                            IndexOperator::LtEq => ordering.is_le(),
+                            IndexOperator::Eq => ordering.is_eq(),
+                        })
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // NULLs sort last, as in `ORDER BY`. Values that can't be compared make the order
+        // meaningless, so they fail the scan rather than be taken as equal
+        let mut incomparable = false;
+
+        rows.sort_by(|(a, ..), (b, ..)| match (a.is_null(), b.is_null()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Greater,
+            (false, true) => Ordering::Less,
+            (false, false) => a.evaluate_cmp(b).unwrap_or_else(|| {
+                incomparable = true;
+                Ordering::Equal
+            }),
+        });
+
+        if incomparable {
+            return Err(
+                Error::Unsupported("ordering an index over values that can't be compared").into(),
+            );
+        }
+
+        if asc == Some(false) {
+            rows.reverse();
+        }
+
+        Ok(Box::pin(futures::stream::iter(
+            rows.into_iter().map(|(_, key, row)| Ok((key, row))),
+        )))
+    }
+}
+
+impl<S: Store + StoreMut + MaybeSendSync> EncryptedStore<S> {
+    /// Creates the `EncryptedStore` with the given store and key, drawing nonces from the
+    /// system's secure random number generator.
     ///
     /// Additionally creates the `encrypted_meta` table in the store if it doesn't exist.
     ///
     /// # Errors
     ///
     /// Returns an error if the store fails to fetch the schema or insert the schema.
-    pub async fn new(
-        mut store: S,
+    pub async fn new(store: S, key: UnboundKey) -> Result<Self, Error> {
+        Self::new_with_nonce_sequence(store, key, RandomNonce::new()).await
+    }
+
+    /// Like [`EncryptedStore::new`], but with an AES-256-GCM key made from raw key bytes, e.g. a
+    /// `[u8; 32]` or, with the `secrecy` and `zeroize` features, a type that wipes them when
+    /// dropped. The bytes are only read to make the key, and aren't kept.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidKeyMaterial`] if the bytes aren't 32 bytes long, or an error like
+    /// [`EncryptedStore::new`].
+    pub async fn from_key_bytes(store: S, key: &(impl KeyBytes + ?Sized)) -> Result<Self, Error> {
+        Self::new(store, Algorithm::Aes256Gcm.key(key.key_bytes())?).await
+    }
+}
+
+impl<S: Store + MaybeSendSync> EncryptedStore<S> {
+    /// Opens an existing encrypted store for reading only, e.g. on a read replica or for an
+    /// analytics job, so the inner store only needs to implement [`Store`].
+    ///
+    /// The key is checked like in [`EncryptedStore::new`], but nothing is written: a store
+    /// without a key check is refused rather than set up.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::NonEncryptedDatabase`] if the store has no key check,
+    /// [`Error::InvalidKey`] if the key doesn't match it, or an error if the metadata can't be
+    /// read.
+    pub async fn open_read_only(store: S, key: UnboundKey) -> Result<Self, Error> {
+        Self::open_read_only_in_namespace(store, key, DEFAULT_NAMESPACE).await
+    }
+
+    /// Like [`EncryptedStore::open_read_only`], for a store whose own tables are kept under
+    /// `namespace`, as with [`EncryptedStore::new_in_namespace`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error like [`EncryptedStore::open_read_only`].
+    pub async fn open_read_only_in_namespace(
+        store: S,
         key: UnboundKey,
-        mut nonce_sequence: NonceSeq,
+        namespace: &str,
     ) -> Result<Self, Error> {
         let key = LessSafeKey::new(key);
+        let tables = InternalTables::new(namespace);
 
-        if let Some(table) = store.fetch_data("encrypted_meta", &Key::U8(0)).await? {
-            match table {
-                DataRow::Map(mut map) => {
-                    let encrypted_key = map.get_mut("key").ok_or(Error::InvalidValue)?;
+        if !check_key(&store, &tables, &key)
+            .await?
+            .ok_or(Error::NonEncryptedDatabase)?
+        {
+            return Err(Error::InvalidKey);
+        }
 
-                    if encdec::decrypt_value_in_place(&key, encrypted_key).is_err() {
-                        return Err(Error::InvalidKey);
-                    };
-                }
-                DataRow::Vec(_) => return Err(Error::InvalidValue),
+        let schema_material = read_schema_material(&store, &tables, &key)
+            .await?
+            .unwrap_or_else(|| encdec::derive_material(&key, SCHEMA_KEY_LABEL).to_vec());
+
+        Ok(Self {
+            schema_keys: SchemaKeys::new(key.algorithm(), &schema_material)?,
+            tables,
+            ..Self::from_parts(store, key, CheckedNonces::new(RandomNonce::new()))
+        })
+    }
+
+    /// Like [`EncryptedStore::open_read_only`], for a store described by the given config, as
+    /// opened by [`EncryptedStore::from_config`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidConfig`] if the config doesn't [validate](EncryptionConfig::validate),
+    /// an error if the key material doesn't fit the config, or an error like
+    /// [`EncryptedStore::open_read_only`].
+    pub async fn open_read_only_from_config(
+        store: S,
+        config: EncryptionConfig,
+        key_material: &[u8],
+    ) -> Result<Self, Error> {
+        config.validate()?;
+
+        let key = config.key(key_material)?;
+        let namespace = config.namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE);
+
+        Ok(Self {
+            rotation_schedule: config.rotation,
+            ..Self::open_read_only_in_namespace(store, key, namespace)
+                .await?
+                .with_policy(config.policy)
+                .with_compression(config.compression)
+        })
+    }
+}
+
+impl<S: Store + StoreMut + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>
+    EncryptedStore<S, NonceSeq>
+{
+    /// Like [`EncryptedStore::new`], but with nonces from the given sequence, e.g. a
+    /// [`CounterNonce`].
+    ///
+    /// Nonces must never repeat under a key. Debug builds panic when the nonce sequence repeats
+    /// one of its recent nonces, to catch broken sequences before they make it to production.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error like [`EncryptedStore::new`].
+    pub async fn new_with_nonce_sequence(
+        store: S,
+        key: UnboundKey,
+        nonce_sequence: NonceSeq,
+    ) -> Result<Self, Error> {
+        Self::new_in_namespace(store, key, nonce_sequence, DEFAULT_NAMESPACE).await
+    }
+
+    /// Like [`EncryptedStore::new_with_nonce_sequence`], but keeps the store's own tables, from
+    /// the key check to the token vault, under names starting with `namespace` instead of
+    /// `encrypted_`, e.g. so they don't collide with user tables or names the inner store
+    /// reserves.
+    ///
+    /// The namespace must stay the same for the lifetime of the store: opened in another one,
+    /// the store looks like a new one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error like [`EncryptedStore::new`].
+    pub async fn new_in_namespace(
+        store: S,
+        key: UnboundKey,
+        nonce_sequence: NonceSeq,
+        namespace: &str,
+    ) -> Result<Self, Error> {
+        Self::open_in_namespace(store, key, nonce_sequence, namespace, true).await
+    }
+
+    /// Opens the store like [`EncryptedStore::new_in_namespace`], but only sets it up if it has
+    /// no key check yet when `set_up` is true, and returns [`Error::NonEncryptedDatabase`]
+    /// otherwise.
+    #[allow(clippy::too_many_lines)]
+    async fn open_in_namespace(
+        mut store: S,
+        key: UnboundKey,
+        nonce_sequence: NonceSeq,
+        namespace: &str,
+        set_up: bool,
+    ) -> Result<Self, Error> {
+        let mut nonce_sequence = CheckedNonces::new(nonce_sequence);
+        let key = LessSafeKey::new(key);
+        let tables = InternalTables::new(namespace);
+
+        let wrote_sentinel = if let Some(valid) = check_key(&store, &tables, &key).await? {
+            if !valid {
+                return Err(Error::InvalidKey);
             }
+
+            false
         } else {
-            store
-                .insert_schema(&Schema {
-                    table_name: "encrypted_meta".to_string(),
-                    column_defs: Some(vec![ColumnDef {
-                        name: "key".to_string(),
-                        data_type: DataType::Bytea,
-                        nullable: false,
-                        default: None,
-                        unique: None,
-                        comment: None,
-                    }]),
-                    indexes: vec![],
-                    engine: None,
-                    foreign_keys: vec![],
-                    comment: Some("Table to store the EncryptedStore metadata".to_string()),
-                })
-                .await?;
+            if !set_up {
+                return Err(Error::NonEncryptedDatabase);
+            }
+
+            create_meta_table(&mut store, &tables).await?;
 
             store
                 .insert_data(
-                    "encrypted_meta",
+                    &tables.meta,
                     vec![(
                         Key::U8(0),
                         DataRow::Map(
@@ -126,6 +1686,7 @@ impl<S: Store + StoreMut, NonceSeq: NonceSequence> EncryptedStore<S, NonceSeq> {
                                     &key,
                                     &mut nonce_sequence,
                                     &mut value,
+                                    Compression::None,
                                 )?;
 
                                 value
@@ -136,120 +1697,379 @@ impl<S: Store + StoreMut, NonceSeq: NonceSequence> EncryptedStore<S, NonceSeq> {
                     )],
                 )
                 .await?;
-        }
+
+            let mut created_at = Value::Timestamp(chrono::Utc::now().naive_utc());
+
+            encdec::encrypt_value_in_place(
+                &key,
+                &mut nonce_sequence,
+                &mut created_at,
+                Compression::None,
+            )?;
+
+            store
+                .insert_data(
+                    &tables.meta,
+                    vec![(
+                        CREATED_AT_ROW,
+                        DataRow::Map(HashMap::from([("created_at".to_string(), created_at)])),
+                    )],
+                )
+                .await?;
+
+            true
+        };
+
+        let schema_material =
+            if let Some(material) = read_schema_material(&store, &tables, &key).await? {
+                material
+            } else {
+                let material = encdec::derive_material(&key, SCHEMA_KEY_LABEL);
+                let mut value = Value::Bytea(material.to_vec());
+
+                encdec::encrypt_value_in_place(
+                    &key,
+                    &mut nonce_sequence,
+                    &mut value,
+                    Compression::None,
+                )?;
+
+                store
+                    .insert_data(
+                        &tables.meta,
+                        vec![(
+                            SCHEMA_KEY_ROW,
+                            DataRow::Map(HashMap::from([("schema_key".to_string(), value)])),
+                        )],
+                    )
+                    .await?;
+
+                material.to_vec()
+            };
+
+        let names = pseudonym::read_names(&store, &tables, &key).await?;
 
         Ok(Self {
-            key,
-            nonce_sequence,
-            store,
+            schema_keys: SchemaKeys::new(key.algorithm(), &schema_material)?,
+            tables,
+            wrote_sentinel,
+            names: Mutex::new(names),
+            ..Self::from_parts(store, key, nonce_sequence)
         })
     }
 
-    /// Creates the `EncryptedStore` with the given store, key, and nonce sequence.
-    ///
-    /// Does not check for a correct key. If the key is invalid, the store will return an error when fetching data.
-    pub fn new_unchecked(store: S, key: UnboundKey, nonce_sequence: NonceSeq) -> Self {
-        Self {
-            key: LessSafeKey::new(key),
-            nonce_sequence,
-            store,
-        }
+    /// Creates the `EncryptedStore` with the given store, key, and nonce sequence.
+    ///
+    /// Does not check for a correct key. If the key is invalid, the store will return an error when fetching data.
+    ///
+    /// The keys protecting schemas (pseudonyms and column defaults) are derived from the key
+    /// rather than read from the store, so stores whose key was changed must be opened with
+    /// [`EncryptedStore::new`] if they use them.
+    pub fn new_unchecked(store: S, key: UnboundKey, nonce_sequence: NonceSeq) -> Self {
+        Self::from_parts(
+            store,
+            LessSafeKey::new(key),
+            CheckedNonces::new(nonce_sequence),
+        )
+    }
+
+    /// Puts a store taken apart with [`EncryptedStore::into_parts`] back together, along with
+    /// the online key change it was in, if any. Like [`EncryptedStore::new_unchecked`], the key
+    /// isn't checked, and settings like the policy are set again with the `with_` methods.
+    ///
+    /// The state of a [`PersistentNonceSequence`] isn't saved by the store put back together:
+    /// reopen it with [`EncryptedStore::new_with_persistent_nonces`] to keep saving it.
+    pub fn from_store_parts(parts: StoreParts<S, NonceSeq>) -> Self {
+        let mut store = Self::from_parts(
+            parts.store,
+            parts.key,
+            CheckedNonces::new(parts.nonce_sequence),
+        );
+        store.previous_keys = parts.previous_key.map(encdec::KeySet::new);
+
+        store
+    }
+
+    /// Creates the `EncryptedStore` described by the given config.
+    ///
+    /// The key is built from `key_material` according to the config's algorithm and KDF, and
+    /// checked like in [`EncryptedStore::new`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidConfig`] if the config doesn't [validate](EncryptionConfig::validate),
+    /// or an error if the key material doesn't fit the config, or if [`EncryptedStore::new`]
+    /// fails.
+    pub async fn from_config(
+        store: S,
+        config: EncryptionConfig,
+        key_material: &[u8],
+        nonce_sequence: NonceSeq,
+    ) -> Result<Self, Error> {
+        Self::open_from_config(store, config, key_material, nonce_sequence, true).await
+    }
+
+    /// Like [`EncryptedStore::from_config`], but refuses a store without a key check rather than
+    /// setting it up, e.g. to change the key of a store that's meant to exist without writing
+    /// to one that doesn't.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::NonEncryptedDatabase`] if the store has no key check, or an error like
+    /// [`EncryptedStore::from_config`].
+    pub async fn open_existing_from_config(
+        store: S,
+        config: EncryptionConfig,
+        key_material: &[u8],
+        nonce_sequence: NonceSeq,
+    ) -> Result<Self, Error> {
+        Self::open_from_config(store, config, key_material, nonce_sequence, false).await
+    }
+
+    /// Opens the store described by the config, setting it up if `set_up` is true, as
+    /// [`EncryptedStore::open_in_namespace`] does.
+    async fn open_from_config(
+        store: S,
+        config: EncryptionConfig,
+        key_material: &[u8],
+        nonce_sequence: NonceSeq,
+        set_up: bool,
+    ) -> Result<Self, Error> {
+        config.validate()?;
+
+        let key = config.key(key_material)?;
+        let namespace = config.namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE);
+
+        Ok(Self {
+            rotation_schedule: config.rotation,
+            ..Self::open_in_namespace(store, key, nonce_sequence, namespace, set_up)
+                .await?
+                .with_policy(config.policy)
+                .with_compression(config.compression)
+        })
     }
 
     // fn check_key(table: HashMap<String, >)
 }
 
-impl<S: Store + StoreMut, NonceSeq: NonceSequence> EncryptedStore<S, NonceSeq> {
-    /// Change the key used for encryption.
-    /// Rewrites all the data in the store with the new key and a new nonce.
-    ///
-    /// You should be careful when using this method and create a backup of the data before calling it or begin a transaction.
-    ///
-    /// # Errors
-    ///
-    /// Returns an error if the store fails to fetch, decrypt, or re-encrypt the data.
+impl<S: AlterTable + Store + StoreMut + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>
+    EncryptedStore<S, NonceSeq>
+{
+    /// Encrypts the values the inner store materialized into the existing rows of a table for a
+    /// column just added, which it wrote in plain text as the last value of each row.
     ///
-    /// You should revert to the backup and retry later if this happens.
-    pub async fn change_key(mut self, new_key: UnboundKey) -> Result<Self, Error> {
-        let new_key = LessSafeKey::new(new_key);
+    /// Only those values are touched: the rest of the row is already encrypted, and a plaintext
+    /// `BYTEA` default would otherwise be mistaken for a ciphertext. The default is encrypted in
+    /// the stored schema too, but some stores empty a table when its schema is inserted again, so
+    /// the rows are copied a batch at a time into a staging table with the new schema, which is
+    /// then swapped in.
+    async fn encrypt_added_column(
+        &mut self,
+        table_name: &str,
+        column_name: &str,
+    ) -> Result<(), Error> {
+        let inner_table_name = self.inner_table_name(table_name).into_owned();
+        let Some(mut schema) = self.store.fetch_schema(&inner_table_name).await? else {
+            return Ok(());
+        };
+        let columns = self.table_columns(table_name).await?;
+        let staging_name = format!("{}{inner_table_name}", self.tables.staging_prefix);
 
-        // identify table names
-        let schemas = self.store.fetch_all_schemas().await?;
+        self.encrypt_defaults(&mut schema)?;
 
-        for schema in schemas {
-            let keys = self
+        // a copy left by an interrupted call may be missing rows
+        if self.store.fetch_schema(&staging_name).await?.is_some() {
+            self.store.delete_schema(&staging_name).await?;
+        }
+
+        self.store
+            .insert_schema(&Schema {
+                table_name: staging_name.clone(),
+                ..schema
+            })
+            .await?;
+
+        // the table keeps its rows until the swap, so the scan skips past the last one copied
+        let mut last_key = None;
+
+        loop {
+            let mut rows = self
                 .store
-                .scan_data(&schema.table_name)
+                .scan_data(&inner_table_name)
                 .await?
-                .map(|r| r.map(|(k, _)| k))
-                .collect::<Vec<_>>()
-                .await;
+                .try_filter(|(key, _)| {
+                    future::ready(last_key.as_ref().is_none_or(|last| key > last))
+                })
+                .take(self.batch_size)
+                .try_collect::<Vec<_>>()
+                .await?;
+            let done = rows.len() < self.batch_size;
 
-            for key in keys {
-                let key = key?;
+            last_key = rows.last().map(|(key, _)| key.clone());
 
-                let mut row = self
-                    .store
-                    .fetch_data(&schema.table_name, &key)
-                    .await?
-                    .ok_or(Error::InvalidValue)?;
-
-                match row {
-                    DataRow::Map(ref mut row) => {
-                        for value in row.values_mut() {
-                            encdec::decrypt_value_in_place(&self.key, value)?;
-
-                            encdec::encrypt_value_in_place(
-                                &new_key,
-                                &mut self.nonce_sequence,
-                                value,
-                            )?;
-                        }
-                    }
-                    DataRow::Vec(ref mut row) => {
-                        for value in row {
-                            if encdec::decrypt_value_in_place(&self.key, value)? {
-                                encdec::encrypt_value_in_place(
-                                    &new_key,
-                                    &mut self.nonce_sequence,
-                                    value,
-                                )?;
-                            };
-                        }
-                    }
-                }
+            self.prepare_nonces().await?;
 
-                self.store
-                    .insert_data(&schema.table_name, vec![(key, row)])
+            for (key, row) in &mut rows {
+                self.encrypt_added_value(table_name, &columns, column_name, key, row)
                     .await?;
             }
+
+            self.store.insert_data(&staging_name, rows).await?;
+
+            if done {
+                break;
+            }
         }
 
-        Ok(Self {
-            key: new_key,
-            nonce_sequence: self.nonce_sequence,
-            store: self.store,
-        })
+        self.store.delete_schema(&inner_table_name).await?;
+        self.store
+            .rename_schema(&staging_name, &inner_table_name)
+            .await?;
+        self.clear_schema_cache();
+
+        Ok(())
+    }
+
+    /// Encrypts the value of an added column materialized into a row by the inner store.
+    async fn encrypt_added_value(
+        &mut self,
+        table_name: &str,
+        columns: &TableColumns,
+        column_name: &str,
+        key: &Key,
+        row: &mut DataRow,
+    ) -> Result<(), Error> {
+        if encdec::is_whole_row(row) {
+            // the materialized value follows the ciphertext, so the row is sealed again
+            encdec::decrypt_row_in_place(self.row_keys(), row, self.compression)?;
+
+            let key = encdec::decrypt_row_key(self.row_keys(), key.clone())?;
+            let identity = self.row_identity(table_name, &key)?;
+
+            return self.encrypt_rows(table_name, columns, [(identity.as_ref(), row)]);
+        }
+
+        let DataRow::Vec(values) = row else {
+            // schemaless tables have no columns to add
+            return Ok(());
+        };
+        let Some(value) = values.last_mut() else {
+            return Ok(());
+        };
+
+        if self.policy.tokenizes(table_name, column_name) && !value.is_null() {
+            *value = Value::Str(self.tokenize(value).await?);
+        }
+
+        let sealing = columns.sealing(&self.policy, table_name, Some(column_name), value);
+
+        encdec::seal_row_value_in_place(
+            self.ciphers
+                .select(&self.key, self.policy.table_algorithm(table_name)),
+            &self.value_key,
+            &mut *self.nonces(),
+            table_name,
+            Some(column_name),
+            value,
+            sealing,
+            self.compression,
+        )
     }
 }
 
 #[async_trait(?Send)]
-impl<S: Store, NonceSeq: NonceSequence> Store for EncryptedStore<S, NonceSeq> {
+impl<S: Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync> Store
+    for EncryptedStore<S, NonceSeq>
+{
     async fn fetch_schema(&self, table_name: &str) -> Result<Option<Schema>> {
-        self.store.fetch_schema(table_name).await
+        if let Some(schema) = self.cached_schema(table_name) {
+            return Ok(schema);
+        }
+
+        let schema = match self
+            .store
+            .fetch_schema(&self.inner_table_name(table_name))
+            .await?
+        {
+            Some(schema) => {
+                let mut schema = self.reveal_schema(schema).await?;
+
+                self.decrypt_defaults(&mut schema)?;
+
+                Some(schema)
+            }
+            None => None,
+        };
+
+        self.cache_schema(table_name, schema.clone());
+
+        Ok(schema)
     }
 
     async fn fetch_all_schemas(&self) -> Result<Vec<Schema>> {
-        self.store.fetch_all_schemas().await
+        if let Some(schemas) = self.cached_schemas() {
+            return Ok(schemas);
+        }
+
+        let schemas = self.store.fetch_all_schemas().await?;
+
+        let mut revealed = Vec::with_capacity(schemas.len());
+
+        for schema in schemas {
+            // the store's own tables aren't the user's
+            if self.tables.contains(&schema.table_name) {
+                continue;
+            }
+
+            let mut schema = self.reveal_schema(schema).await?;
+
+            self.decrypt_defaults(&mut schema)?;
+
+            revealed.push(schema);
+        }
+
+        if self.policy.pseudonymizes_names() {
+            // the inner store orders them by pseudonym
+            revealed.sort_by(|a, b| a.table_name.cmp(&b.table_name));
+        }
+
+        self.cache_schemas(revealed.clone());
+
+        Ok(revealed)
     }
 
     async fn fetch_data(&self, table_name: &str, key: &Key) -> Result<Option<DataRow>> {
-        let data = self.store.fetch_data(table_name, key).await?;
+        let inner_table_name = self.inner_table_name(table_name);
+        let mut data = self
+            .store
+            .fetch_data(&inner_table_name, &*self.inner_key(table_name, key)?)
+            .await?;
+
+        if data.is_none() {
+            if let Some(previous_key) = self.previous_inner_key(table_name, key)? {
+                data = self
+                    .store
+                    .fetch_data(&inner_table_name, &previous_key)
+                    .await?;
+            }
+        }
+
+        if !self.encrypts_table(table_name) {
+            return Ok(data);
+        }
 
         match data {
-            Some(mut data) => {
-                tracing::info!(?data);
-                encdec::decrypt_row_in_place(&self.key, &mut data).map_err(GluesqlError::from)?;
+            Some(data) => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(%table_name, "opening row");
+
+                let columns = self.read_columns(table_name).await?;
+                let mut data = self.open_large_row(table_name, &columns, key, data).await?;
+
+                if self.policy.has_tokenized_columns(table_name) {
+                    self.detokenize_row(table_name, &columns, &mut data).await?;
+                }
+
                 Ok(Some(data))
             }
             None => Ok(None),
@@ -257,68 +2077,223 @@ impl<S: Store, NonceSeq: NonceSequence> Store for EncryptedStore<S, NonceSeq> {
     }
 
     async fn scan_data(&self, table_name: &str) -> Result<RowIter<'_>> {
-        match self.store.scan_data(table_name).await {
-            Ok(rows) => Ok(Box::pin(rows.map(|row| match row {
-                Ok((key, mut row)) => {
-                    encdec::decrypt_row_in_place(&self.key, &mut row)
-                        .map_err(GluesqlError::from)?;
+        let inner_table_name = self.inner_table_name(table_name);
 
-                    Ok((key, row))
-                }
-                Err(e) => Err(e),
-            }))),
+        if !self.encrypts_table(table_name) {
+            return self.store.scan_data(&inner_table_name).await;
+        }
+
+        let encrypts_row_keys = self.encrypts_row_keys(table_name);
+
+        match self.store.scan_data(&inner_table_name).await {
+            Ok(rows) if self.policy.has_tokenized_columns(table_name) => {
+                // detokenizing hits the vault, which doesn't fit in a synchronous `map`
+                let scanned =
+                    Arc::new((table_name.to_owned(), self.read_columns(table_name).await?));
+
+                Ok(Box::pin(
+                    rows.chunks(self.scan_chunk_size())
+                        .then(move |chunk| {
+                            let scanned = Arc::clone(&scanned);
+
+                            async move {
+                                let (table_name, columns) = &*scanned;
+                                let mut opened = Vec::with_capacity(chunk.len());
+
+                                for row in chunk {
+                                    opened.push(
+                                        self.open_tokenized_row(
+                                            table_name,
+                                            columns,
+                                            encrypts_row_keys,
+                                            row,
+                                        )
+                                        .await,
+                                    );
+                                }
+
+                                stream::iter(opened)
+                            }
+                        })
+                        .flatten(),
+                ))
+            }
+            Ok(rows) if self.scan_concurrency > 1 => {
+                let columns = self.read_columns(table_name).await?;
+
+                Ok(self.scan_concurrently(table_name.to_owned(), columns, encrypts_row_keys, rows))
+            }
+            Ok(rows) => {
+                let table_name = table_name.to_owned();
+                let columns = self.read_columns(&table_name).await?;
+
+                Ok(Box::pin(rows.map(move |row| match row {
+                    Ok((mut key, mut row)) => {
+                        if encrypts_row_keys {
+                            key = encdec::decrypt_row_key(self.row_keys(), key)
+                                .map_err(GluesqlError::from)?;
+                        }
+
+                        self.open_row(&table_name, &columns, &key, &mut row)?;
+
+                        Ok((key, row))
+                    }
+                    Err(e) => Err(e),
+                })))
+            }
             Err(e) => Err(e),
         }
     }
 
     async fn fetch_referencings(&self, table_name: &str) -> Result<Vec<Referencing>> {
-        self.store.fetch_referencings(table_name).await
+        let mut referencings = self
+            .store
+            .fetch_referencings(&self.inner_table_name(table_name))
+            .await?;
+
+        if self.policy.pseudonymizes_names() {
+            for referencing in &mut referencings {
+                referencing.table_name = self
+                    .reveal_name(std::mem::take(&mut referencing.table_name))
+                    .await?;
+                self.reveal_foreign_key(&mut referencing.foreign_key)
+                    .await?;
+            }
+        }
+
+        Ok(referencings)
     }
 }
 
 #[async_trait(?Send)]
-impl<S: StoreMut, NonceSeq: NonceSequence> StoreMut for EncryptedStore<S, NonceSeq> {
+impl<S: Store + StoreMut + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync> StoreMut
+    for EncryptedStore<S, NonceSeq>
+{
     async fn insert_schema(&mut self, schema: &Schema) -> Result<()> {
-        self.store.insert_schema(schema).await
+        self.clear_schema_cache();
+        self.prepare_nonces().await?;
+
+        let mut inner_schema = self.pseudonymize_schema(schema).await?;
+
+        if self.encrypts_table(&schema.table_name) {
+            self.encrypt_defaults(&mut inner_schema)?;
+        }
+
+        self.store.insert_schema(&inner_schema).await
     }
 
     async fn delete_schema(&mut self, table_name: &str) -> Result<()> {
-        self.store.delete_schema(table_name).await
+        self.clear_schema_cache();
+
+        let inner_table_name = self.inner_table_name(table_name).into_owned();
+
+        self.store.delete_schema(&inner_table_name).await
     }
 
     async fn append_data(&mut self, table_name: &str, mut rows: Vec<DataRow>) -> Result<()> {
-        tracing::info!("appending");
+        #[cfg(feature = "tracing")]
+        tracing::debug!(%table_name, rows = rows.len(), "appending");
+
+        let inner_table_name = self.inner_table_name(table_name);
+
+        if !self.encrypts_table(table_name) {
+            return self.store.append_data(&inner_table_name, rows).await;
+        }
+
+        self.prepare_nonces().await?;
+
+        let columns = self.table_columns(table_name).await?;
 
         for row in &mut rows {
-            encdec::encrypt_row_in_place(&self.key, &mut self.nonce_sequence, row)
-                .map_err(GluesqlError::from)?;
+            self.tokenize_row(table_name, &columns, row).await?;
         }
 
-        tracing::info!(?rows);
+        // the inner store only picks the keys of appended rows once they're written
+        let rows = rows.into_iter().map(|row| (None, row)).collect();
+        let rows = self.encrypt_batch(table_name, &columns, rows).await?;
 
-        self.store.append_data(table_name, rows).await
+        self.store.append_data(&inner_table_name, rows).await
     }
 
     async fn insert_data(&mut self, table_name: &str, mut rows: Vec<(Key, DataRow)>) -> Result<()> {
-        tracing::info!(?rows, %table_name, "inserting");
+        #[cfg(feature = "tracing")]
+        tracing::debug!(%table_name, rows = rows.len(), "inserting");
+
+        let inner_table_name = self.inner_table_name(table_name);
+
+        if !self.encrypts_table(table_name) {
+            return self.store.insert_data(&inner_table_name, rows).await;
+        }
+
+        self.prepare_nonces().await?;
+
+        let columns = self.table_columns(table_name).await?;
+        // rows not rewritten since the key was changed would otherwise linger under their old key
+        let mut previous_keys = Vec::new();
+
+        for (key, row) in &mut rows {
+            self.tokenize_row(table_name, &columns, row).await?;
+            previous_keys.extend(self.previous_inner_key(table_name, key)?);
+        }
+
+        let (mut keys, rows): (Vec<_>, Vec<_>) = rows.into_iter().unzip();
+        let rows = keys
+            .iter()
+            .zip(rows)
+            .map(|(key, row)| Ok((self.row_identity(table_name, key)?, row)))
+            .collect::<Result<_, Error>>()?;
+        let rows = self.encrypt_batch(table_name, &columns, rows).await?;
+
+        if self.encrypts_row_keys(table_name) {
+            for key in &mut keys {
+                *key = encdec::encrypt_key(&self.key, &self.name_key, key)?;
+            }
+        }
 
-        for (_, ref mut row) in &mut rows {
-            encdec::encrypt_row_in_place(&self.key, &mut self.nonce_sequence, row)
-                .map_err(GluesqlError::from)?;
+        let rows = keys.into_iter().zip(rows).collect::<Vec<_>>();
+
+        self.store.insert_data(&inner_table_name, rows).await?;
+
+        if !previous_keys.is_empty() {
+            self.store
+                .delete_data(&inner_table_name, previous_keys)
+                .await?;
         }
 
-        self.store.insert_data(table_name, rows).await
+        Ok(())
     }
 
-    async fn delete_data(&mut self, table_name: &str, keys: Vec<Key>) -> Result<()> {
-        self.store.delete_data(table_name, keys).await
+    async fn delete_data(&mut self, table_name: &str, mut keys: Vec<Key>) -> Result<()> {
+        if self.encrypts_row_keys(table_name) {
+            let mut previous_keys = Vec::new();
+
+            for key in &mut keys {
+                previous_keys.extend(self.previous_inner_key(table_name, key)?);
+                *key = encdec::encrypt_key(&self.key, &self.name_key, key)?;
+            }
+
+            keys.extend(previous_keys);
+        }
+
+        self.store
+            .delete_data(&self.inner_table_name(table_name), keys)
+            .await
     }
 }
 
 #[async_trait(?Send)]
-impl<S: AlterTable, NonceSeq: NonceSequence> AlterTable for EncryptedStore<S, NonceSeq> {
+impl<S: AlterTable + Store + StoreMut + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>
+    AlterTable for EncryptedStore<S, NonceSeq>
+{
     async fn rename_schema(&mut self, table_name: &str, new_table_name: &str) -> Result<()> {
-        self.store.rename_schema(table_name, new_table_name).await
+        self.clear_schema_cache();
+
+        let new_table_name = self.pseudonymize_table_name(new_table_name).await?;
+        let inner_table_name = self.inner_table_name(table_name).into_owned();
+
+        self.store
+            .rename_schema(&inner_table_name, &new_table_name)
+            .await
     }
 
     async fn rename_column(
@@ -327,13 +2302,39 @@ impl<S: AlterTable, NonceSeq: NonceSequence> AlterTable for EncryptedStore<S, No
         column_name: &str,
         new_column_name: &str,
     ) -> Result<()> {
+        self.clear_schema_cache();
+
+        let new_column_name = self.pseudonymize_column_name(new_column_name).await?;
+
         self.store
-            .rename_column(table_name, column_name, new_column_name)
+            .rename_column(
+                &self.inner_table_name(table_name),
+                &self.inner_column_name(column_name),
+                &new_column_name,
+            )
             .await
     }
 
+    /// The inner store materializes the default of the new column into the existing rows, so
+    /// unlike in `insert_schema`, it is passed to the inner store unencrypted. The table is then
+    /// swapped for a copy with the values it materialized and the default in its schema
+    /// encrypted.
     async fn add_column(&mut self, table_name: &str, column_def: &ColumnDef) -> Result<()> {
-        self.store.add_column(table_name, column_def).await
+        self.clear_schema_cache();
+        self.prepare_nonces().await?;
+
+        let inner_column_def = self.pseudonymize_column_def(column_def).await?;
+
+        self.store
+            .add_column(&self.inner_table_name(table_name), &inner_column_def)
+            .await?;
+
+        if self.encrypts_table(table_name) {
+            self.encrypt_added_column(table_name, &column_def.name)
+                .await?;
+        }
+
+        Ok(())
     }
 
     async fn drop_column(
@@ -342,14 +2343,31 @@ impl<S: AlterTable, NonceSeq: NonceSequence> AlterTable for EncryptedStore<S, No
         column_name: &str,
         if_exists: bool,
     ) -> Result<()> {
+        self.clear_schema_cache();
+
+        if self.encrypts_table(table_name)
+            && self.policy.table_mode(table_name) == EncryptionMode::Row
+        {
+            return Err(Error::Unsupported("dropping a column of a table in row mode").into());
+        }
+
         self.store
-            .drop_column(table_name, column_name, if_exists)
+            .drop_column(
+                &self.inner_table_name(table_name),
+                &self.inner_column_name(column_name),
+                if_exists,
+            )
             .await
     }
 }
 
 #[async_trait(?Send)]
-impl<S: Index, NonceSeq: NonceSequence> Index for EncryptedStore<S, NonceSeq> {
+impl<S: Index + Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync> Index
+    for EncryptedStore<S, NonceSeq>
+{
+    /// Indexes of encrypted tables are built over what the inner store holds, so they're only
+    /// used to look up values that aren't encrypted or are encrypted deterministically. Other
+    /// scans decrypt the whole table and filter it instead.
     async fn scan_indexed_data(
         &self,
         table_name: &str,
@@ -357,52 +2375,227 @@ impl<S: Index, NonceSeq: NonceSequence> Index for EncryptedStore<S, NonceSeq> {
         asc: Option<bool>,
         cmp_value: Option<(&IndexOperator, Value)>,
     ) -> Result<RowIter<'_>> {
+        let inner_table_name = self.inner_table_name(table_name);
+
+        if !self.encrypts_table(table_name) {
+            return self
+                .store
+                .scan_indexed_data(&inner_table_name, index_name, asc, cmp_value)
+                .await;
+        }
+
+        let schema = Store::fetch_schema(self, table_name)
+            .await?
+            .ok_or(Error::InvalidValue)?;
+        let Some(Expr::Identifier(column_name)) = schema
+            .indexes
+            .iter()
+            .find(|index| index.name == index_name)
+            .map(|index| &index.expr)
+        else {
+            return Err(Error::Unsupported("indexes over expressions of encrypted tables").into());
+        };
+
+        let inner_cmp_value = match &cmp_value {
+            // other columns may hold tokens too, so rows of such tables come from a scan
+            Some((IndexOperator::Eq, value))
+                if self.policy.table_mode(table_name) == EncryptionMode::Column
+                    && !self.policy.has_tokenized_columns(table_name) =>
+            {
+                let columns = self.table_columns(table_name).await?;
+                let mut value = value.clone();
+
+                match columns.sealing(&self.policy, table_name, Some(column_name), &value) {
+                    encdec::Sealing::Plain => Some(value),
+                    encdec::Sealing::Deterministic if self.previous_keys.is_none() => {
+                        encdec::encrypt_row_value_deterministically(
+                            self.ciphers
+                                .select(&self.key, self.policy.table_algorithm(table_name)),
+                            &self.value_key,
+                            table_name,
+                            Some(column_name),
+                            &mut value,
+                            self.compression,
+                        )?;
+
+                        Some(value)
+                    }
+                    // values not rewritten since the key was changed are sealed under the
+                    // previous key, so rows come from a scan
+                    encdec::Sealing::Deterministic | encdec::Sealing::Random => None,
+                }
+            }
+            _ => None,
+        };
+
+        let Some(inner_cmp_value) = inner_cmp_value else {
+            let position = schema
+                .column_defs
+                .iter()
+                .flatten()
+                .position(|column_def| &column_def.name == column_name);
+
+            return self
+                .scan_and_filter(table_name, column_name, position, asc, cmp_value)
+                .await;
+        };
+
+        let encrypts_row_keys = self.encrypts_row_keys(table_name);
+
         match self
             .store
-            .scan_indexed_data(table_name, index_name, asc, cmp_value)
+            .scan_indexed_data(
+                &inner_table_name,
+                index_name,
+                asc,
+                Some((&IndexOperator::Eq, inner_cmp_value)),
+            )
             .await
         {
-            Ok(rows) => Ok(Box::pin(rows.map(|row| match row {
-                Ok((key, mut row)) => {
-                    encdec::decrypt_row_in_place(&self.key, &mut row)
-                        .map_err(GluesqlError::from)?;
+            Ok(rows) => {
+                let table_name = table_name.to_owned();
+                let columns = self.read_columns(&table_name).await?;
 
-                    Ok((key, row))
-                }
-                Err(e) => Err(e),
-            }))),
+                Ok(Box::pin(rows.map(move |row| match row {
+                    Ok((mut key, mut row)) => {
+                        if encrypts_row_keys {
+                            key = encdec::decrypt_row_key(self.row_keys(), key)
+                                .map_err(GluesqlError::from)?;
+                        }
+
+                        self.open_row(&table_name, &columns, &key, &mut row)?;
+
+                        Ok((key, row))
+                    }
+                    Err(e) => Err(e),
+                })))
+            }
             Err(e) => Err(e),
         }
     }
 }
 
 #[async_trait(?Send)]
-impl<S: IndexMut, NonceSeq: NonceSequence> IndexMut for EncryptedStore<S, NonceSeq> {
+impl<S: IndexMut + Store + StoreMut + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>
+    IndexMut for EncryptedStore<S, NonceSeq>
+{
+    /// The inner store indexes what it holds, so indexes of encrypted tables must be over a
+    /// column. If the policy encrypts indexed columns deterministically, the existing rows are
+    /// encrypted again, a batch at a time, so the index can be used to look values up.
     async fn create_index(
         &mut self,
         table_name: &str,
         index_name: &str,
         column: &OrderByExpr,
     ) -> Result<()> {
+        self.clear_schema_cache();
+
+        let encrypted = self.encrypts_table(table_name);
+
+        if encrypted && !matches!(column.expr, Expr::Identifier(_)) {
+            return Err(Error::Unsupported("indexes over expressions of encrypted tables").into());
+        }
+
+        let inner_column = OrderByExpr {
+            expr: self.inner_expr(&column.expr),
+            asc: column.asc,
+        };
+
+        let inner_table_name = self.inner_table_name(table_name).into_owned();
+
         self.store
-            .create_index(table_name, index_name, column)
-            .await
+            .create_index(&inner_table_name, index_name, &inner_column)
+            .await?;
+
+        if encrypted
+            && self.policy.encrypts_indexed_deterministically()
+            && self.policy.table_mode(table_name) == EncryptionMode::Column
+        {
+            // the index is now part of the schema, so the column is encrypted deterministically.
+            // The index can't find the rows left as they were, so it's dropped if that fails
+            if let Err(error) = self.rewrite_rows(table_name).await {
+                self.clear_schema_cache();
+                self.store.drop_index(&inner_table_name, index_name).await?;
+
+                return Err(error);
+            }
+        }
+
+        Ok(())
     }
 
     async fn drop_index(&mut self, table_name: &str, index_name: &str) -> Result<()> {
-        self.store.drop_index(table_name, index_name).await
+        self.clear_schema_cache();
+
+        self.store
+            .drop_index(&self.inner_table_name(table_name), index_name)
+            .await
+    }
+}
+
+impl<S: Store + StoreMut + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>
+    EncryptedStore<S, NonceSeq>
+{
+    /// Writes the rows of a table again a batch at a time, e.g. so a column just indexed is
+    /// encrypted deterministically.
+    ///
+    /// Rows keep their keys, and so their place in the inner store, so each batch is taken past
+    /// the rows already written.
+    async fn rewrite_rows(&mut self, table_name: &str) -> Result<()> {
+        let mut written = 0;
+
+        loop {
+            let rows = Store::scan_data(self, table_name)
+                .await?
+                .skip(written)
+                .take(self.batch_size)
+                .try_collect::<Vec<_>>()
+                .await?;
+            let done = rows.len() < self.batch_size;
+
+            written += rows.len();
+
+            if !rows.is_empty() {
+                StoreMut::insert_data(self, table_name, rows).await?;
+            }
+
+            if done {
+                return Ok(());
+            }
+        }
     }
 }
 
 #[async_trait(?Send)]
-impl<S: Metadata, NonceSeq: NonceSequence> Metadata for EncryptedStore<S, NonceSeq> {
+impl<S: Metadata + Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync> Metadata
+    for EncryptedStore<S, NonceSeq>
+{
+    /// The storage API has no way to write metadata, so it can't be sealed: the inner store
+    /// keeps what it records about tables, like when they were created, in plaintext, and it's
+    /// passed on as it is, under the names of the tables. The store's own tables are left out.
     async fn scan_table_meta(&self) -> Result<MetaIter> {
-        self.store.scan_table_meta().await
+        let meta = self.store.scan_table_meta().await?;
+
+        let mut revealed = Vec::new();
+
+        for entry in meta {
+            let (inner_table_name, meta) = entry?;
+
+            if self.tables.contains(&inner_table_name) {
+                continue;
+            }
+
+            revealed.push(Ok((self.reveal_name(inner_table_name).await?, meta)));
+        }
+
+        Ok(Box::new(revealed.into_iter()))
     }
 }
 
 #[async_trait(?Send)]
-impl<S: Transaction, NonceSeq: NonceSequence> Transaction for EncryptedStore<S, NonceSeq> {
+impl<S: Transaction + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync> Transaction
+    for EncryptedStore<S, NonceSeq>
+{
     async fn begin(&mut self, autocommit: bool) -> Result<bool> {
         self.store.begin(autocommit).await
     }
@@ -412,30 +2605,79 @@ impl<S: Transaction, NonceSeq: NonceSequence> Transaction for EncryptedStore<S,
     }
 
     async fn rollback(&mut self) -> Result<()> {
+        // schema and function changes made in the transaction are undone along with the rest,
+        // and so may the state of the nonce sequence be
+        self.clear_schema_cache();
+        self.functions.as_mut().clear();
+        self.nonce_state_lost();
+
         self.store.rollback().await
     }
 }
 
 #[async_trait(?Send)]
-impl<S: CustomFunction, NonceSeq: NonceSequence> CustomFunction for EncryptedStore<S, NonceSeq> {
+impl<S: CustomFunction + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync> CustomFunction
+    for EncryptedStore<S, NonceSeq>
+{
     async fn fetch_function(&self, func_name: &str) -> Result<Option<&StructCustomFunction>> {
-        self.store.fetch_function(func_name).await
+        if let Some(func) = self.functions.get(func_name) {
+            return Ok(Some(func));
+        }
+
+        match self.store.fetch_function(func_name).await? {
+            Some(func) => {
+                let func = self.decrypt_function(func.clone())?;
+
+                Ok(Some(
+                    self.functions.insert(func_name.to_owned(), Box::new(func)),
+                ))
+            }
+            None => Ok(None),
+        }
     }
 
     async fn fetch_all_functions(&self) -> Result<Vec<&StructCustomFunction>> {
-        self.store.fetch_all_functions().await
+        let mut functions = Vec::new();
+
+        for func in self.store.fetch_all_functions().await? {
+            let func = match self.functions.get(&func.func_name) {
+                Some(func) => func,
+                None => self.functions.insert(
+                    func.func_name.clone(),
+                    Box::new(self.decrypt_function(func.clone())?),
+                ),
+            };
+
+            functions.push(func);
+        }
+
+        Ok(functions)
     }
 }
 
 #[async_trait(?Send)]
-impl<S: CustomFunctionMut, NonceSeq: NonceSequence> CustomFunctionMut
-    for EncryptedStore<S, NonceSeq>
+impl<
+        S: Store + StoreMut + CustomFunctionMut + MaybeSendSync,
+        NonceSeq: NonceSequence + MaybeSendSync,
+    > CustomFunctionMut for EncryptedStore<S, NonceSeq>
 {
     async fn insert_function(&mut self, func: StructCustomFunction) -> Result<()> {
-        self.store.insert_function(func).await
+        self.prepare_nonces().await?;
+
+        let encrypted = self.encrypt_function(func.clone())?;
+
+        self.store.insert_function(encrypted).await?;
+        self.functions
+            .as_mut()
+            .insert(func.func_name.clone(), Box::new(func));
+
+        Ok(())
     }
 
     async fn delete_function(&mut self, func_name: &str) -> Result<()> {
-        self.store.delete_function(func_name).await
+        self.store.delete_function(func_name).await?;
+        self.functions.as_mut().remove(func_name);
+
+        Ok(())
     }
 }
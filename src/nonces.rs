@@ -0,0 +1,668 @@
+use std::collections::HashMap;
+#[cfg(debug_assertions)]
+use std::collections::HashSet;
+use std::{
+    collections::VecDeque,
+    num::NonZeroU16,
+    sync::{MutexGuard, PoisonError},
+};
+
+use async_trait::async_trait;
+use gluesql_core::{
+    data::{Key, Value},
+    store::{DataRow, Store, StoreMut},
+};
+use ring::{
+    aead::{Nonce, NonceSequence, UnboundKey, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+
+use crate::{
+    create_meta_table, encdec, EncryptedStore, Error, InternalTables, MaybeSendSync,
+    DEFAULT_NAMESPACE,
+};
+
+/// Key of the metadata row holding the state of the nonce sequence. Writers with an id keep
+/// theirs under `Key::U16` of it instead.
+const NONCE_STATE_ROW: Key = Key::U8(3);
+
+/// Number of bits of a [`CounterNonce`] left to its counter when the writer id takes the rest.
+const WRITER_COUNTER_BITS: u32 = 48;
+
+/// Number of nonces a [`CounterNonce`] reserves at a time. A new reservation is saved once half
+/// of the current one is used up.
+const RESERVATION: u64 = 1 << 20;
+
+/// Number of nonces [`SourcedNonces`] fetch ahead from their source. More are fetched once half
+/// of them are used up.
+const PREFETCHED_NONCES: usize = 256;
+
+/// Number of bytes of the nonces of a [`NonceBatch::Counter`] the sequence fills in, leaving the
+/// rest to the batch's counter.
+pub const BATCH_PREFIX_LEN: usize = NONCE_LEN - 4;
+
+/// Number of recent nonces debug builds remember, to catch a nonce sequence repeating one.
+#[cfg(debug_assertions)]
+const CHECKED_NONCES: usize = 1 << 16;
+
+/// The nonce sequence of a store, checked in debug builds for repeating any of its recent
+/// nonces, which would break the encryption of every value sealed with them. Release builds
+/// pass the nonces through.
+pub struct CheckedNonces<NonceSeq> {
+    inner: NonceSeq,
+    #[cfg(debug_assertions)]
+    issued: HashSet<[u8; NONCE_LEN]>,
+    /// The nonces in `issued`, oldest first.
+    #[cfg(debug_assertions)]
+    order: VecDeque<[u8; NONCE_LEN]>,
+}
+
+impl<NonceSeq> CheckedNonces<NonceSeq> {
+    pub(crate) fn new(inner: NonceSeq) -> Self {
+        Self {
+            inner,
+            #[cfg(debug_assertions)]
+            issued: HashSet::new(),
+            #[cfg(debug_assertions)]
+            order: VecDeque::new(),
+        }
+    }
+
+    pub(crate) const fn inner_mut(&mut self) -> &mut NonceSeq {
+        &mut self.inner
+    }
+
+    pub(crate) fn into_inner(self) -> NonceSeq {
+        self.inner
+    }
+}
+
+impl<NonceSeq: NonceSequence> CheckedNonces<NonceSeq> {
+    /// Draws the nonces of a batch with `batch`, checked like the nonces drawn one at a time by
+    /// the first nonce of the batch.
+    pub(crate) fn advance_batch(
+        &mut self,
+        batch: fn(&mut NonceSeq) -> Result<NonceBatch, ring::error::Unspecified>,
+    ) -> Result<NonceBatch, ring::error::Unspecified> {
+        let batch = batch(&mut self.inner)?;
+
+        #[cfg(debug_assertions)]
+        self.check(match &batch {
+            NonceBatch::Derived(base) => *base,
+            NonceBatch::Counter(prefix) => {
+                let mut nonce = [0; NONCE_LEN];
+                nonce[..BATCH_PREFIX_LEN].copy_from_slice(prefix);
+                nonce
+            }
+        });
+
+        Ok(batch)
+    }
+
+    #[cfg(debug_assertions)]
+    fn check(&mut self, bytes: [u8; NONCE_LEN]) {
+        assert!(
+            self.issued.insert(bytes),
+            "nonce sequence repeated the nonce {bytes:02x?}"
+        );
+        self.order.push_back(bytes);
+
+        if self.order.len() > CHECKED_NONCES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.issued.remove(&oldest);
+            }
+        }
+    }
+}
+
+impl<NonceSeq: NonceSequence> NonceSequence for CheckedNonces<NonceSeq> {
+    fn advance(&mut self) -> Result<Nonce, ring::error::Unspecified> {
+        let nonce = self.inner.advance()?;
+
+        #[cfg(debug_assertions)]
+        self.check(*nonce.as_ref());
+
+        Ok(nonce)
+    }
+}
+
+/// The nonces of a batch of values, drawn from a nonce sequence at once so sealing a wide batch
+/// doesn't advance it for every value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonceBatch {
+    /// A nonce the nonces of the values are derived from, by hashing it along with a counter.
+    /// They're as unlikely to repeat as random nonces are, whatever the sequence's nonces look
+    /// like.
+    Derived([u8; NONCE_LEN]),
+    /// The first [`BATCH_PREFIX_LEN`] bytes of the nonces of the values, which end with a 32-bit
+    /// counter. No other nonce of the sequence may start with them, e.g. because the sequence
+    /// counts in those bytes and leaves the others zero.
+    Counter([u8; BATCH_PREFIX_LEN]),
+}
+
+/// A nonce sequence with state that must survive restarts, e.g. a counter that must never go
+/// back.
+///
+/// Stores opened with [`EncryptedStore::new_with_persistent_nonces`] load the state when
+/// they're opened, and save it in their metadata before writes and when they're closed with
+/// [`EncryptedStore::close`]. In a transaction, the state is saved along with the data and
+/// committed with it. The state is stored unencrypted, so it mustn't hold secrets.
+pub trait PersistentNonceSequence: NonceSequence {
+    /// Restores the state saved last, or sets up a new sequence if none was saved yet.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the state is malformed.
+    fn load_state(&mut self, state: Option<&[u8]>) -> Result<(), Error>;
+
+    /// Returns the state to save before the sequence advances any further, or `None` if the
+    /// state saved last still holds.
+    fn save_state(&mut self) -> Option<Vec<u8>>;
+
+    /// Called once the state returned by [`PersistentNonceSequence::save_state`] is saved, or
+    /// with `saved` unset if it didn't make it, e.g. because its transaction was rolled back,
+    /// and needs saving again.
+    fn state_saved(&mut self, saved: bool) {
+        let _ = saved;
+    }
+
+    /// Returns the id of the writer using the sequence, if several of them share the inner
+    /// store. Each writer saves its state apart from the others'.
+    fn writer_id(&self) -> Option<NonZeroU16> {
+        None
+    }
+
+    /// Returns whether the sequence is close enough to running out that writes must stop, e.g.
+    /// so one isn't left halfway when the last nonce is used. The store then refuses writes with
+    /// [`Error::NoncesExhausted`], but can still be read.
+    fn exhausted(&self) -> bool {
+        false
+    }
+
+    /// Draws the nonces of a batch of values at once.
+    ///
+    /// Defaults to deriving them from a single nonce drawn with [`NonceSequence::advance`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the sequence ran out of nonces.
+    fn advance_batch(&mut self) -> Result<NonceBatch, ring::error::Unspecified> {
+        Ok(NonceBatch::Derived(*self.advance()?.as_ref()))
+    }
+}
+
+/// The methods of [`PersistentNonceSequence`], kept by the store so code generic over any nonce
+/// sequence can call them.
+pub struct NonceStateHooks<NonceSeq> {
+    save: fn(&mut NonceSeq) -> Option<Vec<u8>>,
+    saved: fn(&mut NonceSeq, bool),
+    exhausted: fn(&NonceSeq) -> bool,
+    batch: fn(&mut NonceSeq) -> Result<NonceBatch, ring::error::Unspecified>,
+    /// Key of the metadata row the state is saved in.
+    row: Key,
+}
+
+impl<NonceSeq: PersistentNonceSequence> NonceStateHooks<NonceSeq> {
+    fn new(row: Key) -> Self {
+        Self {
+            save: NonceSeq::save_state,
+            saved: NonceSeq::state_saved,
+            exhausted: NonceSeq::exhausted,
+            batch: NonceSeq::advance_batch,
+            row,
+        }
+    }
+}
+
+/// [`SourcedNonces::refill`], kept by the store like [`NonceStateHooks`].
+pub type RefillNonces<NonceSeq> = fn(&mut NonceSeq) -> RefillFuture<'_>;
+
+#[cfg(not(feature = "send"))]
+pub type RefillFuture<'a> = futures::future::LocalBoxFuture<'a, Result<(), Error>>;
+#[cfg(feature = "send")]
+pub type RefillFuture<'a> = futures::future::BoxFuture<'a, Result<(), Error>>;
+
+/// A source of nonces that may have to wait for them, e.g. a remote service or a hardware random
+/// number generator.
+///
+/// Stores opened with [`EncryptedStore::new_with_nonce_source`] fetch nonces ahead, a few hundred
+/// at a time, when they're opened and before writes and bulk rewrites, so sealing never waits on
+/// the source.
+#[cfg_attr(not(feature = "send"), async_trait(?Send))]
+#[cfg_attr(feature = "send", async_trait)]
+pub trait NonceSource: MaybeSendSync {
+    /// Fetches `count` nonces, none of which may ever have been fetched before under the same
+    /// key.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the nonces can't be fetched.
+    async fn fetch_nonces(&mut self, count: usize) -> Result<Vec<[u8; NONCE_LEN]>, Error>;
+}
+
+/// A [`NonceSource`] drawing from a [`NonceSequence`], e.g. to try out a store opened with
+/// [`EncryptedStore::new_with_nonce_source`] without the source it's meant for.
+#[derive(Debug, Clone, Default)]
+pub struct SyncNonceSource<NonceSeq>(pub NonceSeq);
+
+#[cfg_attr(not(feature = "send"), async_trait(?Send))]
+#[cfg_attr(feature = "send", async_trait)]
+impl<NonceSeq: NonceSequence + MaybeSendSync> NonceSource for SyncNonceSource<NonceSeq> {
+    async fn fetch_nonces(&mut self, count: usize) -> Result<Vec<[u8; NONCE_LEN]>, Error> {
+        (0..count)
+            .map(|_| Ok(*self.0.advance()?.as_ref()))
+            .collect()
+    }
+}
+
+/// The nonce sequence of a store opened with [`EncryptedStore::new_with_nonce_source`], handing
+/// out the nonces fetched ahead from its source.
+///
+/// Sealing fails with [`Error::EncryptionError`] if they run out before more are fetched, which
+/// only a single write or batch sealing hundreds of batches of values may do.
+pub struct SourcedNonces<Src> {
+    source: Src,
+    fetched: VecDeque<[u8; NONCE_LEN]>,
+}
+
+impl<Src: NonceSource> SourcedNonces<Src> {
+    /// Fetches more nonces once half of them are used up.
+    async fn refill(&mut self) -> Result<(), Error> {
+        if self.fetched.len() >= PREFETCHED_NONCES / 2 {
+            return Ok(());
+        }
+
+        let nonces = self
+            .source
+            .fetch_nonces(PREFETCHED_NONCES - self.fetched.len())
+            .await?;
+        self.fetched.extend(nonces);
+
+        Ok(())
+    }
+}
+
+impl<Src> NonceSequence for SourcedNonces<Src> {
+    fn advance(&mut self) -> Result<Nonce, ring::error::Unspecified> {
+        self.fetched
+            .pop_front()
+            .map(Nonce::assume_unique_for_key)
+            .ok_or(ring::error::Unspecified)
+    }
+}
+
+fn refill_nonces<Src: NonceSource>(nonces: &mut SourcedNonces<Src>) -> RefillFuture<'_> {
+    Box::pin(nonces.refill())
+}
+
+/// Random nonces from the system's secure random number generator, which [`EncryptedStore::new`]
+/// uses.
+///
+/// With 96-bit nonces, a key should seal no more than about 2^32 values before it's changed,
+/// for the odds of two of them sharing a nonce to stay negligible.
+#[derive(Debug, Clone)]
+pub struct RandomNonce {
+    rng: SystemRandom,
+}
+
+impl RandomNonce {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            rng: SystemRandom::new(),
+        }
+    }
+}
+
+impl Default for RandomNonce {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NonceSequence for RandomNonce {
+    fn advance(&mut self) -> Result<Nonce, ring::error::Unspecified> {
+        let mut nonce = [0; NONCE_LEN];
+        self.rng.fill(&mut nonce)?;
+
+        Ok(Nonce::assume_unique_for_key(nonce))
+    }
+}
+
+/// Nonces counting up from a high-water mark saved in the metadata of the store, so no nonce is
+/// ever used twice under a key, as random nonces may be after enough writes.
+///
+/// The counter takes the first 8 bytes of the nonce and leaves the last 4 to the nonces the
+/// store derives from it for a batch. Counters are reserved ahead of use and the reservation is
+/// saved before any of them is used, so a crash skips some counters rather than reusing them.
+/// A single `EncryptedStore` may write to the inner store at a time, unless each writer is
+/// given its own id with [`CounterNonce::with_writer_id`].
+///
+/// Open a store using it with [`EncryptedStore::new_with_counter_nonce`].
+#[derive(Debug, Default)]
+pub struct CounterNonce {
+    writer_id: Option<NonZeroU16>,
+    next: u64,
+    /// The first counter that isn't reserved yet, which `advance` stops at.
+    reserved: u64,
+    /// The reservation being saved.
+    pending: Option<u64>,
+}
+
+impl CounterNonce {
+    /// Returns a counter for one of several processes writing to the same inner store, which
+    /// must each have an id of their own, for as long as they use the same key.
+    ///
+    /// The id takes the first 2 bytes of the nonce, leaving 48 bits to the counter, and each
+    /// writer saves its high-water mark apart from the others'. Ids start at 1, so writers never
+    /// share nonces with a store written without one.
+    ///
+    /// Open a store using it with [`EncryptedStore::new_with_persistent_nonces`].
+    #[must_use]
+    pub fn with_writer_id(writer_id: NonZeroU16) -> Self {
+        Self {
+            writer_id: Some(writer_id),
+            ..Self::default()
+        }
+    }
+
+    /// Returns the next counter, i.e. how many nonces were used so far.
+    #[must_use]
+    pub const fn position(&self) -> u64 {
+        self.next
+    }
+
+    /// Returns the first counter out of range.
+    const fn limit(&self) -> u64 {
+        match self.writer_id {
+            Some(_) => 1 << WRITER_COUNTER_BITS,
+            None => u64::MAX,
+        }
+    }
+}
+
+impl NonceSequence for CounterNonce {
+    fn advance(&mut self) -> Result<Nonce, ring::error::Unspecified> {
+        if self.next >= self.reserved {
+            return Err(ring::error::Unspecified);
+        }
+
+        let mut nonce = [0; NONCE_LEN];
+        nonce[..8].copy_from_slice(&self.next.to_be_bytes());
+
+        if let Some(writer_id) = self.writer_id {
+            if self.next >= self.limit() {
+                return Err(ring::error::Unspecified);
+            }
+
+            nonce[..2].copy_from_slice(&writer_id.get().to_be_bytes());
+        }
+
+        self.next += 1;
+
+        Ok(Nonce::assume_unique_for_key(nonce))
+    }
+}
+
+impl PersistentNonceSequence for CounterNonce {
+    fn load_state(&mut self, state: Option<&[u8]>) -> Result<(), Error> {
+        self.next = match state {
+            Some(state) => u64::from_be_bytes(state.try_into().map_err(|_| Error::InvalidValue)?),
+            None => 0,
+        };
+        // nothing is reserved until a reservation is saved, which the store does before it
+        // seals anything
+        self.reserved = self.next;
+        self.pending = None;
+
+        Ok(())
+    }
+
+    fn save_state(&mut self) -> Option<Vec<u8>> {
+        if self.reserved - self.next >= RESERVATION / 2 {
+            return None;
+        }
+
+        let mark = self.next.saturating_add(RESERVATION);
+        self.pending = Some(mark);
+
+        Some(mark.to_be_bytes().to_vec())
+    }
+
+    fn state_saved(&mut self, saved: bool) {
+        match (saved, self.pending.take()) {
+            (true, Some(mark)) => self.reserved = mark,
+            (true, None) => {}
+            // whatever was reserved may be gone, so nothing is until a new reservation is saved
+            (false, _) => self.reserved = self.next,
+        }
+    }
+
+    fn writer_id(&self) -> Option<NonZeroU16> {
+        self.writer_id
+    }
+
+    /// Stops writes once less than a reservation is left, which no single write comes close to
+    /// using up.
+    fn exhausted(&self) -> bool {
+        self.limit().saturating_sub(self.next) <= RESERVATION
+    }
+
+    /// Hands the last 4 bytes of the nonce of a counter to the batch, which the counter always
+    /// leaves zero.
+    fn advance_batch(&mut self) -> Result<NonceBatch, ring::error::Unspecified> {
+        let nonce = self.advance()?;
+        let (prefix, rest) = nonce.as_ref().split_at(BATCH_PREFIX_LEN);
+        debug_assert_eq!(rest, [0; NONCE_LEN - BATCH_PREFIX_LEN]);
+
+        Ok(NonceBatch::Counter(
+            prefix
+                .try_into()
+                .expect("the prefix is split at its length"),
+        ))
+    }
+}
+
+/// Writes the state of a persistent nonce sequence to the metadata of a store.
+async fn write_nonce_state<S: StoreMut>(
+    store: &mut S,
+    tables: &InternalTables,
+    state_row: Key,
+    state: Vec<u8>,
+) -> Result<(), Error> {
+    store
+        .insert_data(
+            &tables.meta,
+            vec![(
+                state_row,
+                DataRow::Map(HashMap::from([("state".to_owned(), Value::Bytea(state))])),
+            )],
+        )
+        .await?;
+
+    Ok(())
+}
+
+impl<S: Store + StoreMut + MaybeSendSync, NonceSeq: PersistentNonceSequence + MaybeSendSync>
+    EncryptedStore<S, NonceSeq>
+{
+    /// Like [`EncryptedStore::new`], but loads the state of the nonce sequence from the metadata
+    /// of the store, and saves it from then on.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error like [`EncryptedStore::new`], or if the state can't be loaded or saved.
+    pub async fn new_with_persistent_nonces(
+        store: S,
+        key: UnboundKey,
+        nonce_sequence: NonceSeq,
+    ) -> Result<Self, Error> {
+        Self::new_with_persistent_nonces_in_namespace(store, key, nonce_sequence, DEFAULT_NAMESPACE)
+            .await
+    }
+
+    /// Like [`EncryptedStore::new_with_persistent_nonces`], but keeps the store's own tables,
+    /// the saved state included, under names starting with `namespace`, as with
+    /// [`EncryptedStore::new_in_namespace`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error like [`EncryptedStore::new_with_persistent_nonces`].
+    pub async fn new_with_persistent_nonces_in_namespace(
+        mut store: S,
+        key: UnboundKey,
+        mut nonce_sequence: NonceSeq,
+        namespace: &str,
+    ) -> Result<Self, Error> {
+        let tables = InternalTables::new(namespace);
+        let state_row = nonce_sequence
+            .writer_id()
+            .map_or(NONCE_STATE_ROW, |writer_id| Key::U16(writer_id.get()));
+        let state = match store.fetch_data(&tables.meta, &state_row).await? {
+            Some(DataRow::Map(mut map)) => match map.remove("state") {
+                Some(Value::Bytea(state)) => Some(state),
+                _ => return Err(Error::InvalidValue),
+            },
+            Some(DataRow::Vec(_)) => return Err(Error::InvalidValue),
+            None => None,
+        };
+
+        nonce_sequence.load_state(state.as_deref())?;
+
+        // the state is saved before the first nonce is drawn, which for a new store seals its
+        // key check, so a crash never leaves nonces in use that the saved state doesn't cover
+        if let Some(state) = nonce_sequence.save_state() {
+            let saved = async {
+                create_meta_table(&mut store, &tables).await?;
+                write_nonce_state(&mut store, &tables, state_row.clone(), state).await
+            }
+            .await;
+
+            nonce_sequence.state_saved(saved.is_ok());
+            saved?;
+        }
+
+        let mut store = Self::new_in_namespace(store, key, nonce_sequence, namespace).await?;
+
+        store.nonce_state = Some(NonceStateHooks::new(state_row));
+
+        Ok(store)
+    }
+}
+
+impl<S: Store + StoreMut + MaybeSendSync, Src: NonceSource> EncryptedStore<S, SourcedNonces<Src>> {
+    /// Like [`EncryptedStore::new`], but with nonces fetched from an asynchronous source.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error like [`EncryptedStore::new`], or if the source fails to fetch nonces.
+    pub async fn new_with_nonce_source(
+        store: S,
+        key: UnboundKey,
+        source: Src,
+    ) -> Result<Self, Error> {
+        let mut nonces = SourcedNonces {
+            source,
+            fetched: VecDeque::with_capacity(PREFETCHED_NONCES),
+        };
+
+        // opening a new store seals its key check
+        nonces.refill().await?;
+
+        let mut store = Self::new_with_nonce_sequence(store, key, nonces).await?;
+        store.nonce_refill = Some(refill_nonces);
+
+        Ok(store)
+    }
+}
+
+impl<S: Store + StoreMut + MaybeSendSync> EncryptedStore<S, CounterNonce> {
+    /// Like [`EncryptedStore::new`], but with nonces from a [`CounterNonce`] picking up where
+    /// the last store opened this way left off.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error like [`EncryptedStore::new_with_persistent_nonces`].
+    pub async fn new_with_counter_nonce(store: S, key: UnboundKey) -> Result<Self, Error> {
+        Self::new_with_persistent_nonces(store, key, CounterNonce::default()).await
+    }
+}
+
+impl<S: Store + StoreMut + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>
+    EncryptedStore<S, NonceSeq>
+{
+    /// Fetches nonces ahead from the nonce source and saves the state of the nonce sequence, as
+    /// needed. Writes and bulk rewrites call it before sealing anything.
+    pub(crate) async fn prepare_nonces(&mut self) -> Result<(), Error> {
+        if let Some(hooks) = &self.nonce_state {
+            if (hooks.exhausted)(self.nonces().inner_mut()) {
+                return Err(Error::NoncesExhausted);
+            }
+        }
+
+        if let Some(refill) = self.nonce_refill {
+            let nonces = self
+                .nonce_sequence
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner);
+
+            refill(nonces.inner_mut()).await?;
+        }
+
+        self.save_nonce_state().await
+    }
+
+    /// Saves the state of the nonce sequence, if it's persistent and has changed.
+    async fn save_nonce_state(&mut self) -> Result<(), Error> {
+        let Some(hooks) = &self.nonce_state else {
+            return Ok(());
+        };
+        let Some(state) = (hooks.save)(self.nonces().inner_mut()) else {
+            return Ok(());
+        };
+        let (saved, row) = (hooks.saved, hooks.row.clone());
+
+        let written = write_nonce_state(&mut self.store, &self.tables, row, state).await;
+
+        saved(self.nonces().inner_mut(), written.is_ok());
+
+        written
+    }
+
+    /// Saves the state of the nonce sequence one last time, and returns the inner store.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the state can't be saved.
+    pub async fn close(mut self) -> Result<S, Error> {
+        self.save_nonce_state().await?;
+
+        Ok(self.store)
+    }
+}
+
+impl<S, NonceSeq: NonceSequence> EncryptedStore<S, NonceSeq> {
+    pub(crate) fn nonces(&self) -> MutexGuard<'_, CheckedNonces<NonceSeq>> {
+        self.nonce_sequence
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Draws the nonces of a batch of values from the nonce sequence, advancing it once.
+    pub(crate) fn batch_nonces(&self) -> Result<encdec::BatchNonces, Error> {
+        let batch = match &self.nonce_state {
+            Some(hooks) => self.nonces().advance_batch(hooks.batch)?,
+            None => NonceBatch::Derived(*self.nonces().advance()?.as_ref()),
+        };
+
+        Ok(encdec::BatchNonces::new(batch))
+    }
+
+    /// Tells the nonce sequence the state it saved last was lost, e.g. because its transaction
+    /// was rolled back.
+    pub(crate) fn nonce_state_lost(&self) {
+        if let Some(hooks) = &self.nonce_state {
+            (hooks.saved)(self.nonces().inner_mut(), false);
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use std::sync::Arc;
+
+/// Receives the events of an `EncryptedStore`, e.g. for auditing, quotas or instrumentation.
+/// Set one with [`EncryptedStore::with_observer`](crate::EncryptedStore::with_observer).
+///
+/// Every method does nothing by default. They're called on the thread doing the work, before
+/// it carries on, so they should return quickly. They're only told about tables, counts and key
+/// ids, never about values.
+pub trait EncryptionObserver: Send + Sync {
+    /// Rows were encrypted to be written to a table, by an insert, update or append.
+    fn rows_encrypted(&self, _table: &str, _rows: usize) {}
+
+    /// A row read from a table was decrypted.
+    fn row_decrypted(&self, _table: &str) {}
+
+    /// A key change finished, and every row is now encrypted with the key `new_key_id`.
+    fn key_rotated(&self, _old_key_id: &str, _new_key_id: &str) {}
+
+    /// The key check was written, as the store was opened on a store without one.
+    fn sentinel_written(&self) {}
+}
+
+/// Lets the application keep a handle on its observer, e.g. to read what it collected.
+impl<T: EncryptionObserver + ?Sized> EncryptionObserver for Arc<T> {
+    fn rows_encrypted(&self, table: &str, rows: usize) {
+        (**self).rows_encrypted(table, rows);
+    }
+
+    fn row_decrypted(&self, table: &str) {
+        (**self).row_decrypted(table);
+    }
+
+    fn key_rotated(&self, old_key_id: &str, new_key_id: &str) {
+        (**self).key_rotated(old_key_id, new_key_id);
+    }
+
+    fn sentinel_written(&self) {
+        (**self).sentinel_written();
+    }
+}
@@ -0,0 +1,37 @@
+//! Encrypts the rows of large batches on rayon's thread pool with the `rayon` feature, and one
+//! after the other without it.
+
+use crate::{encdec::BatchNonces, Error};
+
+/// Rows encrypted by a task, so each one is worth handing to another thread.
+#[cfg(feature = "rayon")]
+const CHUNK_SIZE: usize = 64;
+
+/// Calls `f` on every row of a batch, with nonces drawn from `nonces`.
+///
+/// With the `rayon` feature, batches of more than a chunk are split into chunks sealed in
+/// parallel. Each chunk gets its own range of the counters of `nonces`, fixed before any of
+/// them starts, so nonces never repeat whichever thread seals a chunk.
+pub fn for_each_row<T: Send>(
+    rows: &mut [T],
+    mut nonces: BatchNonces,
+    f: impl Fn(&mut BatchNonces, &mut T) -> Result<(), Error> + Sync,
+) -> Result<(), Error> {
+    #[cfg(feature = "rayon")]
+    if rows.len() > CHUNK_SIZE {
+        use rayon::prelude::*;
+
+        let nonces = nonces
+            .split(rows.len().div_ceil(CHUNK_SIZE))
+            .collect::<Vec<_>>();
+
+        return rows
+            .par_chunks_mut(CHUNK_SIZE)
+            .zip(nonces)
+            .try_for_each(|(chunk, mut nonces)| {
+                chunk.iter_mut().try_for_each(|row| f(&mut nonces, row))
+            });
+    }
+
+    rows.iter_mut().try_for_each(|row| f(&mut nonces, row))
+}
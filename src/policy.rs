@@ -0,0 +1,543 @@
+use std::collections::{HashMap, HashSet};
+
+use gluesql_core::{ast::DataType, data::Value};
+use serde::{Deserialize, Serialize};
+
+use crate::{Algorithm, Error};
+
+/// Selects which tables an `EncryptedStore` encrypts.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TableFilter {
+    /// Encrypt every table.
+    #[default]
+    All,
+    /// Only encrypt the listed tables.
+    Allow(HashSet<String>),
+    /// Encrypt every table except the listed ones.
+    Deny(HashSet<String>),
+}
+
+impl TableFilter {
+    /// Returns whether the filter selects the given table.
+    #[must_use]
+    pub fn contains(&self, table_name: &str) -> bool {
+        match self {
+            Self::All => true,
+            Self::Allow(tables) => tables.contains(table_name),
+            Self::Deny(tables) => !tables.contains(table_name),
+        }
+    }
+}
+
+/// Selects which values of an encrypted table are encrypted, by their type.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TypeFilter {
+    /// Encrypt values of every type.
+    #[default]
+    All,
+    /// Only encrypt values of the listed types.
+    ///
+    /// `BYTEA` values that could be mistaken for a ciphertext are encrypted regardless, so
+    /// they're read back as they were written. `NULL` values are left as-is.
+    Only(Vec<DataType>),
+}
+
+impl TypeFilter {
+    /// Returns whether the filter selects the given value.
+    #[must_use]
+    pub fn contains(&self, value: &Value) -> bool {
+        match self {
+            Self::All => true,
+            Self::Only(types) => value
+                .get_type()
+                .is_some_and(|data_type| types.contains(&data_type)),
+        }
+    }
+}
+
+/// How the rows of an encrypted table are encrypted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncryptionMode {
+    /// Encrypt every value on its own, each carrying its own nonce and tag.
+    #[default]
+    Column,
+    /// Encrypt the whole row as a single value, trading column-level policies for a much
+    /// smaller per-row overhead.
+    ///
+    /// Type filters, tokenized columns and deterministic encryption don't apply to tables in row
+    /// mode, and their columns can't be dropped.
+    Row,
+}
+
+/// Whether encrypting a column hides which of its values are `NULL`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Nulls {
+    /// Encrypt `NULL` values like any other, so they can't be told apart from other values.
+    Hide,
+    /// Leave `NULL` values as-is, saving the space of encrypting them.
+    Expose,
+}
+
+/// Decides what data is encrypted by an `EncryptedStore`.
+///
+/// Tables that aren't encrypted are still routed through the `EncryptedStore`,
+/// but their rows are passed to the inner store as-is.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct EncryptionPolicy {
+    tables: TableFilter,
+    types: TypeFilter,
+    table_types: HashMap<String, TypeFilter>,
+    column_nulls: HashMap<String, HashMap<String, Nulls>>,
+    mode: EncryptionMode,
+    table_modes: HashMap<String, EncryptionMode>,
+    table_algorithms: HashMap<String, Algorithm>,
+    auto_algorithm: bool,
+    tokenized: HashMap<String, HashSet<String>>,
+    encrypt_map_keys: bool,
+    encrypt_row_keys: bool,
+    deterministic_unique: bool,
+    deterministic_indexed: bool,
+    pseudonymize_names: bool,
+    passthrough: bool,
+}
+
+impl EncryptionPolicy {
+    /// Creates a policy that encrypts everything.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only encrypt the given tables.
+    #[must_use]
+    pub fn allow_tables<I: IntoIterator<Item = T>, T: Into<String>>(mut self, tables: I) -> Self {
+        self.tables = TableFilter::Allow(tables.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Encrypt every table except the given ones.
+    #[must_use]
+    pub fn deny_tables<I: IntoIterator<Item = T>, T: Into<String>>(mut self, tables: I) -> Self {
+        self.tables = TableFilter::Deny(tables.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Only encrypt values of the given types, in every table without its own type filter.
+    #[must_use]
+    pub fn encrypt_types<I: IntoIterator<Item = DataType>>(mut self, types: I) -> Self {
+        self.types = TypeFilter::Only(types.into_iter().collect());
+        self
+    }
+
+    /// Only encrypt values of the given types in the given table.
+    ///
+    /// Overrides the store-wide type filter for that table.
+    #[must_use]
+    pub fn encrypt_table_types<I: IntoIterator<Item = DataType>>(
+        mut self,
+        table_name: impl Into<String>,
+        types: I,
+    ) -> Self {
+        self.table_types.insert(
+            table_name.into(),
+            TypeFilter::Only(types.into_iter().collect()),
+        );
+        self
+    }
+
+    /// Sets whether `NULL` values of the given column are hidden.
+    ///
+    /// By default, `NULL` values are hidden when the type filter encrypts every type, and exposed
+    /// otherwise. Doesn't apply to tables in row mode, where the whole row is encrypted.
+    #[must_use]
+    pub fn with_column_nulls(
+        mut self,
+        table_name: impl Into<String>,
+        column_name: impl Into<String>,
+        nulls: Nulls,
+    ) -> Self {
+        self.column_nulls
+            .entry(table_name.into())
+            .or_default()
+            .insert(column_name.into(), nulls);
+        self
+    }
+
+    /// Sets how the rows of encrypted tables without their own mode are encrypted.
+    #[must_use]
+    pub const fn with_mode(mut self, mode: EncryptionMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets how the rows of the given table are encrypted.
+    ///
+    /// Overrides the store-wide mode for that table. Rows are decrypted according to the header of
+    /// their ciphertext, so existing rows stay readable after a table's mode changes.
+    #[must_use]
+    pub fn with_table_mode(mut self, table_name: impl Into<String>, mode: EncryptionMode) -> Self {
+        self.table_modes.insert(table_name.into(), mode);
+        self
+    }
+
+    /// Encrypts the rows of the given table with another algorithm than the one of the key, e.g.
+    /// one that's faster on the platforms the table is synced to.
+    ///
+    /// The key of the algorithm is derived from the key of the store. Every ciphertext records its
+    /// algorithm, so existing rows stay readable after a table's algorithm changes.
+    #[must_use]
+    pub fn with_table_algorithm(
+        mut self,
+        table_name: impl Into<String>,
+        algorithm: Algorithm,
+    ) -> Self {
+        self.table_algorithms.insert(table_name.into(), algorithm);
+        self
+    }
+
+    /// Encrypts the rows of tables without an algorithm of their own with the one that's fastest
+    /// on the CPU the store runs on, as picked by [`Algorithm::detect`].
+    ///
+    /// Stores synced between machines may then hold rows under different algorithms, which is
+    /// fine since every ciphertext records its algorithm.
+    #[must_use]
+    pub const fn with_auto_algorithm(mut self) -> Self {
+        self.auto_algorithm = true;
+        self
+    }
+
+    /// Replace the values of the given columns with random tokens, keeping the values themselves
+    /// encrypted in a separate vault table.
+    ///
+    /// Equal values share their token. Purging a token from the vault crypto-shreds its value in
+    /// every row holding it: those rows read `NULL` from then on. Tokens are only issued in
+    /// encrypted tables.
+    #[must_use]
+    pub fn tokenize_columns<I: IntoIterator<Item = T>, T: Into<String>>(
+        mut self,
+        table_name: impl Into<String>,
+        column_names: I,
+    ) -> Self {
+        self.tokenized
+            .entry(table_name.into())
+            .or_default()
+            .extend(column_names.into_iter().map(Into::into));
+        self
+    }
+
+    /// Deterministically encrypt the keys (field names) of schemaless rows in column mode.
+    ///
+    /// Rows in row mode never leak their keys, since the whole row is encrypted.
+    #[must_use]
+    pub const fn encrypt_map_keys(mut self) -> Self {
+        self.encrypt_map_keys = true;
+        self
+    }
+
+    /// Deterministically encrypt the keys rows of encrypted tables are stored under.
+    ///
+    /// Equal keys still give equal ciphertexts, so rows can be fetched and deleted by key, but the
+    /// inner store no longer orders rows by key: scans return them in an arbitrary order. Keys
+    /// generated by the inner store (e.g. for tables without a primary key) are left as-is.
+    #[must_use]
+    pub const fn encrypt_row_keys(mut self) -> Self {
+        self.encrypt_row_keys = true;
+        self
+    }
+
+    /// Deterministically encrypt the values of `UNIQUE` columns, so inner stores enforcing the
+    /// constraint on their own still can.
+    ///
+    /// Values of a unique column are all distinct, and the nonces are derived from the table and
+    /// column along with the value, so equal values of other columns give other ciphertexts.
+    #[must_use]
+    pub const fn encrypt_unique_deterministically(mut self) -> Self {
+        self.deterministic_unique = true;
+        self
+    }
+
+    /// Deterministically encrypt the values of indexed columns, so the inner store's indexes can
+    /// look values up by equality.
+    ///
+    /// Reveals which rows share a value in those columns. Other index scans of encrypted tables
+    /// decrypt and filter the whole table.
+    #[must_use]
+    pub const fn encrypt_indexed_deterministically(mut self) -> Self {
+        self.deterministic_indexed = true;
+        self
+    }
+
+    /// Store tables and columns under pseudonyms (an HMAC of their name) in the inner store, so
+    /// its files and keys don't reveal the schema.
+    ///
+    /// The real names are kept encrypted in a separate table. Columns with the same name share
+    /// their pseudonym across tables.
+    #[must_use]
+    pub const fn pseudonymize_names(mut self) -> Self {
+        self.pseudonymize_names = true;
+        self
+    }
+
+    /// Pass everything through to the inner store unencrypted, overriding the rest of the policy.
+    ///
+    /// Lets applications deploy the `EncryptedStore` first and turn encryption on later through
+    /// their config. Values written in passthrough mode stay readable once encryption is turned
+    /// on, except for the rare `BYTEA` values starting like a ciphertext.
+    ///
+    /// Settings of columns, like tokenized columns, are refused along with it, since they'd never
+    /// apply.
+    #[must_use]
+    pub const fn passthrough(mut self) -> Self {
+        self.passthrough = true;
+        self
+    }
+
+    /// Finds settings the rest of the policy keeps from ever applying, which are likely mistakes.
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        self.validate_passthrough()?;
+
+        let mut unencrypted_settings = self
+            .tokenized
+            .keys()
+            .map(|table| (table, "tokenized columns"))
+            .chain(self.table_modes.keys().map(|table| (table, "a mode")))
+            .chain(
+                self.table_algorithms
+                    .keys()
+                    .map(|table| (table, "an algorithm")),
+            )
+            .chain(
+                self.table_types
+                    .keys()
+                    .map(|table| (table, "a type filter")),
+            )
+            .chain(
+                self.column_nulls
+                    .keys()
+                    .map(|table| (table, "NULL settings")),
+            )
+            .filter(|(table, _)| !self.tables.contains(table))
+            .collect::<Vec<_>>();
+        unencrypted_settings.sort_unstable();
+
+        if let Some((table, setting)) = unencrypted_settings.first() {
+            return Err(Error::InvalidConfig(format!(
+                "table {table} is given {setting}, but the policy doesn't encrypt it; \
+                 encrypt the table or drop the setting"
+            )));
+        }
+
+        let deterministic_settings = [
+            (
+                self.deterministic_unique,
+                "deterministically encrypted unique columns",
+            ),
+            (
+                self.deterministic_indexed,
+                "deterministically encrypted indexed columns",
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(set, setting)| set.then_some(setting))
+        .collect::<Vec<_>>();
+
+        if let Some(setting) = deterministic_settings.first() {
+            if self.mode == EncryptionMode::Row
+                && !self
+                    .table_modes
+                    .values()
+                    .any(|&mode| mode == EncryptionMode::Column)
+            {
+                return Err(Error::InvalidConfig(format!(
+                    "every table is encrypted in row mode, where {setting} don't apply; \
+                     encrypt some in column mode or drop the setting"
+                )));
+            }
+        }
+
+        let mut row_mode_settings =
+            self.table_types
+                .keys()
+                .map(|table| (table, "type filters"))
+                .chain(
+                    self.column_nulls
+                        .keys()
+                        .map(|table| (table, "NULL settings")),
+                )
+                .chain(
+                    self.tokenized
+                        .keys()
+                        .map(|table| (table, "tokenized columns")),
+                )
+                .chain(deterministic_settings.iter().flat_map(|&setting| {
+                    self.table_modes.keys().map(move |table| (table, setting))
+                }))
+                .filter(|(table, _)| self.table_mode(table) == EncryptionMode::Row)
+                .collect::<Vec<_>>();
+        row_mode_settings.sort_unstable();
+
+        if let Some((table, setting)) = row_mode_settings.first() {
+            return Err(Error::InvalidConfig(format!(
+                "table {table} is encrypted in row mode, where {setting} don't apply; \
+                 encrypt it in column mode or drop the setting"
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Finds column settings of a policy that passes everything through, which never apply.
+    fn validate_passthrough(&self) -> Result<(), Error> {
+        if !self.passthrough {
+            return Ok(());
+        }
+
+        let mut column_settings = self
+            .tokenized
+            .keys()
+            .map(|table| (Some(table), "tokenized columns"))
+            .chain(
+                self.column_nulls
+                    .keys()
+                    .map(|table| (Some(table), "NULL settings")),
+            )
+            .chain(
+                self.deterministic_unique
+                    .then_some((None, "deterministically encrypted unique columns")),
+            )
+            .chain(
+                self.deterministic_indexed
+                    .then_some((None, "deterministically encrypted indexed columns")),
+            )
+            .collect::<Vec<_>>();
+        column_settings.sort_unstable();
+
+        if let Some((table, setting)) = column_settings.first() {
+            let of_table = table.map_or_else(String::new, |table| format!(" of table {table}"));
+            return Err(Error::InvalidConfig(format!(
+                "the policy passes everything through, where {setting}{of_table} don't \
+                 apply; drop passthrough or the setting"
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether everything is passed through unencrypted.
+    #[must_use]
+    pub const fn is_passthrough(&self) -> bool {
+        self.passthrough
+    }
+
+    /// Returns whether rows of the given table are encrypted.
+    #[must_use]
+    pub fn encrypts_table(&self, table_name: &str) -> bool {
+        !self.passthrough && self.tables.contains(table_name)
+    }
+
+    /// Returns the type filter applied to values of the given table.
+    #[must_use]
+    pub fn table_types(&self, table_name: &str) -> &TypeFilter {
+        self.table_types.get(table_name).unwrap_or(&self.types)
+    }
+
+    /// Returns whether `NULL` values of the given column are hidden, if it was set explicitly.
+    #[must_use]
+    pub fn column_nulls(&self, table_name: &str, column_name: &str) -> Option<Nulls> {
+        self.column_nulls
+            .get(table_name)
+            .and_then(|columns| columns.get(column_name))
+            .copied()
+    }
+
+    /// Returns whether some columns of the given table have their own `NULL` handling.
+    pub(crate) fn has_column_nulls(&self, table_name: &str) -> bool {
+        self.column_nulls.contains_key(table_name)
+    }
+
+    /// Returns whether values of the given column are replaced with tokens.
+    #[must_use]
+    pub fn tokenizes(&self, table_name: &str, column_name: &str) -> bool {
+        !self.passthrough
+            && self
+                .tokenized
+                .get(table_name)
+                .is_some_and(|columns| columns.contains(column_name))
+    }
+
+    /// Returns whether some columns of the given table are replaced with tokens.
+    pub(crate) fn has_tokenized_columns(&self, table_name: &str) -> bool {
+        !self.passthrough && self.tokenized.contains_key(table_name)
+    }
+
+    /// Returns whether a value of the given table and column is encrypted in column mode.
+    #[must_use]
+    pub fn encrypts_value(
+        &self,
+        table_name: &str,
+        column_name: Option<&str>,
+        value: &Value,
+    ) -> bool {
+        if value.is_null() {
+            if let Some(nulls) = column_name.and_then(|name| self.column_nulls(table_name, name)) {
+                return nulls == Nulls::Hide;
+            }
+        }
+
+        self.table_types(table_name).contains(value)
+    }
+
+    /// Returns whether the keys of schemaless rows are encrypted.
+    #[must_use]
+    pub const fn encrypts_map_keys(&self) -> bool {
+        self.encrypt_map_keys && !self.passthrough
+    }
+
+    /// Returns whether the keys of rows in encrypted tables are encrypted.
+    #[must_use]
+    pub const fn encrypts_row_keys(&self) -> bool {
+        self.encrypt_row_keys && !self.passthrough
+    }
+
+    /// Returns whether the values of `UNIQUE` columns are encrypted deterministically.
+    #[must_use]
+    pub const fn encrypts_unique_deterministically(&self) -> bool {
+        self.deterministic_unique && !self.passthrough
+    }
+
+    /// Returns whether the values of indexed columns are encrypted deterministically.
+    #[must_use]
+    pub const fn encrypts_indexed_deterministically(&self) -> bool {
+        self.deterministic_indexed && !self.passthrough
+    }
+
+    /// Returns whether tables and columns are stored under pseudonyms.
+    #[must_use]
+    pub const fn pseudonymizes_names(&self) -> bool {
+        self.pseudonymize_names && !self.passthrough
+    }
+
+    /// Returns the algorithm the rows of the given table are encrypted with, if it was set.
+    #[must_use]
+    pub fn table_algorithm(&self, table_name: &str) -> Option<Algorithm> {
+        self.table_algorithms
+            .get(table_name)
+            .copied()
+            .or_else(|| self.auto_algorithm.then(Algorithm::detect))
+    }
+
+    /// Returns how rows of the given table are encrypted.
+    #[must_use]
+    pub fn table_mode(&self, table_name: &str) -> EncryptionMode {
+        self.table_modes
+            .get(table_name)
+            .copied()
+            .unwrap_or(self.mode)
+    }
+}
@@ -0,0 +1,310 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::{MutexGuard, PoisonError},
+};
+
+use futures::TryStreamExt;
+use gluesql_core::{
+    ast::{ColumnDef, Expr, ForeignKey},
+    data::{Key, Schema, Value},
+    store::{DataRow, Store, StoreMut},
+};
+use ring::{
+    aead::{LessSafeKey, NonceSequence},
+    hmac,
+};
+
+use crate::{
+    encdec, scanned_str, Compression, EncryptedStore, Error, InternalTables, MaybeSendSync,
+};
+
+/// Prefix of table pseudonyms.
+const TABLE_PREFIX: &str = "t_";
+/// Prefix of column pseudonyms.
+const COLUMN_PREFIX: &str = "c_";
+
+/// Returns the pseudonym of a name: a truncated HMAC of it, safe to use as a file name.
+fn pseudonym(key: &hmac::Key, prefix: &str, name: &str) -> String {
+    let mac = hmac::sign(key, format!("{prefix}{name}").as_bytes());
+
+    format!("{prefix}{}", encdec::to_hex(&mac.as_ref()[..16]))
+}
+
+/// Reads the real names recorded in the names table, for stores that can't read it later on.
+/// Names that don't open with the key are left out, and looked up when they're needed.
+pub async fn read_names<S: Store>(
+    store: &S,
+    tables: &InternalTables,
+    key: &LessSafeKey,
+) -> Result<HashMap<String, String>, Error> {
+    if store.fetch_schema(&tables.names).await?.is_none() {
+        return Ok(HashMap::new());
+    }
+
+    let mut names = HashMap::new();
+    let mut rows = store.scan_data(&tables.names).await?;
+
+    while let Some((row_key, row)) = rows.try_next().await? {
+        let (Some(pseudonym), DataRow::Map(mut values)) = (scanned_str(row_key), row) else {
+            return Err(Error::InvalidValue);
+        };
+        let mut value = values.remove("name").ok_or(Error::InvalidValue)?;
+
+        if encdec::decrypt_value_in_place(key, &mut value, Compression::None).is_ok() {
+            if let Value::Str(name) = value {
+                names.insert(pseudonym, name);
+            }
+        }
+    }
+
+    Ok(names)
+}
+
+impl<S, NonceSeq: NonceSequence> EncryptedStore<S, NonceSeq> {
+    fn names(&self) -> MutexGuard<'_, HashMap<String, String>> {
+        self.names.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the real names behind the pseudonyms seen so far.
+    pub(crate) fn known_names(&self) -> HashMap<String, String> {
+        self.names().clone()
+    }
+
+    /// Returns the name the table is stored under in the inner store.
+    pub(crate) fn inner_table_name<'a>(&self, table_name: &'a str) -> Cow<'a, str> {
+        if self.policy.pseudonymizes_names() && !self.tables.contains(table_name) {
+            Cow::Owned(pseudonym(
+                &self.schema_keys.pseudonym_key,
+                TABLE_PREFIX,
+                table_name,
+            ))
+        } else {
+            Cow::Borrowed(table_name)
+        }
+    }
+
+    /// Returns the name the column is stored under in the inner store.
+    ///
+    /// Columns with the same name share their pseudonym across tables.
+    pub(crate) fn inner_column_name<'a>(&self, column_name: &'a str) -> Cow<'a, str> {
+        if self.policy.pseudonymizes_names() {
+            Cow::Owned(pseudonym(
+                &self.schema_keys.pseudonym_key,
+                COLUMN_PREFIX,
+                column_name,
+            ))
+        } else {
+            Cow::Borrowed(column_name)
+        }
+    }
+
+    /// Replaces a column identifier with its pseudonym. Other expressions are left as-is.
+    pub(crate) fn inner_expr(&self, expr: &Expr) -> Expr {
+        match expr {
+            Expr::Identifier(name) => Expr::Identifier(self.inner_column_name(name).into_owned()),
+            expr => expr.clone(),
+        }
+    }
+}
+
+impl<S: Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>
+    EncryptedStore<S, NonceSeq>
+{
+    /// Returns the real name behind a pseudonym, or the name itself if it isn't one.
+    pub(crate) async fn reveal_name(&self, name: String) -> Result<String, Error> {
+        if !self.policy.pseudonymizes_names()
+            || !(name.starts_with(TABLE_PREFIX) || name.starts_with(COLUMN_PREFIX))
+        {
+            return Ok(name);
+        }
+
+        if let Some(revealed) = self.names().get(&name) {
+            return Ok(revealed.clone());
+        }
+
+        match self
+            .store
+            .fetch_data(&self.tables.names, &Key::Str(name.clone()))
+            .await?
+        {
+            Some(DataRow::Map(mut values)) => {
+                let mut value = values.remove("name").ok_or(Error::InvalidValue)?;
+
+                self.decrypt_value(&mut value, Compression::None)?;
+
+                match value {
+                    Value::Str(revealed) => {
+                        self.names().insert(name, revealed.clone());
+
+                        Ok(revealed)
+                    }
+                    _ => Err(Error::InvalidValue),
+                }
+            }
+            Some(DataRow::Vec(_)) => Err(Error::InvalidValue),
+            None => Ok(name),
+        }
+    }
+
+    /// Translates a schema fetched from the inner store back to the real names.
+    pub(crate) async fn reveal_schema(&self, mut schema: Schema) -> Result<Schema, Error> {
+        if !self.policy.pseudonymizes_names() {
+            return Ok(schema);
+        }
+
+        schema.table_name = self.reveal_name(schema.table_name).await?;
+
+        for column_def in schema.column_defs.iter_mut().flatten() {
+            column_def.name = self
+                .reveal_name(std::mem::take(&mut column_def.name))
+                .await?;
+        }
+
+        for index in &mut schema.indexes {
+            if let Expr::Identifier(name) = &mut index.expr {
+                *name = self.reveal_name(std::mem::take(name)).await?;
+            }
+        }
+
+        for foreign_key in &mut schema.foreign_keys {
+            self.reveal_foreign_key(foreign_key).await?;
+        }
+
+        Ok(schema)
+    }
+
+    /// Translates a foreign key fetched from the inner store back to the real names.
+    pub(crate) async fn reveal_foreign_key(
+        &self,
+        foreign_key: &mut ForeignKey,
+    ) -> Result<(), Error> {
+        for name in [
+            &mut foreign_key.referencing_column_name,
+            &mut foreign_key.referenced_table_name,
+            &mut foreign_key.referenced_column_name,
+        ] {
+            *name = self.reveal_name(std::mem::take(name)).await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<S: Store + StoreMut + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>
+    EncryptedStore<S, NonceSeq>
+{
+    /// Records the real name behind a pseudonym in the names table.
+    async fn record_name(&mut self, pseudonym: &str, name: &str) -> Result<(), Error> {
+        if self.store.fetch_schema(&self.tables.names).await?.is_none() {
+            self.store
+                .insert_schema(&Schema {
+                    table_name: self.tables.names.clone(),
+                    column_defs: None,
+                    indexes: vec![],
+                    engine: None,
+                    foreign_keys: vec![],
+                    comment: Some(
+                        "Table to store the real names of pseudonymized tables and columns"
+                            .to_string(),
+                    ),
+                })
+                .await?;
+            self.clear_schema_cache();
+        }
+
+        let mut value = Value::Str(name.to_owned());
+
+        encdec::encrypt_value_in_place(
+            &self.key,
+            &mut *self.nonces(),
+            &mut value,
+            Compression::None,
+        )?;
+
+        self.store
+            .insert_data(
+                &self.tables.names,
+                vec![(
+                    Key::Str(pseudonym.to_owned()),
+                    DataRow::Map(HashMap::from([("name".to_owned(), value)])),
+                )],
+            )
+            .await?;
+        self.names().insert(pseudonym.to_owned(), name.to_owned());
+
+        Ok(())
+    }
+
+    /// Returns the pseudonym of a table, recording its real name.
+    pub(crate) async fn pseudonymize_table_name(
+        &mut self,
+        table_name: &str,
+    ) -> Result<String, Error> {
+        let pseudonym = self.inner_table_name(table_name).into_owned();
+
+        if pseudonym != table_name {
+            self.record_name(&pseudonym, table_name).await?;
+        }
+
+        Ok(pseudonym)
+    }
+
+    /// Returns the pseudonym of a column, recording its real name.
+    pub(crate) async fn pseudonymize_column_name(
+        &mut self,
+        column_name: &str,
+    ) -> Result<String, Error> {
+        let pseudonym = self.inner_column_name(column_name).into_owned();
+
+        if pseudonym != column_name {
+            self.record_name(&pseudonym, column_name).await?;
+        }
+
+        Ok(pseudonym)
+    }
+
+    /// Returns the column definition as stored in the inner store, recording its real name.
+    pub(crate) async fn pseudonymize_column_def(
+        &mut self,
+        column_def: &ColumnDef,
+    ) -> Result<ColumnDef, Error> {
+        Ok(ColumnDef {
+            name: self.pseudonymize_column_name(&column_def.name).await?,
+            ..column_def.clone()
+        })
+    }
+
+    /// Returns the schema as stored in the inner store, recording its real names.
+    pub(crate) async fn pseudonymize_schema(&mut self, schema: &Schema) -> Result<Schema, Error> {
+        if !self.policy.pseudonymizes_names() || self.tables.contains(&schema.table_name) {
+            return Ok(schema.clone());
+        }
+
+        let mut schema = schema.clone();
+
+        schema.table_name = self.pseudonymize_table_name(&schema.table_name).await?;
+
+        for column_def in schema.column_defs.iter_mut().flatten() {
+            column_def.name = self.pseudonymize_column_name(&column_def.name).await?;
+        }
+
+        for index in &mut schema.indexes {
+            index.expr = self.inner_expr(&index.expr);
+        }
+
+        for foreign_key in &mut schema.foreign_keys {
+            foreign_key.referencing_column_name = self
+                .pseudonymize_column_name(&foreign_key.referencing_column_name)
+                .await?;
+            foreign_key.referenced_table_name = self
+                .pseudonymize_table_name(&foreign_key.referenced_table_name)
+                .await?;
+            foreign_key.referenced_column_name = self
+                .pseudonymize_column_name(&foreign_key.referenced_column_name)
+                .await?;
+        }
+
+        Ok(schema)
+    }
+}
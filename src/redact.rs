@@ -0,0 +1,125 @@
+use std::fmt::{self, Debug, Formatter};
+
+use gluesql_core::data::Key;
+
+use crate::Error;
+
+/// Renders a row key by its type, and size for strings and bytes, rather than its value, which
+/// is data of the table like any other.
+pub struct RedactedKey<'a>(pub(crate) &'a Key);
+
+impl Debug for RedactedKey<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Key::Str(key) => write!(f, "Str(<{} bytes>)", key.len()),
+            Key::Bytea(key) => write!(f, "Bytea(<{} bytes>)", key.len()),
+            key => {
+                // the value is only formatted to read the name of its variant off it
+                let rendered = format!("{key:?}");
+                let variant = rendered.split('(').next().unwrap_or_default();
+
+                if variant.len() == rendered.len() {
+                    f.write_str(variant)
+                } else {
+                    write!(f, "{variant}(<redacted>)")
+                }
+            }
+        }
+    }
+}
+
+/// Renders a list of row keys like [`RedactedKey`].
+pub struct RedactedKeys<'a>(pub(crate) &'a [Key]);
+
+impl Debug for RedactedKeys<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(RedactedKey))
+            .finish()
+    }
+}
+
+/// Renders bytes of plaintext by their size.
+struct RedactedBytes<'a>(&'a [u8]);
+
+impl Debug for RedactedBytes<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "<{} bytes>", self.0.len())
+    }
+}
+
+/// Row keys and decrypted values are redacted, so errors can be logged without leaking data.
+impl Debug for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NonEncryptedDatabase => f.write_str("NonEncryptedDatabase"),
+            Self::InvalidKey => f.write_str("InvalidKey"),
+            Self::InvalidKeyMaterial => f.write_str("InvalidKeyMaterial"),
+            Self::SerializationError(error) => {
+                f.debug_tuple("SerializationError").field(error).finish()
+            }
+            Self::StoreError(error) => f.debug_tuple("StoreError").field(error).finish(),
+            Self::EncryptionError => f.write_str("EncryptionError"),
+            Self::InvalidValue => f.write_str("InvalidValue"),
+            Self::DecryptFailed {
+                table,
+                key,
+                column,
+                kind,
+            } => f
+                .debug_struct("DecryptFailed")
+                .field("table", table)
+                .field("key", &RedactedKey(key))
+                .field("column", column)
+                .field("kind", kind)
+                .finish(),
+            Self::Unsupported(operation) => f.debug_tuple("Unsupported").field(operation).finish(),
+            Self::UnsupportedValueVersion {
+                version,
+                table,
+                key,
+                raw,
+            } => f
+                .debug_struct("UnsupportedValueVersion")
+                .field("version", version)
+                .field("table", table)
+                .field("key", &key.as_ref().map(RedactedKey))
+                .field("raw", &raw.as_deref().map(RedactedBytes))
+                .finish(),
+            Self::PlaintextValue { table, key } => f
+                .debug_struct("PlaintextValue")
+                .field("table", table)
+                .field("key", &RedactedKey(key))
+                .finish(),
+            Self::KeyChangeInProgress {
+                old_key_id,
+                new_key_id,
+            } => f
+                .debug_struct("KeyChangeInProgress")
+                .field("old_key_id", old_key_id)
+                .field("new_key_id", new_key_id)
+                .finish(),
+            Self::StagedCopyMismatch {
+                table,
+                expected,
+                found,
+            } => f
+                .debug_struct("StagedCopyMismatch")
+                .field("table", table)
+                .field("expected", expected)
+                .field("found", found)
+                .finish(),
+            Self::TableExists(table) => f.debug_tuple("TableExists").field(table).finish(),
+            Self::BlockingTaskDropped => f.write_str("BlockingTaskDropped"),
+            Self::CiphertextTooLarge { table, key, len } => f
+                .debug_struct("CiphertextTooLarge")
+                .field("table", table)
+                .field("key", &RedactedKey(key))
+                .field("len", len)
+                .finish(),
+            Self::NoncesExhausted => f.write_str("NoncesExhausted"),
+            Self::InvalidConfig(problem) => f.debug_tuple("InvalidConfig").field(problem).finish(),
+            Self::Backup(problem) => f.debug_tuple("Backup").field(problem).finish(),
+        }
+    }
+}
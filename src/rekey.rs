@@ -0,0 +1,162 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    task::{Poll, Waker},
+};
+
+use futures::future;
+use gluesql_core::store::{Store, StoreMut, Transaction};
+use ring::aead::{NonceSequence, UnboundKey};
+
+use crate::{
+    CancellationToken, EncryptedStore, Error, KeyChange, KeyChangeProgress, MaybeSendSync,
+};
+
+/// Where a key change started with [`EncryptedStore::spawn_rekey`] is at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RekeyState {
+    /// Rewriting tables, or waiting to be polled for the first time.
+    Running,
+    /// Paused with [`RekeyHandle::pause`], at or before the next batch boundary.
+    Paused,
+    /// Every table was rewritten.
+    Done,
+    /// Stopped with [`RekeyHandle::cancel`], and checkpointed.
+    Cancelled,
+    /// Stopped by an error, which the task returns.
+    Failed,
+}
+
+impl RekeyState {
+    #[must_use]
+    pub const fn is_finished(self) -> bool {
+        matches!(self, Self::Done | Self::Cancelled | Self::Failed)
+    }
+}
+
+#[derive(Debug)]
+struct Status {
+    state: RekeyState,
+    progress: KeyChangeProgress,
+    /// Tasks waiting for the key change to finish.
+    waiters: Vec<Waker>,
+}
+
+/// Watches and steers a key change started with [`EncryptedStore::spawn_rekey`].
+///
+/// Clones watch the same key change.
+#[derive(Debug, Clone)]
+pub struct RekeyHandle {
+    token: CancellationToken,
+    status: Arc<Mutex<Status>>,
+}
+
+impl RekeyHandle {
+    fn status(&self) -> MutexGuard<'_, Status> {
+        self.status.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    #[must_use]
+    pub fn state(&self) -> RekeyState {
+        let state = self.status().state;
+
+        match state {
+            RekeyState::Running if self.token.is_paused() => RekeyState::Paused,
+            state => state,
+        }
+    }
+
+    /// Returns the progress reported after the last batch.
+    #[must_use]
+    pub fn progress(&self) -> KeyChangeProgress {
+        self.status().progress
+    }
+
+    /// Holds the key change at the next batch boundary until [`RekeyHandle::resume`] is called.
+    pub fn pause(&self) {
+        self.token.pause();
+    }
+
+    pub fn resume(&self) {
+        self.token.resume();
+    }
+
+    /// Stops the key change at the next batch boundary, checkpointing it like
+    /// [`EncryptedStore::change_key_with_progress`].
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    /// Waits for the key change to finish, and returns how it did.
+    pub async fn finished(&self) -> RekeyState {
+        future::poll_fn(|cx| {
+            let mut status = self.status();
+
+            if status.state.is_finished() {
+                Poll::Ready(status.state)
+            } else {
+                status.waiters.push(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    fn finish(&self, state: RekeyState) {
+        let mut status = self.status();
+
+        status.state = state;
+
+        for waker in status.waiters.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl<
+        S: Store + StoreMut + Transaction + MaybeSendSync,
+        NonceSeq: NonceSequence + MaybeSendSync,
+    > EncryptedStore<S, NonceSeq>
+{
+    /// Prepares [`EncryptedStore::change_key`] to run as a background task, returning the task
+    /// and a handle to poll its status, pause or cancel it, and wait for it to finish.
+    ///
+    /// Nothing happens until the task is polled. The store isn't `Send`, so it's meant to be
+    /// spawned on a local executor, e.g. with `tokio::task::spawn_local`. The task returns the
+    /// store once it's done, or cancelled.
+    pub fn spawn_rekey(
+        self,
+        new_key: UnboundKey,
+    ) -> (
+        impl Future<Output = Result<KeyChange<Self>, Error>>,
+        RekeyHandle,
+    ) {
+        let handle = RekeyHandle {
+            token: CancellationToken::new(),
+            status: Arc::new(Mutex::new(Status {
+                state: RekeyState::Running,
+                progress: KeyChangeProgress::default(),
+                waiters: Vec::new(),
+            })),
+        };
+        let task_handle = handle.clone();
+
+        let task = async move {
+            let result = self
+                .change_key_with_progress(new_key, &task_handle.token, |progress| {
+                    task_handle.status().progress = progress;
+                })
+                .await;
+
+            task_handle.finish(match &result {
+                Ok(KeyChange::Done(_)) => RekeyState::Done,
+                Ok(KeyChange::Cancelled(_)) => RekeyState::Cancelled,
+                Err(_) => RekeyState::Failed,
+            });
+
+            result
+        };
+
+        (task, handle)
+    }
+}
@@ -0,0 +1,223 @@
+use std::fmt::{self, Debug, Formatter};
+
+use futures::TryStreamExt;
+use gluesql_core::{
+    data::Key,
+    store::{DataRow, Store, StoreMut, Transaction},
+};
+use ring::aead::{LessSafeKey, NonceSequence, UnboundKey};
+
+use crate::{encdec, in_transaction, redact::RedactedKey, EncryptedStore, Error, MaybeSendSync};
+
+/// Outcome of [`EncryptedStore::repair_with_keys`].
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Rows an older key opened, now rewritten with the key of the store.
+    pub repaired: u64,
+    /// Tables and keys, as stored in the inner store, of the rows no key opened.
+    pub unrecoverable: Vec<(String, Key)>,
+}
+
+/// The keys of unrecoverable rows are redacted, since plaintext row keys are data of the table.
+impl Debug for RepairReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RepairReport")
+            .field("repaired", &self.repaired)
+            .field(
+                "unrecoverable",
+                &self
+                    .unrecoverable
+                    .iter()
+                    .map(|(table, key)| (table, RedactedKey(key)))
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+/// Rows of a table to rewrite with the key of the store, along with the keys they move away
+/// from.
+struct RepairBatch {
+    rows: Vec<(Key, DataRow)>,
+    moved: Vec<Key>,
+    unrecoverable: Vec<Key>,
+}
+
+impl<
+        S: Store + StoreMut + Transaction + MaybeSendSync,
+        NonceSeq: NonceSequence + MaybeSendSync,
+    > EncryptedStore<S, NonceSeq>
+{
+    /// Opens the rows the key of the store doesn't with the given older keys, and rewrites the
+    /// ones they open with the key of the store, e.g. after a key change was botched or
+    /// restarted with another key.
+    ///
+    /// Every encrypted table is scanned but the metadata, whose key check decides the key of
+    /// the store. Rows are rewritten a batch at a time, each batch in a transaction if the inner
+    /// store supports them, and rows holding values under several keys are repaired as a whole.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store fails to fetch, re-encrypt, or write the data. Rows no key
+    /// opens are reported rather than returned as errors.
+    pub async fn repair_with_keys(
+        &mut self,
+        keys: impl IntoIterator<Item = UnboundKey>,
+    ) -> Result<RepairReport, Error> {
+        let old_keys = keys
+            .into_iter()
+            .map(|key| encdec::KeySet::new(LessSafeKey::new(key)))
+            .collect::<Vec<_>>();
+
+        in_transaction(self, async |store: &mut Self, autocommit| {
+            store.repair_tables(autocommit, &old_keys).await
+        })
+        .await
+    }
+
+    /// Repairs the tables of [`EncryptedStore::repair_with_keys`] in the transaction it began.
+    async fn repair_tables(
+        &mut self,
+        autocommit: bool,
+        old_keys: &[encdec::KeySet],
+    ) -> Result<RepairReport, Error> {
+        let mut report = RepairReport::default();
+
+        for (table_name, encrypts_row_keys) in self.rotated_tables(true).await? {
+            if table_name == self.tables.meta {
+                continue;
+            }
+
+            let (revealed_name, columns) = self.revealed_columns(&table_name).await?;
+
+            loop {
+                let batch = self
+                    .repair_batch(
+                        &table_name,
+                        &revealed_name,
+                        &columns,
+                        encrypts_row_keys,
+                        old_keys,
+                    )
+                    .await?;
+                let done = batch.rows.len() < self.batch_size;
+
+                report.repaired += batch.rows.len() as u64;
+
+                if done {
+                    let revealed = self.reveal_name(table_name.clone()).await?;
+
+                    report.unrecoverable.extend(
+                        batch
+                            .unrecoverable
+                            .into_iter()
+                            .map(|key| (revealed.clone(), key)),
+                    );
+                }
+
+                if !batch.rows.is_empty() {
+                    self.store.insert_data(&table_name, batch.rows).await?;
+
+                    if !batch.moved.is_empty() {
+                        self.store.delete_data(&table_name, batch.moved).await?;
+                    }
+                }
+
+                self.commit_batch(autocommit).await?;
+
+                if done {
+                    break;
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Scans a table for the next batch of rows the key of the store doesn't open but one of
+    /// `old_keys` does, and re-encrypts them with the key of the store.
+    ///
+    /// Repaired rows open from then on, so every scan starts over. The rows no key opens are
+    /// only complete once a scan finds less than a batch to repair, since it then went through
+    /// the whole table.
+    ///
+    /// `revealed_name` and `columns` are the name and columns of the table as the app sees them,
+    /// see [`EncryptedStore::revealed_columns`].
+    async fn repair_batch(
+        &mut self,
+        table_name: &str,
+        revealed_name: &str,
+        columns: &[String],
+        encrypts_row_keys: bool,
+        old_keys: &[encdec::KeySet],
+    ) -> Result<RepairBatch, Error> {
+        self.prepare_nonces().await?;
+
+        let mut nonces = self.batch_nonces()?;
+        let keys = encdec::RowKeys {
+            previous: None,
+            ..self.row_keys()
+        };
+        let opens = |keys: encdec::RowKeys<'_>, key: &Key, row: &DataRow| {
+            let mut row = row.clone();
+
+            (!encrypts_row_keys || encdec::decrypt_row_key(keys, key.clone()).is_ok())
+                && encdec::decrypt_row_in_place(keys, &mut row, self.compression).is_ok()
+        };
+        let mut batch = RepairBatch {
+            rows: Vec::new(),
+            moved: Vec::new(),
+            unrecoverable: Vec::new(),
+        };
+        let mut rows = self.store.scan_data(table_name).await?;
+
+        while let Some((key, mut row)) = rows.try_next().await? {
+            if opens(self.row_keys(), &key, &row) {
+                continue;
+            }
+
+            // values already rewritten with the key of the store still open with it
+            let Some(old_keys) = old_keys
+                .iter()
+                .map(|old| encdec::RowKeys {
+                    previous: Some(old),
+                    ..keys
+                })
+                .find(|old_keys| opens(*old_keys, &key, &row))
+            else {
+                batch.unrecoverable.push(key);
+                continue;
+            };
+
+            encdec::reencrypt_row_in_place(
+                old_keys,
+                keys,
+                &mut nonces,
+                revealed_name,
+                columns,
+                &mut row,
+                self.compression,
+            )?;
+
+            if encrypts_row_keys {
+                let row_key = encdec::decrypt_row_key(old_keys, key.clone())?;
+                let new_key = encdec::encrypt_key(keys.key, keys.name_key, &row_key)?;
+
+                // rows whose values alone are under an older key keep their key
+                if new_key != key {
+                    batch.moved.push(key);
+                }
+
+                batch.rows.push((new_key, row));
+            } else {
+                batch.rows.push((key, row));
+            }
+
+            if batch.rows.len() == self.batch_size {
+                break;
+            }
+        }
+
+        Ok(batch)
+    }
+}
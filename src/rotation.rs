@@ -0,0 +1,2083 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    task::{Poll, Waker},
+    thread,
+    time::Duration,
+};
+
+use chrono::{NaiveDateTime, Utc};
+use futures::{future, StreamExt, TryStreamExt};
+use gluesql_core::{
+    data::{Key, Schema, Value},
+    store::{AlterTable, DataRow, Store, StoreMut, Transaction},
+};
+use ring::aead::{LessSafeKey, NonceSequence, UnboundKey};
+
+use crate::{
+    encdec, in_transaction, parallel, trace::CryptoSpan, Codec, Compression, EncryptedStore, Error,
+    MaybeSendSync, CREATED_AT_ROW,
+};
+
+/// Label of the key material identifying a key in checkpoints.
+const KEY_ID_LABEL: &str = "gluesql-encryption key id";
+
+/// Stands in for the old key in the checkpoints of [`EncryptedStore::encrypt_existing_store`].
+const PLAINTEXT_KEY_ID: &str = "plaintext";
+
+/// Returns an identifier of the key, which reveals nothing about the key itself.
+fn key_id(key: &LessSafeKey) -> String {
+    encdec::to_hex(&encdec::derive_material(key, KEY_ID_LABEL)[..8])
+}
+
+/// Lets another task stop or pause a key change at the next batch boundary.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<TokenState>);
+
+#[derive(Debug, Default)]
+struct TokenState {
+    cancelled: AtomicBool,
+    pause: Mutex<Pause>,
+}
+
+#[derive(Debug, Default)]
+struct Pause {
+    paused: bool,
+    /// Key changes waiting for the token to be resumed, each under its own id so polling one
+    /// again replaces its waker.
+    waiters: HashMap<u64, Waker>,
+    next_waiter: u64,
+}
+
+impl Pause {
+    const fn add_waiter(&mut self) -> u64 {
+        self.next_waiter += 1;
+        self.next_waiter
+    }
+
+    fn wake_waiters(&mut self) {
+        for (_, waker) in self.waiters.drain() {
+            waker.wake();
+        }
+    }
+}
+
+impl CancellationToken {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks the key changes watching this token, or any clone of it, to stop.
+    ///
+    /// Paused key changes stop too, without waiting to be resumed.
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::Relaxed);
+        self.pause_state().wake_waiters();
+    }
+
+    #[must_use]
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Holds the key changes watching this token at the next batch boundary until it's resumed.
+    ///
+    /// Unlike a cancelled key change, a paused one isn't checkpointed and returned; it waits
+    /// where it stopped.
+    pub fn pause(&self) {
+        self.pause_state().paused = true;
+    }
+
+    /// Lets paused key changes go on.
+    pub fn resume(&self) {
+        let mut pause = self.pause_state();
+
+        pause.paused = false;
+        pause.wake_waiters();
+    }
+
+    #[must_use]
+    pub fn is_paused(&self) -> bool {
+        self.pause_state().paused
+    }
+
+    fn pause_state(&self) -> MutexGuard<'_, Pause> {
+        self.0.pause.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Waits until the token isn't paused, or is cancelled.
+    async fn unpaused(&self) {
+        let mut waiter = None;
+
+        future::poll_fn(|cx| {
+            let mut pause = self.pause_state();
+
+            if !pause.paused || self.is_cancelled() {
+                if let Some(waiter) = waiter {
+                    pause.waiters.remove(&waiter);
+                }
+
+                Poll::Ready(())
+            } else {
+                let waiter = *waiter.get_or_insert_with(|| pause.add_waiter());
+
+                pause.waiters.insert(waiter, cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await;
+    }
+}
+
+/// Progress of a key change, reported after every batch.
+///
+/// Counts start over when an interrupted key change is resumed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyChangeProgress {
+    /// Tables fully rewritten.
+    pub tables_done: usize,
+    /// Tables to rewrite in total.
+    pub tables_total: usize,
+    /// Rows rewritten, across all tables.
+    pub rows_done: u64,
+    /// Bytes of ciphertext written.
+    pub bytes_rewritten: u64,
+}
+
+/// Work a key change would do, as estimated by [`EncryptedStore::estimate_rekey`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RekeyEstimate {
+    /// Tables to rewrite, internal ones included.
+    pub tables: usize,
+    /// Rows to rewrite, across all tables.
+    pub rows: u64,
+    /// Bytes of ciphertext to rewrite.
+    pub bytes: u64,
+}
+
+/// Which rows of the store are under its key, as reported by
+/// [`EncryptedStore::rotation_status`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RotationStatus {
+    /// Identifier of the key of the store, which reveals nothing about the key itself.
+    pub key_id: String,
+    /// The encrypted tables, internal ones included.
+    pub tables: Vec<TableRotationStatus>,
+}
+
+impl RotationStatus {
+    /// Returns whether every row is under the key of the store, so older keys can be destroyed.
+    #[must_use]
+    pub fn is_complete(&self) -> bool {
+        self.tables.iter().all(|table| table.old_rows == 0)
+    }
+}
+
+/// Rows of a table under the key of the store and under older ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableRotationStatus {
+    pub table_name: String,
+    pub current_rows: u64,
+    /// Rows the key of the store doesn't open, e.g. because an online key change hasn't
+    /// rewritten them yet.
+    pub old_rows: u64,
+}
+
+/// A finished key change, as recorded in the rotation history.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRotationRecord {
+    /// Identifier of the key the store changed from, which reveals nothing about the key.
+    pub old_key_id: String,
+    /// Identifier of the key the store changed to.
+    pub new_key_id: String,
+    /// When the key change finished, in UTC.
+    pub finished_at: NaiveDateTime,
+    /// Rows rewritten with the new key, across all tables and interruptions.
+    pub rows_rewritten: u64,
+    /// Time from the start of the key change to its end, interruptions included.
+    pub duration: Duration,
+}
+
+impl KeyRotationRecord {
+    fn to_value(&self) -> Value {
+        Value::Map(
+            [
+                ("old_key_id".to_owned(), Value::Str(self.old_key_id.clone())),
+                ("new_key_id".to_owned(), Value::Str(self.new_key_id.clone())),
+                ("finished_at".to_owned(), Value::Timestamp(self.finished_at)),
+                ("rows_rewritten".to_owned(), Value::U64(self.rows_rewritten)),
+                (
+                    "duration_micros".to_owned(),
+                    Value::U64(u64::try_from(self.duration.as_micros()).unwrap_or(u64::MAX)),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        )
+    }
+
+    fn from_value(value: Value) -> Result<Self, Error> {
+        let Value::Map(mut values) = value else {
+            return Err(Error::InvalidValue);
+        };
+        let mut remove = |name| values.remove(name).ok_or(Error::InvalidValue);
+
+        match (
+            remove("old_key_id")?,
+            remove("new_key_id")?,
+            remove("finished_at")?,
+            remove("rows_rewritten")?,
+            remove("duration_micros")?,
+        ) {
+            (
+                Value::Str(old_key_id),
+                Value::Str(new_key_id),
+                Value::Timestamp(finished_at),
+                Value::U64(rows_rewritten),
+                Value::U64(duration),
+            ) => Ok(Self {
+                old_key_id,
+                new_key_id,
+                finished_at,
+                rows_rewritten,
+                duration: Duration::from_micros(duration),
+            }),
+            _ => Err(Error::InvalidValue),
+        }
+    }
+}
+
+/// Outcome of a key change that can be cancelled.
+#[derive(Debug)]
+pub enum KeyChange<T> {
+    /// Every table was rewritten, and the store uses the new key.
+    Done(T),
+    /// The key change stopped at a batch boundary. The store still uses the old key, and
+    /// nothing should be written to it until the key change is resumed.
+    Cancelled(T),
+}
+
+impl<T> KeyChange<T> {
+    #[must_use]
+    pub const fn is_cancelled(&self) -> bool {
+        matches!(self, Self::Cancelled(_))
+    }
+
+    /// Returns the store, whether or not the key change finished.
+    #[must_use]
+    pub fn into_inner(self) -> T {
+        match self {
+            Self::Done(store) | Self::Cancelled(store) => store,
+        }
+    }
+}
+
+/// Progress of a key change through a table, recorded with every batch so an interrupted key
+/// change can be resumed.
+struct Checkpoint {
+    old_key_id: String,
+    new_key_id: String,
+    /// Last row rewritten, if the table's rows keep their keys.
+    last_key: Option<Key>,
+    /// Whether every row of the table was rewritten.
+    done: bool,
+    /// Whether the store reads with both keys while the key change is in progress.
+    online: bool,
+    /// Whether the rows up to the end of the table were rewritten, and the table is scanned
+    /// whole for rows still under the old key before it's done.
+    verifying: bool,
+    /// Rows of the table rewritten so far.
+    rows: u64,
+    /// When the key change started, before any interruption.
+    started_at: NaiveDateTime,
+}
+
+impl Checkpoint {
+    fn to_row(&self) -> Result<DataRow, Error> {
+        let last_key = match &self.last_key {
+            Some(key) => Value::Bytea(postcard::to_extend(key, Vec::new())?),
+            None => Value::Null,
+        };
+
+        Ok(DataRow::Map(
+            [
+                ("old_key_id".to_owned(), Value::Str(self.old_key_id.clone())),
+                ("new_key_id".to_owned(), Value::Str(self.new_key_id.clone())),
+                ("last_key".to_owned(), last_key),
+                ("done".to_owned(), Value::Bool(self.done)),
+                ("online".to_owned(), Value::Bool(self.online)),
+                ("verifying".to_owned(), Value::Bool(self.verifying)),
+                ("rows".to_owned(), Value::U64(self.rows)),
+                ("started_at".to_owned(), Value::Timestamp(self.started_at)),
+            ]
+            .into_iter()
+            .collect(),
+        ))
+    }
+
+    fn from_row(row: DataRow) -> Result<Self, Error> {
+        let DataRow::Map(mut values) = row else {
+            return Err(Error::InvalidValue);
+        };
+        let mut string = |name| match values.remove(name) {
+            Some(Value::Str(value)) => Ok(value),
+            _ => Err(Error::InvalidValue),
+        };
+
+        Ok(Self {
+            old_key_id: string("old_key_id")?,
+            new_key_id: string("new_key_id")?,
+            last_key: match values.remove("last_key") {
+                Some(Value::Bytea(key)) => Some(postcard::from_bytes(&key)?),
+                Some(Value::Null) | None => None,
+                Some(_) => return Err(Error::InvalidValue),
+            },
+            done: matches!(values.remove("done"), Some(Value::Bool(true))),
+            online: matches!(values.remove("online"), Some(Value::Bool(true))),
+            verifying: matches!(values.remove("verifying"), Some(Value::Bool(true))),
+            rows: match values.remove("rows") {
+                Some(Value::U64(rows)) => rows,
+                _ => 0,
+            },
+            // checkpoints recorded before the start was
+            started_at: match values.remove("started_at") {
+                Some(Value::Timestamp(started_at)) => started_at,
+                _ => Utc::now().naive_utc(),
+            },
+        })
+    }
+}
+
+/// The key a store is changing to.
+struct KeyRotation {
+    old_key_id: String,
+    new_key_id: String,
+    keys: encdec::KeySet,
+    online: bool,
+    started_at: NaiveDateTime,
+}
+
+impl KeyRotation {
+    fn new(old_key: &LessSafeKey, new_key: LessSafeKey, online: bool) -> Self {
+        Self {
+            old_key_id: key_id(old_key),
+            new_key_id: key_id(&new_key),
+            keys: encdec::KeySet::new(new_key),
+            online,
+            started_at: Utc::now().naive_utc(),
+        }
+    }
+
+    /// Returns whether the checkpoint was recorded by this key change.
+    fn records(&self, checkpoint: &Checkpoint) -> bool {
+        checkpoint.old_key_id == self.old_key_id
+            && checkpoint.new_key_id == self.new_key_id
+            && checkpoint.online == self.online
+    }
+
+    fn checkpoint(&self, table: &RotatedTable, done: bool) -> Checkpoint {
+        Checkpoint {
+            old_key_id: self.old_key_id.clone(),
+            new_key_id: self.new_key_id.clone(),
+            last_key: table.last_key.clone(),
+            done,
+            online: self.online,
+            verifying: table.verifying,
+            rows: table.rows,
+            started_at: self.started_at,
+        }
+    }
+}
+
+/// A table `change_key` rewrites.
+struct RotatedTable {
+    /// Name of the table in the inner store.
+    table_name: String,
+    /// Name of the table as the app sees it, and its columns, which the nonces of its
+    /// deterministically encrypted values are derived from.
+    revealed_name: String,
+    columns: Vec<String>,
+    encrypts_row_keys: bool,
+    /// Last row rewritten in place, if any.
+    last_key: Option<Key>,
+    /// Whether the table is scanned whole for rows still under the old key.
+    verifying: bool,
+    /// Rows rewritten so far.
+    rows: u64,
+}
+
+impl RotatedTable {
+    fn new(table_name: String, encrypts_row_keys: bool) -> Self {
+        Self {
+            revealed_name: table_name.clone(),
+            columns: Vec::new(),
+            table_name,
+            encrypts_row_keys,
+            last_key: None,
+            verifying: false,
+            rows: 0,
+        }
+    }
+
+    /// Returns the table where its checkpoint left it, or `None` if it's done.
+    fn resume(
+        table_name: String,
+        encrypts_row_keys: bool,
+        checkpoint: Option<Checkpoint>,
+    ) -> Option<Self> {
+        match checkpoint {
+            Some(checkpoint) if checkpoint.done => None,
+            Some(checkpoint) => Some(Self {
+                last_key: checkpoint.last_key,
+                verifying: checkpoint.verifying,
+                rows: checkpoint.rows,
+                ..Self::new(table_name, encrypts_row_keys)
+            }),
+            None => Some(Self::new(table_name, encrypts_row_keys)),
+        }
+    }
+}
+
+/// A batch of rows of a table, re-encrypted with the new key.
+struct RewrittenBatch {
+    rows: Vec<(Key, DataRow)>,
+    /// Keys of the rows moved to a new key, which must be deleted.
+    moved: Vec<Key>,
+    last_key: Option<Key>,
+    bytes: u64,
+}
+
+/// Re-encrypts a batch of rows of a table, moving them to new keys if their keys are encrypted.
+fn rewrite_batch(
+    table: &RotatedTable,
+    keys: encdec::RowKeys<'_>,
+    new_keys: encdec::RowKeys<'_>,
+    nonces: encdec::BatchNonces,
+    mut batch: Vec<(Key, DataRow)>,
+    compression: Compression,
+) -> Result<RewrittenBatch, Error> {
+    let span = CryptoSpan::rekey_table(&table.table_name, batch.len());
+    let mut rewritten = RewrittenBatch {
+        rows: Vec::with_capacity(batch.len()),
+        moved: Vec::new(),
+        last_key: None,
+        bytes: 0,
+    };
+
+    parallel::for_each_row(&mut batch, nonces, |nonces, (_, row)| {
+        encdec::reencrypt_row_in_place(
+            keys,
+            new_keys,
+            nonces,
+            &table.revealed_name,
+            &table.columns,
+            row,
+            compression,
+        )
+    })?;
+
+    for (key, row) in batch {
+        rewritten.bytes += encdec::ciphertext_len(&row) as u64;
+
+        if table.encrypts_row_keys {
+            let row_key = encdec::decrypt_row_key(keys, key.clone())?;
+            let new_row_key = encdec::encrypt_key(new_keys.key, new_keys.name_key, &row_key)?;
+
+            rewritten.rows.push((new_row_key, row));
+            rewritten.moved.push(key);
+        } else {
+            rewritten.last_key = Some(key.clone());
+            rewritten.rows.push((key, row));
+        }
+    }
+
+    span.record_bytes(rewritten.bytes);
+
+    Ok(rewritten)
+}
+
+impl<S: Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>
+    EncryptedStore<S, NonceSeq>
+{
+    /// Returns the checkpoints of the key change in progress, by table.
+    async fn fetch_checkpoints(&self) -> Result<HashMap<String, Checkpoint>, Error> {
+        if self
+            .store
+            .fetch_schema(&self.tables.rotation)
+            .await?
+            .is_none()
+        {
+            return Ok(HashMap::new());
+        }
+
+        self.store
+            .scan_data(&self.tables.rotation)
+            .await?
+            .map_err(Error::from)
+            .and_then(|(key, row)| {
+                future::ready(crate::scanned_str(key).ok_or(Error::InvalidValue).and_then(
+                    |table_name| {
+                        Checkpoint::from_row(row).map(|checkpoint| (table_name, checkpoint))
+                    },
+                ))
+            })
+            .try_collect()
+            .await
+    }
+
+    /// Returns the name of a table of the inner store as the app sees it, along with its columns,
+    /// which the nonces of its deterministically encrypted values are derived from.
+    pub(crate) async fn revealed_columns(
+        &self,
+        table_name: &str,
+    ) -> Result<(String, Vec<String>), Error> {
+        if self.tables.contains(table_name) {
+            return Ok((table_name.to_owned(), Vec::new()));
+        }
+
+        let revealed_name = self.reveal_name(table_name.to_owned()).await?;
+        let columns = self.load_columns(&revealed_name).await?.names;
+
+        Ok((revealed_name, columns))
+    }
+
+    /// Returns the tables `change_key` rewrites, as stored in the inner store, and whether their
+    /// row keys are encrypted.
+    ///
+    /// Internal tables come last: the real names of the others are needed to tell which ones are
+    /// encrypted, and the key check must only pass with the new key once everything else does.
+    pub(crate) async fn rotated_tables(
+        &self,
+        user_tables: bool,
+    ) -> Result<Vec<(String, bool)>, Error> {
+        let mut tables = Vec::new();
+
+        if user_tables {
+            for schema in self.store.fetch_all_schemas().await? {
+                if self.tables.contains(&schema.table_name) {
+                    continue;
+                }
+
+                let table_name = self.reveal_name(schema.table_name.clone()).await?;
+
+                if self.encrypts_table(&table_name) {
+                    tables.push((schema.table_name, self.encrypts_row_keys(&table_name)));
+                }
+            }
+        }
+
+        for table_name in [
+            &self.tables.names,
+            &self.tables.vault,
+            &self.tables.audit,
+            &self.tables.meta,
+        ] {
+            if self.store.fetch_schema(table_name).await?.is_some() {
+                tables.push((table_name.clone(), false));
+            }
+        }
+
+        Ok(tables)
+    }
+
+    /// Returns the key changes this store went through, oldest first, e.g. as evidence that its
+    /// key was rotated.
+    ///
+    /// Key changes are recorded once they finish. Records are encrypted like the key check, and
+    /// rewritten along with it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the history can't be read or decrypted.
+    pub async fn rotation_history(&self) -> Result<Vec<KeyRotationRecord>, Error> {
+        if self.store.fetch_schema(&self.tables.meta).await?.is_none() {
+            return Ok(Vec::new());
+        }
+
+        let mut rows = self.store.scan_data(&self.tables.meta).await?;
+        let mut history = Vec::new();
+
+        // records are told apart by their column rather than their key, which stores like sled
+        // scan as bytes
+        while let Some((_, row)) = rows.try_next().await? {
+            let DataRow::Map(mut values) = row else {
+                continue;
+            };
+            let Some(mut value) = values.remove("rotation") else {
+                continue;
+            };
+
+            self.decrypt_value(&mut value, Compression::None)?;
+            history.push(KeyRotationRecord::from_value(value)?);
+        }
+
+        history.sort_by_key(|record| record.finished_at);
+
+        Ok(history)
+    }
+
+    /// Returns whether the key is older than the rotation schedule allows, counting from the last
+    /// key change, or from the creation of the store if the key was never changed.
+    ///
+    /// Stores without a schedule are never due, and stores whose age is unknown, e.g. created by
+    /// an older version of the crate and never rotated since, always are.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the history can't be read or decrypted.
+    pub async fn rotation_due(&self) -> Result<bool, Error> {
+        let Some(schedule) = self.rotation_schedule else {
+            return Ok(false);
+        };
+
+        let key_since = match self.rotation_history().await?.pop() {
+            Some(record) => Some(record.finished_at),
+            None => self.created_at().await?,
+        };
+
+        Ok(key_since
+            .is_none_or(|key_since| Utc::now().naive_utc() - key_since >= schedule.period()))
+    }
+
+    /// Returns when the store was created, if it was recorded.
+    async fn created_at(&self) -> Result<Option<NaiveDateTime>, Error> {
+        match self
+            .store
+            .fetch_data(&self.tables.meta, &CREATED_AT_ROW)
+            .await?
+        {
+            Some(DataRow::Map(mut values)) => {
+                let mut value = values.remove("created_at").ok_or(Error::InvalidValue)?;
+
+                self.decrypt_value(&mut value, Compression::None)?;
+
+                match value {
+                    Value::Timestamp(created_at) => Ok(Some(created_at)),
+                    _ => Err(Error::InvalidValue),
+                }
+            }
+            Some(DataRow::Vec(_)) => Err(Error::InvalidValue),
+            None => Ok(None),
+        }
+    }
+
+    /// Counts the tables, rows and bytes of ciphertext [`EncryptedStore::change_key`] would
+    /// rewrite, without rewriting anything, e.g. to schedule the key change of a large store.
+    ///
+    /// Every row is scanned, but none is decrypted. Rows a resumed key change wouldn't rewrite
+    /// again are counted all the same.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store fails to scan the data.
+    pub async fn estimate_rekey(&self) -> Result<RekeyEstimate, Error> {
+        let tables = self.rotated_tables(true).await?;
+        let mut estimate = RekeyEstimate {
+            tables: tables.len(),
+            ..RekeyEstimate::default()
+        };
+
+        for (table_name, _) in tables {
+            let mut rows = self.store.scan_data(&table_name).await?;
+
+            while let Some((_, row)) = rows.try_next().await? {
+                estimate.rows += 1;
+                estimate.bytes += encdec::ciphertext_len(&row) as u64;
+            }
+        }
+
+        Ok(estimate)
+    }
+
+    /// Counts the rows of every encrypted table that are still under an older key than the
+    /// store's, e.g. to tell when an online key change is done with the old key and it can be
+    /// destroyed.
+    ///
+    /// Ciphertexts don't name their key, so every row is opened to tell which key it's under,
+    /// except in tables with encrypted row keys, where the key of the row is enough.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store fails to scan the data, or a row is malformed.
+    pub async fn rotation_status(&self) -> Result<RotationStatus, Error> {
+        let keys = encdec::RowKeys {
+            previous: None,
+            ..self.row_keys()
+        };
+        let mut status = RotationStatus {
+            key_id: key_id(&self.key),
+            tables: Vec::new(),
+        };
+
+        for (table_name, encrypts_row_keys) in self.rotated_tables(true).await? {
+            let mut rows = self.store.scan_data(&table_name).await?;
+            let (mut current_rows, mut old_rows) = (0, 0);
+
+            while let Some((key, mut row)) = rows.try_next().await? {
+                let current = if encrypts_row_keys {
+                    encdec::is_key_of(&self.key, &key)
+                } else {
+                    self.opens_with(keys, &mut row)?
+                };
+
+                if current {
+                    current_rows += 1;
+                } else {
+                    old_rows += 1;
+                }
+            }
+
+            status.tables.push(TableRotationStatus {
+                table_name: self.reveal_name(table_name).await?,
+                current_rows,
+                old_rows,
+            });
+        }
+
+        Ok(status)
+    }
+
+    /// Returns whether a row opens with the given keys, decrypting it in place if so.
+    fn opens_with(&self, keys: encdec::RowKeys<'_>, row: &mut DataRow) -> Result<bool, Error> {
+        match encdec::decrypt_row_in_place(keys, row, self.compression) {
+            Ok(()) => Ok(true),
+            Err(Error::EncryptionError) => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Returns the next batch of rows of a table to rewrite.
+    ///
+    /// The scan can't be kept open while rows are written, so each batch starts a new one. Rows
+    /// rewritten in place keep their position, so it skips those up to the last one done, which
+    /// may have been deleted since; rows moved to a new key are told apart by it instead.
+    async fn scan_batch(
+        &self,
+        table: &RotatedTable,
+        new_key: &LessSafeKey,
+    ) -> Result<Vec<(Key, DataRow)>, Error> {
+        let last_key = table.last_key.as_ref();
+
+        Ok(self
+            .store
+            .scan_data(&table.table_name)
+            .await?
+            .try_filter(|(key, _)| {
+                future::ready(
+                    last_key.is_none_or(|last| key > last)
+                        && !(table.encrypts_row_keys && encdec::is_key_of(new_key, key)),
+                )
+            })
+            .take(self.batch_size)
+            .try_collect::<Vec<_>>()
+            .await?)
+    }
+
+    /// Returns the next batch of rows of a table rewritten in place that are still under the old
+    /// key of an online key change, scanning the table whole.
+    async fn scan_old_rows(&self, table: &RotatedTable) -> Result<Vec<(Key, DataRow)>, Error> {
+        let keys = encdec::RowKeys {
+            previous: None,
+            ..self.row_keys()
+        };
+        let mut rows = self.store.scan_data(&table.table_name).await?;
+        let mut old_rows = Vec::new();
+
+        while old_rows.len() < self.batch_size {
+            let Some((key, row)) = rows.try_next().await? else {
+                break;
+            };
+
+            if !self.opens_with(keys, &mut row.clone())? {
+                old_rows.push((key, row));
+            }
+        }
+
+        Ok(old_rows)
+    }
+}
+
+impl<
+        S: Store + StoreMut + Transaction + MaybeSendSync,
+        NonceSeq: NonceSequence + MaybeSendSync,
+    > EncryptedStore<S, NonceSeq>
+{
+    /// Change the key used for encryption.
+    /// Rewrites all the data in the store with the new key and a new nonce.
+    ///
+    /// The store can't be used while the key changes; see [`EncryptedStore::start_key_change`]
+    /// for a key change that keeps it online.
+    ///
+    /// Rows are rewritten in batches of [`EncryptedStore::with_batch_size`] rows, so only a
+    /// batch of them per table is held in memory at a time. Up to
+    /// [`EncryptedStore::with_change_key_concurrency`] tables are rewritten at once, each in
+    /// order, and their batches are committed together in a transaction if the inner store
+    /// supports them, so a failure rolls back the batch in progress instead of leaving it partly
+    /// rewritten. Inside a transaction begun by the caller, the batches are left for the
+    /// caller to commit instead.
+    ///
+    /// The progress is checkpointed along with every batch. If a key change is interrupted, the
+    /// store still opens with the old key, and calling this again with the same new key resumes
+    /// it where it stopped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store fails to fetch, decrypt, or re-encrypt the data, or if
+    /// another key change was interrupted and must be resumed first, or is still online.
+    ///
+    /// Only on stores without transactions may a batch have been written without its
+    /// checkpoint, in which case the key change can't be resumed and you should revert to a
+    /// backup.
+    pub async fn change_key(self, new_key: UnboundKey) -> Result<Self, Error> {
+        self.change_key_with_progress(new_key, &CancellationToken::new(), |_| {})
+            .await
+            .map(KeyChange::into_inner)
+    }
+
+    /// Rewrites every value and row in the store with the given codec, so ciphertexts written
+    /// with an older one, e.g. by a version of the crate that encoded values with plain postcard,
+    /// aren't left behind once it's no longer read.
+    ///
+    /// The key stays the same. The rewrite is batched and resumed like a key change, and blocks
+    /// key changes until it's done.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the codec can't be written, or like [`EncryptedStore::change_key`].
+    pub async fn migrate_codec(self, codec: Codec) -> Result<Self, Error> {
+        if codec != Codec::CURRENT {
+            return Err(Error::Unsupported(
+                "values can only be written with the current codec",
+            ));
+        }
+
+        let rotation = KeyRotation::new(&self.key, self.key.clone(), false);
+
+        self.rewrite_all(rotation, &CancellationToken::new(), |_| {})
+            .await
+            .map(KeyChange::into_inner)
+    }
+
+    /// Changes the key to the one `new_key` provides if [`EncryptedStore::rotation_due`], e.g.
+    /// from a maintenance loop. Otherwise the store is returned as-is, and `new_key` isn't called.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `new_key` fails, or like [`EncryptedStore::change_key`].
+    pub async fn rotate_if_due(
+        self,
+        new_key: impl FnOnce() -> Result<UnboundKey, Error>,
+    ) -> Result<Self, Error> {
+        if self.rotation_due().await? {
+            self.change_key(new_key()?).await
+        } else {
+            Ok(self)
+        }
+    }
+
+    /// Like [`EncryptedStore::change_key`], but reports its progress after every batch and stops
+    /// before the next one once `cancel` is cancelled.
+    ///
+    /// A cancelled key change is checkpointed like an interrupted one, and resumed the same way.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error like [`EncryptedStore::change_key`].
+    pub async fn change_key_with_progress(
+        self,
+        new_key: UnboundKey,
+        cancel: &CancellationToken,
+        on_progress: impl FnMut(KeyChangeProgress),
+    ) -> Result<KeyChange<Self>, Error> {
+        let rotation = KeyRotation::new(&self.key, LessSafeKey::new(new_key), false);
+
+        self.rewrite_all(rotation, cancel, on_progress).await
+    }
+
+    /// Rewrites every table with the keys of the rotation, in the order
+    /// [`EncryptedStore::change_key`] describes.
+    async fn rewrite_all(
+        mut self,
+        mut rotation: KeyRotation,
+        cancel: &CancellationToken,
+        mut on_progress: impl FnMut(KeyChangeProgress),
+    ) -> Result<KeyChange<Self>, Error> {
+        let rewritten = in_transaction(&mut self, async |store: &mut Self, autocommit| {
+            store
+                .rewrite_tables(autocommit, &mut rotation, cancel, &mut on_progress)
+                .await
+        })
+        .await?;
+
+        if !rewritten {
+            return Ok(KeyChange::Cancelled(self));
+        }
+
+        let mut store = Self {
+            key: rotation.keys.key,
+            name_key: rotation.keys.name_key,
+            value_key: rotation.keys.value_key,
+            identity_key: rotation.keys.identity_key,
+            ciphers: rotation.keys.ciphers,
+            previous_keys: None,
+            ..self
+        };
+
+        store
+            .finish_key_change(rotation.old_key_id, rotation.new_key_id)
+            .await?;
+
+        Ok(KeyChange::Done(store))
+    }
+
+    /// Rewrites the tables of [`EncryptedStore::rewrite_all`] in the transaction it began.
+    ///
+    /// Returns whether every table was rewritten, or `false` if it was cancelled.
+    async fn rewrite_tables(
+        &mut self,
+        autocommit: bool,
+        rotation: &mut KeyRotation,
+        cancel: &CancellationToken,
+        on_progress: &mut impl FnMut(KeyChangeProgress),
+    ) -> Result<bool, Error> {
+        let mut checkpoints = self.resume_checkpoints(rotation).await?;
+
+        // once the internal tables are being rewritten, the other tables are done and their
+        // names can't be revealed anymore
+        let user_tables = !checkpoints
+            .keys()
+            .any(|table_name| self.tables.contains(table_name));
+        // rows keep their keys if the key stays the same, so they're rewritten in place
+        let keeps_key = rotation.old_key_id == rotation.new_key_id;
+        let (internal, mut user): (Vec<_>, Vec<_>) = self
+            .rotated_tables(user_tables)
+            .await?
+            .into_iter()
+            .filter_map(|(table_name, encrypts_row_keys)| {
+                let checkpoint = checkpoints.remove(&table_name);
+
+                RotatedTable::resume(table_name, encrypts_row_keys && !keeps_key, checkpoint)
+            })
+            .partition(|table| self.tables.contains(&table.table_name));
+
+        for table in &mut user {
+            (table.revealed_name, table.columns) = self.revealed_columns(&table.table_name).await?;
+        }
+
+        let mut progress = KeyChangeProgress {
+            tables_total: user.len() + internal.len(),
+            ..KeyChangeProgress::default()
+        };
+        let concurrency = self.change_key_concurrency;
+
+        // the internal tables go one at a time, so the key check is the last thing to change
+        for (tables, concurrency) in [(user, concurrency), (internal, 1)] {
+            let finished = self
+                .rotate_tables(
+                    autocommit,
+                    rotation,
+                    tables,
+                    concurrency,
+                    cancel,
+                    &mut progress,
+                    on_progress,
+                )
+                .await?;
+
+            if !finished {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Records a finished key change in the rotation history, then clears its checkpoints.
+    ///
+    /// The store must already use the new key.
+    async fn finish_key_change(
+        &mut self,
+        old_key_id: String,
+        new_key_id: String,
+    ) -> Result<(), Error> {
+        // codec migrations keep the key, and aren't key changes to observe
+        let observed = self
+            .observer
+            .clone()
+            .filter(|_| old_key_id != new_key_id)
+            .map(|observer| (observer, old_key_id.clone(), new_key_id.clone()));
+
+        // the record and the checkpoints it sums up go together
+        in_transaction(self, async |store: &mut Self, _| {
+            store.record_key_change(old_key_id, new_key_id).await
+        })
+        .await?;
+
+        if let Some((observer, old_key_id, new_key_id)) = observed {
+            observer.key_rotated(&old_key_id, &new_key_id);
+        }
+
+        Ok(())
+    }
+
+    /// Writes the record of [`EncryptedStore::finish_key_change`] and clears the checkpoints,
+    /// in the transaction it began.
+    async fn record_key_change(
+        &mut self,
+        old_key_id: String,
+        new_key_id: String,
+    ) -> Result<(), Error> {
+        // codec migrations keep the key
+        if old_key_id == new_key_id {
+            self.store.delete_schema(&self.tables.rotation).await?;
+            self.clear_schema_cache();
+
+            return Ok(());
+        }
+
+        let checkpoints = self.fetch_checkpoints().await?;
+        let finished_at = Utc::now().naive_utc();
+        let started_at = checkpoints
+            .values()
+            .map(|checkpoint| checkpoint.started_at)
+            .min()
+            .unwrap_or(finished_at);
+        let record = KeyRotationRecord {
+            old_key_id,
+            new_key_id,
+            finished_at,
+            rows_rewritten: checkpoints.values().map(|checkpoint| checkpoint.rows).sum(),
+            duration: (finished_at - started_at).to_std().unwrap_or_default(),
+        };
+        let mut value = record.to_value();
+
+        encdec::encrypt_value_in_place(
+            &self.key,
+            &mut *self.nonces(),
+            &mut value,
+            Compression::None,
+        )?;
+
+        self.store
+            .insert_data(
+                &self.tables.meta,
+                vec![(
+                    Key::Timestamp(finished_at),
+                    DataRow::Map(HashMap::from([("rotation".to_owned(), value)])),
+                )],
+            )
+            .await?;
+        self.store.delete_schema(&self.tables.rotation).await?;
+        self.clear_schema_cache();
+
+        Ok(())
+    }
+
+    /// Starts changing the key without taking the store offline.
+    ///
+    /// Only the key check is rewritten here; the returned store writes with the new key right
+    /// away, and reads with the new key first and the old one second. Rows are then rewritten a
+    /// batch at a time by [`EncryptedStore::continue_key_change`], e.g. from a background task,
+    /// while the store serves requests as usual.
+    ///
+    /// Until the key change finished, the store only opens with the new key, and must be given
+    /// the old one with [`EncryptedStore::with_previous_key`]. Index scans of deterministically
+    /// encrypted values are unavailable in the meantime, since a value has a ciphertext under
+    /// each key.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store fails to rewrite the key check, or if another key change is
+    /// in progress.
+    pub async fn start_key_change(mut self, new_key: UnboundKey) -> Result<Self, Error> {
+        if let Some(previous) = &self.previous_keys {
+            return Err(Error::KeyChangeInProgress {
+                old_key_id: key_id(&previous.key),
+                new_key_id: key_id(&self.key),
+            });
+        }
+
+        let mut rotation = KeyRotation::new(&self.key, LessSafeKey::new(new_key), true);
+        in_transaction(&mut self, async |store: &mut Self, autocommit| {
+            let mut checkpoints = store.resume_checkpoints(&mut rotation).await?;
+
+            if !checkpoints
+                .remove(&store.tables.meta)
+                .is_some_and(|checkpoint| checkpoint.done)
+            {
+                let table = RotatedTable::new(store.tables.meta.clone(), false);
+
+                store
+                    .rotate_tables(
+                        autocommit,
+                        &rotation,
+                        vec![table],
+                        1,
+                        &CancellationToken::new(),
+                        &mut KeyChangeProgress::default(),
+                        &mut |_| {},
+                    )
+                    .await?;
+            }
+
+            Ok(())
+        })
+        .await?;
+
+        let previous_keys = encdec::KeySet {
+            key: self.key,
+            name_key: self.name_key,
+            value_key: self.value_key,
+            identity_key: self.identity_key,
+            ciphers: self.ciphers,
+        };
+
+        Ok(Self {
+            key: rotation.keys.key,
+            name_key: rotation.keys.name_key,
+            value_key: rotation.keys.value_key,
+            identity_key: rotation.keys.identity_key,
+            ciphers: rotation.keys.ciphers,
+            previous_keys: Some(previous_keys),
+            ..self
+        })
+    }
+
+    /// Rewrites the next batch of rows of an online key change with the new key.
+    ///
+    /// Returns `true` once every row was rewritten, at which point the store stops reading with
+    /// the old key, and the old key can be discarded.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store fails to fetch, decrypt, or re-encrypt the data, or if the
+    /// key change in progress isn't the one this store is making.
+    pub async fn continue_key_change(&mut self) -> Result<bool, Error> {
+        let Some(previous) = &self.previous_keys else {
+            return Ok(true);
+        };
+
+        let old_key_id = key_id(&previous.key);
+        let new_key_id = key_id(&self.key);
+
+        // the batch is read in the transaction it's written in
+        let rewritten = in_transaction(self, async |store: &mut Self, _| {
+            store
+                .rewrite_next_batch(old_key_id.clone(), new_key_id.clone())
+                .await
+        })
+        .await?;
+
+        if !rewritten {
+            return Ok(false);
+        }
+
+        self.finish_key_change(old_key_id, new_key_id).await?;
+        self.previous_keys = None;
+
+        Ok(true)
+    }
+
+    /// Rewrites the next batch of [`EncryptedStore::continue_key_change`] in the transaction it
+    /// began.
+    ///
+    /// Returns whether every table was rewritten.
+    async fn rewrite_next_batch(
+        &mut self,
+        old_key_id: String,
+        new_key_id: String,
+    ) -> Result<bool, Error> {
+        let mut checkpoints = self.fetch_checkpoints().await?;
+
+        if let Some(checkpoint) = checkpoints.values().next() {
+            if !checkpoint.online
+                || checkpoint.old_key_id != old_key_id
+                || checkpoint.new_key_id != new_key_id
+            {
+                return Err(Error::KeyChangeInProgress {
+                    old_key_id: checkpoint.old_key_id.clone(),
+                    new_key_id: checkpoint.new_key_id.clone(),
+                });
+            }
+        }
+
+        let started_at = checkpoints
+            .values()
+            .map(|checkpoint| checkpoint.started_at)
+            .min()
+            .unwrap_or_else(|| Utc::now().naive_utc());
+        let next = self.rotated_tables(true).await?.into_iter().find_map(
+            |(table_name, encrypts_row_keys)| {
+                let checkpoint = checkpoints.remove(&table_name);
+
+                RotatedTable::resume(table_name, encrypts_row_keys, checkpoint)
+            },
+        );
+
+        let Some(mut table) = next else {
+            return Ok(true);
+        };
+
+        (table.revealed_name, table.columns) = self.revealed_columns(&table.table_name).await?;
+        self.prepare_nonces().await?;
+
+        let batch = if table.verifying {
+            self.scan_old_rows(&table).await?
+        } else {
+            self.scan_batch(&table, &self.key).await?
+        };
+        let reached_end = batch.len() < self.batch_size;
+        // the rows the app wrote while the key changed are under the new key, but a scan
+        // resumed past the last row done can still miss some, e.g. in stores that don't scan in
+        // key order, so a table rewritten in place is only done once a scan of it whole finds
+        // none left under the old key
+        let done = reached_end && (table.encrypts_row_keys || table.verifying);
+        let keys = self.row_keys();
+        let rewritten = rewrite_batch(
+            &table,
+            keys,
+            encdec::RowKeys {
+                previous: None,
+                ..keys
+            },
+            self.batch_nonces()?,
+            batch,
+            self.compression,
+        )?;
+
+        if rewritten.last_key.is_some() {
+            table.last_key.clone_from(&rewritten.last_key);
+        }
+
+        let checkpoint = Checkpoint {
+            old_key_id,
+            new_key_id,
+            last_key: table.last_key,
+            done,
+            online: true,
+            verifying: table.verifying || reached_end,
+            rows: table.rows + rewritten.rows.len() as u64,
+            started_at,
+        };
+
+        self.write_batches(
+            vec![(&table.table_name, rewritten)],
+            vec![(Key::Str(table.table_name.clone()), checkpoint.to_row()?)],
+        )
+        .await?;
+
+        Ok(false)
+    }
+
+    /// Returns the checkpoints the key change can resume from, creating the table holding them
+    /// if needed.
+    ///
+    /// A resumed key change keeps the time it started at.
+    async fn resume_checkpoints(
+        &mut self,
+        rotation: &mut KeyRotation,
+    ) -> Result<HashMap<String, Checkpoint>, Error> {
+        let mut checkpoints = self.fetch_checkpoints().await?;
+
+        if let Some(checkpoint) = checkpoints.values().next() {
+            if !checkpoint.online
+                && checkpoint.old_key_id != PLAINTEXT_KEY_ID
+                && checkpoint.old_key_id != checkpoint.new_key_id
+                && checkpoint.new_key_id == rotation.old_key_id
+            {
+                // the key change to the current key finished, but its checkpoints weren't cleared
+                self.store.delete_schema(&self.tables.rotation).await?;
+                self.clear_schema_cache();
+                checkpoints.clear();
+            } else if !rotation.records(checkpoint) {
+                return Err(Error::KeyChangeInProgress {
+                    old_key_id: checkpoint.old_key_id.clone(),
+                    new_key_id: checkpoint.new_key_id.clone(),
+                });
+            }
+        }
+
+        if let Some(started_at) = checkpoints
+            .values()
+            .map(|checkpoint| checkpoint.started_at)
+            .min()
+        {
+            rotation.started_at = started_at;
+        }
+
+        self.insert_rotation_schema().await?;
+
+        Ok(checkpoints)
+    }
+
+    /// Creates the table holding checkpoints if it doesn't exist.
+    async fn insert_rotation_schema(&mut self) -> Result<(), Error> {
+        if self
+            .store
+            .fetch_schema(&self.tables.rotation)
+            .await?
+            .is_none()
+        {
+            self.store
+                .insert_schema(&Schema {
+                    table_name: self.tables.rotation.clone(),
+                    column_defs: None,
+                    indexes: vec![],
+                    engine: None,
+                    foreign_keys: vec![],
+                    comment: Some("Table to store the progress of key changes".to_string()),
+                })
+                .await?;
+            self.clear_schema_cache();
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites tables with the new key, `concurrency` of them at once, in rounds of a batch
+    /// from each, committing each round if `autocommit` began the transaction it's in.
+    ///
+    /// Returns whether every table was rewritten, or `false` if it was cancelled.
+    #[allow(clippy::too_many_arguments)]
+    async fn rotate_tables(
+        &mut self,
+        autocommit: bool,
+        rotation: &KeyRotation,
+        tables: Vec<RotatedTable>,
+        concurrency: usize,
+        cancel: &CancellationToken,
+        progress: &mut KeyChangeProgress,
+        on_progress: &mut impl FnMut(KeyChangeProgress),
+    ) -> Result<bool, Error> {
+        let mut pending = tables.into_iter();
+        let mut active = pending.by_ref().take(concurrency).collect::<Vec<_>>();
+
+        while !active.is_empty() {
+            cancel.unpaused().await;
+
+            if cancel.is_cancelled() {
+                return Ok(false);
+            }
+
+            self.prepare_nonces().await?;
+
+            let batches = future::try_join_all(
+                active
+                    .iter()
+                    .map(|table| self.scan_batch(table, &rotation.keys.key)),
+            )
+            .await?;
+            let mut nonces = Vec::with_capacity(batches.len());
+
+            for _ in &batches {
+                nonces.push(self.batch_nonces()?);
+            }
+
+            let keys = self.row_keys();
+            let new_keys = rotation.keys.row_keys();
+            let compression = self.compression;
+            let rewrite =
+                |table: &RotatedTable, batch: Vec<(Key, DataRow)>, nonces: encdec::BatchNonces| {
+                    rewrite_batch(table, keys, new_keys, nonces, batch, compression)
+                };
+
+            // the rows are re-encrypted on a thread per table, but written from this one
+            let rewritten = if active.len() == 1 {
+                batches
+                    .into_iter()
+                    .zip(nonces)
+                    .map(|(batch, nonces)| rewrite(&active[0], batch, nonces))
+                    .collect::<Result<Vec<_>, _>>()?
+            } else {
+                thread::scope(|scope| {
+                    // collected so every table is spawned before the first one is joined
+                    #[allow(clippy::needless_collect)]
+                    let handles = active
+                        .iter()
+                        .zip(batches)
+                        .zip(nonces)
+                        .map(|((table, batch), nonces)| {
+                            scope.spawn(move || rewrite(table, batch, nonces))
+                        })
+                        .collect::<Vec<_>>();
+
+                    handles
+                        .into_iter()
+                        .map(|handle| {
+                            handle
+                                .join()
+                                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                        })
+                        .collect::<Result<Vec<_>, _>>()
+                })?
+            };
+
+            // a short batch is the last one of its table
+            let mut done = Vec::with_capacity(active.len());
+            let mut checkpoints = Vec::with_capacity(active.len());
+
+            for (table, batch) in active.iter_mut().zip(&rewritten) {
+                let table_done = batch.rows.len() < self.batch_size;
+
+                if batch.last_key.is_some() {
+                    table.last_key.clone_from(&batch.last_key);
+                }
+
+                table.rows += batch.rows.len() as u64;
+                checkpoints.push((
+                    Key::Str(table.table_name.clone()),
+                    rotation.checkpoint(table, table_done).to_row()?,
+                ));
+                done.push(table_done);
+
+                progress.rows_done += batch.rows.len() as u64;
+                progress.bytes_rewritten += batch.bytes;
+            }
+
+            self.write_batches(
+                active
+                    .iter()
+                    .map(|table| table.table_name.as_str())
+                    .zip(rewritten)
+                    .collect(),
+                checkpoints,
+            )
+            .await?;
+            self.commit_batch(autocommit).await?;
+
+            progress.tables_done += done.iter().filter(|&&done| done).count();
+            active = active
+                .into_iter()
+                .zip(done)
+                .filter_map(|(table, done)| (!done).then_some(table))
+                .collect();
+            active.extend(pending.by_ref().take(concurrency - active.len()));
+
+            on_progress(*progress);
+        }
+
+        Ok(true)
+    }
+    /// Writes batches of rewritten rows, along with the checkpoints recording them, in the
+    /// transaction the batches were read in.
+    async fn write_batches(
+        &mut self,
+        batches: Vec<(&str, RewrittenBatch)>,
+        checkpoints: Vec<(Key, DataRow)>,
+    ) -> Result<(), Error> {
+        for (table_name, batch) in batches {
+            if !batch.rows.is_empty() {
+                self.store.insert_data(table_name, batch.rows).await?;
+            }
+
+            if !batch.moved.is_empty() {
+                self.store.delete_data(table_name, batch.moved).await?;
+            }
+        }
+
+        if !checkpoints.is_empty() {
+            self.store
+                .insert_data(&self.tables.rotation, checkpoints)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Encrypts the data of an inner store that was used without encryption, so an existing
+    /// database can be adopted without exporting and importing it.
+    ///
+    /// Open the plaintext store with [`EncryptedStore::new`] and the policy it's meant to have,
+    /// then call this before anything else. Rows are encrypted a batch at a time, each batch
+    /// committed along with a checkpoint, and calling this again after an interruption resumes
+    /// where it stopped. Tables whose names the policy pseudonymizes are moved to their
+    /// pseudonyms.
+    ///
+    /// Schemas can't be rewritten in place, so the column defaults of tables that keep their
+    /// names are left in plain text.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store fails to fetch, encrypt, or write the data, or if a key
+    /// change was interrupted and must be resumed first.
+    pub async fn encrypt_existing_store(&mut self) -> Result<(), Error> {
+        in_transaction(self, async |store: &mut Self, autocommit| {
+            store.encrypt_tables(autocommit).await
+        })
+        .await
+    }
+
+    /// Encrypts the tables of [`EncryptedStore::encrypt_existing_store`] in the transaction it
+    /// began.
+    async fn encrypt_tables(&mut self, autocommit: bool) -> Result<(), Error> {
+        let key_id = key_id(&self.key);
+        let mut checkpoints = self.fetch_checkpoints().await?;
+
+        if let Some(checkpoint) = checkpoints.values().next() {
+            if checkpoint.old_key_id != PLAINTEXT_KEY_ID || checkpoint.new_key_id != key_id {
+                return Err(Error::KeyChangeInProgress {
+                    old_key_id: checkpoint.old_key_id.clone(),
+                    new_key_id: checkpoint.new_key_id.clone(),
+                });
+            }
+        }
+
+        let started_at = checkpoints
+            .values()
+            .map(|checkpoint| checkpoint.started_at)
+            .min()
+            .unwrap_or_else(|| Utc::now().naive_utc());
+
+        self.insert_rotation_schema().await?;
+
+        for schema in self.store.fetch_all_schemas().await? {
+            let table_name = schema.table_name.clone();
+
+            if self.tables.contains(&table_name)
+                || checkpoints
+                    .get(&table_name)
+                    .is_some_and(|checkpoint| checkpoint.done)
+                // tables already moved to their pseudonym
+                || self.reveal_name(table_name.clone()).await? != table_name
+            {
+                continue;
+            }
+
+            let inner_table_name = self.inner_table_name(&table_name).into_owned();
+            let moved = inner_table_name != table_name;
+
+            if moved {
+                if self.store.fetch_schema(&inner_table_name).await?.is_none() {
+                    StoreMut::insert_schema(self, &schema).await?;
+                }
+            } else if !self.encrypts_table(&table_name) {
+                continue;
+            }
+
+            // moved rows leave the table, and rows under encrypted keys are told apart by them
+            let encrypts_row_keys = !moved && self.encrypts_row_keys(&table_name);
+            let checkpoint = checkpoints.remove(&table_name);
+            let Some(mut table) = RotatedTable::resume(table_name, encrypts_row_keys, checkpoint)
+            else {
+                continue;
+            };
+
+            loop {
+                let batch = self.scan_batch(&table, &self.key).await?;
+                let done = batch.len() < self.batch_size;
+
+                self.encrypt_plaintext_batch(&mut table, moved, batch, done, started_at)
+                    .await?;
+                self.commit_batch(autocommit).await?;
+
+                if done {
+                    break;
+                }
+            }
+        }
+
+        self.store.delete_schema(&self.tables.rotation).await?;
+        self.clear_schema_cache();
+
+        Ok(())
+    }
+
+    /// Writes a batch of rows of a plaintext table through the encrypting write path, and
+    /// deletes what's left of them under their plaintext table name or key.
+    ///
+    /// Rows encrypted by an interrupted call are decrypted first, so they're written as-is.
+    async fn encrypt_plaintext_batch(
+        &mut self,
+        table: &mut RotatedTable,
+        moved: bool,
+        batch: Vec<(Key, DataRow)>,
+        done: bool,
+        started_at: NaiveDateTime,
+    ) -> Result<(), Error> {
+        let table_name = table.table_name.clone();
+        let encrypts_table = self.encrypts_table(&table_name);
+        let columns = self.read_columns(&table_name).await?;
+        let mut rows = Vec::with_capacity(batch.len());
+        let mut plaintext_keys = Vec::new();
+
+        for (key, mut row) in batch {
+            if encrypts_table {
+                encdec::decrypt_row_in_place(self.row_keys(), &mut row, self.compression)?;
+
+                if self.policy.has_tokenized_columns(&table_name) {
+                    self.detokenize_row(&table_name, &columns, &mut row).await?;
+                }
+            }
+
+            if moved || table.encrypts_row_keys {
+                plaintext_keys.push(key.clone());
+            } else {
+                table.last_key = Some(key.clone());
+            }
+
+            rows.push((key, row));
+        }
+
+        table.rows += rows.len() as u64;
+
+        let checkpoint = Checkpoint {
+            old_key_id: PLAINTEXT_KEY_ID.to_owned(),
+            new_key_id: key_id(&self.key),
+            last_key: table.last_key.clone(),
+            done,
+            online: false,
+            verifying: false,
+            rows: table.rows,
+            started_at,
+        };
+
+        if !rows.is_empty() {
+            StoreMut::insert_data(self, &table_name, rows).await?;
+        }
+
+        if !plaintext_keys.is_empty() {
+            self.store.delete_data(&table_name, plaintext_keys).await?;
+        }
+
+        if moved && done {
+            self.store.delete_schema(&table_name).await?;
+            self.clear_schema_cache();
+        }
+
+        self.store
+            .insert_data(
+                &self.tables.rotation,
+                vec![(Key::Str(table_name.clone()), checkpoint.to_row()?)],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Decrypts every row back to plain text and returns the inner store, e.g. to stop using
+    /// the `EncryptedStore` or take a plaintext snapshot for debugging.
+    ///
+    /// Rows are decrypted a batch at a time, each batch committed in a transaction if the inner
+    /// store supports them. Rows already decrypted are left as-is, so calling this again after
+    /// an interruption finishes the job. Tables with pseudonymized names move back to their real
+    /// names, and the internal tables are deleted last, the key check being the very last.
+    ///
+    /// Schemas can't be rewritten in place, so the column defaults of tables that keep their
+    /// names stay encrypted, and so do custom functions.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store fails to fetch, decrypt, or write the data, or if a key
+    /// change was interrupted and must be resumed first.
+    pub async fn decrypt_into_plaintext(mut self) -> Result<S, Error> {
+        in_transaction(&mut self, async |store: &mut Self, autocommit| {
+            store.decrypt_tables(autocommit).await
+        })
+        .await?;
+
+        Ok(self.store)
+    }
+
+    /// Decrypts the tables of [`EncryptedStore::decrypt_into_plaintext`] in the transaction it
+    /// began.
+    async fn decrypt_tables(&mut self, autocommit: bool) -> Result<(), Error> {
+        if let Some(checkpoint) = self.fetch_checkpoints().await?.into_values().next() {
+            return Err(Error::KeyChangeInProgress {
+                old_key_id: checkpoint.old_key_id,
+                new_key_id: checkpoint.new_key_id,
+            });
+        }
+
+        for schema in self.store.fetch_all_schemas().await? {
+            if self.tables.contains(&schema.table_name) {
+                continue;
+            }
+
+            let inner_table_name = schema.table_name.clone();
+            let table_name = self.reveal_name(inner_table_name.clone()).await?;
+            let moved = table_name != inner_table_name;
+
+            if !moved && !self.encrypts_table(&table_name) {
+                continue;
+            }
+
+            if moved && self.store.fetch_schema(&table_name).await?.is_none() {
+                let mut schema = self.reveal_schema(schema).await?;
+
+                self.decrypt_defaults(&mut schema)?;
+                self.store.insert_schema(&schema).await?;
+                self.clear_schema_cache();
+            }
+
+            let mut last_key = None;
+
+            loop {
+                let (done, next_key) = self
+                    .decrypt_plaintext_batch(&table_name, &inner_table_name, last_key)
+                    .await?;
+                self.commit_batch(autocommit).await?;
+
+                if done {
+                    break;
+                }
+
+                last_key = next_key;
+            }
+        }
+
+        let tables = &self.tables;
+
+        for table_name in [
+            &tables.vault,
+            &tables.names,
+            &tables.rotation,
+            &tables.audit,
+            &tables.meta,
+        ] {
+            if self.store.fetch_schema(table_name).await?.is_some() {
+                self.store.delete_schema(table_name).await?;
+                self.clear_schema_cache();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decrypts the next batch of rows of a table in place, or into the table of its real name
+    /// if it's pseudonymized, and returns whether it was the last one along with the last row
+    /// left in place.
+    ///
+    /// Rows under encrypted keys, or in a pseudonymized table, move to their plaintext key and
+    /// table, so only the others are skipped past.
+    async fn decrypt_plaintext_batch(
+        &mut self,
+        table_name: &str,
+        inner_table_name: &str,
+        last_key: Option<Key>,
+    ) -> Result<(bool, Option<Key>), Error> {
+        let moved = table_name != inner_table_name;
+        let encrypts_table = self.encrypts_table(table_name);
+        let encrypts_row_keys = self.encrypts_row_keys(table_name);
+        let batch = self
+            .store
+            .scan_data(inner_table_name)
+            .await?
+            .try_skip_while(|(key, _)| {
+                future::ready(Ok(last_key.as_ref().is_some_and(|last| key != last)))
+            })
+            .skip(usize::from(last_key.is_some()))
+            .try_filter(|(key, _)| {
+                future::ready(moved || !encrypts_row_keys || encdec::is_key_of(&self.key, key))
+            })
+            .take(self.batch_size)
+            .try_collect::<Vec<_>>()
+            .await?;
+        let done = batch.len() < self.batch_size;
+        let columns = self.read_columns(table_name).await?;
+        let mut rows = Vec::with_capacity(batch.len());
+        let mut encrypted_keys = Vec::new();
+        let mut last_key = last_key;
+
+        for (key, mut row) in batch {
+            let plaintext_key = if encrypts_row_keys && encdec::is_key_of(&self.key, &key) {
+                encdec::decrypt_row_key(self.row_keys(), key.clone())?
+            } else {
+                key.clone()
+            };
+
+            if encrypts_table {
+                encdec::decrypt_row_in_place(self.row_keys(), &mut row, self.compression)?;
+
+                if self.policy.has_tokenized_columns(table_name) {
+                    self.detokenize_row(table_name, &columns, &mut row).await?;
+                }
+            }
+
+            if moved || plaintext_key != key {
+                encrypted_keys.push(key);
+            } else {
+                last_key = Some(key);
+            }
+
+            rows.push((plaintext_key, row));
+        }
+
+        if !rows.is_empty() {
+            self.store.insert_data(table_name, rows).await?;
+        }
+
+        if !encrypted_keys.is_empty() {
+            self.store
+                .delete_data(inner_table_name, encrypted_keys)
+                .await?;
+        }
+
+        if moved && done {
+            self.store.delete_schema(inner_table_name).await?;
+            self.clear_schema_cache();
+        }
+
+        Ok((done, last_key))
+    }
+
+    /// Re-encrypts the rows of a table under the current key with fresh nonces, e.g. after a
+    /// nonce sequence was found to repeat, or to rewrite values encrypted by an older version.
+    ///
+    /// Values encrypted deterministically stay the same, and so do the keys of rows. The store
+    /// has a single key, so a table can't be moved to a key of its own: use
+    /// [`EncryptedStore::change_key`] to rotate the key of every table.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store fails to fetch, decrypt, or re-encrypt the data, or if a key
+    /// change was interrupted and must be resumed first.
+    pub async fn reencrypt_table(&mut self, table_name: &str) -> Result<(), Error> {
+        if !self.encrypts_table(table_name) {
+            return Ok(());
+        }
+
+        in_transaction(self, async |store: &mut Self, autocommit| {
+            store.reencrypt_rows(autocommit, table_name).await
+        })
+        .await
+    }
+
+    /// Rewrites the rows of [`EncryptedStore::reencrypt_table`] in the transaction it began.
+    async fn reencrypt_rows(&mut self, autocommit: bool, table_name: &str) -> Result<(), Error> {
+        let key_id = key_id(&self.key);
+
+        // tables halfway through a key change can't be opened with the current key alone
+        if let Some(checkpoint) = self.fetch_checkpoints().await?.into_values().next() {
+            if checkpoint.new_key_id != key_id {
+                return Err(Error::KeyChangeInProgress {
+                    old_key_id: checkpoint.old_key_id,
+                    new_key_id: checkpoint.new_key_id,
+                });
+            }
+        }
+
+        // row keys are encrypted deterministically, so every row is rewritten in place
+        let mut table = RotatedTable::new(self.inner_table_name(table_name).into_owned(), false);
+        (table.revealed_name, table.columns) = self.revealed_columns(&table.table_name).await?;
+
+        loop {
+            self.prepare_nonces().await?;
+
+            let batch = self.scan_batch(&table, &self.key).await?;
+            let done = batch.len() < self.batch_size;
+            let keys = self.row_keys();
+            let rewritten = rewrite_batch(
+                &table,
+                keys,
+                encdec::RowKeys {
+                    previous: None,
+                    ..keys
+                },
+                self.batch_nonces()?,
+                batch,
+                self.compression,
+            )?;
+
+            if rewritten.last_key.is_some() {
+                table.last_key.clone_from(&rewritten.last_key);
+            }
+
+            self.write_batches(vec![(&table.table_name, rewritten)], Vec::new())
+                .await?;
+            self.commit_batch(autocommit).await?;
+
+            if done {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<
+        S: Store + StoreMut + Transaction + AlterTable + MaybeSendSync,
+        NonceSeq: NonceSequence + MaybeSendSync,
+    > EncryptedStore<S, NonceSeq>
+{
+    /// Like [`EncryptedStore::change_key`], but copies each table with the new key into a
+    /// staging table, and only swaps the copy in once it's complete and holds as many rows as the
+    /// table, so the original data is untouched until then.
+    ///
+    /// The swap deletes the table and renames the copy in a transaction if the inner store
+    /// supports them. Tables are copied one at a time, so the store needs room for a second copy
+    /// of its largest table.
+    ///
+    /// If a staged key change is interrupted, calling this again with the same new key resumes
+    /// it from the table it stopped at, copying that table again from the start.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error like [`EncryptedStore::change_key`], or if a copy doesn't match its
+    /// table.
+    pub async fn change_key_staged(mut self, new_key: UnboundKey) -> Result<Self, Error> {
+        let mut rotation = KeyRotation::new(&self.key, LessSafeKey::new(new_key), false);
+        let mut checkpoints = self.resume_checkpoints(&mut rotation).await?;
+
+        // once the internal tables are being swapped, the other tables are done and their names
+        // can't be revealed anymore
+        let user_tables = !checkpoints
+            .keys()
+            .any(|table_name| self.tables.contains(table_name));
+
+        for (table_name, encrypts_row_keys) in self.rotated_tables(user_tables).await? {
+            let checkpoint = checkpoints.remove(&table_name);
+
+            if let Some(table) = RotatedTable::resume(table_name, encrypts_row_keys, checkpoint) {
+                self.stage_table(&rotation, table).await?;
+            }
+        }
+
+        let mut store = Self {
+            key: rotation.keys.key,
+            name_key: rotation.keys.name_key,
+            value_key: rotation.keys.value_key,
+            identity_key: rotation.keys.identity_key,
+            ciphers: rotation.keys.ciphers,
+            previous_keys: None,
+            ..self
+        };
+
+        store
+            .finish_key_change(rotation.old_key_id, rotation.new_key_id)
+            .await?;
+
+        Ok(store)
+    }
+
+    /// Copies a table into a staging table with the new key, then swaps the copy in.
+    async fn stage_table(
+        &mut self,
+        rotation: &KeyRotation,
+        mut table: RotatedTable,
+    ) -> Result<(), Error> {
+        let staging_name = format!("{}{}", self.tables.staging_prefix, table.table_name);
+
+        // a copy left by an interrupted key change may be missing rows
+        if self.store.fetch_schema(&staging_name).await?.is_some() {
+            self.store.delete_schema(&staging_name).await?;
+            self.clear_schema_cache();
+        }
+
+        let Some(schema) = self.store.fetch_schema(&table.table_name).await? else {
+            return Ok(());
+        };
+
+        (table.revealed_name, table.columns) = self.revealed_columns(&table.table_name).await?;
+
+        self.store
+            .insert_schema(&Schema {
+                table_name: staging_name.clone(),
+                ..schema
+            })
+            .await?;
+        self.clear_schema_cache();
+
+        // the table keeps its rows until the swap, so the scan skips past the last one copied
+        let mut scanned = RotatedTable::new(table.table_name.clone(), false);
+
+        loop {
+            self.prepare_nonces().await?;
+
+            let batch = self.scan_batch(&scanned, &rotation.keys.key).await?;
+            let done = batch.len() < self.batch_size;
+
+            scanned.last_key = batch.last().map(|(key, _)| key.clone());
+
+            let nonces = self.batch_nonces()?;
+            let rewritten = rewrite_batch(
+                &table,
+                self.row_keys(),
+                rotation.keys.row_keys(),
+                nonces,
+                batch,
+                self.compression,
+            )?;
+
+            table.rows += rewritten.rows.len() as u64;
+            self.write_batches(
+                vec![(
+                    &staging_name,
+                    RewrittenBatch {
+                        moved: Vec::new(),
+                        ..rewritten
+                    },
+                )],
+                Vec::new(),
+            )
+            .await?;
+
+            if done {
+                break;
+            }
+        }
+
+        let found = self
+            .store
+            .scan_data(&staging_name)
+            .await?
+            .try_fold(0, |count, _| future::ready(Ok(count + 1)))
+            .await?;
+
+        if found != table.rows {
+            return Err(Error::StagedCopyMismatch {
+                table: self.reveal_name(table.table_name).await?,
+                expected: table.rows,
+                found,
+            });
+        }
+
+        let checkpoint = rotation.checkpoint(&table, true).to_row()?;
+        in_transaction(self, async |store: &mut Self, _| {
+            store.store.delete_schema(&table.table_name).await?;
+            store
+                .store
+                .rename_schema(&staging_name, &table.table_name)
+                .await?;
+            store.clear_schema_cache();
+            store
+                .store
+                .insert_data(
+                    &store.tables.rotation,
+                    vec![(Key::Str(table.table_name.clone()), checkpoint)],
+                )
+                .await?;
+
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{pin::pin, task::Context};
+
+    use futures::{task::noop_waker_ref, FutureExt};
+
+    use super::CancellationToken;
+
+    #[test]
+    fn paused_key_changes_wait_with_a_single_waker() {
+        let token = CancellationToken::new();
+        let mut cx = Context::from_waker(noop_waker_ref());
+        token.pause();
+
+        let mut unpaused = pin!(token.unpaused());
+        for _ in 0..3 {
+            assert!(unpaused.poll_unpin(&mut cx).is_pending());
+        }
+        assert_eq!(token.pause_state().waiters.len(), 1);
+
+        token.resume();
+        assert!(unpaused.poll_unpin(&mut cx).is_ready());
+        assert!(token.pause_state().waiters.is_empty());
+    }
+}
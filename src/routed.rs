@@ -0,0 +1,324 @@
+use async_trait::async_trait;
+use gluesql_core::{
+    ast::{ColumnDef, IndexOperator, OrderByExpr},
+    data::{CustomFunction as StructCustomFunction, Key, Schema, Value},
+    error::Result,
+    executor::Referencing,
+    store::{
+        AlterTable, CustomFunction, CustomFunctionMut, DataRow, Index, IndexMut, MetaIter,
+        Metadata, RowIter, Store, StoreMut, Transaction,
+    },
+};
+
+use crate::{Error, MaybeSendSync, TableFilter};
+
+/// Sends some tables to a plain store and every other table to an encrypted one, presenting
+/// both as a single store.
+///
+/// Useful to keep e.g. caches unencrypted next to encrypted user data. Custom functions are
+/// always kept in the encrypted store. Transactions are started on both stores, but aren't
+/// atomic across them.
+#[derive(Debug)]
+pub struct RoutedStore<E, P> {
+    encrypted: E,
+    plain: P,
+    plain_tables: TableFilter,
+}
+
+impl<E, P> RoutedStore<E, P> {
+    /// Creates the `RoutedStore`, sending the tables selected by `plain_tables` to `plain`.
+    pub const fn new(encrypted: E, plain: P, plain_tables: TableFilter) -> Self {
+        Self {
+            encrypted,
+            plain,
+            plain_tables,
+        }
+    }
+
+    /// Returns the encrypted and plain stores.
+    pub fn into_stores(self) -> (E, P) {
+        (self.encrypted, self.plain)
+    }
+
+    fn is_plain(&self, table_name: &str) -> bool {
+        self.plain_tables.contains(table_name)
+    }
+}
+
+#[async_trait(?Send)]
+impl<E: Store + MaybeSendSync, P: Store + MaybeSendSync> Store for RoutedStore<E, P> {
+    async fn fetch_schema(&self, table_name: &str) -> Result<Option<Schema>> {
+        if self.is_plain(table_name) {
+            self.plain.fetch_schema(table_name).await
+        } else {
+            self.encrypted.fetch_schema(table_name).await
+        }
+    }
+
+    async fn fetch_all_schemas(&self) -> Result<Vec<Schema>> {
+        let mut schemas = self.encrypted.fetch_all_schemas().await?;
+        schemas.retain(|schema| !self.is_plain(&schema.table_name));
+
+        let plain = self.plain.fetch_all_schemas().await?;
+        schemas.extend(
+            plain
+                .into_iter()
+                .filter(|schema| self.is_plain(&schema.table_name)),
+        );
+
+        schemas.sort_by(|a, b| a.table_name.cmp(&b.table_name));
+
+        Ok(schemas)
+    }
+
+    async fn fetch_data(&self, table_name: &str, key: &Key) -> Result<Option<DataRow>> {
+        if self.is_plain(table_name) {
+            self.plain.fetch_data(table_name, key).await
+        } else {
+            self.encrypted.fetch_data(table_name, key).await
+        }
+    }
+
+    async fn scan_data(&self, table_name: &str) -> Result<RowIter<'_>> {
+        if self.is_plain(table_name) {
+            self.plain.scan_data(table_name).await
+        } else {
+            self.encrypted.scan_data(table_name).await
+        }
+    }
+
+    async fn fetch_referencings(&self, table_name: &str) -> Result<Vec<Referencing>> {
+        // referencing tables may live in either store
+        let mut referencings = self.encrypted.fetch_referencings(table_name).await?;
+        referencings.retain(|referencing| !self.is_plain(&referencing.table_name));
+
+        let plain = self.plain.fetch_referencings(table_name).await?;
+        referencings.extend(
+            plain
+                .into_iter()
+                .filter(|referencing| self.is_plain(&referencing.table_name)),
+        );
+
+        Ok(referencings)
+    }
+}
+
+#[async_trait(?Send)]
+impl<E: StoreMut + MaybeSendSync, P: StoreMut + MaybeSendSync> StoreMut for RoutedStore<E, P> {
+    async fn insert_schema(&mut self, schema: &Schema) -> Result<()> {
+        if self.is_plain(&schema.table_name) {
+            self.plain.insert_schema(schema).await
+        } else {
+            self.encrypted.insert_schema(schema).await
+        }
+    }
+
+    async fn delete_schema(&mut self, table_name: &str) -> Result<()> {
+        if self.is_plain(table_name) {
+            self.plain.delete_schema(table_name).await
+        } else {
+            self.encrypted.delete_schema(table_name).await
+        }
+    }
+
+    async fn append_data(&mut self, table_name: &str, rows: Vec<DataRow>) -> Result<()> {
+        if self.is_plain(table_name) {
+            self.plain.append_data(table_name, rows).await
+        } else {
+            self.encrypted.append_data(table_name, rows).await
+        }
+    }
+
+    async fn insert_data(&mut self, table_name: &str, rows: Vec<(Key, DataRow)>) -> Result<()> {
+        if self.is_plain(table_name) {
+            self.plain.insert_data(table_name, rows).await
+        } else {
+            self.encrypted.insert_data(table_name, rows).await
+        }
+    }
+
+    async fn delete_data(&mut self, table_name: &str, keys: Vec<Key>) -> Result<()> {
+        if self.is_plain(table_name) {
+            self.plain.delete_data(table_name, keys).await
+        } else {
+            self.encrypted.delete_data(table_name, keys).await
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<E: AlterTable + MaybeSendSync, P: AlterTable + MaybeSendSync> AlterTable
+    for RoutedStore<E, P>
+{
+    async fn rename_schema(&mut self, table_name: &str, new_table_name: &str) -> Result<()> {
+        match (self.is_plain(table_name), self.is_plain(new_table_name)) {
+            (true, true) => self.plain.rename_schema(table_name, new_table_name).await,
+            (false, false) => {
+                self.encrypted
+                    .rename_schema(table_name, new_table_name)
+                    .await
+            }
+            _ => {
+                Err(Error::Unsupported("renaming a table to a name routed to another store").into())
+            }
+        }
+    }
+
+    async fn rename_column(
+        &mut self,
+        table_name: &str,
+        column_name: &str,
+        new_column_name: &str,
+    ) -> Result<()> {
+        if self.is_plain(table_name) {
+            self.plain
+                .rename_column(table_name, column_name, new_column_name)
+                .await
+        } else {
+            self.encrypted
+                .rename_column(table_name, column_name, new_column_name)
+                .await
+        }
+    }
+
+    async fn add_column(&mut self, table_name: &str, column_def: &ColumnDef) -> Result<()> {
+        if self.is_plain(table_name) {
+            self.plain.add_column(table_name, column_def).await
+        } else {
+            self.encrypted.add_column(table_name, column_def).await
+        }
+    }
+
+    async fn drop_column(
+        &mut self,
+        table_name: &str,
+        column_name: &str,
+        if_exists: bool,
+    ) -> Result<()> {
+        if self.is_plain(table_name) {
+            self.plain
+                .drop_column(table_name, column_name, if_exists)
+                .await
+        } else {
+            self.encrypted
+                .drop_column(table_name, column_name, if_exists)
+                .await
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<E: Index + MaybeSendSync, P: Index + MaybeSendSync> Index for RoutedStore<E, P> {
+    async fn scan_indexed_data(
+        &self,
+        table_name: &str,
+        index_name: &str,
+        asc: Option<bool>,
+        cmp_value: Option<(&IndexOperator, Value)>,
+    ) -> Result<RowIter<'_>> {
+        if self.is_plain(table_name) {
+            self.plain
+                .scan_indexed_data(table_name, index_name, asc, cmp_value)
+                .await
+        } else {
+            self.encrypted
+                .scan_indexed_data(table_name, index_name, asc, cmp_value)
+                .await
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<E: IndexMut + MaybeSendSync, P: IndexMut + MaybeSendSync> IndexMut for RoutedStore<E, P> {
+    async fn create_index(
+        &mut self,
+        table_name: &str,
+        index_name: &str,
+        column: &OrderByExpr,
+    ) -> Result<()> {
+        if self.is_plain(table_name) {
+            self.plain
+                .create_index(table_name, index_name, column)
+                .await
+        } else {
+            self.encrypted
+                .create_index(table_name, index_name, column)
+                .await
+        }
+    }
+
+    async fn drop_index(&mut self, table_name: &str, index_name: &str) -> Result<()> {
+        if self.is_plain(table_name) {
+            self.plain.drop_index(table_name, index_name).await
+        } else {
+            self.encrypted.drop_index(table_name, index_name).await
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<E: Metadata + MaybeSendSync, P: Metadata + MaybeSendSync> Metadata for RoutedStore<E, P> {
+    async fn scan_table_meta(&self) -> Result<MetaIter> {
+        let mut meta = Vec::new();
+
+        for entry in self.encrypted.scan_table_meta().await? {
+            if !matches!(&entry, Ok((table_name, _)) if self.is_plain(table_name)) {
+                meta.push(entry);
+            }
+        }
+
+        for entry in self.plain.scan_table_meta().await? {
+            if matches!(&entry, Ok((table_name, _)) if self.is_plain(table_name)) {
+                meta.push(entry);
+            }
+        }
+
+        Ok(Box::new(meta.into_iter()))
+    }
+}
+
+#[async_trait(?Send)]
+impl<E: Transaction + MaybeSendSync, P: Transaction + MaybeSendSync> Transaction
+    for RoutedStore<E, P>
+{
+    async fn begin(&mut self, autocommit: bool) -> Result<bool> {
+        let encrypted = self.encrypted.begin(autocommit).await?;
+        let plain = self.plain.begin(autocommit).await?;
+
+        Ok(encrypted || plain)
+    }
+
+    async fn commit(&mut self) -> Result<()> {
+        self.encrypted.commit().await?;
+        self.plain.commit().await
+    }
+
+    async fn rollback(&mut self) -> Result<()> {
+        self.encrypted.rollback().await?;
+        self.plain.rollback().await
+    }
+}
+
+#[async_trait(?Send)]
+impl<E: CustomFunction + MaybeSendSync, P: MaybeSendSync> CustomFunction for RoutedStore<E, P> {
+    async fn fetch_function(&self, func_name: &str) -> Result<Option<&StructCustomFunction>> {
+        self.encrypted.fetch_function(func_name).await
+    }
+
+    async fn fetch_all_functions(&self) -> Result<Vec<&StructCustomFunction>> {
+        self.encrypted.fetch_all_functions().await
+    }
+}
+
+#[async_trait(?Send)]
+impl<E: CustomFunctionMut + MaybeSendSync, P: MaybeSendSync> CustomFunctionMut
+    for RoutedStore<E, P>
+{
+    async fn insert_function(&mut self, func: StructCustomFunction) -> Result<()> {
+        self.encrypted.insert_function(func).await
+    }
+
+    async fn delete_function(&mut self, func_name: &str) -> Result<()> {
+        self.encrypted.delete_function(func_name).await
+    }
+}
@@ -0,0 +1,52 @@
+use std::{
+    collections::HashMap,
+    sync::{MutexGuard, PoisonError},
+};
+
+use gluesql_core::data::Schema;
+use ring::aead::NonceSequence;
+
+use crate::EncryptedStore;
+
+/// Schemas as `fetch_schema` and `fetch_all_schemas` last returned them, with their names
+/// revealed and their defaults decrypted.
+#[derive(Debug, Default)]
+pub struct SchemaCache {
+    /// Schemas by table name, `None` for tables that don't exist.
+    tables: HashMap<String, Option<Schema>>,
+    all: Option<Vec<Schema>>,
+}
+
+impl<S, NonceSeq: NonceSequence> EncryptedStore<S, NonceSeq> {
+    fn schema_cache(&self) -> MutexGuard<'_, SchemaCache> {
+        self.schemas.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    #[allow(clippy::option_option)]
+    pub(crate) fn cached_schema(&self, table_name: &str) -> Option<Option<Schema>> {
+        self.schema_cache().tables.get(table_name).cloned()
+    }
+
+    pub(crate) fn cache_schema(&self, table_name: &str, schema: Option<Schema>) {
+        self.schema_cache()
+            .tables
+            .insert(table_name.to_owned(), schema);
+    }
+
+    pub(crate) fn cached_schemas(&self) -> Option<Vec<Schema>> {
+        self.schema_cache().all.clone()
+    }
+
+    pub(crate) fn cache_schemas(&self, schemas: Vec<Schema>) {
+        self.schema_cache().all = Some(schemas);
+    }
+
+    /// Forgets the schemas read so far, so the next reads fetch them from the inner store again.
+    ///
+    /// Schema changes made through the store are picked up on their own. Call this after the
+    /// schemas of the inner store were changed some other way, e.g. by another client sharing
+    /// it.
+    pub fn clear_schema_cache(&self) {
+        *self.schema_cache() = SchemaCache::default();
+    }
+}
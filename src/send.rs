@@ -0,0 +1,28 @@
+//! What the `send` feature asks of the types an `EncryptedStore` is generic over.
+//!
+//! The feature makes the store itself `Send + Sync`, and the futures of [`NonceSource`]s
+//! `Send`, but not the futures of the store: gluesql 0.16 declares its store traits
+//! `?Send`, so those stay `!Send` and have to be awaited on the thread that polls them first,
+//! e.g. with a `LocalSet` in a multithreaded runtime.
+//!
+//! [`NonceSource`]: crate::NonceSource
+
+/// `Send + Sync` with the `send` feature, and implemented by every type without it.
+///
+/// The inner store and nonce sequence of an `EncryptedStore`, and the stores a
+/// [`RoutedStore`](crate::RoutedStore) routes to, must implement it for the store to implement
+/// the store traits, so that with the feature on, the store can be moved to and shared between
+/// threads, e.g. by a multithreaded server. Only the store is, not the futures its store traits
+/// return.
+#[cfg(feature = "send")]
+pub trait MaybeSendSync: Send + Sync {}
+
+#[cfg(feature = "send")]
+impl<T: Send + Sync + ?Sized> MaybeSendSync for T {}
+
+/// Implemented by every type, since the `send` feature is off.
+#[cfg(not(feature = "send"))]
+pub trait MaybeSendSync {}
+
+#[cfg(not(feature = "send"))]
+impl<T: ?Sized> MaybeSendSync for T {}
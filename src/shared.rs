@@ -0,0 +1,245 @@
+use std::{
+    fmt::{self, Debug, Formatter},
+    ops::Deref,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use gluesql_core::{
+    ast::{ColumnDef, IndexOperator, OrderByExpr},
+    data::{CustomFunction as StructCustomFunction, Key, Schema, Value},
+    error::Result,
+    executor::Referencing,
+    store::{
+        AlterTable, CustomFunction, CustomFunctionMut, DataRow, Index, IndexMut, MetaIter,
+        Metadata, RowIter, Store, StoreMut, Transaction,
+    },
+};
+use ring::aead::NonceSequence;
+
+use crate::{EncryptedStore, Error, MaybeSendSync, RandomNonce};
+
+const READ_ONLY: Error = Error::Unsupported("writing through a SharedStore");
+
+/// An [`EncryptedStore`] shared by any number of tasks or threads, each running read queries
+/// through its own clone, e.g. in its own `Glue`.
+///
+/// Reads only take a shared reference to the store, and its caches are behind locks, so clones
+/// read concurrently. Writes, and explicit transactions, fail with [`Error::Unsupported`]; they
+/// go through the store taken back with [`SharedStore::try_unwrap`].
+///
+/// The store is `Send` and `Sync` if its inner store is too, and its nonce sequence is `Send`.
+pub struct SharedStore<S, NonceSeq: NonceSequence = RandomNonce>(Arc<EncryptedStore<S, NonceSeq>>);
+
+impl<S, NonceSeq: NonceSequence> EncryptedStore<S, NonceSeq> {
+    /// Shares the store, see [`SharedStore`].
+    #[must_use]
+    pub fn into_shared(self) -> SharedStore<S, NonceSeq> {
+        SharedStore(Arc::new(self))
+    }
+}
+
+impl<S, NonceSeq: NonceSequence> SharedStore<S, NonceSeq> {
+    /// Takes the store back, if this is the last clone.
+    ///
+    /// # Errors
+    ///
+    /// Returns the shared store if it still has other clones.
+    pub fn try_unwrap(self) -> Result<EncryptedStore<S, NonceSeq>, Self> {
+        Arc::try_unwrap(self.0).map_err(Self)
+    }
+}
+
+impl<S, NonceSeq: NonceSequence> Clone for SharedStore<S, NonceSeq> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<S, NonceSeq: NonceSequence> Deref for SharedStore<S, NonceSeq> {
+    type Target = EncryptedStore<S, NonceSeq>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<S: Debug, NonceSeq: NonceSequence> Debug for SharedStore<S, NonceSeq> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SharedStore").field(&self.0).finish()
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync> Store
+    for SharedStore<S, NonceSeq>
+{
+    async fn fetch_schema(&self, table_name: &str) -> Result<Option<Schema>> {
+        self.0.fetch_schema(table_name).await
+    }
+
+    async fn fetch_all_schemas(&self) -> Result<Vec<Schema>> {
+        self.0.fetch_all_schemas().await
+    }
+
+    async fn fetch_data(&self, table_name: &str, key: &Key) -> Result<Option<DataRow>> {
+        self.0.fetch_data(table_name, key).await
+    }
+
+    async fn scan_data(&self, table_name: &str) -> Result<RowIter<'_>> {
+        self.0.scan_data(table_name).await
+    }
+
+    async fn fetch_referencings(&self, table_name: &str) -> Result<Vec<Referencing>> {
+        self.0.fetch_referencings(table_name).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync> StoreMut
+    for SharedStore<S, NonceSeq>
+{
+    async fn insert_schema(&mut self, _schema: &Schema) -> Result<()> {
+        Err(READ_ONLY.into())
+    }
+
+    async fn delete_schema(&mut self, _table_name: &str) -> Result<()> {
+        Err(READ_ONLY.into())
+    }
+
+    async fn append_data(&mut self, _table_name: &str, _rows: Vec<DataRow>) -> Result<()> {
+        Err(READ_ONLY.into())
+    }
+
+    async fn insert_data(&mut self, _table_name: &str, _rows: Vec<(Key, DataRow)>) -> Result<()> {
+        Err(READ_ONLY.into())
+    }
+
+    async fn delete_data(&mut self, _table_name: &str, _keys: Vec<Key>) -> Result<()> {
+        Err(READ_ONLY.into())
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync> AlterTable
+    for SharedStore<S, NonceSeq>
+{
+    async fn rename_schema(&mut self, _table_name: &str, _new_table_name: &str) -> Result<()> {
+        Err(READ_ONLY.into())
+    }
+
+    async fn rename_column(
+        &mut self,
+        _table_name: &str,
+        _column_name: &str,
+        _new_column_name: &str,
+    ) -> Result<()> {
+        Err(READ_ONLY.into())
+    }
+
+    async fn add_column(&mut self, _table_name: &str, _column_def: &ColumnDef) -> Result<()> {
+        Err(READ_ONLY.into())
+    }
+
+    async fn drop_column(
+        &mut self,
+        _table_name: &str,
+        _column_name: &str,
+        _if_exists: bool,
+    ) -> Result<()> {
+        Err(READ_ONLY.into())
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: Index + Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync> Index
+    for SharedStore<S, NonceSeq>
+{
+    async fn scan_indexed_data(
+        &self,
+        table_name: &str,
+        index_name: &str,
+        asc: Option<bool>,
+        cmp_value: Option<(&IndexOperator, Value)>,
+    ) -> Result<RowIter<'_>> {
+        self.0
+            .scan_indexed_data(table_name, index_name, asc, cmp_value)
+            .await
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync> IndexMut
+    for SharedStore<S, NonceSeq>
+{
+    async fn create_index(
+        &mut self,
+        _table_name: &str,
+        _index_name: &str,
+        _column: &OrderByExpr,
+    ) -> Result<()> {
+        Err(READ_ONLY.into())
+    }
+
+    async fn drop_index(&mut self, _table_name: &str, _index_name: &str) -> Result<()> {
+        Err(READ_ONLY.into())
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: Metadata + Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync> Metadata
+    for SharedStore<S, NonceSeq>
+{
+    async fn scan_table_meta(&self) -> Result<MetaIter> {
+        self.0.scan_table_meta().await
+    }
+}
+
+/// Reads don't need a transaction, so the ones gluesql begins around each statement aren't
+/// started. Explicit transactions are refused like writes.
+#[async_trait(?Send)]
+impl<S: Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync> Transaction
+    for SharedStore<S, NonceSeq>
+{
+    async fn begin(&mut self, autocommit: bool) -> Result<bool> {
+        if autocommit {
+            Ok(false)
+        } else {
+            Err(Error::Unsupported("transactions on a SharedStore").into())
+        }
+    }
+
+    async fn commit(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn rollback(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: CustomFunction + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync> CustomFunction
+    for SharedStore<S, NonceSeq>
+{
+    async fn fetch_function(&self, func_name: &str) -> Result<Option<&StructCustomFunction>> {
+        self.0.fetch_function(func_name).await
+    }
+
+    async fn fetch_all_functions(&self) -> Result<Vec<&StructCustomFunction>> {
+        self.0.fetch_all_functions().await
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: CustomFunction + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync> CustomFunctionMut
+    for SharedStore<S, NonceSeq>
+{
+    async fn insert_function(&mut self, _func: StructCustomFunction) -> Result<()> {
+        Err(READ_ONLY.into())
+    }
+
+    async fn delete_function(&mut self, _func_name: &str) -> Result<()> {
+        Err(READ_ONLY.into())
+    }
+}
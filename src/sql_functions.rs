@@ -0,0 +1,178 @@
+use gluesql_core::{
+    ast::{AstLiteral, Expr, Function, Query, SelectItem, SetExpr, Statement, UnaryOperator},
+    data::Value,
+    error::Result,
+    prelude::{Glue, Payload},
+    store::{GStore, GStoreMut},
+};
+
+use crate::{encdec, Error, ValueCipher};
+
+/// Evaluates `ENCRYPT()` and `DECRYPT()` in the statements it executes, with a [`ValueCipher`],
+/// e.g. to encrypt a few values of a table the policy of the store leaves in plain text.
+///
+/// gluesql's own custom functions are written in SQL, so these are evaluated around the
+/// statement instead, where they may appear:
+///
+/// - `ENCRYPT(<literal>)` as a value of `INSERT ... VALUES` or of an `UPDATE` assignment, which
+///   is replaced by its ciphertext before the statement runs. Ciphertexts are `BYTEA` values, so
+///   the column must be one too.
+/// - `DECRYPT(<expr>)` as a column of a `SELECT`, whose values are decrypted once it ran. Values
+///   that aren't ciphertexts are left as they are.
+///
+/// Anywhere else, they're left to gluesql, which fails to find them.
+#[derive(Debug)]
+pub struct CipherFunctions {
+    cipher: ValueCipher,
+}
+
+impl CipherFunctions {
+    #[must_use]
+    pub const fn new(cipher: ValueCipher) -> Self {
+        Self { cipher }
+    }
+
+    /// Like `Glue::execute`, evaluating `ENCRYPT()` and `DECRYPT()` along the way.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a statement fails like in `Glue::execute`, or if a value fails to be
+    /// encrypted or decrypted.
+    pub async fn execute<T: GStore + GStoreMut>(
+        &self,
+        glue: &mut Glue<T>,
+        sql: impl AsRef<str>,
+    ) -> Result<Vec<Payload>> {
+        let mut payloads = Vec::new();
+
+        for mut statement in glue.plan(sql).await? {
+            let decrypted = self.rewrite(&mut statement)?;
+            let mut payload = glue.execute_stmt(&statement).await?;
+
+            if let Payload::Select { labels, rows } = &mut payload {
+                for label in &decrypted {
+                    let Some(column) = labels.iter().position(|name| name == label) else {
+                        continue;
+                    };
+
+                    for row in rows.iter_mut() {
+                        row[column] = self.cipher.decrypt_value(&row[column])?;
+                    }
+                }
+            }
+
+            payloads.push(payload);
+        }
+
+        Ok(payloads)
+    }
+
+    /// Replaces the `ENCRYPT()` calls of a statement by their ciphertext, and the `DECRYPT()`
+    /// columns by their argument, returning the labels of the latter.
+    fn rewrite(&self, statement: &mut Statement) -> Result<Vec<String>, Error> {
+        match statement {
+            Statement::Insert { source, .. } => {
+                if let SetExpr::Values(values) = &mut source.body {
+                    for expr in values.0.iter_mut().flatten() {
+                        self.encrypt_call(expr)?;
+                    }
+                }
+
+                Ok(Vec::new())
+            }
+            Statement::Update { assignments, .. } => {
+                for assignment in assignments {
+                    self.encrypt_call(&mut assignment.value)?;
+                }
+
+                Ok(Vec::new())
+            }
+            Statement::Query(query) => Ok(decrypt_columns(query)),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    fn encrypt_call(&self, expr: &mut Expr) -> Result<(), Error> {
+        let Some(literal) = call_argument(expr, "ENCRYPT") else {
+            return Ok(());
+        };
+        let Some(literal) = literal_value(literal) else {
+            return Err(Error::Unsupported("ENCRYPT() of anything but a literal"));
+        };
+        let value = match &literal {
+            AstLiteral::Boolean(value) => Value::Bool(*value),
+            AstLiteral::QuotedString(value) => Value::Str(value.clone()),
+            AstLiteral::HexString(hex) => Value::Bytea(encdec::from_hex(hex)?),
+            AstLiteral::Null => Value::Null,
+            AstLiteral::Number(number) => {
+                let number = number.to_string();
+
+                match number.parse() {
+                    Ok(number) => Value::I64(number),
+                    Err(_) => Value::F64(number.parse().map_err(|_| Error::InvalidValue)?),
+                }
+            }
+        };
+        // values left unencrypted, like NULL, are inserted as they are
+        *expr = match self.cipher.encrypt_value(&value)? {
+            Value::Bytea(encrypted) => {
+                Expr::Literal(AstLiteral::HexString(encdec::to_hex(&encrypted)))
+            }
+            _ => Expr::Literal(literal),
+        };
+
+        Ok(())
+    }
+}
+
+/// Returns the only argument of a call to the given function.
+fn call_argument<'a>(expr: &'a Expr, name: &str) -> Option<&'a Expr> {
+    match expr {
+        Expr::Function(function) => match &**function {
+            Function::Custom {
+                name: called,
+                exprs,
+            } if called.eq_ignore_ascii_case(name) => match exprs.as_slice() {
+                [argument] => Some(argument),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Returns the literal an expression is made of, folding the minus the parser reads negative
+/// numbers with.
+fn literal_value(expr: &Expr) -> Option<AstLiteral> {
+    match expr {
+        Expr::Literal(literal) => Some(literal.clone()),
+        Expr::UnaryOp {
+            op: UnaryOperator::Minus,
+            expr,
+        } => match &**expr {
+            Expr::Literal(AstLiteral::Number(number)) => Some(AstLiteral::Number(-number)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Replaces the `DECRYPT()` columns of a query by their argument, returning their labels.
+fn decrypt_columns(query: &mut Query) -> Vec<String> {
+    let SetExpr::Select(select) = &mut query.body else {
+        return Vec::new();
+    };
+    let mut labels = Vec::new();
+
+    for item in &mut select.projection {
+        if let SelectItem::Expr { expr, label } = item {
+            if let Some(argument) = call_argument(expr, "DECRYPT") {
+                *expr = argument.clone();
+                labels.push(label.clone());
+            }
+        }
+    }
+
+    labels
+}
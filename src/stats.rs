@@ -0,0 +1,107 @@
+use futures::TryStreamExt;
+use gluesql_core::store::Store;
+use ring::aead::NonceSequence;
+
+use crate::{encdec, EncryptedStore, EncryptionMode, Error, MaybeSendSync};
+
+/// What encryption costs in storage, as measured by [`EncryptedStore::stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StorageStats {
+    /// The encrypted tables, internal ones left out.
+    pub tables: Vec<TableStats>,
+}
+
+/// Sizes of the sealed values of an encrypted table. In row mode, every row is a single sealed
+/// value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableStats {
+    pub table_name: String,
+    pub mode: EncryptionMode,
+    pub rows: u64,
+    /// Values sealed, leaving out those the policy leaves as-is.
+    pub sealed_values: u64,
+    /// Bytes of the encoded plaintexts.
+    pub plaintext_bytes: u64,
+    /// Bytes of the plaintexts once compressed, which is what gets sealed.
+    pub compressed_bytes: u64,
+    /// Bytes of the ciphertexts as stored.
+    pub ciphertext_bytes: u64,
+}
+
+impl TableStats {
+    /// Returns the bytes sealing adds to a value on average, on top of its compressed
+    /// plaintext: the envelope, nonce and tag.
+    #[must_use]
+    pub fn overhead_per_value(&self) -> u64 {
+        (self.ciphertext_bytes - self.compressed_bytes)
+            .checked_div(self.sealed_values)
+            .unwrap_or(0)
+    }
+
+    /// Returns the size of the compressed plaintexts relative to the plaintexts, below 1 when
+    /// compression pays off.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)] // a ratio needs no more precision than f64 has
+    pub fn compression_ratio(&self) -> f64 {
+        if self.plaintext_bytes == 0 {
+            return 1.0;
+        }
+
+        self.compressed_bytes as f64 / self.plaintext_bytes as f64
+    }
+}
+
+impl<S: Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>
+    EncryptedStore<S, NonceSeq>
+{
+    /// Measures the plaintext, compressed and ciphertext sizes of the values of every encrypted
+    /// table, e.g. to weigh column mode against row mode or decide on compression.
+    ///
+    /// Every sealed value is opened and compressed again to be measured, so this reads the
+    /// whole store and takes about as long as decrypting it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store fails to scan the data, or a value fails to open.
+    pub async fn stats(&self) -> Result<StorageStats, Error> {
+        let mut stats = StorageStats::default();
+
+        for schema in self.store.fetch_all_schemas().await? {
+            if self.tables.contains(&schema.table_name) {
+                continue;
+            }
+
+            let table_name = self.reveal_name(schema.table_name.clone()).await?;
+
+            if !self.encrypts_table(&table_name) {
+                continue;
+            }
+
+            let mut rows = self.store.scan_data(&schema.table_name).await?;
+            let mut table = TableStats {
+                mode: self.policy.table_mode(&table_name),
+                table_name,
+                rows: 0,
+                sealed_values: 0,
+                plaintext_bytes: 0,
+                compressed_bytes: 0,
+                ciphertext_bytes: 0,
+            };
+
+            while let Some((_, row)) = rows.try_next().await? {
+                table.rows += 1;
+
+                for len in encdec::measure_row(self.row_keys(), row, self.compression)? {
+                    table.sealed_values += 1;
+                    table.plaintext_bytes += len.plaintext as u64;
+                    table.compressed_bytes += len.compressed as u64;
+                    table.ciphertext_bytes += len.ciphertext as u64;
+                }
+            }
+
+            stats.tables.push(table);
+        }
+
+        Ok(stats)
+    }
+}
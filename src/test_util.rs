@@ -0,0 +1,120 @@
+//! Helpers for tests of code using an `EncryptedStore`, behind the `test-util` feature.
+//!
+//! They trade security for convenience, and must never be used outside of tests.
+
+pub mod conformance;
+
+use gluesql_core::store::{Store, StoreMut};
+use rand_chacha::{
+    rand_core::{RngCore, SeedableRng},
+    ChaCha20Rng,
+};
+use ring::aead::{Nonce, NonceSequence, UnboundKey, NONCE_LEN};
+
+use crate::{EncryptedStore, Error, MaybeSendSync};
+
+/// Random nonces from a `ChaCha20` generator seeded by the OS, to open stores without
+/// [`RandomNonce`](crate::RandomNonce).
+pub struct RandNonce(pub ChaCha20Rng);
+
+impl RandNonce {
+    #[must_use]
+    pub fn new() -> Self {
+        Self(ChaCha20Rng::from_os_rng())
+    }
+}
+
+impl Default for RandNonce {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NonceSequence for RandNonce {
+    fn advance(&mut self) -> Result<Nonce, ring::error::Unspecified> {
+        let mut nonce = [0; 12];
+        self.0.fill_bytes(&mut nonce);
+        Ok(Nonce::assume_unique_for_key(nonce))
+    }
+}
+
+/// Returns an AES-256-GCM key of all zeroes, the same on every call.
+///
+/// # Panics
+///
+/// Never, since the key fits the algorithm.
+#[must_use]
+pub fn new_key() -> UnboundKey {
+    let algorithm = &ring::aead::AES_256_GCM;
+    let key_bytes = &[0; 32];
+    UnboundKey::new(algorithm, key_bytes).unwrap()
+}
+
+/// Nonces counting up from a base derived from a seed, so a store sealing the same values in
+/// the same order always seals them into the same ciphertexts.
+///
+/// Nonces never repeat within a sequence, but two sequences from the same seed give out the
+/// same ones, so they must never seal anything but test data under a real key.
+#[derive(Debug, Clone)]
+pub struct SeededNonce {
+    base: [u8; NONCE_LEN],
+    counter: u64,
+}
+
+impl SeededNonce {
+    #[must_use]
+    pub fn new(seed: u64) -> Self {
+        let mut base = [0; NONCE_LEN];
+        ChaCha20Rng::seed_from_u64(seed).fill_bytes(&mut base);
+
+        Self { base, counter: 0 }
+    }
+}
+
+impl NonceSequence for SeededNonce {
+    fn advance(&mut self) -> Result<Nonce, ring::error::Unspecified> {
+        let mut nonce = self.base;
+
+        // the last bytes are left to the nonces the store derives for a batch
+        for (byte, counter) in nonce.iter_mut().zip(self.counter.to_be_bytes()) {
+            *byte ^= counter;
+        }
+
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or(ring::error::Unspecified)?;
+
+        Ok(Nonce::assume_unique_for_key(nonce))
+    }
+}
+
+/// Returns an AES-256-GCM key derived from a seed, the same for every call with that seed.
+///
+/// # Panics
+///
+/// Never, since the key fits the algorithm.
+#[must_use]
+pub fn seeded_key(seed: u64) -> UnboundKey {
+    let mut key_bytes = [0; 32];
+    ChaCha20Rng::seed_from_u64(seed).fill_bytes(&mut key_bytes);
+
+    UnboundKey::new(&ring::aead::AES_256_GCM, &key_bytes).unwrap()
+}
+
+/// Opens a store with [`seeded_key`] and [`SeededNonce`] from the same seed, e.g. for snapshot
+/// tests of what the inner store holds.
+///
+/// Given the same seed and statements, the stores write the same rows to their inner store,
+/// but for the time the store was created at, kept in its metadata, and `MAP` values, whose
+/// entries are sealed in no set order.
+///
+/// # Errors
+///
+/// Returns an error if the store can't be opened, see [`EncryptedStore::new`].
+pub async fn deterministic_store<S: Store + StoreMut + MaybeSendSync>(
+    store: S,
+    seed: u64,
+) -> Result<EncryptedStore<S, SeededNonce>, Error> {
+    EncryptedStore::new_with_nonce_sequence(store, seeded_key(seed), SeededNonce::new(seed)).await
+}
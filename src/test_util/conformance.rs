@@ -0,0 +1,350 @@
+//! Checks that an `EncryptedStore` wrapping a custom inner store behaves like a plain gluesql
+//! store, run against a fresh store each by [`generate_conformance_tests!`].
+//!
+//! Every check panics on the first statement that doesn't give the expected result, the way
+//! the `gluesql-test-suite` tests do.
+//!
+//! [`generate_conformance_tests!`]: crate::generate_conformance_tests
+
+use gluesql_core::{
+    data::Value,
+    prelude::{Glue, Payload},
+    store::{GStore, GStoreMut, Store},
+};
+use ring::aead::NonceSequence;
+
+use crate::{in_transaction, EncryptedStore, MaybeSendSync};
+
+/// Generates a test per check of this module, each given a new store by `$new_store`, an async
+/// function returning an empty `EncryptedStore`.
+///
+/// Inner stores don't all support indexes, so their check is only generated when `with_indexes`
+/// is passed.
+///
+/// ```ignore
+/// async fn new_store() -> EncryptedStore<MyStorage, RandNonce> {
+///     EncryptedStore::new_with_nonce_sequence(MyStorage::default(), new_key(), RandNonce::new())
+///         .await
+///         .unwrap()
+/// }
+///
+/// gluesql_encryption::generate_conformance_tests!(tokio::test, new_store, with_indexes);
+/// ```
+#[macro_export]
+macro_rules! generate_conformance_tests {
+    ($test: meta, $new_store: path, with_indexes) => {
+        $crate::generate_conformance_tests!($test, $new_store);
+
+        #[$test]
+        async fn conformance_indexes() {
+            $crate::test_util::conformance::indexes($new_store().await).await;
+        }
+    };
+    ($test: meta, $new_store: path) => {
+        #[$test]
+        async fn conformance_schemas() {
+            $crate::test_util::conformance::schemas($new_store().await).await;
+        }
+
+        #[$test]
+        async fn conformance_primary_keys() {
+            $crate::test_util::conformance::primary_keys($new_store().await).await;
+        }
+
+        #[$test]
+        async fn conformance_defaults() {
+            $crate::test_util::conformance::defaults($new_store().await).await;
+        }
+
+        #[$test]
+        async fn conformance_bytea_columns() {
+            $crate::test_util::conformance::bytea_columns($new_store().await).await;
+        }
+    };
+}
+
+async fn execute<S, NonceSeq: NonceSequence>(
+    glue: &mut Glue<EncryptedStore<S, NonceSeq>>,
+    sql: &str,
+) -> Vec<Payload>
+where
+    EncryptedStore<S, NonceSeq>: GStore + GStoreMut,
+{
+    glue.execute(sql)
+        .await
+        .unwrap_or_else(|error| panic!("{sql}: {error}"))
+}
+
+async fn select<S, NonceSeq: NonceSequence>(
+    glue: &mut Glue<EncryptedStore<S, NonceSeq>>,
+    sql: &str,
+    labels: &[&str],
+    rows: Vec<Vec<Value>>,
+) where
+    EncryptedStore<S, NonceSeq>: GStore + GStoreMut,
+{
+    assert_eq!(
+        execute(glue, sql).await,
+        vec![Payload::Select {
+            labels: labels.iter().map(ToString::to_string).collect(),
+            rows,
+        }],
+        "{sql}",
+    );
+}
+
+/// Fails if a row the store just wrote is left in plain text in the inner store.
+async fn assert_encrypted<S: Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>(
+    store: &mut EncryptedStore<S, NonceSeq>,
+) where
+    EncryptedStore<S, NonceSeq>: GStore + GStoreMut,
+{
+    let report = in_transaction(store, async |store: &mut EncryptedStore<S, NonceSeq>, _| {
+        store.plaintext_report().await
+    })
+    .await
+    .unwrap();
+
+    assert!(report.is_clean(), "plaintext rows in {:?}", report.tables);
+}
+
+/// Creates, alters and drops tables, and checks the store lists them, and only them, with their
+/// columns.
+///
+/// # Panics
+///
+/// If the store doesn't behave as expected.
+pub async fn schemas<S: Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>(
+    store: EncryptedStore<S, NonceSeq>,
+) where
+    EncryptedStore<S, NonceSeq>: GStore + GStoreMut,
+{
+    let mut glue = Glue::new(store);
+
+    execute(
+        &mut glue,
+        "CREATE TABLE Person (id INTEGER, name TEXT NULL);",
+    )
+    .await;
+    execute(&mut glue, "CREATE TABLE Pet (id INTEGER);").await;
+    execute(&mut glue, "ALTER TABLE Pet ADD COLUMN owner INTEGER NULL;").await;
+    execute(&mut glue, "ALTER TABLE Pet RENAME TO Animal;").await;
+
+    let mut schemas = glue.storage.fetch_all_schemas().await.unwrap();
+    schemas.sort_by(|a, b| a.table_name.cmp(&b.table_name));
+    let tables = schemas
+        .iter()
+        .map(|schema| schema.table_name.as_str())
+        .collect::<Vec<_>>();
+
+    assert_eq!(tables, ["Animal", "Person"]);
+
+    let columns = schemas[0]
+        .column_defs
+        .iter()
+        .flatten()
+        .map(|column| column.name.as_str())
+        .collect::<Vec<_>>();
+
+    assert_eq!(columns, ["id", "owner"]);
+
+    execute(&mut glue, "INSERT INTO Animal VALUES (1, NULL);").await;
+    select(
+        &mut glue,
+        "SELECT * FROM Animal;",
+        &["id", "owner"],
+        vec![vec![Value::I64(1), Value::Null]],
+    )
+    .await;
+
+    execute(&mut glue, "DROP TABLE Animal;").await;
+
+    assert!(glue.storage.fetch_schema("Animal").await.unwrap().is_none());
+    assert_encrypted(&mut glue.storage).await;
+}
+
+/// Writes, updates and deletes rows of a table with a primary key, and looks them up by key.
+///
+/// # Panics
+///
+/// If the store doesn't behave as expected.
+pub async fn primary_keys<S: Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>(
+    store: EncryptedStore<S, NonceSeq>,
+) where
+    EncryptedStore<S, NonceSeq>: GStore + GStoreMut,
+{
+    let mut glue = Glue::new(store);
+
+    execute(
+        &mut glue,
+        "CREATE TABLE Item (id INTEGER PRIMARY KEY, name TEXT);",
+    )
+    .await;
+    execute(
+        &mut glue,
+        "INSERT INTO Item VALUES (3, 'c'), (1, 'a'), (2, 'b');",
+    )
+    .await;
+    execute(&mut glue, "UPDATE Item SET name = 'B' WHERE id = 2;").await;
+    execute(&mut glue, "DELETE FROM Item WHERE id = 3;").await;
+
+    select(
+        &mut glue,
+        "SELECT name FROM Item WHERE id = 2;",
+        &["name"],
+        vec![vec![Value::Str("B".to_owned())]],
+    )
+    .await;
+    select(
+        &mut glue,
+        "SELECT id, name FROM Item ORDER BY id;",
+        &["id", "name"],
+        vec![
+            vec![Value::I64(1), Value::Str("a".to_owned())],
+            vec![Value::I64(2), Value::Str("B".to_owned())],
+        ],
+    )
+    .await;
+
+    assert_encrypted(&mut glue.storage).await;
+}
+
+/// Fills in column defaults on insert, and on existing rows when a column is added.
+///
+/// # Panics
+///
+/// If the store doesn't behave as expected.
+pub async fn defaults<S: Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>(
+    store: EncryptedStore<S, NonceSeq>,
+) where
+    EncryptedStore<S, NonceSeq>: GStore + GStoreMut,
+{
+    let mut glue = Glue::new(store);
+
+    execute(
+        &mut glue,
+        "CREATE TABLE Account (id INTEGER, name TEXT DEFAULT 'unnamed', credit INTEGER DEFAULT 1 + 1);",
+    )
+    .await;
+    execute(&mut glue, "INSERT INTO Account (id) VALUES (1);").await;
+    execute(
+        &mut glue,
+        "ALTER TABLE Account ADD COLUMN active BOOLEAN DEFAULT true;",
+    )
+    .await;
+
+    select(
+        &mut glue,
+        "SELECT * FROM Account;",
+        &["id", "name", "credit", "active"],
+        vec![vec![
+            Value::I64(1),
+            Value::Str("unnamed".to_owned()),
+            Value::I64(2),
+            Value::Bool(true),
+        ]],
+    )
+    .await;
+
+    assert_encrypted(&mut glue.storage).await;
+}
+
+/// Stores `BYTEA` values, which look like ciphertexts to the inner store, and filters on them.
+///
+/// # Panics
+///
+/// If the store doesn't behave as expected.
+pub async fn bytea_columns<S: Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>(
+    store: EncryptedStore<S, NonceSeq>,
+) where
+    EncryptedStore<S, NonceSeq>: GStore + GStoreMut,
+{
+    let mut glue = Glue::new(store);
+
+    execute(
+        &mut glue,
+        "CREATE TABLE Blob (id INTEGER, data BYTEA NULL);",
+    )
+    .await;
+    execute(
+        &mut glue,
+        "INSERT INTO Blob VALUES (1, X'0102'), (2, NULL);",
+    )
+    .await;
+
+    select(
+        &mut glue,
+        "SELECT id, data FROM Blob ORDER BY id;",
+        &["id", "data"],
+        vec![
+            vec![Value::I64(1), Value::Bytea(vec![1, 2])],
+            vec![Value::I64(2), Value::Null],
+        ],
+    )
+    .await;
+    select(
+        &mut glue,
+        "SELECT id FROM Blob WHERE data = X'0102';",
+        &["id"],
+        vec![vec![Value::I64(1)]],
+    )
+    .await;
+}
+
+/// Creates and drops indexes, and filters and orders rows through them.
+///
+/// # Panics
+///
+/// If the store doesn't behave as expected.
+pub async fn indexes<S: Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>(
+    store: EncryptedStore<S, NonceSeq>,
+) where
+    EncryptedStore<S, NonceSeq>: GStore + GStoreMut,
+{
+    let mut glue = Glue::new(store);
+
+    execute(
+        &mut glue,
+        "CREATE TABLE Score (id INTEGER, points INTEGER);",
+    )
+    .await;
+    execute(
+        &mut glue,
+        "INSERT INTO Score VALUES (1, 30), (2, 10), (3, 20);",
+    )
+    .await;
+    execute(&mut glue, "CREATE INDEX idx_points ON Score (points);").await;
+
+    let schema = glue.storage.fetch_schema("Score").await.unwrap().unwrap();
+
+    assert_eq!(schema.indexes.len(), 1);
+    assert_eq!(schema.indexes[0].name, "idx_points");
+
+    execute(&mut glue, "INSERT INTO Score VALUES (4, 40);").await;
+    select(
+        &mut glue,
+        "SELECT id FROM Score WHERE points > 15 ORDER BY points;",
+        &["id"],
+        vec![
+            vec![Value::I64(3)],
+            vec![Value::I64(1)],
+            vec![Value::I64(4)],
+        ],
+    )
+    .await;
+
+    execute(&mut glue, "DROP INDEX Score.idx_points;").await;
+
+    let schema = glue.storage.fetch_schema("Score").await.unwrap().unwrap();
+
+    assert!(schema.indexes.is_empty());
+    select(
+        &mut glue,
+        "SELECT COUNT(*) FROM Score WHERE points > 15;",
+        &["COUNT(*)"],
+        vec![vec![Value::I64(3)]],
+    )
+    .await;
+
+    assert_encrypted(&mut glue.storage).await;
+}
@@ -0,0 +1,95 @@
+//! Spans around the sealing and opening of rows, so operators can profile where encryption time
+//! goes. They only carry the table, row count and ciphertext size, never values, and compile to
+//! nothing without the `tracing` feature.
+
+use gluesql_core::store::DataRow;
+
+/// Target of the spans, e.g. to enable them with `gluesql_encryption::crypto=debug`.
+#[cfg(feature = "tracing")]
+const CRYPTO_TARGET: &str = "gluesql_encryption::crypto";
+
+/// A span entered until it's dropped.
+#[cfg(feature = "tracing")]
+pub struct CryptoSpan(tracing::span::EnteredSpan);
+
+#[cfg(not(feature = "tracing"))]
+pub struct CryptoSpan;
+
+#[cfg(feature = "tracing")]
+impl CryptoSpan {
+    /// Sealing a batch of rows written to a table.
+    pub(crate) fn encrypt_row(table: &str, rows: usize) -> Self {
+        Self(
+            tracing::debug_span!(
+                target: CRYPTO_TARGET,
+                "encrypt_row",
+                table,
+                rows,
+                bytes = tracing::field::Empty,
+            )
+            .entered(),
+        )
+    }
+
+    /// Opening rows read from a table.
+    pub(crate) fn decrypt_row(table: &str, rows: usize) -> Self {
+        Self(
+            tracing::debug_span!(
+                target: CRYPTO_TARGET,
+                "decrypt_row",
+                table,
+                rows,
+                bytes = tracing::field::Empty,
+            )
+            .entered(),
+        )
+    }
+
+    /// Re-encrypting a batch of rows of a table with a new key.
+    pub(crate) fn rekey_table(table: &str, rows: usize) -> Self {
+        Self(
+            tracing::debug_span!(
+                target: CRYPTO_TARGET,
+                "rekey_table",
+                table,
+                rows,
+                bytes = tracing::field::Empty,
+            )
+            .entered(),
+        )
+    }
+
+    /// Records the bytes of ciphertext of the rows sealed or opened.
+    pub(crate) fn record_rows<'a>(&self, rows: impl IntoIterator<Item = &'a DataRow>) {
+        let bytes = rows
+            .into_iter()
+            .map(crate::encdec::ciphertext_len)
+            .sum::<usize>();
+
+        self.record_bytes(bytes as u64);
+    }
+
+    pub(crate) fn record_bytes(&self, bytes: u64) {
+        self.0.record("bytes", bytes);
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+#[allow(clippy::unused_self, clippy::needless_pass_by_value)]
+impl CryptoSpan {
+    pub(crate) const fn encrypt_row(_: &str, _: usize) -> Self {
+        Self
+    }
+
+    pub(crate) const fn decrypt_row(_: &str, _: usize) -> Self {
+        Self
+    }
+
+    pub(crate) const fn rekey_table(_: &str, _: usize) -> Self {
+        Self
+    }
+
+    pub(crate) fn record_rows<'a>(&self, _: impl IntoIterator<Item = &'a DataRow>) {}
+
+    pub(crate) const fn record_bytes(&self, _: u64) {}
+}
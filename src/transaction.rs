@@ -0,0 +1,73 @@
+//! Transactions around what the store does outside of statements.
+//!
+//! Stores like sled only read and write in transactions, which `Glue` begins around each
+//! statement, but opening a store, bulk rewrites and everything else called on the store directly
+//! have to begin their own.
+
+use gluesql_core::store::Transaction;
+use ring::aead::NonceSequence;
+
+use crate::{EncryptedStore, Error, MaybeSendSync};
+
+/// Runs `f` in a transaction of `store`, committed if `f` succeeds and rolled back otherwise.
+///
+/// A transaction begun elsewhere is left to whoever began it: `f` is told whether it runs in a
+/// transaction of its own with `autocommit`, e.g. to commit it a batch at a time.
+///
+/// # Errors
+///
+/// Returns the error of `f`, or an error if the transaction can't be begun or ended.
+pub async fn in_transaction<S: Transaction, T>(
+    store: &mut S,
+    f: impl AsyncFnOnce(&mut S, bool) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let autocommit = store.begin(true).await?;
+    let done = f(store, autocommit).await;
+
+    if autocommit {
+        match done {
+            Ok(_) => store.commit().await?,
+            Err(_) => store.rollback().await?,
+        }
+    }
+
+    done
+}
+
+/// Like [`in_transaction`], but hands `store` over to `open`, e.g. to wrap it in an
+/// [`EncryptedStore`], and commits the transaction on the store it returns.
+///
+/// If `open` fails, the store is dropped along with the transaction.
+///
+/// # Errors
+///
+/// Returns the error of `open`, or an error if the transaction can't be begun or committed.
+pub async fn open_in_transaction<S: Transaction, T: Transaction>(
+    mut store: S,
+    open: impl AsyncFnOnce(S) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let autocommit = store.begin(true).await?;
+    let mut opened = open(store).await?;
+
+    if autocommit {
+        opened.commit().await?;
+    }
+
+    Ok(opened)
+}
+
+impl<S: Transaction + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>
+    EncryptedStore<S, NonceSeq>
+{
+    /// Commits the batch written in a transaction of [`in_transaction`], and begins the next one,
+    /// so a failure only rolls back the batch in progress. Transactions begun elsewhere are left
+    /// alone.
+    pub(crate) async fn commit_batch(&mut self, autocommit: bool) -> Result<(), Error> {
+        if autocommit {
+            self.store.commit().await?;
+            self.store.begin(true).await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,88 @@
+use std::fmt::{self, Debug, Formatter};
+
+use gluesql_core::data::Value;
+use ring::aead::{LessSafeKey, NonceSequence, UnboundKey};
+
+use crate::{encdec, Compression, EncryptedStore, Error, RandomNonce};
+
+/// Encrypts and decrypts single values in the format an `EncryptedStore` stores the values of
+/// its rows in, e.g. for columns an application handles outside of gluesql.
+///
+/// Values it encrypts with the key of a store are read back by the store like any other, and
+/// it decrypts the values the store writes, including the ones of tables with another
+/// algorithm. Get one sharing the key and compression of a store with
+/// [`EncryptedStore::value_cipher`].
+pub struct ValueCipher {
+    keys: encdec::KeySet,
+    compression: Compression,
+}
+
+impl Debug for ValueCipher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ValueCipher")
+            .field("compression", &self.compression)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ValueCipher {
+    #[must_use]
+    pub fn new(key: UnboundKey) -> Self {
+        Self {
+            keys: encdec::KeySet::new(LessSafeKey::new(key)),
+            compression: Compression::default(),
+        }
+    }
+
+    /// Sets the compression applied to values before they're encrypted, which must match the
+    /// one of the store reading them.
+    #[must_use]
+    pub const fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Encrypts a value with a random nonce, returning the `BYTEA` value a store would write.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the value can't be encoded or sealed.
+    pub fn encrypt_value(&self, value: &Value) -> Result<Value, Error> {
+        let mut value = value.clone();
+
+        encdec::encrypt_value_in_place(
+            &self.keys.key,
+            &mut RandomNonce::new(),
+            &mut value,
+            self.compression,
+        )?;
+
+        Ok(value)
+    }
+
+    /// Decrypts a value encrypted by a store or [`ValueCipher::encrypt_value`]. Values that
+    /// aren't ciphertexts, e.g. because the policy of the store leaves them as-is, are returned
+    /// as they are.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the value is a ciphertext the key doesn't open.
+    pub fn decrypt_value(&self, value: &Value) -> Result<Value, Error> {
+        let mut value = value.clone();
+
+        encdec::decrypt_row_value_in_place(self.keys.row_keys(), &mut value, self.compression)?;
+
+        Ok(value)
+    }
+}
+
+impl<S, NonceSeq: NonceSequence> EncryptedStore<S, NonceSeq> {
+    /// Returns a [`ValueCipher`] with the key and compression of the store.
+    #[must_use]
+    pub fn value_cipher(&self) -> ValueCipher {
+        ValueCipher {
+            keys: self.key_set(),
+            compression: self.compression,
+        }
+    }
+}
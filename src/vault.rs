@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use gluesql_core::{
+    data::{Key, Schema, Value},
+    store::{DataRow, Store, StoreMut},
+};
+use ring::{aead::NonceSequence, hmac};
+
+use crate::{encdec, EncryptedStore, Error, MaybeSendSync, TableColumns};
+
+/// Prefix of the tokens replacing tokenized values in user tables.
+const TOKEN_PREFIX: &str = "tok:";
+/// Prefix of the vault keys pointing from a value to its token.
+const LOOKUP_PREFIX: &str = "val:";
+
+impl<S, NonceSeq: NonceSequence> EncryptedStore<S, NonceSeq> {
+    /// Returns the key of the vault row holding the token of a value.
+    ///
+    /// It's an HMAC of the value, so equal values share their token.
+    fn lookup_key(&self, value: &Value) -> Result<Key, Error> {
+        let mac = hmac::sign(&self.schema_keys.token_key, &encdec::encode_value(value)?);
+
+        Ok(Key::Str(format!(
+            "{LOOKUP_PREFIX}{}",
+            encdec::to_hex(&mac.as_ref()[..16])
+        )))
+    }
+}
+
+impl<S: Store + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>
+    EncryptedStore<S, NonceSeq>
+{
+    /// Returns the value behind a token, or `None` if the token was purged or never existed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the vault can't be read or decrypted.
+    pub async fn detokenize(&self, token: &str) -> Result<Option<Value>, Error> {
+        match self
+            .store
+            .fetch_data(&self.tables.vault, &Key::Str(token.to_owned()))
+            .await?
+        {
+            Some(DataRow::Map(mut values)) => {
+                let mut value = values.remove("value").ok_or(Error::InvalidValue)?;
+
+                self.decrypt_value(&mut value, self.compression)?;
+
+                Ok(Some(value))
+            }
+            Some(DataRow::Vec(_)) => Err(Error::InvalidValue),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the token of a value, or `None` if it has none.
+    async fn find_token(&self, value: &Value) -> Result<Option<String>, Error> {
+        match self
+            .store
+            .fetch_data(&self.tables.vault, &self.lookup_key(value)?)
+            .await?
+        {
+            Some(DataRow::Map(mut values)) => match values.remove("token") {
+                Some(Value::Str(token)) => Ok(Some(token)),
+                _ => Err(Error::InvalidValue),
+            },
+            Some(DataRow::Vec(_)) => Err(Error::InvalidValue),
+            None => Ok(None),
+        }
+    }
+
+    /// Replaces the tokens in the tokenized columns of a row with their values.
+    ///
+    /// Purged tokens are replaced with `NULL`.
+    pub(crate) async fn detokenize_row(
+        &self,
+        table_name: &str,
+        columns: &TableColumns,
+        row: &mut DataRow,
+    ) -> Result<(), Error> {
+        for (column_name, value) in encdec::named_values_mut(row, &columns.names) {
+            if !column_name.is_some_and(|name| self.policy.tokenizes(table_name, name)) {
+                continue;
+            }
+
+            if let Value::Str(token) = value {
+                if token.starts_with(TOKEN_PREFIX) {
+                    *value = self.detokenize(token).await?.unwrap_or(Value::Null);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<S: Store + StoreMut + MaybeSendSync, NonceSeq: NonceSequence + MaybeSendSync>
+    EncryptedStore<S, NonceSeq>
+{
+    /// Returns the token of a value, storing the value in the vault if it has none yet.
+    ///
+    /// Tokens are random, so a value purged and then stored again gets a new token.
+    pub(crate) async fn tokenize(&mut self, value: &Value) -> Result<String, Error> {
+        if let Some(token) = self.find_token(value).await? {
+            return Ok(token);
+        }
+
+        if self.store.fetch_schema(&self.tables.vault).await?.is_none() {
+            self.store
+                .insert_schema(&Schema {
+                    table_name: self.tables.vault.clone(),
+                    column_defs: None,
+                    indexes: vec![],
+                    engine: None,
+                    foreign_keys: vec![],
+                    comment: Some("Table to store the values behind tokens".to_string()),
+                })
+                .await?;
+            self.clear_schema_cache();
+        }
+
+        // the token and the sealed value both come from a single nonce drawn from the sequence
+        let mut nonces = self.batch_nonces()?;
+        let mac = hmac::sign(&self.schema_keys.token_key, nonces.advance()?.as_ref());
+        let token = format!("{TOKEN_PREFIX}{}", encdec::to_hex(&mac.as_ref()[..16]));
+
+        let mut encrypted = value.clone();
+
+        encdec::encrypt_value_in_place(&self.key, &mut nonces, &mut encrypted, self.compression)?;
+
+        let lookup_key = self.lookup_key(value)?;
+
+        self.store
+            .insert_data(
+                &self.tables.vault,
+                vec![
+                    (
+                        Key::Str(token.clone()),
+                        DataRow::Map(HashMap::from([("value".to_owned(), encrypted)])),
+                    ),
+                    (
+                        lookup_key,
+                        DataRow::Map(HashMap::from([(
+                            "token".to_owned(),
+                            Value::Str(token.clone()),
+                        )])),
+                    ),
+                ],
+            )
+            .await?;
+
+        Ok(token)
+    }
+
+    /// Replaces the values in the tokenized columns of a row with their tokens.
+    pub(crate) async fn tokenize_row(
+        &mut self,
+        table_name: &str,
+        columns: &TableColumns,
+        row: &mut DataRow,
+    ) -> Result<(), Error> {
+        for (column_name, value) in encdec::named_values_mut(row, &columns.names) {
+            if column_name.is_some_and(|name| self.policy.tokenizes(table_name, name))
+                && !value.is_null()
+            {
+                *value = Value::Str(self.tokenize(value).await?);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the value behind a token from the vault, so every row holding the token reads
+    /// `NULL` from now on.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the vault can't be read or written.
+    pub async fn purge_token(&mut self, token: &str) -> Result<(), Error> {
+        let Some(value) = self.detokenize(token).await? else {
+            return Ok(());
+        };
+
+        let lookup_key = self.lookup_key(&value)?;
+
+        self.store
+            .delete_data(
+                &self.tables.vault,
+                vec![Key::Str(token.to_owned()), lookup_key],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Purges the token of a value, crypto-shredding the value everywhere it was tokenized.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the vault can't be read or written.
+    pub async fn purge_value(&mut self, value: &Value) -> Result<(), Error> {
+        match self.find_token(value).await? {
+            Some(token) => self.purge_token(&token).await,
+            None => Ok(()),
+        }
+    }
+}
@@ -1,20 +1,22 @@
+// the errors of the store wrap gluesql's, which are large
+#![allow(clippy::result_large_err)]
+
 use {
     async_trait::async_trait,
     gluesql_core::{
         data::Value,
         prelude::{Glue, Payload},
     },
-    gluesql_encryption::EncryptedStore,
+    gluesql_encryption::{
+        test_util::{self, RandNonce},
+        EncryptedStore,
+    },
     gluesql_memory_storage::MemoryStorage,
     gluesql_test_suite::*,
     ring::aead::UnboundKey,
     std::vec,
-    test_utils::RandNonce,
 };
 
-#[path = "../src/test_utils.rs"]
-mod test_utils;
-
 struct EncryptedTester {
     glue: Glue<EncryptedStore<MemoryStorage, RandNonce>>,
 }
@@ -26,7 +28,7 @@ impl Tester<EncryptedStore<MemoryStorage, RandNonce>> for EncryptedTester {
 
         let glue = Glue::new(EncryptedStore::new_unchecked(
             storage,
-            test_utils::new_key(),
+            test_util::new_key(),
             RandNonce::new(),
         ));
 
@@ -46,6 +48,38 @@ generate_metadata_table_tests!(tokio::test, EncryptedTester);
 
 generate_custom_function_tests!(tokio::test, EncryptedTester);
 
+async fn new_conformance_store() -> EncryptedStore<MemoryStorage, RandNonce> {
+    EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+}
+
+gluesql_encryption::generate_conformance_tests!(tokio::test, new_conformance_store);
+
+mod sled_conformance {
+    use {
+        gluesql_encryption::{
+            test_util::{self, RandNonce},
+            EncryptedStore,
+        },
+        gluesql_sled_storage::SledStorage,
+    };
+
+    async fn new_store() -> EncryptedStore<SledStorage, RandNonce> {
+        super::open_in_transaction(
+            SledStorage::try_from(sled::Config::default().temporary(true)).unwrap(),
+            test_util::new_key(),
+        )
+        .await
+    }
+
+    gluesql_encryption::generate_conformance_tests!(tokio::test, new_store, with_indexes);
+}
+
 macro_rules! exec {
     ($glue: ident $sql: literal) => {
         $glue.execute($sql).await.unwrap();
@@ -58,13 +92,62 @@ macro_rules! test {
     };
 }
 
+/// Opens an `EncryptedStore` over a store like sled, which only reads and writes in
+/// transactions, checking the key in one.
+async fn open_in_transaction<S>(store: S, key: UnboundKey) -> EncryptedStore<S, RandNonce>
+where
+    S: gluesql_core::store::Store
+        + gluesql_core::store::StoreMut
+        + gluesql_core::store::Transaction
+        + gluesql_encryption::MaybeSendSync,
+{
+    gluesql_encryption::open_in_transaction(store, async |store| {
+        EncryptedStore::new_with_nonce_sequence(store, key, RandNonce::new()).await
+    })
+    .await
+    .unwrap()
+}
+
+/// Scans a table of a sled store directly, in a transaction since sled only reads in one.
+async fn scan_sled(
+    sled: &gluesql_sled_storage::SledStorage,
+    table_name: &str,
+) -> Vec<(gluesql_core::data::Key, gluesql_core::store::DataRow)> {
+    use {futures::TryStreamExt, gluesql_core::store::Store};
+
+    gluesql_encryption::in_transaction(&mut sled.clone(), async |sled, _| {
+        Ok(Store::scan_data(sled, table_name)
+            .await?
+            .try_collect()
+            .await?)
+    })
+    .await
+    .unwrap()
+}
+
+/// Fetches a row of a sled store directly, in a transaction since sled only reads in one.
+async fn fetch_sled(
+    sled: &gluesql_sled_storage::SledStorage,
+    table_name: &str,
+    key: &gluesql_core::data::Key,
+) -> Option<gluesql_core::store::DataRow> {
+    use gluesql_core::store::{Store, Transaction};
+
+    let mut sled = sled.clone();
+    sled.begin(true).await.unwrap();
+    let row = Store::fetch_data(&sled, table_name, key).await.unwrap();
+    sled.commit().await.unwrap();
+
+    row
+}
+
 #[tokio::test]
 async fn encrypted_storage_checks_key() {
     use gluesql_core::prelude::Glue;
 
-    let storage = EncryptedStore::new(
+    let storage = EncryptedStore::new_with_nonce_sequence(
         MemoryStorage::default(),
-        test_utils::new_key(),
+        test_util::new_key(),
         RandNonce::new(),
     )
     .await
@@ -91,7 +174,7 @@ async fn encrypted_storage_checks_key() {
         }])
     );
 
-    let storage = EncryptedStore::new(
+    let storage = EncryptedStore::new_with_nonce_sequence(
         glue.storage.into_inner(),
         UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap(),
         RandNonce::new(),
@@ -100,7 +183,7 @@ async fn encrypted_storage_checks_key() {
     .unwrap();
 
     assert_eq!(
-        EncryptedStore::new(
+        EncryptedStore::new_with_nonce_sequence(
             storage.into_inner(),
             UnboundKey::new(&ring::aead::AES_256_GCM, &[2; 32]).unwrap(),
             RandNonce::new(),
@@ -115,9 +198,9 @@ async fn encrypted_storage_checks_key() {
 async fn encrypted_storage_change_key() {
     use gluesql_core::prelude::{Glue, Payload};
 
-    let storage = EncryptedStore::new(
+    let storage = EncryptedStore::new_with_nonce_sequence(
         MemoryStorage::default(),
-        test_utils::new_key(),
+        test_util::new_key(),
         RandNonce::new(),
     )
     .await
@@ -144,7 +227,7 @@ async fn encrypted_storage_change_key() {
     );
 
     assert_eq!(
-        EncryptedStore::new(
+        EncryptedStore::new_with_nonce_sequence(
             glue.storage.into_inner(),
             UnboundKey::new(&ring::aead::AES_256_GCM, &[2; 32]).unwrap(),
             RandNonce::new(),
@@ -154,3 +237,5740 @@ async fn encrypted_storage_change_key() {
         gluesql_encryption::Error::InvalidKey
     )
 }
+
+#[tokio::test]
+async fn encrypted_storage_skips_denied_tables() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::store::{DataRow, Store},
+        gluesql_encryption::EncryptionPolicy,
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().deny_tables(["Lookup"]));
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Lookup (id INTEGER);");
+    exec!(glue "CREATE TABLE Secret (id INTEGER);");
+    exec!(glue "INSERT INTO Lookup (id) VALUES (1);");
+    exec!(glue "INSERT INTO Secret (id) VALUES (2);");
+
+    test!(
+        glue
+        "SELECT * FROM Lookup;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1)]],
+            labels: vec!["id".to_owned()],
+        }])
+    );
+
+    let inner = glue.storage.into_inner();
+
+    let lookup = Store::scan_data(&inner, "Lookup")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert_eq!(lookup[0].1, DataRow::Vec(vec![Value::I64(1)]));
+
+    let secret = Store::scan_data(&inner, "Secret")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert!(matches!(&secret[0].1, DataRow::Vec(values) if matches!(values[0], Value::Bytea(_))));
+}
+
+#[tokio::test]
+async fn encrypted_storage_encrypts_selected_types() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::{
+            ast::DataType,
+            store::{DataRow, Store},
+        },
+        gluesql_encryption::EncryptionPolicy,
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().encrypt_types([DataType::Text]));
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Person (id INTEGER, name TEXT);");
+    exec!(glue "INSERT INTO Person (id, name) VALUES (1, 'Alice');");
+
+    test!(
+        glue
+        "SELECT * FROM Person WHERE id = 1;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1), Value::Str("Alice".to_owned())]],
+            labels: vec!["id".to_owned(), "name".to_owned()],
+        }])
+    );
+
+    let rows = Store::scan_data(&glue.storage.into_inner(), "Person")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert!(matches!(
+        &rows[0].1,
+        DataRow::Vec(values) if values[0] == Value::I64(1) && matches!(values[1], Value::Bytea(_))
+    ));
+}
+
+#[tokio::test]
+async fn encrypted_storage_from_config() {
+    use gluesql_encryption::{Algorithm, Compression, EncryptionConfig, Kdf};
+
+    let config: EncryptionConfig = serde_json::from_str(
+        r#"{
+            "algorithm": "cha_cha20_poly1305",
+            "policy": { "tables": { "deny": ["Lookup"] } },
+            "compression": { "kind": "deflate", "level": 6 },
+            "kdf": { "kind": "pbkdf2", "iterations": 1000, "salt": "test" }
+        }"#,
+    )
+    .unwrap();
+
+    assert_eq!(config.algorithm, Algorithm::ChaCha20Poly1305);
+    assert_eq!(config.compression, Compression::Deflate { level: 6 });
+    assert!(matches!(config.kdf, Kdf::Pbkdf2 { .. }));
+    assert!(!config.policy.encrypts_table("Lookup"));
+
+    let storage = EncryptedStore::from_config(
+        MemoryStorage::default(),
+        config.clone(),
+        b"correct horse battery staple",
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Notes (id INTEGER, body TEXT);");
+    exec!(glue "INSERT INTO Notes (id, body) VALUES (1, 'aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa');");
+
+    test!(
+        glue
+        "SELECT * FROM Notes;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![
+                Value::I64(1),
+                Value::Str("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".to_owned())
+            ]],
+            labels: vec!["id".to_owned(), "body".to_owned()],
+        }])
+    );
+
+    // passphrases are checked without opening the store
+    let inner = glue.storage.into_inner();
+
+    assert!(config
+        .verify_key(&inner, b"correct horse battery staple")
+        .await
+        .unwrap());
+    assert!(!config
+        .verify_key(&inner, b"wrong passphrase")
+        .await
+        .unwrap());
+    assert_eq!(
+        config
+            .verify_key(&MemoryStorage::default(), b"correct horse battery staple")
+            .await,
+        Err(gluesql_encryption::Error::NonEncryptedDatabase)
+    );
+
+    assert_eq!(
+        EncryptedStore::from_config(inner, config, b"wrong passphrase", RandNonce::new(),)
+            .await
+            .unwrap_err(),
+        gluesql_encryption::Error::InvalidKey
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_row_mode() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::store::{DataRow, Store},
+        gluesql_encryption::{EncryptionMode, EncryptionPolicy},
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().with_mode(EncryptionMode::Row));
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Wide (id INTEGER, a TEXT, b TEXT);");
+    exec!(glue "INSERT INTO Wide VALUES (1, 'a', 'b');");
+    exec!(glue "ALTER TABLE Wide ADD COLUMN c INTEGER DEFAULT 5;");
+
+    glue.storage = glue
+        .storage
+        .change_key(UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap())
+        .await
+        .unwrap();
+
+    test!(
+        glue
+        "SELECT * FROM Wide;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![
+                Value::I64(1),
+                Value::Str("a".to_owned()),
+                Value::Str("b".to_owned()),
+                Value::I64(5)
+            ]],
+            labels: vec!["id".to_owned(), "a".to_owned(), "b".to_owned(), "c".to_owned()],
+        }])
+    );
+
+    assert!(glue
+        .execute("ALTER TABLE Wide DROP COLUMN a;")
+        .await
+        .is_err());
+
+    let rows = Store::scan_data(&glue.storage.into_inner(), "Wide")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert!(matches!(&rows[0].1, DataRow::Vec(values) if values.len() == 1));
+}
+
+#[tokio::test]
+async fn encrypted_storage_per_table_mode() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::store::{DataRow, Store},
+        gluesql_encryption::{EncryptionMode, EncryptionPolicy},
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().with_table_mode("Analytics", EncryptionMode::Row));
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Analytics (id INTEGER, a TEXT);");
+    exec!(glue "CREATE TABLE Users (id INTEGER, a TEXT);");
+    exec!(glue "INSERT INTO Analytics VALUES (1, 'a');");
+    exec!(glue "INSERT INTO Users VALUES (1, 'a');");
+
+    for table in ["Analytics", "Users"] {
+        test!(
+            glue & format!("SELECT * FROM {table};"),
+            Ok(vec![Payload::Select {
+                rows: vec![vec![Value::I64(1), Value::Str("a".to_owned())]],
+                labels: vec!["id".to_owned(), "a".to_owned()],
+            }])
+        );
+    }
+
+    let inner = glue.storage.into_inner();
+
+    for (table, len) in [("Analytics", 1), ("Users", 2)] {
+        let rows = Store::scan_data(&inner, table)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert!(matches!(&rows[0].1, DataRow::Vec(values) if values.len() == len));
+    }
+}
+
+#[tokio::test]
+async fn encrypted_storage_encrypts_map_keys() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::store::{DataRow, Store},
+        gluesql_encryption::EncryptionPolicy,
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().encrypt_map_keys());
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Logs;");
+    exec!(glue r#"INSERT INTO Logs VALUES ('{"secret_field": 1}');"#);
+
+    glue.storage = glue
+        .storage
+        .change_key(UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap())
+        .await
+        .unwrap();
+
+    test!(
+        glue
+        "SELECT * FROM Logs;",
+        Ok(vec![Payload::SelectMap(vec![[(
+            "secret_field".to_owned(),
+            Value::I64(1)
+        )]
+        .into_iter()
+        .collect()])])
+    );
+
+    let rows = Store::scan_data(&glue.storage.into_inner(), "Logs")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert!(matches!(
+        &rows[0].1,
+        DataRow::Map(values) if values.keys().all(|name| !name.contains("secret_field"))
+    ));
+}
+
+#[tokio::test]
+async fn encrypted_storage_pseudonymizes_names() {
+    use {gluesql_core::store::Store, gluesql_encryption::EncryptionPolicy};
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().pseudonymize_names());
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Person (id INTEGER, name TEXT);");
+    exec!(glue "INSERT INTO Person VALUES (1, 'Alice');");
+    exec!(glue "ALTER TABLE Person RENAME COLUMN name TO full_name;");
+
+    test!(
+        glue
+        "SELECT * FROM Person;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1), Value::Str("Alice".to_owned())]],
+            labels: vec!["id".to_owned(), "full_name".to_owned()],
+        }])
+    );
+
+    let schemas = glue.storage.into_inner().fetch_all_schemas().await.unwrap();
+    assert!(schemas.iter().all(|schema| {
+        schema.table_name != "Person"
+            && schema
+                .column_defs
+                .iter()
+                .flatten()
+                .all(|column_def| column_def.name != "full_name")
+    }));
+}
+
+#[tokio::test]
+async fn encrypted_storage_scans_table_meta() {
+    use {
+        gluesql_core::store::Metadata, gluesql_encryption::EncryptionPolicy,
+        std::collections::HashMap,
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().pseudonymize_names());
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER);");
+
+    // the metadata is under the name of the table, without the store's own tables
+    let meta = glue
+        .storage
+        .scan_table_meta()
+        .await
+        .unwrap()
+        .collect::<Result<HashMap<_, _>, _>>()
+        .unwrap();
+    assert_eq!(meta.keys().collect::<Vec<_>>(), ["Item"]);
+    assert!(matches!(meta["Item"]["CREATED"], Value::Timestamp(_)));
+}
+
+#[tokio::test]
+async fn encrypted_storage_encrypts_defaults() {
+    use gluesql_core::store::Store;
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Account (id INTEGER, token TEXT DEFAULT 'hunter2');");
+    exec!(glue "INSERT INTO Account (id) VALUES (1);");
+    exec!(glue "ALTER TABLE Account ADD COLUMN pin TEXT DEFAULT 'letmein';");
+    exec!(glue "INSERT INTO Account (id) VALUES (2);");
+
+    glue.storage = glue
+        .storage
+        .change_key(UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap())
+        .await
+        .unwrap();
+
+    let row = |id| {
+        vec![
+            Value::I64(id),
+            Value::Str("hunter2".to_owned()),
+            Value::Str("letmein".to_owned()),
+        ]
+    };
+    test!(
+        glue
+        "SELECT * FROM Account ORDER BY id;",
+        Ok(vec![Payload::Select {
+            rows: vec![row(1), row(2)],
+            labels: vec!["id".to_owned(), "token".to_owned(), "pin".to_owned()],
+        }])
+    );
+
+    let schema = glue
+        .storage
+        .into_inner()
+        .fetch_schema("Account")
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(!format!("{schema:?}").contains("hunter2"));
+    assert!(!format!("{schema:?}").contains("letmein"));
+}
+
+#[tokio::test]
+async fn encrypted_storage_adds_columns_in_batches() {
+    use {futures::TryStreamExt, gluesql_core::store::Store};
+
+    let storage = EncryptedStore::new(MemoryStorage::default(), test_util::new_key())
+        .await
+        .unwrap()
+        .with_batch_size(2);
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Account (id INTEGER PRIMARY KEY);");
+    exec!(glue "INSERT INTO Account VALUES (1), (2), (3), (4), (5);");
+    exec!(glue "ALTER TABLE Account ADD COLUMN pin TEXT DEFAULT 'letmein';");
+
+    test!(
+        glue
+        "SELECT * FROM Account ORDER BY id;",
+        Ok(vec![Payload::Select {
+            rows: (1..=5)
+                .map(|id| vec![Value::I64(id), Value::Str("letmein".to_owned())])
+                .collect(),
+            labels: vec!["id".to_owned(), "pin".to_owned()],
+        }])
+    );
+
+    let inner = glue.storage.into_inner();
+    let rows = Store::scan_data(&inner, "Account")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert_eq!(rows.len(), 5);
+    assert!(!format!("{rows:?}").contains("letmein"));
+    assert!(inner
+        .fetch_all_schemas()
+        .await
+        .unwrap()
+        .iter()
+        .all(|schema| !schema.table_name.starts_with("encrypted_staging_")));
+}
+
+#[tokio::test]
+async fn encrypted_storage_encrypts_functions() {
+    use gluesql_core::{
+        ast::{AstLiteral, Expr},
+        store::CustomFunction,
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE FUNCTION add_secret(x INTEGER) RETURN x + 42;");
+
+    test!(
+        glue
+        "SELECT add_secret(1) AS v;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(43)]],
+            labels: vec!["v".to_owned()],
+        }])
+    );
+
+    let inner = glue.storage.into_inner();
+    let func = inner.fetch_function("add_secret").await.unwrap().unwrap();
+    assert!(matches!(
+        func.body,
+        Expr::Literal(AstLiteral::QuotedString(_))
+    ));
+
+    // functions are decrypted again when the store is reopened
+    let mut glue = Glue::new(
+        EncryptedStore::new_with_nonce_sequence(inner, test_util::new_key(), RandNonce::new())
+            .await
+            .unwrap(),
+    );
+
+    test!(
+        glue
+        "SELECT add_secret(2) AS v;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(44)]],
+            labels: vec!["v".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_forgets_functions_on_rollback() {
+    use gluesql_core::store::{CustomFunction, CustomFunctionMut, Transaction};
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE FUNCTION add_secret(x INTEGER) RETURN x + 42;");
+
+    // the function is undone by the inner store's rollback, as if it had been created in the
+    // transaction
+    glue.storage
+        .inner_mut()
+        .delete_function("add_secret")
+        .await
+        .unwrap();
+    glue.storage.rollback().await.unwrap();
+
+    assert!(glue
+        .storage
+        .fetch_function("add_secret")
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn encrypted_storage_encrypts_row_keys() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::{data::Key, store::Store},
+        gluesql_encryption::EncryptionPolicy,
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().encrypt_row_keys());
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Account (id INTEGER PRIMARY KEY, balance INTEGER);");
+    exec!(glue "INSERT INTO Account VALUES (1, 10), (2, 20), (3, 30);");
+    exec!(glue "UPDATE Account SET balance = 25 WHERE id = 2;");
+    exec!(glue "DELETE FROM Account WHERE id = 3;");
+
+    glue.storage = glue
+        .storage
+        .change_key(UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap())
+        .await
+        .unwrap();
+
+    test!(
+        glue
+        "SELECT * FROM Account WHERE id = 2;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(2), Value::I64(25)]],
+            labels: vec!["id".to_owned(), "balance".to_owned()],
+        }])
+    );
+    test!(
+        glue
+        "SELECT id FROM Account ORDER BY id;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1)], vec![Value::I64(2)]],
+            labels: vec!["id".to_owned()],
+        }])
+    );
+
+    let rows = Store::scan_data(&glue.storage.into_inner(), "Account")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert_eq!(rows.len(), 2);
+    assert!(rows.iter().all(|(key, _)| matches!(key, Key::Bytea(_))));
+}
+
+#[tokio::test]
+async fn encrypted_storage_column_nulls() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::store::{DataRow, Store},
+        gluesql_encryption::{EncryptionPolicy, Nulls},
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().with_column_nulls(
+        "Person",
+        "nickname",
+        Nulls::Expose,
+    ));
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Person (id INTEGER, nickname TEXT NULL, email TEXT NULL);");
+    exec!(glue "INSERT INTO Person VALUES (1, NULL, NULL);");
+
+    test!(
+        glue
+        "SELECT * FROM Person;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1), Value::Null, Value::Null]],
+            labels: vec!["id".to_owned(), "nickname".to_owned(), "email".to_owned()],
+        }])
+    );
+
+    let rows = Store::scan_data(&glue.storage.into_inner(), "Person")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert!(matches!(
+        &rows[0].1,
+        DataRow::Vec(values) if values[1] == Value::Null && matches!(values[2], Value::Bytea(_))
+    ));
+}
+
+#[tokio::test]
+async fn routed_store_keeps_plain_tables_unencrypted() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::store::{DataRow, Store},
+        gluesql_encryption::{RoutedStore, TableFilter},
+    };
+
+    let encrypted = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    let storage = RoutedStore::new(
+        encrypted,
+        MemoryStorage::default(),
+        TableFilter::Allow(["Cache".to_owned()].into_iter().collect()),
+    );
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Person (id INTEGER, name TEXT);");
+    exec!(glue "CREATE TABLE Cache (id INTEGER, hits INTEGER);");
+    exec!(glue "INSERT INTO Person VALUES (1, 'Alice');");
+    exec!(glue "INSERT INTO Cache VALUES (1, 42);");
+
+    test!(
+        glue
+        "SELECT name, hits FROM Person JOIN Cache ON Person.id = Cache.id;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::Str("Alice".to_owned()), Value::I64(42)]],
+            labels: vec!["name".to_owned(), "hits".to_owned()],
+        }])
+    );
+
+    let (encrypted, plain) = glue.storage.into_stores();
+
+    let rows = Store::scan_data(&plain, "Cache")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert_eq!(rows[0].1, DataRow::Vec(vec![Value::I64(1), Value::I64(42)]));
+    assert!(plain.fetch_schema("Person").await.unwrap().is_none());
+
+    let rows = Store::scan_data(&encrypted.into_inner(), "Person")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert!(matches!(
+        &rows[0].1,
+        DataRow::Vec(values) if values.iter().all(|value| matches!(value, Value::Bytea(_)))
+    ));
+}
+
+#[tokio::test]
+async fn encrypted_storage_passthrough() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::store::{DataRow, Store},
+        gluesql_encryption::EncryptionPolicy,
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().pseudonymize_names().passthrough());
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Person (id INTEGER, name TEXT);");
+    exec!(glue "INSERT INTO Person VALUES (1, 'Alice');");
+
+    let store = glue.storage.into_inner();
+    let rows = Store::scan_data(&store, "Person")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert_eq!(
+        rows[0].1,
+        DataRow::Vec(vec![Value::I64(1), Value::Str("Alice".to_owned())])
+    );
+
+    // turning encryption on keeps the existing rows readable
+    let storage =
+        EncryptedStore::new_with_nonce_sequence(store, test_util::new_key(), RandNonce::new())
+            .await
+            .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "INSERT INTO Person VALUES (2, 'Bob');");
+
+    test!(
+        glue
+        "SELECT * FROM Person;",
+        Ok(vec![Payload::Select {
+            rows: vec![
+                vec![Value::I64(1), Value::Str("Alice".to_owned())],
+                vec![Value::I64(2), Value::Str("Bob".to_owned())],
+            ],
+            labels: vec!["id".to_owned(), "name".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_per_table_algorithm() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::store::{DataRow, Store},
+        gluesql_encryption::{Algorithm, EncryptionMode, EncryptionPolicy},
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(
+        EncryptionPolicy::new()
+            .with_table_algorithm("Synced", Algorithm::ChaCha20Poly1305)
+            .with_table_algorithm("SyncedRows", Algorithm::ChaCha20Poly1305)
+            .with_table_mode("SyncedRows", EncryptionMode::Row),
+    );
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Native (id INTEGER);");
+    exec!(glue "CREATE TABLE Synced (id INTEGER);");
+    exec!(glue "CREATE TABLE SyncedRows (id INTEGER);");
+    exec!(glue "INSERT INTO Native VALUES (1);");
+    exec!(glue "INSERT INTO Synced VALUES (2);");
+    exec!(glue "INSERT INTO SyncedRows VALUES (3);");
+
+    glue.storage = glue
+        .storage
+        .change_key(UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap())
+        .await
+        .unwrap();
+
+    for (table_name, id) in [("Native", 1), ("Synced", 2), ("SyncedRows", 3)] {
+        test!(
+            glue
+            format!("SELECT id FROM {table_name};"),
+            Ok(vec![Payload::Select {
+                rows: vec![vec![Value::I64(id)]],
+                labels: vec!["id".to_owned()],
+            }])
+        );
+    }
+
+    let store = glue.storage.into_inner();
+    for (table_name, chacha) in [("Native", false), ("Synced", true)] {
+        let rows = Store::scan_data(&store, table_name)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert!(matches!(
+            &rows[0].1,
+            DataRow::Vec(values)
+                if matches!(&values[0], Value::Bytea(encrypted) if encrypted.starts_with(b"GEV\x01GEC\x03") == chacha)
+        ));
+    }
+}
+
+#[tokio::test]
+async fn encrypted_storage_encrypts_unique_deterministically() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::store::{DataRow, Store},
+        gluesql_encryption::EncryptionPolicy,
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().encrypt_unique_deterministically());
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE User (id INTEGER, email TEXT UNIQUE);");
+    exec!(glue "CREATE TABLE Invite (id INTEGER, email TEXT UNIQUE);");
+    exec!(glue "INSERT INTO User VALUES (1, 'alice@example.com');");
+    exec!(glue "INSERT INTO Invite VALUES (2, 'alice@example.com');");
+
+    assert!(glue
+        .execute("INSERT INTO User VALUES (3, 'alice@example.com');")
+        .await
+        .is_err());
+
+    glue.storage = glue
+        .storage
+        .change_key(UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap())
+        .await
+        .unwrap();
+
+    test!(
+        glue
+        "SELECT email FROM User;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::Str("alice@example.com".to_owned())]],
+            labels: vec!["email".to_owned()],
+        }])
+    );
+
+    async fn first_row(store: &MemoryStorage, table_name: &str) -> DataRow {
+        Store::scan_data(store, table_name)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap()
+            .remove(0)
+            .1
+    }
+
+    let user = first_row(glue.storage.inner(), "User").await;
+    let invite = first_row(glue.storage.inner(), "Invite").await;
+
+    // a row written again with the new key is sealed like the rewritten one
+    exec!(glue "DELETE FROM User;");
+    exec!(glue "INSERT INTO User VALUES (1, 'alice@example.com');");
+    let rewritten = first_row(glue.storage.inner(), "User").await;
+
+    let (DataRow::Vec(user), DataRow::Vec(invite), DataRow::Vec(rewritten)) =
+        (user, invite, rewritten)
+    else {
+        panic!("rows should be stored as vectors");
+    };
+    assert_ne!(user[0], invite[0]);
+    // equal values of other tables or columns don't give equal ciphertexts
+    assert_ne!(user[1], invite[1]);
+    assert_eq!(user[1], rewritten[1]);
+}
+
+#[tokio::test]
+async fn encrypted_storage_scans_indexes() {
+    use {
+        gluesql_core::store::Transaction, gluesql_encryption::EncryptionPolicy,
+        gluesql_sled_storage::SledStorage,
+    };
+
+    let mut storage = SledStorage::try_from(sled::Config::default().temporary(true)).unwrap();
+    // sled only reads and writes in transactions, so the key check is made in one
+    storage.begin(true).await.unwrap();
+    let mut storage =
+        EncryptedStore::new_with_nonce_sequence(storage, test_util::new_key(), RandNonce::new())
+            .await
+            .unwrap()
+            .with_policy(EncryptionPolicy::new().encrypt_unique_deterministically());
+    storage.commit().await.unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER, code TEXT UNIQUE);");
+    exec!(glue "INSERT INTO Item VALUES (3, 'c'), (1, 'a'), (2, 'b');");
+    exec!(glue "CREATE INDEX idx_id ON Item (id);");
+    exec!(glue "CREATE INDEX idx_code ON Item (code);");
+
+    test!(
+        glue
+        "SELECT id FROM Item WHERE id > 1;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(2)], vec![Value::I64(3)]],
+            labels: vec!["id".to_owned()],
+        }])
+    );
+    test!(
+        glue
+        "SELECT id FROM Item WHERE code = 'b';",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(2)]],
+            labels: vec!["id".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_encrypts_indexed_deterministically() {
+    use {
+        gluesql_core::store::{DataRow, Transaction},
+        gluesql_encryption::EncryptionPolicy,
+        gluesql_sled_storage::SledStorage,
+    };
+
+    let mut storage = SledStorage::try_from(sled::Config::default().temporary(true)).unwrap();
+    storage.begin(true).await.unwrap();
+    let mut storage =
+        EncryptedStore::new_with_nonce_sequence(storage, test_util::new_key(), RandNonce::new())
+            .await
+            .unwrap()
+            .with_policy(EncryptionPolicy::new().encrypt_indexed_deterministically())
+            .with_batch_size(1);
+    storage.commit().await.unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER, color TEXT);");
+    exec!(glue "INSERT INTO Item VALUES (1, 'red'), (2, 'blue');");
+    // the rows are written again a batch at a time
+    exec!(glue "CREATE INDEX idx_color ON Item (color);");
+    exec!(glue "INSERT INTO Item VALUES (3, 'red');");
+
+    test!(
+        glue
+        "SELECT id FROM Item WHERE color = 'red';",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1)], vec![Value::I64(3)]],
+            labels: vec!["id".to_owned()],
+        }])
+    );
+
+    let rows = scan_sled(&glue.storage.into_inner(), "Item").await;
+    let colors = rows
+        .iter()
+        .map(|(_, row)| match row {
+            DataRow::Vec(values) => values[1].clone(),
+            DataRow::Map(_) => panic!("rows should be stored as vectors"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(colors[0], colors[2]);
+    assert_ne!(colors[0], colors[1]);
+}
+
+#[tokio::test]
+async fn encrypted_storage_drops_indexes_it_cant_fill() {
+    use {
+        gluesql_core::{
+            ast::{Expr, OrderByExpr},
+            store::{IndexMut, Store, Transaction},
+        },
+        gluesql_encryption::EncryptionPolicy,
+        gluesql_sled_storage::SledStorage,
+    };
+
+    let sled = SledStorage::try_from(sled::Config::default().temporary(true)).unwrap();
+    let mut glue = Glue::new(open_in_transaction(sled.clone(), test_util::seeded_key(1)).await);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER, color TEXT);");
+    exec!(glue "INSERT INTO Item VALUES (1, 'red'), (2, 'blue');");
+
+    // the second batch of rows written again with the column indexed fails
+    let mut storage = FlakyStore {
+        store: sled,
+        table_name: "Item",
+        writes_left: 1,
+    };
+    storage.begin(true).await.unwrap();
+    let mut storage = EncryptedStore::new(storage, test_util::seeded_key(1))
+        .await
+        .unwrap()
+        .with_policy(EncryptionPolicy::new().encrypt_indexed_deterministically())
+        .with_batch_size(1);
+
+    let created = storage
+        .create_index(
+            "Item",
+            "idx_color",
+            &OrderByExpr {
+                expr: Expr::Identifier("color".to_owned()),
+                asc: None,
+            },
+        )
+        .await;
+    assert!(created.is_err());
+
+    let schema = Store::fetch_schema(&storage.inner().store, "Item")
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(schema.indexes.is_empty());
+}
+
+#[tokio::test]
+async fn encrypted_storage_tokenizes_columns() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::store::{DataRow, Store},
+        gluesql_encryption::EncryptionPolicy,
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().tokenize_columns("Patient", ["ssn"]));
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Patient (id INTEGER, ssn TEXT);");
+    exec!(glue "INSERT INTO Patient VALUES (1, '123-45-6789'), (2, '987-65-4321'), (3, '123-45-6789');");
+
+    test!(
+        glue
+        "SELECT id, ssn FROM Patient;",
+        Ok(vec![Payload::Select {
+            rows: vec![
+                vec![Value::I64(1), Value::Str("123-45-6789".to_owned())],
+                vec![Value::I64(2), Value::Str("987-65-4321".to_owned())],
+                vec![Value::I64(3), Value::Str("123-45-6789".to_owned())],
+            ],
+            labels: vec!["id".to_owned(), "ssn".to_owned()],
+        }])
+    );
+
+    glue.storage
+        .purge_value(&Value::Str("123-45-6789".to_owned()))
+        .await
+        .unwrap();
+
+    test!(
+        glue
+        "SELECT id, ssn FROM Patient;",
+        Ok(vec![Payload::Select {
+            rows: vec![
+                vec![Value::I64(1), Value::Null],
+                vec![Value::I64(2), Value::Str("987-65-4321".to_owned())],
+                vec![Value::I64(3), Value::Null],
+            ],
+            labels: vec!["id".to_owned(), "ssn".to_owned()],
+        }])
+    );
+
+    let rows = Store::scan_data(&glue.storage.into_inner(), "Patient")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+
+    for (_, row) in rows {
+        match row {
+            DataRow::Vec(values) => {
+                assert!(matches!(&values[1], Value::Str(token) if token.starts_with("tok:")));
+            }
+            DataRow::Map(_) => panic!("rows should be stored as vectors"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn encrypted_storage_round_trips_every_type() {
+    use gluesql_encryption::{EncryptionMode, EncryptionPolicy};
+
+    let sqls = [
+        "CREATE TABLE Everything (
+            b BOOLEAN, i INT8, u UINT128, f FLOAT, d DECIMAL, s TEXT, y BYTEA, n INET,
+            da DATE, ts TIMESTAMP, t TIME, iv INTERVAL, id UUID, m MAP, l LIST
+        );",
+        "INSERT INTO Everything VALUES (
+            TRUE, -8, 128, 1.5, 2.25, 'text', X'0102', '::1',
+            '2024-01-02', '2024-01-02 03:04:05', '03:04:05', INTERVAL '1' MONTH,
+            'a7e1ec9b-93c0-4a4f-bf35-7cd1e1d9cd7b', '{\"a\": {\"b\": [1, 2]}}', '[1, \"two\", null]'
+        );",
+        "INSERT INTO Everything VALUES (
+            NULL, NULL, NULL, NULL, NULL, NULL, NULL, NULL,
+            NULL, NULL, NULL, INTERVAL '3' SECOND, NULL, NULL, NULL
+        );",
+    ];
+
+    for mode in [EncryptionMode::Column, EncryptionMode::Row] {
+        let mut plain = Glue::new(MemoryStorage::default());
+        let storage = EncryptedStore::new_with_nonce_sequence(
+            MemoryStorage::default(),
+            test_util::new_key(),
+            RandNonce::new(),
+        )
+        .await
+        .unwrap()
+        .with_policy(EncryptionPolicy::new().with_mode(mode));
+        let mut glue = Glue::new(storage);
+
+        for sql in sqls {
+            plain.execute(sql).await.unwrap();
+            glue.execute(sql).await.unwrap();
+        }
+
+        assert_eq!(
+            glue.execute("SELECT * FROM Everything;").await,
+            plain.execute("SELECT * FROM Everything;").await
+        );
+    }
+}
+
+#[tokio::test]
+async fn encrypted_storage_reports_unsupported_value_versions() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::store::{DataRow, Store, StoreMut},
+        ring::aead::{Aad, LessSafeKey, Nonce},
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER);");
+    exec!(glue "INSERT INTO Item VALUES (1);");
+
+    let mut storage = glue.storage.into_inner();
+    let (key, _) = Store::scan_data(&storage, "Item")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap()
+        .remove(0);
+
+    // a value encoded by a future version of the crate
+    let nonce = [0; 12];
+    let mut encrypted = vec![0x82, 0];
+    let tag = LessSafeKey::new(test_util::new_key())
+        .seal_in_place_separate_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(nonce),
+            &mut encrypted,
+        )
+        .unwrap();
+    let forged = [&b"GEV\x01"[..], &nonce[..], &encrypted, tag.as_ref()].concat();
+
+    storage
+        .insert_data(
+            "Item",
+            vec![(key, DataRow::Vec(vec![Value::Bytea(forged)]))],
+        )
+        .await
+        .unwrap();
+
+    let storage =
+        EncryptedStore::new_with_nonce_sequence(storage, test_util::new_key(), RandNonce::new())
+            .await
+            .unwrap();
+    let error = storage
+        .scan_data("Item")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap_err();
+
+    assert!(error
+        .to_string()
+        .contains("unsupported version 0x82 (table: Some(\"Item\")"));
+}
+
+#[tokio::test]
+async fn encrypted_storage_strict_reads() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::{
+            ast::DataType,
+            store::{DataRow, Store, StoreMut},
+        },
+        gluesql_encryption::EncryptionPolicy,
+    };
+
+    let policy = EncryptionPolicy::new().encrypt_table_types("Item", [DataType::Text]);
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(policy.clone())
+    .with_strict_reads(true);
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER, name TEXT);");
+    exec!(glue "INSERT INTO Item VALUES (1, 'encrypted');");
+
+    // integers are left as-is by the policy
+    test!(
+        glue
+        "SELECT id, name FROM Item;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1), Value::Str("encrypted".to_owned())]],
+            labels: vec!["id".to_owned(), "name".to_owned()],
+        }])
+    );
+
+    let mut storage = glue.storage.into_inner();
+    let (key, _) = Store::scan_data(&storage, "Item")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap()
+        .remove(0);
+    storage
+        .insert_data(
+            "Item",
+            vec![(
+                key,
+                DataRow::Vec(vec![Value::I64(1), Value::Str("bypassed".to_owned())]),
+            )],
+        )
+        .await
+        .unwrap();
+
+    let storage =
+        EncryptedStore::new_with_nonce_sequence(storage, test_util::new_key(), RandNonce::new())
+            .await
+            .unwrap()
+            .with_policy(policy)
+            .with_strict_reads(true);
+    let error = storage
+        .scan_data("Item")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap_err();
+
+    assert!(error
+        .to_string()
+        .contains("plaintext value in encrypted table Item"));
+}
+
+#[tokio::test]
+async fn encrypted_storage_encrypts_added_columns() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::store::{DataRow, Store},
+        gluesql_encryption::{EncryptionMode, EncryptionPolicy},
+    };
+
+    for mode in [EncryptionMode::Column, EncryptionMode::Row] {
+        let storage = EncryptedStore::new_with_nonce_sequence(
+            MemoryStorage::default(),
+            test_util::new_key(),
+            RandNonce::new(),
+        )
+        .await
+        .unwrap()
+        .with_policy(EncryptionPolicy::new().with_mode(mode));
+        let mut glue = Glue::new(storage);
+
+        exec!(glue "CREATE TABLE Item (id INTEGER);");
+        exec!(glue "INSERT INTO Item VALUES (1);");
+        exec!(glue "ALTER TABLE Item ADD COLUMN name TEXT DEFAULT 'default';");
+        exec!(glue "ALTER TABLE Item ADD COLUMN data BYTEA DEFAULT X'0102';");
+
+        test!(
+            glue
+            "SELECT id, name, data FROM Item;",
+            Ok(vec![Payload::Select {
+                rows: vec![vec![
+                    Value::I64(1),
+                    Value::Str("default".to_owned()),
+                    Value::Bytea(vec![1, 2]),
+                ]],
+                labels: vec!["id".to_owned(), "name".to_owned(), "data".to_owned()],
+            }])
+        );
+
+        let rows = Store::scan_data(&glue.storage.into_inner(), "Item")
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        match &rows[0].1 {
+            DataRow::Vec(values) => {
+                assert!(values.iter().all(|value| matches!(value, Value::Bytea(_))));
+                assert!(!values.contains(&Value::Bytea(vec![1, 2])));
+            }
+            DataRow::Map(_) => panic!("rows should be stored as vectors"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn encrypted_storage_tells_bytea_from_ciphertexts() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::{
+            ast::DataType,
+            store::{DataRow, Store},
+        },
+        gluesql_encryption::EncryptionPolicy,
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().encrypt_table_types("Blob", [DataType::Text]));
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Blob (id INTEGER, header BYTEA, body BYTEA, name TEXT);");
+    exec!(glue "CREATE TABLE Secret (id INTEGER, body BYTEA);");
+    exec!(glue "INSERT INTO Blob VALUES (1, X'47455257', X'0102', 'a'), (2, X'', X'4745560100', 'b');");
+    exec!(glue "INSERT INTO Secret VALUES (1, X'0102'), (2, X'');");
+
+    test!(
+        glue
+        "SELECT header, body, name FROM Blob;",
+        Ok(vec![Payload::Select {
+            rows: vec![
+                vec![
+                    Value::Bytea(b"GERW".to_vec()),
+                    Value::Bytea(vec![1, 2]),
+                    Value::Str("a".to_owned()),
+                ],
+                vec![
+                    Value::Bytea(vec![]),
+                    Value::Bytea(b"GEV\x01\x00".to_vec()),
+                    Value::Str("b".to_owned()),
+                ],
+            ],
+            labels: vec!["header".to_owned(), "body".to_owned(), "name".to_owned()],
+        }])
+    );
+    test!(
+        glue
+        "SELECT body FROM Secret;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::Bytea(vec![1, 2])], vec![Value::Bytea(vec![])]],
+            labels: vec!["body".to_owned()],
+        }])
+    );
+
+    let rows = Store::scan_data(&glue.storage.into_inner(), "Blob")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    let values = rows
+        .into_iter()
+        .map(|(_, row)| match row {
+            DataRow::Vec(values) => values,
+            DataRow::Map(_) => panic!("rows should be stored as vectors"),
+        })
+        .collect::<Vec<_>>();
+
+    // plain bytes are left as-is, but bytes that look like ciphertexts are encrypted
+    assert_eq!(values[0][2], Value::Bytea(vec![1, 2]));
+    assert_eq!(values[1][1], Value::Bytea(vec![]));
+    assert_ne!(values[0][1], Value::Bytea(b"GERW".to_vec()));
+    assert_ne!(values[1][2], Value::Bytea(b"GEV\x01\x00".to_vec()));
+}
+
+#[tokio::test]
+async fn encrypted_storage_migrates_legacy_ciphertexts() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::store::{DataRow, Store, StoreMut},
+        gluesql_encryption::Codec,
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Secret (id INTEGER, name TEXT);");
+    exec!(glue "INSERT INTO Secret VALUES (1, 'a');");
+
+    // versions of the crate before the envelope sealed values without it
+    let mut inner = glue.storage.into_inner();
+    let (key, row) = Store::scan_data(&inner, "Secret")
+        .await
+        .unwrap()
+        .try_next()
+        .await
+        .unwrap()
+        .unwrap();
+    let DataRow::Vec(mut values) = row else {
+        panic!("rows should be stored as vectors");
+    };
+    let Value::Bytea(name) = &values[1] else {
+        panic!("the name should be encrypted");
+    };
+    let legacy = name[4..].to_vec();
+    values[1] = Value::Bytea(legacy.clone());
+    inner
+        .insert_data("Secret", vec![(key, DataRow::Vec(values))])
+        .await
+        .unwrap();
+
+    // without the flag, bytes without an envelope are plain bytes
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        inner.clone(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+    test!(
+        glue
+        "SELECT name FROM Secret;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::Bytea(legacy)]],
+            labels: vec!["name".to_owned()],
+        }])
+    );
+
+    let storage =
+        EncryptedStore::new_with_nonce_sequence(inner, test_util::new_key(), RandNonce::new())
+            .await
+            .unwrap()
+            .with_legacy_ciphertexts(true)
+            .migrate_codec(Codec::CURRENT)
+            .await
+            .unwrap()
+            .with_legacy_ciphertexts(false);
+    let mut glue = Glue::new(storage);
+    test!(
+        glue
+        "SELECT name FROM Secret;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::Str("a".to_owned())]],
+            labels: vec!["name".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_change_key_in_batches() {
+    use gluesql_encryption::EncryptionPolicy;
+
+    for policy in [
+        EncryptionPolicy::new(),
+        EncryptionPolicy::new().encrypt_row_keys(),
+    ] {
+        let storage = EncryptedStore::new_with_nonce_sequence(
+            MemoryStorage::default(),
+            test_util::new_key(),
+            RandNonce::new(),
+        )
+        .await
+        .unwrap()
+        .with_policy(policy);
+        let mut glue = Glue::new(storage);
+
+        exec!(glue "CREATE TABLE Big (id INTEGER PRIMARY KEY, v INTEGER);");
+
+        let values = (1..=2500)
+            .map(|id| format!("({id}, {})", id * 2))
+            .collect::<Vec<_>>()
+            .join(", ");
+        glue.execute(format!("INSERT INTO Big VALUES {values};"))
+            .await
+            .unwrap();
+
+        glue.storage = glue
+            .storage
+            .change_key(UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap())
+            .await
+            .unwrap();
+
+        test!(
+            glue
+            "SELECT COUNT(*), SUM(v) FROM Big;",
+            Ok(vec![Payload::Select {
+                rows: vec![vec![Value::I64(2500), Value::I64(2500 * 2501)]],
+                labels: vec!["COUNT(*)".to_owned(), "SUM(v)".to_owned()],
+            }])
+        );
+        test!(
+            glue
+            "SELECT v FROM Big WHERE id = 1234;",
+            Ok(vec![Payload::Select {
+                rows: vec![vec![Value::I64(2468)]],
+                labels: vec!["v".to_owned()],
+            }])
+        );
+    }
+}
+
+#[tokio::test]
+async fn encrypted_storage_change_key_in_transactions() {
+    use {
+        gluesql_core::store::Transaction, gluesql_encryption::EncryptionPolicy,
+        gluesql_sled_storage::SledStorage,
+    };
+
+    let mut storage = SledStorage::try_from(sled::Config::default().temporary(true)).unwrap();
+    storage.begin(true).await.unwrap();
+    let mut storage =
+        EncryptedStore::new_with_nonce_sequence(storage, test_util::new_key(), RandNonce::new())
+            .await
+            .unwrap()
+            .with_policy(EncryptionPolicy::new().encrypt_row_keys());
+    storage.commit().await.unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Big (id INTEGER PRIMARY KEY, v INTEGER);");
+
+    let values = (1..=1500)
+        .map(|id| format!("({id}, {id})"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    glue.execute(format!("INSERT INTO Big VALUES {values};"))
+        .await
+        .unwrap();
+
+    glue.storage = glue
+        .storage
+        .change_key(UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap())
+        .await
+        .unwrap();
+
+    // every batch was committed, so no transaction is left open
+    exec!(glue "BEGIN;");
+    exec!(glue "DELETE FROM Big WHERE id > 1000;");
+    exec!(glue "COMMIT;");
+
+    test!(
+        glue
+        "SELECT COUNT(*), SUM(v) FROM Big;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1000), Value::I64(500 * 1001)]],
+            labels: vec!["COUNT(*)".to_owned(), "SUM(v)".to_owned()],
+        }])
+    );
+}
+
+/// Store failing writes of rows into a table once it ran out of them, to interrupt `change_key`.
+struct FlakyStore<S> {
+    store: S,
+    table_name: &'static str,
+    writes_left: usize,
+}
+
+#[async_trait(?Send)]
+impl<S: gluesql_core::store::Store> gluesql_core::store::Store for FlakyStore<S> {
+    async fn fetch_schema(
+        &self,
+        table_name: &str,
+    ) -> gluesql_core::error::Result<Option<gluesql_core::data::Schema>> {
+        self.store.fetch_schema(table_name).await
+    }
+
+    async fn fetch_all_schemas(
+        &self,
+    ) -> gluesql_core::error::Result<Vec<gluesql_core::data::Schema>> {
+        self.store.fetch_all_schemas().await
+    }
+
+    async fn fetch_data(
+        &self,
+        table_name: &str,
+        key: &gluesql_core::data::Key,
+    ) -> gluesql_core::error::Result<Option<gluesql_core::store::DataRow>> {
+        self.store.fetch_data(table_name, key).await
+    }
+
+    async fn scan_data(
+        &self,
+        table_name: &str,
+    ) -> gluesql_core::error::Result<gluesql_core::store::RowIter<'_>> {
+        self.store.scan_data(table_name).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: gluesql_core::store::StoreMut> gluesql_core::store::StoreMut for FlakyStore<S> {
+    async fn insert_schema(
+        &mut self,
+        schema: &gluesql_core::data::Schema,
+    ) -> gluesql_core::error::Result<()> {
+        self.store.insert_schema(schema).await
+    }
+
+    async fn delete_schema(&mut self, table_name: &str) -> gluesql_core::error::Result<()> {
+        self.store.delete_schema(table_name).await
+    }
+
+    async fn append_data(
+        &mut self,
+        table_name: &str,
+        rows: Vec<gluesql_core::store::DataRow>,
+    ) -> gluesql_core::error::Result<()> {
+        self.store.append_data(table_name, rows).await
+    }
+
+    async fn insert_data(
+        &mut self,
+        table_name: &str,
+        rows: Vec<(gluesql_core::data::Key, gluesql_core::store::DataRow)>,
+    ) -> gluesql_core::error::Result<()> {
+        if table_name == self.table_name {
+            if self.writes_left == 0 {
+                return Err(gluesql_core::error::Error::StorageMsg(
+                    "interrupted".to_owned(),
+                ));
+            }
+
+            self.writes_left -= 1;
+        }
+
+        self.store.insert_data(table_name, rows).await
+    }
+
+    async fn delete_data(
+        &mut self,
+        table_name: &str,
+        keys: Vec<gluesql_core::data::Key>,
+    ) -> gluesql_core::error::Result<()> {
+        self.store.delete_data(table_name, keys).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: gluesql_core::store::Index> gluesql_core::store::Index for FlakyStore<S> {
+    async fn scan_indexed_data<'a>(
+        &'a self,
+        table_name: &str,
+        index_name: &str,
+        asc: Option<bool>,
+        cmp_value: Option<(&gluesql_core::ast::IndexOperator, Value)>,
+    ) -> gluesql_core::error::Result<gluesql_core::store::RowIter<'a>> {
+        self.store
+            .scan_indexed_data(table_name, index_name, asc, cmp_value)
+            .await
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: gluesql_core::store::IndexMut> gluesql_core::store::IndexMut for FlakyStore<S> {
+    async fn create_index(
+        &mut self,
+        table_name: &str,
+        index_name: &str,
+        column: &gluesql_core::ast::OrderByExpr,
+    ) -> gluesql_core::error::Result<()> {
+        self.store
+            .create_index(table_name, index_name, column)
+            .await
+    }
+
+    async fn drop_index(
+        &mut self,
+        table_name: &str,
+        index_name: &str,
+    ) -> gluesql_core::error::Result<()> {
+        self.store.drop_index(table_name, index_name).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: gluesql_core::store::Transaction> gluesql_core::store::Transaction for FlakyStore<S> {
+    async fn begin(&mut self, autocommit: bool) -> gluesql_core::error::Result<bool> {
+        self.store.begin(autocommit).await
+    }
+
+    async fn commit(&mut self) -> gluesql_core::error::Result<()> {
+        self.store.commit().await
+    }
+
+    async fn rollback(&mut self) -> gluesql_core::error::Result<()> {
+        self.store.rollback().await
+    }
+}
+
+#[tokio::test]
+async fn encrypted_storage_resumes_change_key() {
+    use gluesql_sled_storage::SledStorage;
+
+    let sled = SledStorage::try_from(sled::Config::default().temporary(true)).unwrap();
+    let storage = open_in_transaction(sled.clone(), test_util::new_key()).await;
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Big (id INTEGER PRIMARY KEY, v INTEGER);");
+
+    let values = (1..=2500)
+        .map(|id| format!("({id}, {id})"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    glue.execute(format!("INSERT INTO Big VALUES {values};"))
+        .await
+        .unwrap();
+
+    let flaky = |writes_left| FlakyStore {
+        store: sled.clone(),
+        table_name: "Big",
+        writes_left,
+    };
+
+    // the first batch is rewritten before the second one fails
+    let interrupted = open_in_transaction(flaky(1), test_util::new_key())
+        .await
+        .change_key(UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap())
+        .await;
+    assert!(interrupted.is_err());
+
+    // the store still opens with the old key, and only changes to the same key can resume
+    let storage = open_in_transaction(flaky(usize::MAX), test_util::new_key()).await;
+    assert!(matches!(
+        storage
+            .change_key(UnboundKey::new(&ring::aead::AES_256_GCM, &[2; 32]).unwrap())
+            .await,
+        Err(gluesql_encryption::Error::KeyChangeInProgress { .. })
+    ));
+
+    open_in_transaction(flaky(usize::MAX), test_util::new_key())
+        .await
+        .change_key(UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap())
+        .await
+        .unwrap();
+
+    let storage = open_in_transaction(
+        sled,
+        UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap(),
+    )
+    .await;
+    let mut glue = Glue::new(storage);
+
+    test!(
+        glue
+        "SELECT COUNT(*), SUM(v) FROM Big;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(2500), Value::I64(1250 * 2501)]],
+            labels: vec!["COUNT(*)".to_owned(), "SUM(v)".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_change_key_progress() {
+    use gluesql_encryption::{CancellationToken, KeyChangeProgress};
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Big (id INTEGER PRIMARY KEY, v INTEGER);");
+
+    let values = (1..=2500)
+        .map(|id| format!("({id}, {id})"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    glue.execute(format!("INSERT INTO Big VALUES {values};"))
+        .await
+        .unwrap();
+
+    // cancelled after the first batch
+    let cancel = CancellationToken::new();
+    let mut events = Vec::new();
+    let change = glue
+        .storage
+        .change_key_with_progress(
+            UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap(),
+            &cancel,
+            |progress| {
+                events.push(progress);
+                cancel.cancel();
+            },
+        )
+        .await
+        .unwrap();
+
+    assert!(change.is_cancelled());
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].tables_done, 0);
+    assert_eq!(events[0].rows_done, 1000);
+    assert!(events[0].bytes_rewritten > 0);
+
+    // resumed where it stopped
+    let mut events = Vec::<KeyChangeProgress>::new();
+    let change = change
+        .into_inner()
+        .change_key_with_progress(
+            UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap(),
+            &CancellationToken::new(),
+            |progress| events.push(progress),
+        )
+        .await
+        .unwrap();
+
+    assert!(!change.is_cancelled());
+
+    let last = events.last().unwrap();
+    assert_eq!(last.tables_done, last.tables_total);
+    assert!(last.rows_done >= 1500);
+
+    glue.storage = change.into_inner();
+
+    test!(
+        glue
+        "SELECT COUNT(*), SUM(v) FROM Big;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(2500), Value::I64(1250 * 2501)]],
+            labels: vec!["COUNT(*)".to_owned(), "SUM(v)".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_batch_size() {
+    use gluesql_encryption::CancellationToken;
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_batch_size(100)
+    .with_change_key_concurrency(1);
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Big (id INTEGER PRIMARY KEY, v INTEGER);");
+
+    let values = (1..=250)
+        .map(|id| format!("({id}, {id})"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    glue.execute(format!("INSERT INTO Big VALUES {values};"))
+        .await
+        .unwrap();
+
+    let mut events = Vec::new();
+    let change = glue
+        .storage
+        .change_key_with_progress(
+            UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap(),
+            &CancellationToken::new(),
+            |progress| events.push(progress),
+        )
+        .await
+        .unwrap();
+
+    // Big is rewritten first, a hundred rows at a time
+    assert_eq!(
+        events[..3]
+            .iter()
+            .map(|progress| progress.rows_done)
+            .collect::<Vec<_>>(),
+        vec![100, 200, 250]
+    );
+
+    glue.storage = change.into_inner();
+
+    test!(
+        glue
+        "SELECT COUNT(*), SUM(v) FROM Big;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(250), Value::I64(125 * 251)]],
+            labels: vec!["COUNT(*)".to_owned(), "SUM(v)".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_spawns_rekey() {
+    use gluesql_encryption::RekeyState;
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Big (id INTEGER PRIMARY KEY, v INTEGER);");
+
+    let values = (1..=2500)
+        .map(|id| format!("({id}, {id})"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    glue.execute(format!("INSERT INTO Big VALUES {values};"))
+        .await
+        .unwrap();
+
+    let (task, handle) = glue
+        .storage
+        .spawn_rekey(UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap());
+
+    // held before the first batch until resumed
+    handle.pause();
+
+    let (change, state) = futures::join!(task, async {
+        tokio::task::yield_now().await;
+
+        assert_eq!(handle.state(), RekeyState::Paused);
+        assert_eq!(handle.progress().rows_done, 0);
+
+        handle.resume();
+        handle.finished().await
+    });
+
+    assert_eq!(state, RekeyState::Done);
+    assert_eq!(handle.state(), RekeyState::Done);
+
+    let progress = handle.progress();
+    assert_eq!(progress.tables_done, progress.tables_total);
+    assert!(progress.rows_done >= 2500);
+
+    glue.storage = change.unwrap().into_inner();
+
+    test!(
+        glue
+        "SELECT COUNT(*), SUM(v) FROM Big;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(2500), Value::I64(1250 * 2501)]],
+            labels: vec!["COUNT(*)".to_owned(), "SUM(v)".to_owned()],
+        }])
+    );
+
+    // a paused key change stops when cancelled
+    let (task, handle) = glue
+        .storage
+        .spawn_rekey(UnboundKey::new(&ring::aead::AES_256_GCM, &[2; 32]).unwrap());
+
+    handle.pause();
+
+    let (change, state) = futures::join!(task, async {
+        tokio::task::yield_now().await;
+
+        handle.cancel();
+        handle.finished().await
+    });
+
+    assert_eq!(state, RekeyState::Cancelled);
+    assert!(change.unwrap().is_cancelled());
+}
+
+#[tokio::test]
+async fn encrypted_storage_change_key_concurrently() {
+    use gluesql_encryption::{CancellationToken, EncryptionPolicy};
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().encrypt_row_keys())
+    .with_change_key_concurrency(2);
+    let mut glue = Glue::new(storage);
+
+    let values = (1..=1500)
+        .map(|id| format!("({id}, {id})"))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    for table_name in ["A", "B", "C"] {
+        glue.execute(format!(
+            "CREATE TABLE {table_name} (id INTEGER PRIMARY KEY, v INTEGER);"
+        ))
+        .await
+        .unwrap();
+        glue.execute(format!("INSERT INTO {table_name} VALUES {values};"))
+            .await
+            .unwrap();
+    }
+
+    let mut events = Vec::new();
+    glue.storage = glue
+        .storage
+        .change_key_with_progress(
+            UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap(),
+            &CancellationToken::new(),
+            |progress| events.push(progress),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+
+    // the first two tables are rewritten a batch at a time, side by side
+    assert_eq!(events[0].rows_done, 2000);
+    assert_eq!(events[1].tables_done, 2);
+
+    let last = events.last().unwrap();
+    assert_eq!(last.tables_done, last.tables_total);
+    assert_eq!(last.rows_done, 3 * 1500 + 3);
+
+    for table_name in ["A", "B", "C"] {
+        test!(
+            glue
+            format!("SELECT COUNT(*), SUM(v) FROM {table_name};"),
+            Ok(vec![Payload::Select {
+                rows: vec![vec![Value::I64(1500), Value::I64(750 * 1501)]],
+                labels: vec!["COUNT(*)".to_owned(), "SUM(v)".to_owned()],
+            }])
+        );
+    }
+}
+
+#[tokio::test]
+async fn encrypted_storage_reencrypts_table() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::store::{DataRow, Store},
+    };
+
+    async fn raw_values(storage: &MemoryStorage, table_name: &str) -> Vec<Value> {
+        Store::scan_data(storage, table_name)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap()
+            .into_iter()
+            .flat_map(|(_, row)| match row {
+                DataRow::Vec(values) => values,
+                DataRow::Map(values) => values.into_values().collect(),
+            })
+            .collect()
+    }
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Secret (id INTEGER, name TEXT);");
+    exec!(glue "CREATE TABLE Other (id INTEGER);");
+    exec!(glue "INSERT INTO Secret VALUES (1, 'a'), (2, 'b');");
+    exec!(glue "INSERT INTO Other VALUES (1);");
+
+    let inner = glue.storage.into_inner();
+    let secret = raw_values(&inner, "Secret").await;
+    let other = raw_values(&inner, "Other").await;
+
+    let mut storage =
+        EncryptedStore::new_with_nonce_sequence(inner, test_util::new_key(), RandNonce::new())
+            .await
+            .unwrap();
+    storage.reencrypt_table("Secret").await.unwrap();
+
+    let inner = storage.into_inner();
+    let reencrypted = raw_values(&inner, "Secret").await;
+
+    assert_eq!(reencrypted.len(), secret.len());
+    assert!(reencrypted.iter().zip(&secret).all(|(new, old)| new != old));
+    assert_eq!(raw_values(&inner, "Other").await, other);
+
+    let storage =
+        EncryptedStore::new_with_nonce_sequence(inner, test_util::new_key(), RandNonce::new())
+            .await
+            .unwrap();
+    let mut glue = Glue::new(storage);
+
+    test!(
+        glue
+        "SELECT * FROM Secret;",
+        Ok(vec![Payload::Select {
+            rows: vec![
+                vec![Value::I64(1), Value::Str("a".to_owned())],
+                vec![Value::I64(2), Value::Str("b".to_owned())],
+            ],
+            labels: vec!["id".to_owned(), "name".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_changes_key_online() {
+    use {
+        gluesql_core::{data::Key, store::Store},
+        gluesql_encryption::EncryptionPolicy,
+    };
+
+    let new_key = || UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap();
+    let policy = || EncryptionPolicy::new().encrypt_row_keys();
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(policy());
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Big (id INTEGER PRIMARY KEY, v INTEGER);");
+
+    let values = (1..=1500)
+        .map(|id| format!("({id}, {})", id * 2))
+        .collect::<Vec<_>>()
+        .join(", ");
+    glue.execute(format!("INSERT INTO Big VALUES {values};"))
+        .await
+        .unwrap();
+
+    glue.storage = glue.storage.start_key_change(new_key()).await.unwrap();
+    assert!(!glue.storage.continue_key_change().await.unwrap());
+
+    // the batch rewrote the rows in the order of their encrypted keys, so the rows it didn't get
+    // to are the ones the new key alone doesn't find
+    let inner = glue.storage.into_inner();
+    let new_only =
+        EncryptedStore::new_with_nonce_sequence(inner.clone(), new_key(), RandNonce::new())
+            .await
+            .unwrap()
+            .with_policy(policy());
+    let mut old_id = None;
+
+    for id in (1..=1500).rev() {
+        if new_only
+            .fetch_data("Big", &Key::I64(id))
+            .await
+            .unwrap()
+            .is_none()
+        {
+            old_id = Some(id);
+            break;
+        }
+    }
+
+    let old_id = old_id.unwrap();
+    let storage = EncryptedStore::new_with_nonce_sequence(inner, new_key(), RandNonce::new())
+        .await
+        .unwrap()
+        .with_policy(policy())
+        .with_previous_key(test_util::new_key());
+    let mut glue = Glue::new(storage);
+
+    // rows of both keys are read, and updating one moves it to the new key
+    glue.execute(format!("UPDATE Big SET v = 0 WHERE id = {old_id};"))
+        .await
+        .unwrap();
+    exec!(glue "INSERT INTO Big VALUES (1501, 0);");
+    test!(
+        glue
+        "SELECT COUNT(*), SUM(v) FROM Big;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1501), Value::I64(1500 * 1501 - old_id * 2)]],
+            labels: vec!["COUNT(*)".to_owned(), "SUM(v)".to_owned()],
+        }])
+    );
+
+    // rows not rewritten yet are still under the old key
+    let status = glue.storage.rotation_status().await.unwrap();
+    let big = status
+        .tables
+        .iter()
+        .find(|table| table.table_name == "Big")
+        .unwrap();
+    assert_eq!((big.current_rows, big.old_rows), (1002, 499));
+    assert!(!status.is_complete());
+
+    // the old key no longer opens the store, but the new one does with the old one's help
+    let inner = glue.storage.into_inner();
+    assert_eq!(
+        EncryptedStore::new_with_nonce_sequence(
+            inner.clone(),
+            test_util::new_key(),
+            RandNonce::new()
+        )
+        .await
+        .unwrap_err(),
+        gluesql_encryption::Error::InvalidKey
+    );
+
+    let mut storage = EncryptedStore::new_with_nonce_sequence(inner, new_key(), RandNonce::new())
+        .await
+        .unwrap()
+        .with_policy(policy())
+        .with_previous_key(test_util::new_key());
+
+    while !storage.continue_key_change().await.unwrap() {}
+
+    assert!(storage.rotation_status().await.unwrap().is_complete());
+
+    let storage =
+        EncryptedStore::new_with_nonce_sequence(storage.into_inner(), new_key(), RandNonce::new())
+            .await
+            .unwrap()
+            .with_policy(policy());
+    let mut glue = Glue::new(storage);
+
+    test!(
+        glue
+        "SELECT COUNT(*), SUM(v) FROM Big;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1501), Value::I64(1500 * 1501 - old_id * 2)]],
+            labels: vec!["COUNT(*)".to_owned(), "SUM(v)".to_owned()],
+        }])
+    );
+    test!(
+        glue & format!("SELECT v FROM Big WHERE id = {old_id};"),
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(0)]],
+            labels: vec!["v".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_changes_key_online_around_deleted_rows() {
+    let new_key = || UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap();
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Big (id INTEGER PRIMARY KEY, v INTEGER);");
+
+    let values = (1..=1500)
+        .map(|id| format!("({id}, {})", id * 2))
+        .collect::<Vec<_>>()
+        .join(", ");
+    glue.execute(format!("INSERT INTO Big VALUES {values};"))
+        .await
+        .unwrap();
+
+    glue.storage = glue.storage.start_key_change(new_key()).await.unwrap();
+    assert!(!glue.storage.continue_key_change().await.unwrap());
+
+    // the row the first batch stopped at is deleted before the next one resumes from it
+    exec!(glue "DELETE FROM Big WHERE id = 1000;");
+
+    while !glue.storage.continue_key_change().await.unwrap() {}
+
+    assert!(glue.storage.rotation_status().await.unwrap().is_complete());
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        glue.storage.into_inner(),
+        new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+
+    test!(
+        glue
+        "SELECT COUNT(*), SUM(v) FROM Big;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1499), Value::I64(1500 * 1501 - 2000)]],
+            labels: vec!["COUNT(*)".to_owned(), "SUM(v)".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_encrypts_existing_store() {
+    use {
+        gluesql_core::{data::Key, store::Store},
+        gluesql_encryption::EncryptionPolicy,
+        gluesql_sled_storage::SledStorage,
+    };
+
+    let sled = SledStorage::try_from(sled::Config::default().temporary(true)).unwrap();
+    let mut glue = Glue::new(sled.clone());
+
+    exec!(glue "CREATE TABLE Big (id INTEGER PRIMARY KEY, v INTEGER);");
+
+    let values = (1..=1500)
+        .map(|id| format!("({id}, {id})"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    glue.execute(format!("INSERT INTO Big VALUES {values};"))
+        .await
+        .unwrap();
+
+    let flaky = |writes_left| FlakyStore {
+        store: sled.clone(),
+        table_name: "Big",
+        writes_left,
+    };
+    let policy = || EncryptionPolicy::new().encrypt_row_keys();
+
+    // the first batch is encrypted before the second one fails, and the rest when resumed
+    let mut interrupted = open_in_transaction(flaky(1), test_util::new_key())
+        .await
+        .with_policy(policy());
+    assert!(interrupted.encrypt_existing_store().await.is_err());
+
+    let mut storage = open_in_transaction(flaky(usize::MAX), test_util::new_key())
+        .await
+        .with_policy(policy());
+    storage.encrypt_existing_store().await.unwrap();
+
+    let rows = scan_sled(&sled, "Big").await;
+    assert_eq!(rows.len(), 1500);
+    assert!(rows.iter().all(|(key, _)| matches!(key, Key::Bytea(_))));
+
+    let storage = open_in_transaction(sled, test_util::new_key())
+        .await
+        .with_policy(policy());
+    let mut glue = Glue::new(storage);
+
+    test!(
+        glue
+        "SELECT COUNT(*), SUM(v) FROM Big;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1500), Value::I64(750 * 1501)]],
+            labels: vec!["COUNT(*)".to_owned(), "SUM(v)".to_owned()],
+        }])
+    );
+
+    // tables with pseudonymized names move to their pseudonyms
+    let mut glue = Glue::new(MemoryStorage::default());
+
+    exec!(glue "CREATE TABLE Secret (id INTEGER, name TEXT DEFAULT 'none');");
+    exec!(glue "INSERT INTO Secret VALUES (1, 'a'), (2, 'b');");
+
+    let mut storage = EncryptedStore::new_with_nonce_sequence(
+        glue.storage,
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().pseudonymize_names());
+    storage.encrypt_existing_store().await.unwrap();
+
+    let mut glue = Glue::new(storage);
+
+    test!(
+        glue
+        "SELECT * FROM Secret;",
+        Ok(vec![Payload::Select {
+            rows: vec![
+                vec![Value::I64(1), Value::Str("a".to_owned())],
+                vec![Value::I64(2), Value::Str("b".to_owned())],
+            ],
+            labels: vec!["id".to_owned(), "name".to_owned()],
+        }])
+    );
+
+    let inner = glue.storage.into_inner();
+    assert!(inner.fetch_schema("Secret").await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn encrypted_storage_decrypts_into_plaintext() {
+    use {gluesql_core::store::Store, gluesql_encryption::EncryptionPolicy};
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(
+        EncryptionPolicy::new()
+            .encrypt_row_keys()
+            .pseudonymize_names()
+            .tokenize_columns("Secret", ["name"]),
+    );
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Secret (id INTEGER PRIMARY KEY, name TEXT);");
+    exec!(glue "INSERT INTO Secret VALUES (1, 'a'), (2, 'b');");
+
+    let inner = glue.storage.decrypt_into_plaintext().await.unwrap();
+
+    let schemas = inner.fetch_all_schemas().await.unwrap();
+    assert_eq!(
+        schemas
+            .iter()
+            .map(|schema| schema.table_name.as_str())
+            .collect::<Vec<_>>(),
+        vec!["Secret"]
+    );
+
+    let mut glue = Glue::new(inner);
+
+    test!(
+        glue
+        "SELECT * FROM Secret WHERE id = 2;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(2), Value::Str("b".to_owned())]],
+            labels: vec!["id".to_owned(), "name".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_estimates_rekey() {
+    use gluesql_encryption::{CancellationToken, KeyChangeProgress};
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Big (id INTEGER PRIMARY KEY, v INTEGER);");
+    exec!(glue "CREATE TABLE Small (id INTEGER);");
+    exec!(glue "INSERT INTO Small VALUES (1), (2);");
+
+    let values = (1..=1500)
+        .map(|id| format!("({id}, {id})"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    glue.execute(format!("INSERT INTO Big VALUES {values};"))
+        .await
+        .unwrap();
+
+    let estimate = glue.storage.estimate_rekey().await.unwrap();
+    assert_eq!(estimate.tables, 3);
+    assert!(estimate.rows > 1502);
+
+    let mut last = KeyChangeProgress::default();
+    glue.storage
+        .change_key_with_progress(
+            UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap(),
+            &CancellationToken::new(),
+            |progress| last = progress,
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(last.tables_total, estimate.tables);
+    assert_eq!(last.rows_done, estimate.rows);
+    assert_eq!(last.bytes_rewritten, estimate.bytes);
+}
+
+#[tokio::test]
+async fn encrypted_storage_records_rotation_history() {
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Secret (id INTEGER);");
+    exec!(glue "INSERT INTO Secret VALUES (1), (2), (3);");
+
+    assert!(glue.storage.rotation_history().await.unwrap().is_empty());
+
+    for byte in [1, 2] {
+        glue.storage = glue
+            .storage
+            .change_key(UnboundKey::new(&ring::aead::AES_256_GCM, &[byte; 32]).unwrap())
+            .await
+            .unwrap();
+    }
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        glue.storage.into_inner(),
+        UnboundKey::new(&ring::aead::AES_256_GCM, &[2; 32]).unwrap(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    let history = storage.rotation_history().await.unwrap();
+
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[0].new_key_id, history[1].old_key_id);
+    assert_ne!(history[0].old_key_id, history[1].new_key_id);
+    assert!(history[0].finished_at <= history[1].finished_at);
+    // the table and the metadata, then the first record too
+    assert_eq!(history[0].rows_rewritten, 3 + 3);
+    assert_eq!(history[1].rows_rewritten, 3 + 4);
+}
+
+#[tokio::test]
+async fn encrypted_storage_rotation_schedule() {
+    use {
+        gluesql_encryption::{EncryptionConfig, RotationSchedule},
+        std::num::NonZeroU32,
+    };
+
+    let config: EncryptionConfig =
+        serde_json::from_str(r#"{ "rotation": { "every_days": 90 } }"#).unwrap();
+    assert_eq!(
+        config.rotation,
+        Some(RotationSchedule {
+            every_days: NonZeroU32::new(90).unwrap()
+        })
+    );
+
+    // a new store's key isn't due yet, so it's kept
+    let storage =
+        EncryptedStore::from_config(MemoryStorage::default(), config, &[0; 32], RandNonce::new())
+            .await
+            .unwrap();
+    assert!(!storage.rotation_due().await.unwrap());
+
+    let storage = storage
+        .rotate_if_due(|| panic!("the key isn't due"))
+        .await
+        .unwrap();
+    assert!(storage.rotation_history().await.unwrap().is_empty());
+
+    // stores without a schedule are never due
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        storage.into_inner(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    assert!(!storage.rotation_due().await.unwrap());
+}
+
+#[tokio::test]
+async fn encrypted_storage_change_key_rolls_back_failed_batches() {
+    use {gluesql_core::data::Key, gluesql_sled_storage::SledStorage};
+
+    let sled = SledStorage::try_from(sled::Config::default().temporary(true)).unwrap();
+    let storage = open_in_transaction(sled.clone(), test_util::new_key())
+        .await
+        .with_policy(gluesql_encryption::EncryptionPolicy::new().encrypt_row_keys());
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Big (id INTEGER PRIMARY KEY, v INTEGER);");
+
+    let values = (1..=1500)
+        .map(|id| format!("({id}, {id})"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    glue.execute(format!("INSERT INTO Big VALUES {values};"))
+        .await
+        .unwrap();
+
+    // rows moved to their new keys are deleted from their old ones in the same transaction, so
+    // a failed batch leaves neither copy behind
+    let interrupted = open_in_transaction(
+        FlakyStore {
+            store: sled.clone(),
+            table_name: "encrypted_rotation",
+            writes_left: 0,
+        },
+        test_util::new_key(),
+    )
+    .await
+    .with_policy(gluesql_encryption::EncryptionPolicy::new().encrypt_row_keys())
+    .change_key(UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap())
+    .await;
+    assert!(interrupted.is_err());
+
+    let rows = scan_sled(&sled, "Big").await;
+    assert_eq!(rows.len(), 1500);
+    assert!(rows.iter().all(|(key, _)| matches!(key, Key::Bytea(_))));
+}
+
+#[tokio::test]
+async fn encrypted_storage_change_key_staged() {
+    use gluesql_encryption::EncryptionPolicy;
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().encrypt_row_keys());
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Big (id INTEGER PRIMARY KEY, v INTEGER);");
+    exec!(glue "CREATE TABLE Small (id INTEGER);");
+    exec!(glue "INSERT INTO Small VALUES (1), (2);");
+
+    let values = (1..=1500)
+        .map(|id| format!("({id}, {id})"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    glue.execute(format!("INSERT INTO Big VALUES {values};"))
+        .await
+        .unwrap();
+
+    glue.storage = glue
+        .storage
+        .change_key_staged(UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap())
+        .await
+        .unwrap();
+
+    test!(
+        glue
+        "SELECT COUNT(*), SUM(v) FROM Big;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1500), Value::I64(750 * 1501)]],
+            labels: vec!["COUNT(*)".to_owned(), "SUM(v)".to_owned()],
+        }])
+    );
+    test!(
+        glue
+        "SELECT v FROM Big WHERE id = 1234;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1234)]],
+            labels: vec!["v".to_owned()],
+        }])
+    );
+
+    let inner = glue.storage.into_inner();
+    assert_eq!(
+        EncryptedStore::new_with_nonce_sequence(
+            inner.clone(),
+            test_util::new_key(),
+            RandNonce::new()
+        )
+        .await
+        .unwrap_err(),
+        gluesql_encryption::Error::InvalidKey
+    );
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        inner,
+        UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(storage.rotation_history().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn encrypted_storage_migrates_codec() {
+    use gluesql_encryption::{Codec, EncryptionPolicy};
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().encrypt_row_keys());
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Secret (id INTEGER PRIMARY KEY, name TEXT);");
+    exec!(glue "INSERT INTO Secret VALUES (1, 'a'), (2, 'b');");
+
+    glue.storage = glue.storage.migrate_codec(Codec::CURRENT).await.unwrap();
+
+    test!(
+        glue
+        "SELECT * FROM Secret WHERE id = 2;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(2), Value::Str("b".to_owned())]],
+            labels: vec!["id".to_owned(), "name".to_owned()],
+        }])
+    );
+    test!(
+        glue
+        "SELECT COUNT(*) FROM Secret;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(2)]],
+            labels: vec!["COUNT(*)".to_owned()],
+        }])
+    );
+
+    // the key didn't change, so it's not recorded as a rotation
+    assert!(glue.storage.rotation_history().await.unwrap().is_empty());
+    assert!(matches!(
+        glue.storage.migrate_codec(Codec::LegacyPostcard).await,
+        Err(gluesql_encryption::Error::Unsupported(_))
+    ));
+}
+
+#[tokio::test]
+async fn encrypted_storage_copies_to_another_store() {
+    use {
+        gluesql_encryption::{EncryptionPolicy, Error},
+        gluesql_sled_storage::SledStorage,
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_batch_size(2);
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER PRIMARY KEY, name TEXT DEFAULT 'none');");
+    exec!(glue "INSERT INTO Item VALUES (1, 'a'), (2, 'b'), (3, 'c');");
+    exec!(glue "CREATE TABLE Log (message TEXT);");
+    exec!(glue "INSERT INTO Log VALUES ('x'), ('y');");
+
+    let sled = SledStorage::try_from(sled::Config::default().temporary(true)).unwrap();
+    let mut other = open_in_transaction(
+        sled,
+        UnboundKey::new(&ring::aead::CHACHA20_POLY1305, &[1; 32]).unwrap(),
+    )
+    .await
+    .with_policy(EncryptionPolicy::new().pseudonymize_names());
+
+    glue.storage.copy_to(&mut other).await.unwrap();
+
+    // copying again would overwrite the tables
+    assert_eq!(
+        glue.storage.copy_to(&mut other).await,
+        Err(Error::TableExists("Item".to_owned()))
+    );
+
+    let mut other = Glue::new(other);
+
+    exec!(other "INSERT INTO Item (id) VALUES (4);");
+
+    test!(
+        other
+        "SELECT id, name FROM Item;",
+        Ok(vec![Payload::Select {
+            rows: vec![
+                vec![Value::I64(1), Value::Str("a".to_owned())],
+                vec![Value::I64(2), Value::Str("b".to_owned())],
+                vec![Value::I64(3), Value::Str("c".to_owned())],
+                vec![Value::I64(4), Value::Str("none".to_owned())],
+            ],
+            labels: vec!["id".to_owned(), "name".to_owned()],
+        }])
+    );
+    test!(
+        other
+        "SELECT COUNT(*) FROM Log;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(2)]],
+            labels: vec!["COUNT(*)".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_copies_tables_without_primary_keys() {
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_batch_size(2);
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Log (message TEXT);");
+    exec!(glue "INSERT INTO Log VALUES ('a'), ('b'), ('c');");
+
+    let mut other = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    glue.storage.copy_to(&mut other).await.unwrap();
+
+    // rows inserted after the copy don't take the keys of the copied ones
+    let mut other = Glue::new(other);
+    exec!(other "INSERT INTO Log VALUES ('d');");
+
+    test!(
+        other
+        "SELECT message FROM Log;",
+        Ok(vec![Payload::Select {
+            rows: ["a", "b", "c", "d"]
+                .into_iter()
+                .map(|message| vec![Value::Str(message.to_owned())])
+                .collect(),
+            labels: vec!["message".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_in_namespace() {
+    use {
+        gluesql_core::store::Store,
+        gluesql_encryption::{EncryptionPolicy, Error},
+    };
+
+    let storage = EncryptedStore::new_in_namespace(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+        "crypt_",
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().pseudonymize_names());
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER);");
+    exec!(glue "INSERT INTO Item VALUES (1);");
+
+    let inner = glue.storage.into_inner();
+    let mut table_names = inner
+        .fetch_all_schemas()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|schema| schema.table_name)
+        .filter(|table_name| !table_name.starts_with("t_"))
+        .collect::<Vec<_>>();
+    table_names.sort();
+
+    assert_eq!(table_names, vec!["crypt_meta", "crypt_names"]);
+
+    // the key check is looked up in the namespace
+    assert_eq!(
+        EncryptedStore::new_in_namespace(
+            inner.clone(),
+            UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap(),
+            RandNonce::new(),
+            "crypt_",
+        )
+        .await
+        .unwrap_err(),
+        Error::InvalidKey
+    );
+
+    let storage =
+        EncryptedStore::new_in_namespace(inner, test_util::new_key(), RandNonce::new(), "crypt_")
+            .await
+            .unwrap()
+            .with_policy(EncryptionPolicy::new().pseudonymize_names());
+    let mut glue = Glue::new(storage);
+
+    test!(
+        glue
+        "SELECT id FROM Item;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1)]],
+            labels: vec!["id".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_persists_nonce_state_in_namespace() {
+    use {gluesql_core::store::Store, gluesql_encryption::CounterNonce};
+
+    let open = |storage: MemoryStorage| {
+        EncryptedStore::new_with_persistent_nonces_in_namespace(
+            storage,
+            test_util::new_key(),
+            CounterNonce::default(),
+            "crypt_",
+        )
+    };
+
+    let mut glue = Glue::new(open(MemoryStorage::default()).await.unwrap());
+
+    exec!(glue "CREATE TABLE Item (id INTEGER);");
+    exec!(glue "INSERT INTO Item VALUES (1);");
+
+    let inner = glue.storage.close().await.unwrap();
+    let mut table_names = inner
+        .fetch_all_schemas()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|schema| schema.table_name)
+        .collect::<Vec<_>>();
+    table_names.sort();
+
+    // the nonce state is saved with the key check, in the namespace
+    assert_eq!(table_names, vec!["Item", "crypt_meta"]);
+
+    let mut glue = Glue::new(open(inner).await.unwrap());
+
+    exec!(glue "INSERT INTO Item VALUES (2);");
+    test!(
+        glue
+        "SELECT id FROM Item;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1)], vec![Value::I64(2)]],
+            labels: vec!["id".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_encrypts_remaining_plaintext() {
+    use gluesql_encryption::{EncryptionPolicy, PlaintextTable};
+
+    let mut plain = Glue::new(MemoryStorage::default());
+
+    exec!(plain "CREATE TABLE Item (id INTEGER PRIMARY KEY, name TEXT);");
+    exec!(plain "INSERT INTO Item VALUES (1, 'a'), (2, 'b'), (3, 'c');");
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        plain.storage,
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().encrypt_row_keys())
+    .with_batch_size(2);
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "INSERT INTO Item VALUES (4, 'd');");
+
+    let report = glue.storage.plaintext_report().await.unwrap();
+    assert_eq!(
+        report.tables,
+        vec![PlaintextTable {
+            table_name: "Item".to_owned(),
+            plaintext_rows: 3,
+            encrypted_rows: 1,
+        }]
+    );
+    assert!(report.tables[0].is_mixed());
+
+    assert_eq!(glue.storage.encrypt_remaining().await.unwrap(), 3);
+    assert!(glue.storage.plaintext_report().await.unwrap().is_clean());
+    assert_eq!(glue.storage.encrypt_remaining().await.unwrap(), 0);
+
+    // rows moved to their encrypted keys
+    test!(
+        glue
+        "SELECT name FROM Item WHERE id = 2;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::Str("b".to_owned())]],
+            labels: vec!["name".to_owned()],
+        }])
+    );
+    test!(
+        glue
+        "SELECT COUNT(*) FROM Item;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(4)]],
+            labels: vec!["COUNT(*)".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_verifies_all_rows() {
+    use gluesql_core::{
+        data::Key,
+        store::{DataRow, Store, StoreMut},
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER PRIMARY KEY, name TEXT);");
+    exec!(glue "INSERT INTO Item VALUES (1, 'a'), (2, 'b'), (3, 'c');");
+
+    let report = glue.storage.verify_all().await.unwrap();
+    assert!(report.is_intact());
+
+    let item = report
+        .tables
+        .iter()
+        .find(|table| table.table_name == "Item")
+        .unwrap();
+    assert_eq!(item.ok, 3);
+
+    // flip a bit of a ciphertext in the inner store
+    let mut inner = glue.storage.into_inner();
+    let Some(DataRow::Vec(mut values)) = inner.fetch_data("Item", &Key::I64(2)).await.unwrap()
+    else {
+        panic!("rows should be stored as vectors");
+    };
+    let Value::Bytea(encrypted) = &mut values[1] else {
+        panic!("values should be encrypted");
+    };
+    *encrypted.last_mut().unwrap() ^= 1;
+    inner
+        .insert_data("Item", vec![(Key::I64(2), DataRow::Vec(values))])
+        .await
+        .unwrap();
+
+    let storage =
+        EncryptedStore::new_with_nonce_sequence(inner, test_util::new_key(), RandNonce::new())
+            .await
+            .unwrap();
+    let report = storage.verify_all().await.unwrap();
+
+    assert!(!report.is_intact());
+
+    let item = report
+        .tables
+        .iter()
+        .find(|table| table.table_name == "Item")
+        .unwrap();
+    assert_eq!((item.ok, item.tampered, item.corrupt), (2, 1, 0));
+    assert_eq!(item.offending_keys, vec![Key::I64(2)]);
+}
+
+#[tokio::test]
+async fn encrypted_storage_reports_corruption() {
+    use {
+        gluesql_core::{
+            data::Key,
+            store::{DataRow, Store, StoreMut},
+        },
+        gluesql_encryption::{Corruption, CorruptionKind},
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER PRIMARY KEY, name TEXT);");
+    exec!(glue "INSERT INTO Item VALUES (1, 'a'), (2, 'b');");
+
+    let mut inner = glue.storage.into_inner();
+    let Some(DataRow::Vec(mut values)) = inner.fetch_data("Item", &Key::I64(2)).await.unwrap()
+    else {
+        panic!("rows should be stored as vectors");
+    };
+    let Value::Bytea(encrypted) = &mut values[1] else {
+        panic!("values should be encrypted");
+    };
+    *encrypted.last_mut().unwrap() ^= 1;
+    inner
+        .insert_data("Item", vec![(Key::I64(2), DataRow::Vec(values))])
+        .await
+        .unwrap();
+
+    // strict reads load the column names, so the failing value is named
+    let storage =
+        EncryptedStore::new_with_nonce_sequence(inner, test_util::new_key(), RandNonce::new())
+            .await
+            .unwrap()
+            .with_strict_reads(true);
+    let mut glue = Glue::new(storage);
+
+    assert!(glue.execute("SELECT * FROM Item;").await.is_err());
+    assert_eq!(
+        glue.storage.take_corruption_report().corruptions,
+        vec![Corruption {
+            table: "Item".to_owned(),
+            key: Key::I64(2),
+            column: Some("name".to_owned()),
+            kind: CorruptionKind::Tampered,
+        }]
+    );
+    assert!(glue.storage.corruption_report().is_empty());
+}
+
+#[tokio::test]
+async fn encrypted_storage_repairs_with_old_keys() {
+    use {
+        gluesql_core::{data::Key, store::Transaction},
+        gluesql_sled_storage::SledStorage,
+    };
+
+    let old_key = || UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap();
+
+    let sled = SledStorage::try_from(sled::Config::default().temporary(true)).unwrap();
+    let storage = open_in_transaction(sled.clone(), test_util::new_key()).await;
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER PRIMARY KEY, name TEXT);");
+    exec!(glue "INSERT INTO Item VALUES (1, 'a'), (2, 'b');");
+
+    // a row written with another key, e.g. by a client left on the old one
+    let mut stale = Glue::new(EncryptedStore::new_unchecked(
+        sled,
+        old_key(),
+        RandNonce::new(),
+    ));
+    exec!(stale "INSERT INTO Item VALUES (3, 'c');");
+
+    // keys that open nothing leave the row as it is
+    let report = glue
+        .storage
+        .repair_with_keys([UnboundKey::new(&ring::aead::AES_256_GCM, &[2; 32]).unwrap()])
+        .await
+        .unwrap();
+    assert_eq!(report.repaired, 0);
+    // sled scans keys as the bytes they're ordered by
+    assert_eq!(
+        report.unrecoverable,
+        vec![(
+            "Item".to_owned(),
+            Key::Bytea(Key::I64(3).to_cmp_be_bytes().unwrap())
+        )]
+    );
+
+    let report = glue.storage.repair_with_keys([old_key()]).await.unwrap();
+    assert_eq!(report.repaired, 1);
+    assert!(report.unrecoverable.is_empty());
+    glue.storage.begin(true).await.unwrap();
+    assert!(glue.storage.verify_all().await.unwrap().is_intact());
+    glue.storage.commit().await.unwrap();
+
+    test!(
+        glue
+        "SELECT name FROM Item WHERE id = 3;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::Str("c".to_owned())]],
+            labels: vec!["name".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_seals_batches_with_distinct_nonces() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::store::{DataRow, Store},
+        std::collections::HashSet,
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER, name TEXT);");
+    exec!(glue "INSERT INTO Item VALUES (1, 'same'), (1, 'same'), (1, 'same');");
+
+    test!(
+        glue
+        "SELECT COUNT(*) FROM Item WHERE name = 'same';",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(3)]],
+            labels: vec!["COUNT(*)".to_owned()],
+        }])
+    );
+
+    let rows = Store::scan_data(&glue.storage.into_inner(), "Item")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    let ciphertexts = rows
+        .into_iter()
+        .flat_map(|(_, row)| match row {
+            DataRow::Vec(values) => values,
+            DataRow::Map(_) => unreachable!(),
+        })
+        .map(|value| match value {
+            Value::Bytea(ciphertext) => ciphertext,
+            value => panic!("{value:?} wasn't encrypted"),
+        })
+        .collect::<HashSet<_>>();
+
+    // the rows of a batch share one advance of the nonce sequence, but no nonce
+    assert_eq!(ciphertexts.len(), 6);
+}
+
+#[tokio::test]
+async fn encrypted_storage_offloads_large_rows() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    let offloaded = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&offloaded);
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_blocking_executor(1024, move |task| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        std::thread::spawn(task);
+    });
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Blob (id INTEGER PRIMARY KEY, data TEXT);");
+    exec!(glue "INSERT INTO Blob VALUES (1, 'small');");
+    assert_eq!(offloaded.load(Ordering::SeqCst), 0);
+
+    let large = "x".repeat(4096);
+    glue.execute(format!("INSERT INTO Blob VALUES (2, '{large}');"))
+        .await
+        .unwrap();
+    assert_eq!(offloaded.load(Ordering::SeqCst), 1);
+
+    test!(
+        glue
+        "SELECT data FROM Blob WHERE id = 2;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::Str(large)]],
+            labels: vec!["data".to_owned()],
+        }])
+    );
+    // primary key lookups go through fetch_data, which opens the row on the executor too
+    assert_eq!(offloaded.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn encrypted_storage_scans_concurrently() {
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_scan_concurrency(4);
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER PRIMARY KEY, name TEXT);");
+
+    let values = (1..=1000)
+        .map(|id| format!("({id}, 'item {id}')"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    glue.execute(format!("INSERT INTO Item VALUES {values};"))
+        .await
+        .unwrap();
+
+    // rows come back in order across chunks opened on different threads
+    test!(
+        glue
+        "SELECT id, name FROM Item;",
+        Ok(vec![Payload::Select {
+            rows: (1..=1000)
+                .map(|id| vec![Value::I64(id), Value::Str(format!("item {id}"))])
+                .collect(),
+            labels: vec!["id".to_owned(), "name".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_caches_schemas() {
+    use {gluesql_core::store::Store, gluesql_sled_storage::SledStorage, test_util::new_key};
+
+    let sled = SledStorage::try_from(sled::Config::default().temporary(true)).unwrap();
+    let mut glue = Glue::new(open_in_transaction(sled.clone(), new_key()).await);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER);");
+    exec!(glue "ALTER TABLE Item ADD COLUMN name TEXT;");
+
+    // schema changes made through the store are seen right away
+    let columns = |schema: Option<gluesql_core::data::Schema>| {
+        schema
+            .unwrap()
+            .column_defs
+            .unwrap()
+            .into_iter()
+            .map(|column_def| column_def.name)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        columns(glue.storage.fetch_schema("Item").await.unwrap()),
+        ["id", "name"]
+    );
+
+    // changes made by another client sharing the inner store aren't, until the cache is cleared
+    let mut other = Glue::new(EncryptedStore::new_unchecked(
+        sled,
+        new_key(),
+        RandNonce::new(),
+    ));
+    exec!(other "DROP TABLE Item;");
+
+    assert!(glue.storage.fetch_schema("Item").await.unwrap().is_some());
+    glue.storage.clear_schema_cache();
+    assert!(glue.storage.fetch_schema("Item").await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn encrypted_storage_reports_stats() {
+    use gluesql_encryption::{Compression, EncryptionMode, EncryptionPolicy};
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_compression(Compression::Deflate { level: 6 })
+    .with_policy(EncryptionPolicy::new().with_table_mode("Analytics", EncryptionMode::Row));
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Analytics (id INTEGER, a TEXT);");
+    exec!(glue "CREATE TABLE Users (id INTEGER, a TEXT);");
+
+    for table in ["Analytics", "Users"] {
+        for id in 1..=3 {
+            glue.execute(format!(
+                "INSERT INTO {table} VALUES ({id}, '{}');",
+                "a".repeat(200)
+            ))
+            .await
+            .unwrap();
+        }
+    }
+
+    let mut stats = glue.storage.stats().await.unwrap().tables;
+    stats.sort_by(|a, b| a.table_name.cmp(&b.table_name));
+
+    assert_eq!(stats.len(), 2);
+    assert_eq!(
+        (stats[0].table_name.as_str(), stats[0].mode, stats[0].rows),
+        ("Analytics", EncryptionMode::Row, 3)
+    );
+    assert_eq!(stats[0].sealed_values, 3);
+    assert_eq!(
+        (stats[1].table_name.as_str(), stats[1].mode, stats[1].rows),
+        ("Users", EncryptionMode::Column, 3)
+    );
+    assert_eq!(stats[1].sealed_values, 6);
+
+    for table in &stats {
+        assert!(table.compression_ratio() < 1.0);
+        assert!(table.ciphertext_bytes > table.compressed_bytes);
+        assert!(table.overhead_per_value() > 0);
+    }
+}
+
+#[tokio::test]
+async fn encrypted_storage_scans_keys() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::{data::Key, store::Store},
+        gluesql_encryption::EncryptionPolicy,
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().encrypt_row_keys());
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Account (id INTEGER PRIMARY KEY, balance INTEGER);");
+    exec!(glue "INSERT INTO Account VALUES (1, 10), (2, 20), (3, 30);");
+
+    let mut keys = glue
+        .storage
+        .scan_keys("Account")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    keys.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    assert_eq!(keys, [Key::I64(1), Key::I64(2), Key::I64(3)]);
+
+    // the keys match those of the decrypted rows
+    let rows = glue
+        .storage
+        .scan_data("Account")
+        .await
+        .unwrap()
+        .map_ok(|(key, _)| key)
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    let scanned = glue
+        .storage
+        .scan_keys("Account")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+
+    assert_eq!(rows, scanned);
+}
+
+#[tokio::test]
+async fn encrypted_storage_auto_algorithm() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::store::{DataRow, Store},
+        gluesql_encryption::{Algorithm, EncryptionPolicy},
+    };
+
+    let policy = EncryptionPolicy::new()
+        .with_table_algorithm("Pinned", Algorithm::Aes128Gcm)
+        .with_auto_algorithm();
+    assert_eq!(policy.table_algorithm("Pinned"), Some(Algorithm::Aes128Gcm));
+    assert_eq!(policy.table_algorithm("Item"), Some(Algorithm::detect()));
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(policy);
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER);");
+    exec!(glue "INSERT INTO Item VALUES (1);");
+
+    test!(
+        glue
+        "SELECT id FROM Item;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1)]],
+            labels: vec!["id".to_owned()],
+        }])
+    );
+
+    // the key of the store is AES-256-GCM, so only ChaCha20-Poly1305 gets a cipher header
+    let chacha = Algorithm::detect() == Algorithm::ChaCha20Poly1305;
+    let rows = Store::scan_data(&glue.storage.into_inner(), "Item")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert!(matches!(
+        &rows[0].1,
+        DataRow::Vec(values)
+            if matches!(&values[0], Value::Bytea(encrypted) if encrypted.starts_with(b"GEV\x01GEC\x03") == chacha)
+    ));
+}
+
+#[tokio::test]
+async fn encrypted_storage_limits_scans() {
+    use {futures::TryStreamExt, gluesql_core::store::Store};
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_scan_concurrency(4)
+    .with_max_in_flight_rows(10)
+    .with_max_ciphertext_len(256);
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER, name TEXT);");
+
+    for id in 1..=100 {
+        glue.execute(format!("INSERT INTO Item VALUES ({id}, 'item {id}');"))
+            .await
+            .unwrap();
+    }
+
+    test!(
+        glue
+        "SELECT COUNT(*) FROM Item;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(100)]],
+            labels: vec!["COUNT(*)".to_owned()],
+        }])
+    );
+
+    glue.execute(format!(
+        "INSERT INTO Item VALUES (101, '{}');",
+        "a".repeat(512)
+    ))
+    .await
+    .unwrap();
+
+    let error = glue
+        .storage
+        .scan_data("Item")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap_err();
+
+    assert!(error
+        .to_string()
+        .contains("bytes in table Item is over the limit"));
+}
+
+#[tokio::test]
+async fn encrypted_storage_counts_nonces() {
+    use {
+        gluesql_core::{
+            data::Key,
+            store::{DataRow, Transaction},
+        },
+        gluesql_sled_storage::SledStorage,
+        test_util::new_key,
+    };
+
+    // sled only reads in transactions
+    let open = |mut sled: SledStorage| async move {
+        sled.begin(true).await.unwrap();
+        let mut storage = EncryptedStore::new_with_counter_nonce(sled, new_key())
+            .await
+            .unwrap();
+        storage.commit().await.unwrap();
+
+        storage
+    };
+
+    let high_water_mark = |row: Option<DataRow>| match row {
+        Some(DataRow::Map(values)) => match &values["state"] {
+            Value::Bytea(state) => u64::from_be_bytes(state.as_slice().try_into().unwrap()),
+            _ => panic!("state isn't a BYTEA"),
+        },
+        _ => panic!("no state"),
+    };
+
+    let sled = SledStorage::try_from(sled::Config::default().temporary(true)).unwrap();
+    let mut glue = Glue::new(open(sled.clone()).await);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER, name TEXT);");
+    exec!(glue "INSERT INTO Item VALUES (1, 'first');");
+
+    let first = high_water_mark(fetch_sled(&sled, "encrypted_meta", &Key::U8(3)).await);
+
+    // reopening skips past everything the last store reserved
+    let mut glue = Glue::new(open(sled.clone()).await);
+
+    exec!(glue "INSERT INTO Item VALUES (2, 'second');");
+
+    let second = high_water_mark(fetch_sled(&sled, "encrypted_meta", &Key::U8(3)).await);
+    assert!(second > first);
+
+    test!(
+        glue
+        "SELECT id, name FROM Item ORDER BY id;",
+        Ok(vec![Payload::Select {
+            rows: vec![
+                vec![Value::I64(1), Value::Str("first".to_owned())],
+                vec![Value::I64(2), Value::Str("second".to_owned())],
+            ],
+            labels: vec!["id".to_owned(), "name".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_reserves_nonces_before_sealing() {
+    use {
+        gluesql_encryption::{Error, PersistentNonceSequence},
+        ring::aead::{Nonce, NonceSequence},
+        std::sync::{Arc, Mutex},
+    };
+
+    /// Counts up without a reservation of its own, and logs when it's saved and drawn from.
+    struct LoggedCounter {
+        next: u64,
+        log: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl NonceSequence for LoggedCounter {
+        fn advance(&mut self) -> Result<Nonce, ring::error::Unspecified> {
+            self.log.lock().unwrap().push("advance");
+
+            let mut nonce = [0; 12];
+            nonce[..8].copy_from_slice(&self.next.to_be_bytes());
+            self.next += 1;
+
+            Ok(Nonce::assume_unique_for_key(nonce))
+        }
+    }
+
+    impl PersistentNonceSequence for LoggedCounter {
+        fn load_state(&mut self, _state: Option<&[u8]>) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn save_state(&mut self) -> Option<Vec<u8>> {
+            Some(self.next.to_be_bytes().to_vec())
+        }
+
+        fn state_saved(&mut self, saved: bool) {
+            self.log
+                .lock()
+                .unwrap()
+                .push(if saved { "saved" } else { "lost" });
+        }
+    }
+
+    let log = Arc::new(Mutex::new(Vec::new()));
+
+    // opening a new store seals its key check, which mustn't happen before the state is saved
+    EncryptedStore::new_with_persistent_nonces(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        LoggedCounter {
+            next: 0,
+            log: Arc::clone(&log),
+        },
+    )
+    .await
+    .unwrap();
+
+    let log = log.lock().unwrap().clone();
+    assert_eq!(log.first(), Some(&"saved"));
+    assert!(log.contains(&"advance"));
+
+    // and a counter whose reservation can't be saved seals nothing
+    let interrupted = EncryptedStore::new_with_counter_nonce(
+        FlakyStore {
+            store: MemoryStorage::default(),
+            table_name: "encrypted_meta",
+            writes_left: 0,
+        },
+        test_util::new_key(),
+    )
+    .await;
+    assert!(interrupted.is_err());
+}
+
+#[tokio::test]
+async fn encrypted_storage_batch_nonces_dont_overlap() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::{
+            data::Key,
+            store::{DataRow, Store, Transaction},
+        },
+        gluesql_encryption::BATCH_PREFIX_LEN,
+        gluesql_sled_storage::SledStorage,
+        std::collections::HashSet,
+    };
+
+    // the nonces of the sealed values of every row, which follow the envelope
+    let nonces = |rows: Vec<(Key, DataRow)>| {
+        rows.into_iter()
+            .map(|(_, row)| match row {
+                DataRow::Vec(values) => values
+                    .iter()
+                    .map(|value| match value {
+                        Value::Bytea(sealed) => sealed[4..16].to_vec(),
+                        value => panic!("{value:?} isn't encrypted"),
+                    })
+                    .collect::<Vec<_>>(),
+                DataRow::Map(_) => panic!("rows should be stored as vectors"),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let sled = SledStorage::try_from(sled::Config::default().temporary(true)).unwrap();
+    // sled only reads and writes in transactions, so the nonce state is loaded in one
+    let mut storage = sled.clone();
+    storage.begin(true).await.unwrap();
+    let mut storage = EncryptedStore::new_with_counter_nonce(storage, test_util::new_key())
+        .await
+        .unwrap();
+    storage.commit().await.unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER, name TEXT, note TEXT);");
+    exec!(glue "INSERT INTO Item VALUES (1, 'a', 'b'), (2, 'c', 'd');");
+    exec!(glue "INSERT INTO Item VALUES (3, 'e', 'f');");
+
+    let rows = nonces(scan_sled(&sled, "Item").await);
+    let all = rows.iter().flatten().collect::<Vec<_>>();
+    assert_eq!(all.len(), 9);
+    assert_eq!(all.iter().collect::<HashSet<_>>().len(), all.len());
+
+    // the rows of a batch share the counter drawn for it, and count through its last 4 bytes
+    let prefix = |nonce: &Vec<u8>| nonce[..BATCH_PREFIX_LEN].to_vec();
+    let batch = &rows[..2].concat();
+    assert!(batch.iter().all(|nonce| prefix(nonce) == prefix(&batch[0])));
+    assert_eq!(
+        batch
+            .iter()
+            .map(|nonce| u32::from_be_bytes(nonce[BATCH_PREFIX_LEN..].try_into().unwrap()))
+            .collect::<HashSet<_>>(),
+        (0..6).collect()
+    );
+    assert!(rows[2]
+        .iter()
+        .all(|nonce| prefix(nonce) != prefix(&batch[0])));
+
+    // nonces derived from random ones don't repeat either
+    let storage = EncryptedStore::new(MemoryStorage::default(), test_util::new_key())
+        .await
+        .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER, name TEXT, note TEXT);");
+    exec!(glue "INSERT INTO Item VALUES (1, 'a', 'b'), (2, 'c', 'd');");
+    exec!(glue "INSERT INTO Item VALUES (3, 'e', 'f');");
+
+    let inner = glue.storage.into_inner();
+    let rows = Store::scan_data(&inner, "Item")
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    let all = nonces(rows).concat();
+    assert_eq!(all.len(), 9);
+    assert_eq!(all.iter().collect::<HashSet<_>>().len(), all.len());
+}
+
+#[tokio::test]
+async fn encrypted_storage_persists_nonce_state() {
+    use {
+        gluesql_encryption::{Error, PersistentNonceSequence},
+        ring::aead::{Nonce, NonceSequence},
+        std::sync::{Arc, Mutex},
+    };
+
+    /// Counts up from its saved state, and logs the calls to its hooks.
+    struct LoggedCounter {
+        next: u64,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl NonceSequence for LoggedCounter {
+        fn advance(&mut self) -> Result<Nonce, ring::error::Unspecified> {
+            let mut nonce = [0; 12];
+            nonce[..8].copy_from_slice(&self.next.to_be_bytes());
+            self.next += 1;
+
+            Ok(Nonce::assume_unique_for_key(nonce))
+        }
+    }
+
+    impl PersistentNonceSequence for LoggedCounter {
+        fn load_state(&mut self, state: Option<&[u8]>) -> Result<(), Error> {
+            self.log.lock().unwrap().push(format!("load {state:?}"));
+            self.next = state.map_or(0, |state| u64::from(state[0]));
+
+            Ok(())
+        }
+
+        fn save_state(&mut self) -> Option<Vec<u8>> {
+            self.log.lock().unwrap().push(format!("save {}", self.next));
+
+            Some(vec![u8::try_from(self.next).unwrap()])
+        }
+    }
+
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let counter = |log: &Arc<Mutex<Vec<String>>>| LoggedCounter {
+        next: 0,
+        log: Arc::clone(log),
+    };
+
+    let storage = EncryptedStore::new_with_persistent_nonces(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        counter(&log),
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER);");
+    exec!(glue "INSERT INTO Item VALUES (1);");
+
+    let saved = log.lock().unwrap().last().unwrap().clone();
+    let inner = glue.storage.close().await.unwrap();
+
+    // the state saved on close is loaded back on open
+    let storage =
+        EncryptedStore::new_with_persistent_nonces(inner, test_util::new_key(), counter(&log))
+            .await
+            .unwrap();
+    let logged = log.lock().unwrap().clone();
+    let closed_at = logged[logged.len() - 3].clone();
+
+    assert_eq!(logged[0], "load None");
+    assert_ne!(saved, closed_at);
+    assert_eq!(
+        logged[logged.len() - 2],
+        format!("load Some([{}])", closed_at.trim_start_matches("save "))
+    );
+
+    let mut glue = Glue::new(storage);
+    test!(
+        glue
+        "SELECT id FROM Item;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1)]],
+            labels: vec!["id".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+#[should_panic(expected = "nonce sequence repeated the nonce")]
+async fn encrypted_storage_catches_repeated_nonces() {
+    use ring::aead::{Nonce, NonceSequence};
+
+    struct StuckNonce;
+
+    impl NonceSequence for StuckNonce {
+        fn advance(&mut self) -> Result<Nonce, ring::error::Unspecified> {
+            Ok(Nonce::assume_unique_for_key([7; 12]))
+        }
+    }
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        StuckNonce,
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER);");
+    exec!(glue "INSERT INTO Item VALUES (1);");
+}
+
+#[tokio::test]
+async fn encrypted_storage_default_nonces() {
+    let storage: EncryptedStore<MemoryStorage> =
+        EncryptedStore::new(MemoryStorage::default(), test_util::new_key())
+            .await
+            .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER, name TEXT);");
+    exec!(glue "INSERT INTO Item VALUES (1, 'a'), (2, 'b');");
+
+    test!(
+        glue
+        "SELECT id, name FROM Item;",
+        Ok(vec![Payload::Select {
+            rows: vec![
+                vec![Value::I64(1), Value::Str("a".to_owned())],
+                vec![Value::I64(2), Value::Str("b".to_owned())],
+            ],
+            labels: vec!["id".to_owned(), "name".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_fetches_nonces_from_source() {
+    use {
+        gluesql_encryption::{Error, NonceSource, SyncNonceSource},
+        std::sync::{Arc, Mutex},
+    };
+
+    /// Hands out random nonces after waiting on the executor, and logs how many it's asked for.
+    struct SlowSource {
+        inner: SyncNonceSource<RandNonce>,
+        fetches: Arc<Mutex<Vec<usize>>>,
+    }
+
+    #[cfg_attr(not(feature = "send"), async_trait(?Send))]
+    #[cfg_attr(feature = "send", async_trait)]
+    impl NonceSource for SlowSource {
+        async fn fetch_nonces(&mut self, count: usize) -> Result<Vec<[u8; 12]>, Error> {
+            tokio::task::yield_now().await;
+            self.fetches.lock().unwrap().push(count);
+
+            self.inner.fetch_nonces(count).await
+        }
+    }
+
+    let fetches = Arc::new(Mutex::new(Vec::new()));
+    let storage = EncryptedStore::new_with_nonce_source(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        SlowSource {
+            inner: SyncNonceSource(RandNonce::new()),
+            fetches: Arc::clone(&fetches),
+        },
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER, name TEXT);");
+
+    for id in 0..300 {
+        glue.execute(format!("INSERT INTO Item VALUES ({id}, 'item {id}');"))
+            .await
+            .unwrap();
+    }
+
+    // nonces are fetched ahead in batches, not one by one
+    let fetches = fetches.lock().unwrap().len();
+    assert!(fetches > 1);
+    assert!(fetches < 300);
+
+    test!(
+        glue
+        "SELECT name FROM Item WHERE id = 299;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::Str("item 299".to_owned())]],
+            labels: vec!["name".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_synthetic_nonces() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::store::{DataRow, Store},
+    };
+
+    // every store draws random nonces, so only derived ones can match
+    let write = |name: &'static str| async move {
+        let storage = EncryptedStore::new_with_nonce_sequence(
+            MemoryStorage::default(),
+            test_util::new_key(),
+            RandNonce::new(),
+        )
+        .await
+        .unwrap()
+        .with_synthetic_nonces(true);
+        let mut glue = Glue::new(storage);
+
+        exec!(glue "CREATE TABLE Item (id INTEGER PRIMARY KEY, name TEXT);");
+        glue.execute(format!("INSERT INTO Item VALUES (1, '{name}');"))
+            .await
+            .unwrap();
+
+        test!(
+            glue
+            "SELECT name FROM Item;",
+            Ok(vec![Payload::Select {
+                rows: vec![vec![Value::Str(name.to_owned())]],
+                labels: vec!["name".to_owned()],
+            }])
+        );
+
+        let rows: Vec<(_, DataRow)> = Store::scan_data(&glue.storage.into_inner(), "Item")
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+
+        rows
+    };
+
+    let first = write("a").await;
+
+    assert!(matches!(&first[0].1, DataRow::Vec(values) if matches!(values[1], Value::Bytea(_))));
+    assert_eq!(first, write("a").await);
+    assert_ne!(first, write("b").await);
+}
+
+#[tokio::test]
+async fn encrypted_storage_partitions_nonces_by_writer() {
+    use {
+        gluesql_core::{
+            data::Key,
+            store::{DataRow, Transaction},
+        },
+        gluesql_encryption::CounterNonce,
+        gluesql_sled_storage::SledStorage,
+        std::num::NonZeroU16,
+    };
+
+    let sled = SledStorage::try_from(sled::Config::default().temporary(true)).unwrap();
+    // sled only reads in transactions
+    let writer = |id| {
+        let mut sled = sled.clone();
+
+        async move {
+            sled.begin(true).await.unwrap();
+            let mut storage = EncryptedStore::new_with_persistent_nonces(
+                sled,
+                test_util::new_key(),
+                CounterNonce::with_writer_id(NonZeroU16::new(id).unwrap()),
+            )
+            .await
+            .unwrap();
+            storage.commit().await.unwrap();
+
+            storage
+        }
+    };
+
+    let mut first = Glue::new(writer(1).await);
+    let mut second = Glue::new(writer(2).await);
+
+    exec!(first "CREATE TABLE Item (id INTEGER PRIMARY KEY, name TEXT);");
+    exec!(first "INSERT INTO Item VALUES (1, 'first');");
+    exec!(second "INSERT INTO Item VALUES (2, 'second');");
+
+    // each writer keeps its own high-water mark
+    for id in [1, 2] {
+        assert!(fetch_sled(&sled, "encrypted_meta", &Key::U16(id))
+            .await
+            .is_some());
+    }
+
+    let rows = scan_sled(&sled, "Item").await;
+    let writer_ids = rows
+        .iter()
+        .map(|(_, row)| match row {
+            // the nonce follows the envelope, and starts with the id of the writer
+            DataRow::Vec(values) => match &values[1] {
+                Value::Bytea(sealed) => u16::from_be_bytes([sealed[4], sealed[5]]),
+                value => panic!("{value:?} isn't encrypted"),
+            },
+            DataRow::Map(_) => panic!("unexpected schemaless row"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(writer_ids, vec![1, 2]);
+
+    test!(
+        second
+        "SELECT name FROM Item ORDER BY id;",
+        Ok(vec![Payload::Select {
+            rows: vec![
+                vec![Value::Str("first".to_owned())],
+                vec![Value::Str("second".to_owned())],
+            ],
+            labels: vec!["name".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_stops_before_nonces_run_out() {
+    use {
+        gluesql_core::{
+            data::Key,
+            store::{DataRow, StoreMut, Transaction},
+        },
+        gluesql_encryption::CounterNonce,
+        gluesql_sled_storage::SledStorage,
+        std::num::NonZeroU16,
+    };
+
+    let mut sled = SledStorage::try_from(sled::Config::default().temporary(true)).unwrap();
+    // sled only reads and writes in transactions
+    let writer = |mut sled: SledStorage| async move {
+        sled.begin(true).await.unwrap();
+        let mut storage = EncryptedStore::new_with_persistent_nonces(
+            sled,
+            test_util::new_key(),
+            CounterNonce::with_writer_id(NonZeroU16::new(1).unwrap()),
+        )
+        .await
+        .unwrap();
+        storage.commit().await.unwrap();
+
+        storage
+    };
+
+    let mut glue = Glue::new(writer(sled.clone()).await);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER, name TEXT);");
+    exec!(glue "INSERT INTO Item VALUES (1, 'first');");
+
+    // a writer's counter has 48 bits, nearly all of them used up here
+    let state = (1_u64 << 48) - 1000;
+    sled.begin(true).await.unwrap();
+    sled.insert_data(
+        "encrypted_meta",
+        vec![(
+            Key::U16(1),
+            DataRow::Map(
+                [(
+                    "state".to_owned(),
+                    Value::Bytea(state.to_be_bytes().to_vec()),
+                )]
+                .into_iter()
+                .collect(),
+            ),
+        )],
+    )
+    .await
+    .unwrap();
+    sled.commit().await.unwrap();
+
+    let mut glue = Glue::new(writer(sled).await);
+
+    let error = glue
+        .execute("INSERT INTO Item VALUES (2, 'second');")
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("running out of nonces"));
+
+    test!(
+        glue
+        "SELECT name FROM Item;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::Str("first".to_owned())]],
+            labels: vec!["name".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_stops_creating_functions_before_nonces_run_out() {
+    use {
+        gluesql_core::{
+            data::Key,
+            store::{DataRow, StoreMut},
+        },
+        gluesql_encryption::CounterNonce,
+        std::{collections::HashMap, num::NonZeroU16},
+    };
+
+    let writer = |storage: MemoryStorage| {
+        EncryptedStore::new_with_persistent_nonces(
+            storage,
+            test_util::new_key(),
+            CounterNonce::with_writer_id(NonZeroU16::new(1).unwrap()),
+        )
+    };
+
+    let glue = Glue::new(writer(MemoryStorage::default()).await.unwrap());
+    let mut inner = glue.storage.into_inner();
+
+    // a writer's counter has 48 bits, nearly all of them used up here
+    let state = (1_u64 << 48) - 1000;
+    inner
+        .insert_data(
+            "encrypted_meta",
+            vec![(
+                Key::U16(1),
+                DataRow::Map(HashMap::from([(
+                    "state".to_owned(),
+                    Value::Bytea(state.to_be_bytes().to_vec()),
+                )])),
+            )],
+        )
+        .await
+        .unwrap();
+
+    let mut glue = Glue::new(writer(inner).await.unwrap());
+
+    let error = glue
+        .execute("CREATE FUNCTION add_one(x INTEGER) RETURN x + 1;")
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("running out of nonces"));
+}
+
+#[cfg(feature = "sled-storage")]
+#[tokio::test]
+async fn encrypted_sled_opens_a_store() {
+    let path = std::env::temp_dir().join(format!("gluesql-encryption-{}", std::process::id()));
+    let storage = gluesql_encryption::encrypted_sled(path.to_str().unwrap(), test_util::new_key())
+        .await
+        .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER);");
+    exec!(glue "INSERT INTO Item VALUES (1);");
+
+    test!(
+        glue
+        "SELECT id FROM Item;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1)]],
+            labels: vec!["id".to_owned()],
+        }])
+    );
+
+    drop(glue);
+    std::fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn from_hex_only_takes_hex_digits() {
+    use gluesql_encryption::{from_hex, Error};
+
+    assert_eq!(from_hex("00ff7A").unwrap(), [0x00, 0xff, 0x7a]);
+    assert!(from_hex("").unwrap().is_empty());
+
+    for hex in ["0", "+f", "-1", "0x", " 1", "é0"] {
+        assert_eq!(from_hex(hex), Err(Error::InvalidValue), "{hex}");
+    }
+}
+
+#[cfg(feature = "cli")]
+#[tokio::test]
+async fn cli_verifies_a_sled_store() {
+    use std::process::Command;
+
+    let path = std::env::temp_dir().join(format!("gluesql-enc-{}", std::process::id()));
+    let storage = gluesql_encryption::encrypted_sled(path.to_str().unwrap(), test_util::new_key())
+        .await
+        .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER, name TEXT);");
+    exec!(glue "INSERT INTO Item VALUES (1, 'a'), (2, 'b');");
+    drop(glue);
+
+    let run = |command: &str, key: &str| {
+        Command::new(env!("CARGO_BIN_EXE_gluesql-enc"))
+            .args([command, &format!("sled:{}", path.display())])
+            .env("GLUESQL_ENC_KEY", key)
+            .output()
+            .unwrap()
+    };
+    let key = "00".repeat(32);
+
+    let output = run("verify-key", &key);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "the key matches\n");
+    assert!(!run("verify-key", &"01".repeat(32)).status.success());
+
+    let output = run("verify", &key);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Item: 2 ok, 0 tampered, 0 corrupt"));
+
+    assert!(run("stats", &key).status.success());
+
+    let new_key = "01".repeat(32);
+    let output = Command::new(env!("CARGO_BIN_EXE_gluesql-enc"))
+        .args(["rekey", &format!("sled:{}", path.display())])
+        .env("GLUESQL_ENC_KEY", &key)
+        .env("GLUESQL_ENC_NEW_KEY", &new_key)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(run("verify", &new_key).status.success());
+    assert!(!run("verify-key", &key).status.success());
+
+    std::fs::remove_dir_all(path).unwrap();
+}
+
+#[cfg(feature = "cli")]
+#[tokio::test]
+async fn cli_doesnt_rekey_with_the_wrong_key() {
+    use {
+        gluesql_core::store::Store,
+        gluesql_encryption::{in_transaction, SledStorage},
+        std::process::Command,
+    };
+
+    let dir = std::env::temp_dir().join(format!("gluesql-enc-rekey-{}", std::process::id()));
+    let (path, empty) = (dir.join("store"), dir.join("empty"));
+    let storage = gluesql_encryption::encrypted_sled(path.to_str().unwrap(), test_util::new_key())
+        .await
+        .unwrap();
+    drop(storage);
+
+    let run = |command: &str, path: &std::path::Path, key: &str| {
+        Command::new(env!("CARGO_BIN_EXE_gluesql-enc"))
+            .args([command, &format!("sled:{}", path.display())])
+            .env("GLUESQL_ENC_KEY", key)
+            .env("GLUESQL_ENC_NEW_KEY", "01".repeat(32))
+            .env_remove("GLUESQL_ENC_CONFIG")
+            .output()
+            .unwrap()
+    };
+
+    // the key is checked before anything is written
+    assert!(!run("rekey", &path, &"02".repeat(32)).status.success());
+    assert!(run("verify-key", &path, &"00".repeat(32)).status.success());
+
+    // and a store without a key check isn't set up
+    assert!(!run("rekey", &empty, &"00".repeat(32)).status.success());
+
+    let mut sled = SledStorage::new(empty.to_str().unwrap()).unwrap();
+    let schemas = in_transaction(&mut sled, async |sled, _| {
+        Ok(Store::fetch_all_schemas(sled).await?)
+    })
+    .await
+    .unwrap();
+    assert!(schemas.is_empty());
+
+    drop(sled);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(feature = "cli")]
+#[tokio::test]
+async fn cli_exports_and_imports_backups() {
+    use std::process::Command;
+
+    let dir = std::env::temp_dir().join(format!("gluesql-enc-backup-{}", std::process::id()));
+    let (source, backup, destination) = (
+        dir.join("source"),
+        dir.join("backup"),
+        dir.join("destination"),
+    );
+    let storage =
+        gluesql_encryption::encrypted_sled(source.to_str().unwrap(), test_util::new_key())
+            .await
+            .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER, name TEXT);");
+    exec!(glue "INSERT INTO Item VALUES (1, 'secret one'), (2, 'secret two');");
+    drop(glue);
+
+    let run = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_gluesql-enc"))
+            .args(args)
+            .env("GLUESQL_ENC_KEY", "00".repeat(32))
+            .env("GLUESQL_ENC_BACKUP_KEY", "07".repeat(32))
+            .env_remove("GLUESQL_ENC_CONFIG")
+            .output()
+            .unwrap()
+    };
+    let (source, backup, destination) = (
+        format!("sled:{}", source.display()),
+        backup.to_str().unwrap(),
+        format!("sled:{}", destination.display()),
+    );
+
+    let output = run(&["export", &source, backup]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "2 rows were exported\n"
+    );
+    assert!(!std::fs::read(backup)
+        .unwrap()
+        .windows(6)
+        .any(|window| window == b"secret"));
+    // backups aren't overwritten
+    assert!(!run(&["export", &source, backup]).status.success());
+
+    let output = run(&["import", backup, &destination]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "2 rows were imported\n"
+    );
+
+    let output = run(&["verify", &destination]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Item: 2 ok, 0 tampered, 0 corrupt"));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(feature = "cli")]
+#[tokio::test]
+async fn cli_opens_stores_from_a_config() {
+    use {
+        gluesql_core::store::Transaction,
+        gluesql_encryption::{Algorithm, EncryptionConfig, SledStorage},
+        std::process::Command,
+    };
+
+    let dir = std::env::temp_dir().join(format!("gluesql-enc-config-{}", std::process::id()));
+    let (path, config_path) = (dir.join("store"), dir.join("config.toml"));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        &config_path,
+        "algorithm = \"cha_cha20_poly1305\"\nnamespace = \"app_crypt_\"\n",
+    )
+    .unwrap();
+
+    let config = EncryptionConfig {
+        algorithm: Algorithm::ChaCha20Poly1305,
+        namespace: Some("app_crypt_".to_owned()),
+        ..EncryptionConfig::default()
+    };
+    let mut sled = SledStorage::new(path.to_str().unwrap()).unwrap();
+    sled.begin(true).await.unwrap();
+    let mut storage = EncryptedStore::from_config(sled, config, &[7; 32], RandNonce::new())
+        .await
+        .unwrap();
+    storage.commit().await.unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER);");
+    exec!(glue "INSERT INTO Item VALUES (1);");
+    drop(glue);
+
+    let store = format!("sled:{}", path.display());
+    let key = "07".repeat(32);
+    let run = |args: &[&str], config: Option<&std::path::Path>| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_gluesql-enc"));
+        command.args(args).env("GLUESQL_ENC_KEY", &key);
+
+        match config {
+            Some(config) => command.env("GLUESQL_ENC_CONFIG", config),
+            None => command.env_remove("GLUESQL_ENC_CONFIG"),
+        };
+
+        command.output().unwrap()
+    };
+
+    // without the config, the key check is looked for in the default namespace
+    assert!(!run(&["verify-key", &store], None).status.success());
+
+    let output = run(
+        &["--config", config_path.to_str().unwrap(), "verify", &store],
+        None,
+    );
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Item: 1 ok, 0 tampered, 0 corrupt"));
+
+    let output = run(&["verify-key", &store], Some(&config_path));
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "the key matches\n");
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn encrypted_storage_builds_without_ring() {
+    use gluesql_encryption::{Algorithm, Error, Nonce, NonceSequence, Unspecified, NONCE_LEN};
+
+    struct CountingNonce(u64);
+
+    impl NonceSequence for CountingNonce {
+        fn advance(&mut self) -> Result<Nonce, Unspecified> {
+            let mut nonce = [0; NONCE_LEN];
+            nonce[..8].copy_from_slice(&self.0.to_be_bytes());
+            self.0 += 1;
+
+            Ok(Nonce::assume_unique_for_key(nonce))
+        }
+    }
+
+    assert_eq!(
+        Algorithm::ChaCha20Poly1305.key(&[7; 16]).unwrap_err(),
+        Error::InvalidKeyMaterial
+    );
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        Algorithm::ChaCha20Poly1305.key(&[7; 32]).unwrap(),
+        CountingNonce(0),
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER);");
+    exec!(glue "INSERT INTO Item VALUES (1);");
+
+    test!(
+        glue
+        "SELECT id FROM Item;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1)]],
+            labels: vec!["id".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_from_key_bytes() {
+    use gluesql_encryption::Error;
+
+    assert_eq!(
+        EncryptedStore::from_key_bytes(MemoryStorage::default(), &[0; 16])
+            .await
+            .err(),
+        Some(Error::InvalidKeyMaterial)
+    );
+
+    let storage = EncryptedStore::from_key_bytes(MemoryStorage::default(), &vec![0; 32])
+        .await
+        .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER);");
+    exec!(glue "INSERT INTO Item VALUES (1);");
+
+    // the same bytes as an AES-256-GCM key open the store
+    let storage: EncryptedStore<MemoryStorage> =
+        EncryptedStore::new(glue.storage.into_inner(), test_util::new_key())
+            .await
+            .unwrap();
+    let mut glue = Glue::new(storage);
+
+    test!(
+        glue
+        "SELECT id FROM Item;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1)]],
+            labels: vec!["id".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_shares_value_format() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::store::{DataRow, Store, StoreMut},
+        gluesql_encryption::{Algorithm, ValueCipher},
+    };
+
+    let storage: EncryptedStore<MemoryStorage> =
+        EncryptedStore::new(MemoryStorage::default(), test_util::new_key())
+            .await
+            .unwrap();
+    let cipher = storage.value_cipher();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER, name TEXT);");
+    exec!(glue "INSERT INTO Item VALUES (1, 'written by the store');");
+
+    let mut inner = glue.storage.into_inner();
+    let rows: Vec<(_, DataRow)> = Store::scan_data(&inner, "Item")
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    let DataRow::Vec(values) = &rows[0].1 else {
+        panic!("unexpected schemaless row");
+    };
+
+    assert_eq!(
+        cipher.decrypt_value(&values[1]).unwrap(),
+        Value::Str("written by the store".to_owned())
+    );
+    assert!(
+        ValueCipher::new(Algorithm::Aes256Gcm.key(&[1; 32]).unwrap())
+            .decrypt_value(&values[1])
+            .is_err()
+    );
+
+    let encrypted = [
+        Value::I64(2),
+        Value::Str("written by the application".to_owned()),
+    ]
+    .iter()
+    .map(|value| cipher.encrypt_value(value).unwrap())
+    .collect();
+    inner
+        .append_data("Item", vec![DataRow::Vec(encrypted)])
+        .await
+        .unwrap();
+
+    let storage: EncryptedStore<MemoryStorage> = EncryptedStore::new(inner, test_util::new_key())
+        .await
+        .unwrap();
+    let mut glue = Glue::new(storage);
+
+    test!(
+        glue
+        "SELECT name FROM Item ORDER BY id;",
+        Ok(vec![Payload::Select {
+            rows: vec![
+                vec![Value::Str("written by the store".to_owned())],
+                vec![Value::Str("written by the application".to_owned())],
+            ],
+            labels: vec!["name".to_owned()],
+        }])
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_clones() {
+    use {gluesql_core::store::Transaction, gluesql_sled_storage::SledStorage};
+
+    let mut sled = SledStorage::try_from(sled::Config::default().temporary(true)).unwrap();
+    // sled only reads and writes in transactions, so the key check is made in one
+    sled.begin(true).await.unwrap();
+    let mut storage: EncryptedStore<SledStorage> = EncryptedStore::new(sled, test_util::new_key())
+        .await
+        .unwrap();
+    storage.commit().await.unwrap();
+    let mut first = Glue::new(storage.clone());
+    let mut second = Glue::new(storage);
+
+    exec!(first "CREATE TABLE Item (id INTEGER, name TEXT);");
+    exec!(first "INSERT INTO Item VALUES (1, 'first');");
+    exec!(second "INSERT INTO Item VALUES (2, 'second');");
+
+    for glue in [&mut first, &mut second] {
+        test!(
+            glue
+            "SELECT id, name FROM Item ORDER BY id;",
+            Ok(vec![Payload::Select {
+                rows: vec![
+                    vec![Value::I64(1), Value::Str("first".to_owned())],
+                    vec![Value::I64(2), Value::Str("second".to_owned())],
+                ],
+                labels: vec!["id".to_owned(), "name".to_owned()],
+            }])
+        );
+    }
+}
+
+#[tokio::test]
+async fn encrypted_storage_reaches_inner_store() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::store::{DataRow, Store},
+    };
+
+    let storage: EncryptedStore<MemoryStorage> =
+        EncryptedStore::new(MemoryStorage::default(), test_util::new_key())
+            .await
+            .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER);");
+    exec!(glue "INSERT INTO Item VALUES (1);");
+
+    let rows: Vec<(_, DataRow)> = Store::scan_data(glue.storage.inner(), "Item")
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    assert!(matches!(&rows[0].1, DataRow::Vec(values) if matches!(values[0], Value::Bytea(_))));
+
+    // a copy of the inner store taken before the second insert only has the first row
+    let snapshot = glue.storage.inner().clone();
+    exec!(glue "INSERT INTO Item VALUES (2);");
+
+    let replaced = glue.storage.swap_inner(snapshot);
+    test!(
+        glue
+        "SELECT id FROM Item;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1)]],
+            labels: vec!["id".to_owned()],
+        }])
+    );
+
+    let mut glue = Glue::new(glue.storage.map_inner(|_| replaced));
+    test!(
+        glue
+        "SELECT id FROM Item ORDER BY id;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1)], vec![Value::I64(2)]],
+            labels: vec!["id".to_owned()],
+        }])
+    );
+}
+
+/// Store only implementing `Store`, like a read replica.
+struct ReadOnlyStore<S>(S);
+
+#[async_trait(?Send)]
+impl<S: gluesql_core::store::Store> gluesql_core::store::Store for ReadOnlyStore<S> {
+    async fn fetch_schema(
+        &self,
+        table_name: &str,
+    ) -> gluesql_core::error::Result<Option<gluesql_core::data::Schema>> {
+        self.0.fetch_schema(table_name).await
+    }
+
+    async fn fetch_all_schemas(
+        &self,
+    ) -> gluesql_core::error::Result<Vec<gluesql_core::data::Schema>> {
+        self.0.fetch_all_schemas().await
+    }
+
+    async fn fetch_data(
+        &self,
+        table_name: &str,
+        key: &gluesql_core::data::Key,
+    ) -> gluesql_core::error::Result<Option<gluesql_core::store::DataRow>> {
+        self.0.fetch_data(table_name, key).await
+    }
+
+    async fn scan_data(
+        &self,
+        table_name: &str,
+    ) -> gluesql_core::error::Result<gluesql_core::store::RowIter<'_>> {
+        self.0.scan_data(table_name).await
+    }
+}
+
+#[tokio::test]
+async fn encrypted_storage_opens_read_only() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::store::{DataRow, Store},
+        gluesql_encryption::Error,
+    };
+
+    let storage: EncryptedStore<MemoryStorage> =
+        EncryptedStore::new(MemoryStorage::default(), test_util::new_key())
+            .await
+            .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER, name TEXT);");
+    exec!(glue "INSERT INTO Item VALUES (1, 'first');");
+
+    let inner = glue.storage.into_inner();
+    let replica =
+        EncryptedStore::open_read_only(ReadOnlyStore(inner.clone()), test_util::new_key())
+            .await
+            .unwrap();
+
+    assert!(replica.fetch_schema("Item").await.unwrap().is_some());
+    let rows: Vec<(_, DataRow)> = replica
+        .scan_data("Item")
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(
+        rows.into_iter().map(|(_, row)| row).collect::<Vec<_>>(),
+        vec![DataRow::Vec(vec![
+            Value::I64(1),
+            Value::Str("first".to_owned())
+        ])]
+    );
+
+    let wrong_key = UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap();
+    assert!(matches!(
+        EncryptedStore::open_read_only(ReadOnlyStore(inner), wrong_key).await,
+        Err(Error::InvalidKey)
+    ));
+
+    // a store without a key check isn't set up, since that would mean writing to it
+    assert!(matches!(
+        EncryptedStore::open_read_only(
+            ReadOnlyStore(MemoryStorage::default()),
+            test_util::new_key()
+        )
+        .await,
+        Err(Error::NonEncryptedDatabase)
+    ));
+}
+
+#[tokio::test]
+async fn encrypted_storage_reports_failed_decryption() {
+    use {
+        gluesql_core::{
+            data::Key,
+            store::{DataRow, Store, StoreMut},
+        },
+        gluesql_encryption::{CorruptionKind, Error},
+    };
+
+    let storage: EncryptedStore<MemoryStorage> =
+        EncryptedStore::new(MemoryStorage::default(), test_util::new_key())
+            .await
+            .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER PRIMARY KEY, name TEXT);");
+    exec!(glue "INSERT INTO Item VALUES (1, 'a');");
+
+    let mut inner = glue.storage.into_inner();
+    let Some(DataRow::Vec(mut values)) = inner.fetch_data("Item", &Key::I64(1)).await.unwrap()
+    else {
+        panic!("rows should be stored as vectors");
+    };
+    let Value::Bytea(encrypted) = &mut values[1] else {
+        panic!("values should be encrypted");
+    };
+    *encrypted.last_mut().unwrap() ^= 1;
+    inner
+        .insert_data("Item", vec![(Key::I64(1), DataRow::Vec(values))])
+        .await
+        .unwrap();
+
+    let storage: EncryptedStore<MemoryStorage> = EncryptedStore::new(inner, test_util::new_key())
+        .await
+        .unwrap()
+        .with_strict_reads(true);
+
+    // strict reads load the column names, so the failing value is named
+    assert_eq!(
+        storage.fetch_data("Item", &Key::I64(1)).await.unwrap_err(),
+        Error::DecryptFailed {
+            table: "Item".to_owned(),
+            key: Key::I64(1),
+            column: Some("name".to_owned()),
+            kind: CorruptionKind::Tampered,
+        }
+        .into()
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_keeps_error_kinds_through_glue() {
+    use {
+        gluesql_core::{
+            data::Key,
+            store::{DataRow, Store, StoreMut},
+        },
+        gluesql_encryption::{Error, ErrorKind},
+    };
+
+    let storage: EncryptedStore<MemoryStorage> =
+        EncryptedStore::new(MemoryStorage::default(), test_util::new_key())
+            .await
+            .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER PRIMARY KEY, name TEXT);");
+    exec!(glue "INSERT INTO Item VALUES (1, 'a');");
+
+    let mut inner = glue.storage.into_inner();
+    let Some(DataRow::Vec(mut values)) = inner.fetch_data("Item", &Key::I64(1)).await.unwrap()
+    else {
+        panic!("rows should be stored as vectors");
+    };
+    let Value::Bytea(encrypted) = &mut values[1] else {
+        panic!("values should be encrypted");
+    };
+    *encrypted.last_mut().unwrap() ^= 1;
+    inner
+        .insert_data("Item", vec![(Key::I64(1), DataRow::Vec(values))])
+        .await
+        .unwrap();
+
+    let storage: EncryptedStore<MemoryStorage> = EncryptedStore::new(inner, test_util::new_key())
+        .await
+        .unwrap();
+    let mut glue = Glue::new(storage);
+
+    let error = glue.execute("SELECT * FROM Item;").await.unwrap_err();
+    assert_eq!(ErrorKind::of(&error), Some(ErrorKind::DecryptFailed));
+
+    let error = glue.execute("SELECT * FROM Missing;").await.unwrap_err();
+    assert_eq!(ErrorKind::of(&error), None);
+
+    // errors of the inner store come out as the inner store returned them
+    let inner_error = || gluesql_core::error::Error::StorageMsg("disk full".to_owned());
+    assert_eq!(
+        gluesql_core::error::Error::from(Error::StoreError(inner_error())),
+        inner_error()
+    );
+}
+
+#[test]
+fn error_codes_are_stable() {
+    use gluesql_encryption::{Error, ErrorKind};
+
+    assert_eq!(Error::InvalidKey.code(), 2);
+    assert_eq!(Error::NoncesExhausted.code(), 17);
+    assert_eq!(Error::InvalidKey.kind().name(), "InvalidKey");
+    assert_eq!(ErrorKind::from_code(8), Some(ErrorKind::DecryptFailed));
+    assert_eq!(ErrorKind::from_code(0), None);
+}
+
+#[test]
+fn errors_and_reports_redact_row_keys() {
+    use {
+        gluesql_core::data::Key,
+        gluesql_encryption::{Corruption, CorruptionKind, Error},
+    };
+
+    let error = Error::UnsupportedValueVersion {
+        version: 0x82,
+        table: Some("User".to_owned()),
+        key: Some(Key::Str("alice@example.com".to_owned())),
+        raw: Some(b"alice's secret".to_vec()),
+    };
+
+    for rendered in [format!("{error}"), format!("{error:?}")] {
+        assert!(!rendered.contains("alice"), "{rendered}");
+        assert!(rendered.contains("User"), "{rendered}");
+    }
+    assert!(format!("{error:?}").contains("Str(<17 bytes>)"));
+
+    let corruption = Corruption {
+        table: "User".to_owned(),
+        key: Key::I64(42),
+        column: None,
+        kind: CorruptionKind::Tampered,
+    };
+    assert!(!format!("{corruption:?}").contains("42"));
+}
+
+#[tokio::test]
+async fn encrypted_storage_calls_decrypt_failure_hook() {
+    use {
+        gluesql_core::{
+            data::Key,
+            store::{DataRow, Store, StoreMut},
+        },
+        gluesql_encryption::{Corruption, CorruptionKind},
+        std::sync::{Arc, Mutex},
+    };
+
+    let storage: EncryptedStore<MemoryStorage> =
+        EncryptedStore::new(MemoryStorage::default(), test_util::new_key())
+            .await
+            .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER PRIMARY KEY, name TEXT);");
+    exec!(glue "INSERT INTO Item VALUES (1, 'a'), (2, 'b');");
+
+    let mut inner = glue.storage.into_inner();
+    let Some(DataRow::Vec(mut values)) = inner.fetch_data("Item", &Key::I64(2)).await.unwrap()
+    else {
+        panic!("rows should be stored as vectors");
+    };
+    let Value::Bytea(encrypted) = &mut values[1] else {
+        panic!("values should be encrypted");
+    };
+    *encrypted.last_mut().unwrap() ^= 1;
+    inner
+        .insert_data("Item", vec![(Key::I64(2), DataRow::Vec(values))])
+        .await
+        .unwrap();
+
+    let failures = Arc::new(Mutex::new(Vec::new()));
+    let storage: EncryptedStore<MemoryStorage> = EncryptedStore::new(inner, test_util::new_key())
+        .await
+        .unwrap()
+        .with_decrypt_failure_hook({
+            let failures = Arc::clone(&failures);
+            move |corruption| failures.lock().unwrap().push(corruption.clone())
+        });
+    let mut glue = Glue::new(storage);
+
+    assert!(glue.execute("SELECT * FROM Item;").await.is_err());
+    assert_eq!(
+        *failures.lock().unwrap(),
+        vec![Corruption {
+            table: "Item".to_owned(),
+            key: Key::I64(2),
+            column: None,
+            kind: CorruptionKind::Tampered,
+        }]
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_notifies_observer() {
+    use {
+        gluesql_encryption::EncryptionObserver,
+        std::sync::{Arc, Mutex},
+    };
+
+    #[derive(Default)]
+    struct Events(Mutex<Vec<String>>);
+
+    impl EncryptionObserver for Events {
+        fn rows_encrypted(&self, table: &str, rows: usize) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("encrypted {rows} rows of {table}"));
+        }
+
+        fn row_decrypted(&self, table: &str) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("decrypted a row of {table}"));
+        }
+
+        fn key_rotated(&self, _old_key_id: &str, _new_key_id: &str) {
+            self.0.lock().unwrap().push("rotated".to_owned());
+        }
+
+        fn sentinel_written(&self) {
+            self.0.lock().unwrap().push("sentinel".to_owned());
+        }
+    }
+
+    let events = Arc::new(Events::default());
+    let storage: EncryptedStore<MemoryStorage> =
+        EncryptedStore::new(MemoryStorage::default(), test_util::new_key())
+            .await
+            .unwrap()
+            .with_observer(Arc::clone(&events));
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER);");
+    exec!(glue "INSERT INTO Item VALUES (1), (2);");
+    exec!(glue "SELECT * FROM Item;");
+
+    let storage = glue
+        .storage
+        .change_key(UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        *events.0.lock().unwrap(),
+        [
+            "sentinel",
+            "encrypted 2 rows of Item",
+            "decrypted a row of Item",
+            "decrypted a row of Item",
+            "rotated",
+        ]
+    );
+
+    // reopening the store doesn't write the key check again
+    events.0.lock().unwrap().clear();
+    let _storage = EncryptedStore::new(
+        storage.into_inner(),
+        UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap(),
+    )
+    .await
+    .unwrap()
+    .with_observer(Arc::clone(&events));
+    assert!(events.0.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn encrypted_storage_audits_access() {
+    let storage: EncryptedStore<MemoryStorage> =
+        EncryptedStore::new(MemoryStorage::default(), test_util::new_key())
+            .await
+            .unwrap()
+            .with_access_audit(true);
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER);");
+    exec!(glue "INSERT INTO Item VALUES (1), (2);");
+    exec!(glue "SELECT * FROM Item;");
+
+    assert_eq!(glue.storage.flush_access_audit().await.unwrap(), 1);
+    assert_eq!(glue.storage.flush_access_audit().await.unwrap(), 0);
+
+    exec!(glue "SELECT * FROM Item;");
+    let taken = glue.storage.take_access_records();
+    assert_eq!(
+        taken
+            .iter()
+            .map(|record| (record.table.as_str(), record.rows))
+            .collect::<Vec<_>>(),
+        [("Item", 2)]
+    );
+
+    let log = glue.storage.access_audit_log().await.unwrap();
+    assert_eq!(
+        log.iter()
+            .map(|record| (record.table.as_str(), record.rows))
+            .collect::<Vec<_>>(),
+        [("Item", 2)]
+    );
+    assert!(log[0].since <= log[0].until);
+
+    // the audit table is rewritten along with the rest of the store
+    let storage = glue
+        .storage
+        .change_key(UnboundKey::new(&ring::aead::AES_256_GCM, &[1; 32]).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(storage.access_audit_log().await.unwrap(), log);
+}
+
+#[tokio::test]
+async fn encrypted_storage_counts_access() {
+    use gluesql_encryption::TableAccessStats;
+
+    let storage: EncryptedStore<MemoryStorage> =
+        EncryptedStore::new(MemoryStorage::default(), test_util::new_key())
+            .await
+            .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER);");
+    exec!(glue "CREATE TABLE Secret (id INTEGER);");
+    exec!(glue "INSERT INTO Item VALUES (1), (2);");
+    exec!(glue "INSERT INTO Secret VALUES (1);");
+    assert!(glue.storage.access_stats().is_empty());
+
+    exec!(glue "SELECT * FROM Item;");
+    exec!(glue "SELECT * FROM Item;");
+
+    let stats = glue.storage.access_stats();
+    assert_eq!(
+        stats.get("Item"),
+        Some(&TableAccessStats {
+            rows_decrypted: 4,
+            failures: 0,
+        })
+    );
+    assert!(!stats.contains_key("Secret"));
+}
+
+#[cfg(feature = "send")]
+#[test]
+fn encrypted_storage_is_send_and_sync() {
+    use gluesql_encryption::{NonceSource, RoutedStore, SyncNonceSource};
+
+    fn assert_send_sync<T: Send + Sync>() {}
+    fn assert_send<T: Send>(_: &T) {}
+
+    assert_send_sync::<EncryptedStore<MemoryStorage, RandNonce>>();
+    assert_send_sync::<RoutedStore<EncryptedStore<MemoryStorage>, MemoryStorage>>();
+
+    // the futures of nonce sources are too, unlike those of the store traits, which gluesql
+    // declares `?Send`
+    let mut source = SyncNonceSource(RandNonce::new());
+    assert_send(&source.fetch_nonces(1));
+}
+
+#[tokio::test]
+async fn encrypted_storage_is_shared_between_threads() {
+    use gluesql_encryption::{ErrorKind, SharedStore};
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER);");
+    exec!(glue "INSERT INTO Item VALUES (1), (2);");
+
+    let shared: SharedStore<MemoryStorage, RandNonce> = glue.storage.into_shared();
+
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            let shared = shared.clone();
+
+            scope.spawn(move || {
+                let mut glue = Glue::new(shared);
+
+                assert_eq!(
+                    futures::executor::block_on(glue.execute("SELECT id FROM Item ORDER BY id;")),
+                    Ok(vec![Payload::Select {
+                        labels: vec!["id".to_owned()],
+                        rows: vec![vec![Value::I64(1)], vec![Value::I64(2)]],
+                    }])
+                );
+            });
+        }
+    });
+
+    assert_eq!(shared.access_stats()["Item"].rows_decrypted, 8);
+
+    let mut glue = Glue::new(shared);
+    let error = glue
+        .execute("INSERT INTO Item VALUES (3);")
+        .await
+        .unwrap_err();
+    assert_eq!(ErrorKind::of(&error), Some(ErrorKind::Unsupported));
+
+    let storage = glue.storage.try_unwrap().unwrap();
+    let mut glue = Glue::new(storage);
+    exec!(glue "INSERT INTO Item VALUES (3);");
+}
+
+#[tokio::test]
+async fn deterministic_stores_write_the_same_rows() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::data::Key,
+        gluesql_core::store::{DataRow, Store},
+    };
+
+    async fn written(seed: u64) -> Vec<(Key, DataRow)> {
+        let storage = test_util::deterministic_store(MemoryStorage::default(), seed)
+            .await
+            .unwrap();
+        let mut glue = Glue::new(storage);
+
+        exec!(glue "CREATE TABLE Item (id INTEGER PRIMARY KEY, name TEXT);");
+        exec!(glue "INSERT INTO Item VALUES (1, 'a'), (2, 'b');");
+        exec!(glue "UPDATE Item SET name = 'c' WHERE id = 2;");
+
+        Store::scan_data(glue.storage.inner(), "Item")
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap()
+    }
+
+    assert_eq!(written(7).await, written(7).await);
+    assert_ne!(written(7).await, written(8).await);
+}
+
+#[tokio::test]
+async fn encryption_config_is_validated() {
+    use gluesql_encryption::{
+        Compression, EncryptionConfig, EncryptionMode, EncryptionPolicy, Error, ErrorKind, Nulls,
+    };
+
+    let config = EncryptionConfig {
+        policy: EncryptionPolicy::new()
+            .deny_tables(["Lookup"])
+            .tokenize_columns("Lookup", ["name"]),
+        ..EncryptionConfig::default()
+    };
+
+    // configs round-trip through serde, contradictions included
+    let json = serde_json::to_string(&config).unwrap();
+    assert_eq!(
+        serde_json::from_str::<EncryptionConfig>(&json).unwrap(),
+        config
+    );
+    assert!(matches!(
+        config.validate(),
+        Err(Error::InvalidConfig(message)) if message.contains("Lookup")
+    ));
+
+    let error =
+        EncryptedStore::from_config(MemoryStorage::default(), config, &[0; 32], RandNonce::new())
+            .await
+            .unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidConfig);
+
+    let row_mode = EncryptionConfig {
+        policy: EncryptionPolicy::new()
+            .with_table_mode("Secret", EncryptionMode::Row)
+            .with_column_nulls("Secret", "note", Nulls::Expose),
+        ..EncryptionConfig::default()
+    };
+    assert_eq!(
+        row_mode.validate().map_err(|error| error.kind()),
+        Err(ErrorKind::InvalidConfig)
+    );
+
+    let compression = EncryptionConfig {
+        compression: Compression::Deflate { level: 11 },
+        ..EncryptionConfig::default()
+    };
+    assert_eq!(
+        compression.validate().map_err(|error| error.kind()),
+        Err(ErrorKind::InvalidConfig)
+    );
+
+    assert!(EncryptionConfig::default().validate().is_ok());
+}
+
+#[test]
+fn encryption_config_rejects_deterministic_columns_in_row_mode() {
+    use gluesql_encryption::{EncryptionConfig, EncryptionMode, EncryptionPolicy, ErrorKind};
+
+    let validate = |policy| {
+        EncryptionConfig {
+            policy,
+            ..EncryptionConfig::default()
+        }
+        .validate()
+        .map_err(|error| error.kind())
+    };
+
+    assert_eq!(
+        validate(
+            EncryptionPolicy::new()
+                .with_table_mode("Secret", EncryptionMode::Row)
+                .encrypt_unique_deterministically()
+        ),
+        Err(ErrorKind::InvalidConfig)
+    );
+    assert_eq!(
+        validate(
+            EncryptionPolicy::new()
+                .with_mode(EncryptionMode::Row)
+                .encrypt_indexed_deterministically()
+        ),
+        Err(ErrorKind::InvalidConfig)
+    );
+    assert!(validate(
+        EncryptionPolicy::new()
+            .with_mode(EncryptionMode::Row)
+            .with_table_mode("Item", EncryptionMode::Column)
+            .encrypt_indexed_deterministically()
+    )
+    .is_ok());
+}
+
+#[test]
+fn encryption_config_rejects_tokenized_columns_in_row_mode() {
+    use gluesql_encryption::{EncryptionConfig, EncryptionMode, EncryptionPolicy, Error};
+
+    let config = EncryptionConfig {
+        policy: EncryptionPolicy::new()
+            .with_table_mode("Secret", EncryptionMode::Row)
+            .tokenize_columns("Secret", ["ssn"]),
+        ..EncryptionConfig::default()
+    };
+    assert!(matches!(
+        config.validate(),
+        Err(Error::InvalidConfig(message)) if message.contains("Secret")
+    ));
+}
+
+#[test]
+fn encryption_config_rejects_column_settings_in_passthrough() {
+    use gluesql_encryption::{EncryptionConfig, EncryptionPolicy, ErrorKind, Nulls};
+
+    let validate = |policy: EncryptionPolicy| {
+        EncryptionConfig {
+            policy: policy.passthrough(),
+            ..EncryptionConfig::default()
+        }
+        .validate()
+        .map_err(|error| error.kind())
+    };
+
+    assert_eq!(
+        validate(EncryptionPolicy::new().tokenize_columns("Item", ["name"])),
+        Err(ErrorKind::InvalidConfig)
+    );
+    assert_eq!(
+        validate(EncryptionPolicy::new().with_column_nulls("Item", "name", Nulls::Expose)),
+        Err(ErrorKind::InvalidConfig)
+    );
+    assert_eq!(
+        validate(EncryptionPolicy::new().encrypt_unique_deterministically()),
+        Err(ErrorKind::InvalidConfig)
+    );
+    assert!(validate(EncryptionPolicy::new()).is_ok());
+}
+
+#[tokio::test]
+async fn encrypted_storage_describes_its_config() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::store::{DataRow, Store},
+        gluesql_encryption::{Algorithm, EncryptionPolicy},
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().deny_tables(["Lookup"]));
+
+    assert_eq!(storage.algorithm(), Algorithm::Aes256Gcm);
+    assert!(!storage.policy().encrypts_table("Lookup"));
+
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER);");
+    exec!(glue "INSERT INTO Item VALUES (1);");
+
+    let rows: Vec<_> = Store::scan_data(glue.storage.inner(), "Item")
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    assert!(matches!(
+        &rows[0].1,
+        DataRow::Vec(values) if matches!(
+            &values[0],
+            Value::Bytea(sealed) if sealed.len() > glue.storage.per_value_overhead()
+        )
+    ));
+    assert_eq!(glue.storage.per_value_overhead(), 4 + 12 + 16);
+}
+
+#[tokio::test]
+async fn cipher_functions_encrypt_selected_values() {
+    use {
+        futures::TryStreamExt,
+        gluesql_core::store::{DataRow, Store},
+        gluesql_encryption::{CipherFunctions, EncryptionPolicy},
+    };
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().deny_tables(["Contact"]));
+    let functions = CipherFunctions::new(storage.value_cipher());
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Contact (id INTEGER, email BYTEA);");
+    functions
+        .execute(
+            &mut glue,
+            "INSERT INTO Contact VALUES (1, ENCRYPT('a@example.com')), (2, NULL);",
+        )
+        .await
+        .unwrap();
+
+    let rows: Vec<(_, DataRow)> = Store::scan_data(glue.storage.inner(), "Contact")
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    assert!(rows.iter().any(|(_, row)| matches!(
+        row,
+        DataRow::Vec(values) if values[0] == Value::I64(1) && matches!(values[1], Value::Bytea(_))
+    )));
+
+    functions
+        .execute(
+            &mut glue,
+            "UPDATE Contact SET email = ENCRYPT('b@example.com') WHERE id = 2;",
+        )
+        .await
+        .unwrap();
+
+    let payloads = functions
+        .execute(
+            &mut glue,
+            "SELECT id, DECRYPT(email) AS email FROM Contact ORDER BY id;",
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        payloads,
+        vec![Payload::Select {
+            labels: vec!["id".to_owned(), "email".to_owned()],
+            rows: vec![
+                vec![Value::I64(1), Value::Str("a@example.com".to_owned())],
+                vec![Value::I64(2), Value::Str("b@example.com".to_owned())],
+            ],
+        }]
+    );
+}
+
+#[tokio::test]
+async fn cipher_functions_encrypt_negative_numbers() {
+    use gluesql_encryption::{CipherFunctions, EncryptionPolicy};
+
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_policy(EncryptionPolicy::new().deny_tables(["Account"]));
+    let functions = CipherFunctions::new(storage.value_cipher());
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Account (id INTEGER, balance BYTEA);");
+    functions
+        .execute(
+            &mut glue,
+            "INSERT INTO Account VALUES (1, ENCRYPT(-1)), (2, ENCRYPT(-2.5));",
+        )
+        .await
+        .unwrap();
+
+    let payloads = functions
+        .execute(
+            &mut glue,
+            "SELECT id, DECRYPT(balance) AS balance FROM Account ORDER BY id;",
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        payloads,
+        vec![Payload::Select {
+            labels: vec!["id".to_owned(), "balance".to_owned()],
+            rows: vec![
+                vec![Value::I64(1), Value::I64(-1)],
+                vec![Value::I64(2), Value::F64(-2.5)],
+            ],
+        }]
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_into_parts() {
+    use gluesql_encryption::CounterNonce;
+
+    let storage = EncryptedStore::new_with_persistent_nonces(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        CounterNonce::default(),
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER);");
+    exec!(glue "INSERT INTO Item VALUES (1);");
+
+    let parts = glue.storage.into_parts();
+    let position = parts.nonce_sequence.position();
+
+    assert!(position > 0);
+    assert!(parts.previous_key.is_none());
+    assert_eq!(parts.key.algorithm(), &ring::aead::AES_256_GCM);
+
+    // the parts make up the same store, whose sequence picks up where it stopped
+    let mut glue = Glue::new(EncryptedStore::from_store_parts(parts));
+
+    exec!(glue "INSERT INTO Item VALUES (2);");
+    test!(
+        glue
+        "SELECT id FROM Item ORDER BY id;",
+        Ok(vec![Payload::Select {
+            rows: vec![vec![Value::I64(1)], vec![Value::I64(2)]],
+            labels: vec!["id".to_owned()],
+        }])
+    );
+    assert!(glue.storage.into_parts().nonce_sequence.position() > position);
+}
+
+#[tokio::test]
+async fn encrypted_storage_exports_backups() {
+    use gluesql_encryption::{Algorithm, ErrorKind};
+
+    let backup_key = || Algorithm::Aes256Gcm.key(&[7; 32]).unwrap();
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap()
+    .with_batch_size(2);
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Item (id INTEGER PRIMARY KEY, name TEXT);");
+    exec!(glue "INSERT INTO Item VALUES (1, 'secret one'), (2, 'secret two'), (3, 'secret three');");
+
+    let mut backup = Vec::new();
+    assert_eq!(
+        glue.storage
+            .export_backup(backup_key(), &mut backup)
+            .await
+            .unwrap(),
+        3
+    );
+    assert!(!backup.windows(6).any(|window| window == b"secret"));
+
+    let restore = || async {
+        EncryptedStore::new_with_nonce_sequence(
+            MemoryStorage::default(),
+            Algorithm::Aes256Gcm.key(&[9; 32]).unwrap(),
+            RandNonce::new(),
+        )
+        .await
+        .unwrap()
+    };
+
+    let mut restored = restore().await;
+    assert_eq!(
+        restored
+            .import_backup(backup_key(), &mut backup.as_slice())
+            .await
+            .unwrap(),
+        3
+    );
+
+    let mut glue = Glue::new(restored);
+    test!(
+        glue
+        "SELECT id, name FROM Item ORDER BY id;",
+        Ok(vec![Payload::Select {
+            labels: vec!["id".to_owned(), "name".to_owned()],
+            rows: vec![
+                vec![Value::I64(1), Value::Str("secret one".to_owned())],
+                vec![Value::I64(2), Value::Str("secret two".to_owned())],
+                vec![Value::I64(3), Value::Str("secret three".to_owned())],
+            ],
+        }])
+    );
+
+    let wrong_key = restore()
+        .await
+        .import_backup(
+            Algorithm::Aes256Gcm.key(&[8; 32]).unwrap(),
+            &mut backup.as_slice(),
+        )
+        .await;
+    assert_eq!(
+        wrong_key.map_err(|error| error.kind()),
+        Err(ErrorKind::Backup)
+    );
+
+    let mut altered = backup.clone();
+    *altered.last_mut().unwrap() ^= 1;
+    let altered = restore()
+        .await
+        .import_backup(backup_key(), &mut altered.as_slice())
+        .await;
+    assert_eq!(
+        altered.map_err(|error| error.kind()),
+        Err(ErrorKind::Backup)
+    );
+
+    let cut_short = restore()
+        .await
+        .import_backup(backup_key(), &mut &backup[..backup.len() - 40])
+        .await;
+    assert_eq!(
+        cut_short.map_err(|error| error.kind()),
+        Err(ErrorKind::Backup)
+    );
+}
+
+#[tokio::test]
+async fn encrypted_storage_imports_tables_without_primary_keys() {
+    use gluesql_encryption::Algorithm;
+
+    let backup_key = || Algorithm::Aes256Gcm.key(&[7; 32]).unwrap();
+    let storage = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    let mut glue = Glue::new(storage);
+
+    exec!(glue "CREATE TABLE Log (message TEXT);");
+    exec!(glue "INSERT INTO Log VALUES ('a'), ('b'), ('c');");
+
+    let mut backup = Vec::new();
+    glue.storage
+        .export_backup(backup_key(), &mut backup)
+        .await
+        .unwrap();
+
+    let mut restored = EncryptedStore::new_with_nonce_sequence(
+        MemoryStorage::default(),
+        test_util::new_key(),
+        RandNonce::new(),
+    )
+    .await
+    .unwrap();
+    restored
+        .import_backup(backup_key(), &mut backup.as_slice())
+        .await
+        .unwrap();
+
+    // rows inserted after the import don't take the keys of the imported ones
+    let mut glue = Glue::new(restored);
+    exec!(glue "INSERT INTO Log VALUES ('d');");
+
+    test!(
+        glue
+        "SELECT message FROM Log;",
+        Ok(vec![Payload::Select {
+            rows: ["a", "b", "c", "d"]
+                .into_iter()
+                .map(|message| vec![Value::Str(message.to_owned())])
+                .collect(),
+            labels: vec!["message".to_owned()],
+        }])
+    );
+}